@@ -0,0 +1,90 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Measures matching topics against a [`SubscriptionTree`] of many subscribers
+//!
+//! Run with `cargo bench --bench subscription_tree`.
+
+use std::hint::black_box;
+use std::str::FromStr;
+use std::time::Instant;
+
+use cloudmqtt::qos::QualityOfService;
+use cloudmqtt::server::subscriptions::SubscriptionOptions;
+use cloudmqtt::server::subscriptions::SubscriptionTree;
+use cloudmqtt::topic::MqttTopicFilter;
+
+const SITES: usize = 100;
+const DEVICES: usize = 100;
+const WORKERS: usize = 8;
+const ITERATIONS: u32 = 10_000;
+
+/// Subscribers to every device, every site and everything, like a fleet of sensors would have,
+/// plus a group of workers sharing the readings of all devices
+fn tree() -> SubscriptionTree<String> {
+    let mut tree = SubscriptionTree::new();
+    let options = SubscriptionOptions::new(QualityOfService::AtLeastOnce);
+
+    for site in 0..SITES {
+        for device in 0..DEVICES {
+            let filter = format!("sites/{site}/devices/{device}/+");
+            tree.subscribe(
+                &MqttTopicFilter::from_str(&filter).unwrap(),
+                format!("device-{site}-{device}"),
+                options.clone(),
+            );
+        }
+
+        let filter = format!("sites/{site}/#");
+        tree.subscribe(
+            &MqttTopicFilter::from_str(&filter).unwrap(),
+            format!("site-{site}"),
+            options.clone(),
+        );
+    }
+    for worker in 0..WORKERS {
+        tree.subscribe(
+            &MqttTopicFilter::from_str("$share/workers/sites/+/devices/+/+").unwrap(),
+            format!("worker-{worker}"),
+            options.clone(),
+        );
+    }
+    tree.subscribe(
+        &MqttTopicFilter::from_str("#").unwrap(),
+        String::from("logger"),
+        options,
+    );
+
+    tree
+}
+
+fn measure(name: &str, mut run: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        run();
+    }
+    let per_iteration = start.elapsed() / ITERATIONS;
+    println!("{name:<32} {:>10} ns/iter", per_iteration.as_nanos());
+}
+
+fn main() {
+    let start = Instant::now();
+    let mut tree = tree();
+    println!("Subscribed {} filters in {:?}", tree.len(), start.elapsed());
+
+    measure("match device topic", || {
+        black_box(tree.matches::<str>(black_box("sites/42/devices/7/temperature"), None));
+    });
+    measure("match unsubscribed topic", || {
+        black_box(tree.matches::<str>(black_box("fleet/42/status"), None));
+    });
+    measure("match $SYS topic", || {
+        black_box(tree.matches::<str>(black_box("$SYS/broker/uptime"), None));
+    });
+    measure("match shared device topic", || {
+        black_box(tree.shared_matches(black_box("sites/42/devices/7/temperature")));
+    });
+}
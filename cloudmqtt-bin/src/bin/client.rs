@@ -10,8 +10,9 @@
 use cloudmqtt::client::connect::MqttClientConnector;
 use cloudmqtt::client::send::Publish;
 use cloudmqtt::client::MqttClient;
+use cloudmqtt::transport::tls::TlsConfig;
+use cloudmqtt::transport::websocket::WebSocketConfig;
 use cloudmqtt::transport::MqttConnectTransport;
-use futures::FutureExt;
 use tokio::net::TcpStream;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -21,6 +22,14 @@
 struct Args {
     #[arg(long)]
     hostname: String,
+
+    /// Connect via TLS, trusting the CA certificates in the given PEM file
+    #[arg(long)]
+    tls_ca: Option<std::path::PathBuf>,
+
+    /// Connect via WebSockets to the given path, e.g. `/mqtt`
+    #[arg(long)]
+    websocket_path: Option<String>,
 }
 
 #[tokio::main]
@@ -40,9 +49,39 @@ async fn main() {
         .with(fmt_layer)
         .init();
 
-    let socket = TcpStream::connect(args.hostname).await.unwrap();
-
-    let connection = MqttConnectTransport::TokioTcp(socket);
+    let socket = TcpStream::connect(&args.hostname).await.unwrap();
+    let port = socket.peer_addr().unwrap().port();
+
+    let server_name = args
+        .hostname
+        .rsplit_once(':')
+        .map_or(args.hostname.as_str(), |(host, _port)| host);
+    // IPv6 addresses are written in brackets, e.g. `[::1]:8883`, the server name has none
+    let server_name = server_name
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(server_name);
+
+    let tls = args.tls_ca.map(|ca| {
+        TlsConfig::builder()
+            .add_root_certificates_pem(&std::fs::read(ca).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+    });
+    let websocket = args
+        .websocket_path
+        .map(|path| WebSocketConfig::new().with_path(path));
+
+    let connection = match (tls, websocket) {
+        (None, None) => MqttConnectTransport::TokioTcp(socket),
+        (Some(tls), None) => tls.connect(socket, server_name).await.unwrap(),
+        (None, Some(websocket)) => websocket.connect(socket, server_name, port).await.unwrap(),
+        (Some(tls), Some(websocket)) => {
+            let socket = tls.connect_stream(socket, server_name).await.unwrap();
+            websocket.connect(socket, server_name, port).await.unwrap()
+        }
+    };
     let client_id =
         cloudmqtt::client_identifier::ProposedClientIdentifier::PotentiallyServerProvided;
 
@@ -77,9 +116,10 @@ async fn main() {
         .await
         .unwrap()
         .acknowledged()
-        .await;
+        .await
+        .unwrap();
 
-    client.ping().await.unwrap().response().await;
+    client.ping().await.unwrap().response().await.unwrap();
 
     tokio::time::sleep(Duration::from_secs(3)).await;
 
@@ -96,5 +136,8 @@ async fn main() {
 
     tokio::time::sleep(Duration::from_secs(20)).await;
 
+    connected.connection_handle.shutdown().await;
+    background.await.unwrap().unwrap();
+
     println!("Sent message! Bye");
 }
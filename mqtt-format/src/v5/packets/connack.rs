@@ -39,6 +39,7 @@ pub enum ConnackReasonCode {
         MalformedPacket = crate::v5::reason_code::MalformedPacket,
         ProtocolError = crate::v5::reason_code::ProtocolError,
         ImplementationSpecificError = crate::v5::reason_code::ImplementationSpecificError,
+        UnsupportedProtocolVersion = crate::v5::reason_code::UnsupportedProtocolVersion,
         ClientIdentifierNotValid = crate::v5::reason_code::ClientIdentifierNotValid,
         BadUsernameOrPassword = crate::v5::reason_code::BadUsernameOrPassword,
         NotAuthorized = crate::v5::reason_code::NotAuthorized,
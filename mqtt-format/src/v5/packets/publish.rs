@@ -17,7 +17,7 @@
 use crate::v5::variable_header::MessageExpiryInterval;
 use crate::v5::variable_header::PayloadFormatIndicator;
 use crate::v5::variable_header::ResponseTopic;
-use crate::v5::variable_header::SubscriptionIdentifier;
+use crate::v5::variable_header::SubscriptionIdentifiers;
 use crate::v5::variable_header::TopicAlias;
 use crate::v5::variable_header::UserProperties;
 use crate::v5::write::MqttWriteError;
@@ -61,7 +61,7 @@ pub struct PublishProperties<'i> {
         user_properties: UserProperties<'i>,
 
         (anker: "_Toc3901117")
-        subscription_identifier: SubscriptionIdentifier,
+        subscription_identifiers: SubscriptionIdentifiers<'i>,
 
         (anker: "_Toc3901118")
         content_type: ContentType<'i>,
@@ -166,7 +166,7 @@ fn test_roundtrip_pubcomp_no_props() {
                 response_topic: None,
                 correlation_data: None,
                 user_properties: None,
-                subscription_identifier: None,
+                subscription_identifiers: None,
                 content_type: None,
             },
             payload: &[0x12, 0x34],
@@ -202,7 +202,7 @@ fn test_roundtrip_puback_with_props() {
                 response_topic: None,
                 correlation_data: None,
                 user_properties: None,
-                subscription_identifier: None,
+                subscription_identifiers: None,
                 content_type: None,
             },
             payload: &[0x12, 0x34],
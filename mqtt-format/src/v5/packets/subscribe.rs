@@ -151,6 +151,11 @@ fn parse(input: &mut &'i Bytes) -> MResult<Subscriptions<'i>> {
         .parse_next(input)
     }
 
+    /// Use subscriptions that were written one after another with [`Subscription::write`]
+    pub fn from_encoded(encoded: &'i [u8]) -> MResult<Subscriptions<'i>> {
+        Self::parse(&mut Bytes::new(encoded))
+    }
+
     pub fn binary_size(&self) -> u32 {
         self.start.len() as u32
     }
@@ -57,6 +57,7 @@ macro_rules! define_properties {
                 parse with $parser:path as $($lt:lifetime)? $kind:ty;
                 write with $writer:path;
                 with size $size_closure:expr;
+                $(allow repeating: $allow_repeating:literal;)?
                 testvalues: [ $($testvalue:expr),* $(,)? ]
         ),*
         $(,)?
@@ -69,7 +70,7 @@ impl<'lt $(, $tylt)?> MqttProperties<'lt> for $name < $($tylt)? >
                 $(where $tylt: 'lt, 'lt: $tylt)?
             {
                 const IDENTIFIER: u32 = $id;
-                const ALLOW_REPEATING: bool = false;
+                const ALLOW_REPEATING: bool = false $(|| $allow_repeating)?;
 
                 fn parse<'input>(input: &mut &'input Bytes) -> MResult<$name <$($tylt)?>>
                     where
@@ -479,10 +480,120 @@ fn next(&mut self) -> Option<Self::Item> {
     }
 }
 
+#[derive(Clone)]
+pub struct SubscriptionIdentifiers<'i>(pub &'i [u8]);
+
+impl<'i> core::cmp::PartialEq for SubscriptionIdentifiers<'i> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<'i> core::fmt::Debug for SubscriptionIdentifiers<'i> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'i> MqttProperties<'i> for SubscriptionIdentifiers<'i> {
+    const IDENTIFIER: u32 = SubscriptionIdentifier::IDENTIFIER;
+    const ALLOW_REPEATING: bool = true;
+
+    fn parse<'input>(input: &mut &'input Bytes) -> MResult<Self>
+    where
+        'input: 'i,
+    {
+        winnow::combinator::trace("SubscriptionIdentifiers", |input: &mut &'input Bytes| {
+            let slice = *input;
+
+            // We only need to verify there is a correct identifier
+            let _identifier = parse_variable_u32.recognize().parse_next(input)?;
+
+            Ok(Self(slice))
+        })
+        .parse_next(input)
+    }
+
+    fn binary_size(&self) -> u32 {
+        self.iter()
+            .enumerate()
+            .map(|(idx, identifier)| {
+                crate::v5::integers::variable_u32_binary_size(identifier)
+                    // Skip the first id length as we do not write it!
+                    + if idx == 0 {
+                        0
+                    } else {
+                        crate::v5::integers::variable_u32_binary_size(Self::IDENTIFIER)
+                    }
+            })
+            .sum()
+    }
+
+    fn write<W: WriteMqttPacket>(&self, buffer: &mut W) -> WResult<W> {
+        let mut iter = self.iter();
+        let first = iter
+            .next()
+            .expect("There is always at least one SubscriptionIdentifier available");
+
+        write_variable_u32(buffer, first)?;
+
+        for identifier in iter {
+            write_variable_u32(buffer, SubscriptionIdentifiers::IDENTIFIER)?;
+            write_variable_u32(buffer, identifier)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'i> SubscriptionIdentifiers<'i> {
+    /// Iterate over the identifiers of all subscriptions a PUBLISH matched
+    pub fn iter(&self) -> SubscriptionIdentifierIterator<'i> {
+        // Like UserProperties, this points to the value of the first identifier, the others
+        // follow as complete properties
+        SubscriptionIdentifierIterator {
+            current: Bytes::new(self.0),
+            first_prop: true,
+        }
+    }
+}
+
+#[allow(missing_debug_implementations)]
+pub struct SubscriptionIdentifierIterator<'i> {
+    current: &'i Bytes,
+    first_prop: bool,
+}
+
+impl<'i> Iterator for SubscriptionIdentifierIterator<'i> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.first_prop {
+            self.first_prop = false;
+            return Some(parse_variable_u32(&mut self.current).expect(
+                "This has already been parsed and the first item should be a SubscriptionIdentifier",
+            ));
+        }
+
+        while !self.current.is_empty() {
+            let property = Property::parse(&mut self.current)
+                .expect("This has already been parsed, and should be valid.");
+
+            match property {
+                Property::SubscriptionIdentifier(identifier) => return Some(identifier.0),
+                _ => continue,
+            }
+        }
+
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use winnow::Bytes;
 
+    use super::SubscriptionIdentifiers;
     use super::UserProperties;
     use crate::v5::integers::write_variable_u32;
     use crate::v5::test::TestWriter;
@@ -563,4 +674,30 @@ fn test_write_properties() {
             _ => panic!("Wrong type"),
         }
     }
+
+    #[test]
+    fn check_subscription_identifier_iteration() {
+        #[rustfmt::skip]
+        let input = &[
+            // The value of the first identifier
+            0x01,
+            // Retain Available
+            RetainAvailable::IDENTIFIER as u8,
+            0x1,
+            // Subscription Identifier
+            SubscriptionIdentifiers::IDENTIFIER as u8,
+            0xC8, 0x01,
+        ];
+
+        let identifiers = SubscriptionIdentifiers(input);
+        assert_eq!(identifiers.iter().collect::<Vec<_>>(), vec![1, 200]);
+
+        let mut writer = TestWriter { buffer: Vec::new() };
+        identifiers.write(&mut writer).unwrap();
+        assert_eq!(
+            writer.buffer,
+            [0x01, SubscriptionIdentifiers::IDENTIFIER as u8, 0xC8, 0x01]
+        );
+        assert_eq!(identifiers.binary_size() as usize, writer.buffer.len());
+    }
 }
@@ -0,0 +1,378 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! A synchronous client for code that does not run on an async runtime
+//!
+//! [`MqttClient`] owns a tokio runtime that is driven by a dedicated thread. The connection's
+//! background task runs there, while every method blocks the calling thread until the operation
+//! on the async [`MqttClient`](super::MqttClient) underneath completes.
+
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::StreamExt;
+
+use super::connect::MqttClientConnectError;
+use super::connect::MqttClientConnector;
+use super::connect_url::MqttConnectUrlError;
+use super::handle::ConnectionHandle;
+use super::send::Acknowledgement;
+use super::send::ConnectionClosed;
+use super::send::Publish;
+use super::send::PublishError;
+use super::subscribe::ReceivedPublishes;
+use super::subscribe::Subscribe;
+use super::subscribe::SubscribeError;
+use crate::packets::connack::ConnackPropertiesView;
+use crate::packets::Suback;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MqttBlockingClientError {
+    #[error("The client is already connected")]
+    AlreadyConnected,
+
+    #[error("The client is not connected")]
+    NotConnected,
+
+    #[error(transparent)]
+    ConnectUrl(#[from] MqttConnectUrlError),
+
+    #[error(transparent)]
+    Connect(#[from] MqttClientConnectError),
+
+    #[error(transparent)]
+    Publish(#[from] PublishError),
+
+    #[error("The server refused the message: {0:?}")]
+    Refused(Acknowledgement),
+
+    #[error(transparent)]
+    Subscribe(#[from] SubscribeError),
+
+    #[error(transparent)]
+    ConnectionClosed(#[from] ConnectionClosed),
+}
+
+pub struct MqttClient {
+    client: super::MqttClient,
+    runtime: tokio::runtime::Handle,
+    connection_handle: Mutex<Option<ConnectionHandle>>,
+    publishes: Mutex<ReceivedPublishes>,
+    stop: Option<futures::channel::oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MqttClient {
+    /// Create a client and start the thread driving its runtime
+    pub fn new() -> std::io::Result<MqttClient> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+
+        let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("cloudmqtt-blocking".to_string())
+            .spawn(move || {
+                let _ = runtime.block_on(stopped);
+            })?;
+
+        let client = super::MqttClient::new_with_default_handlers();
+        let publishes = handle.block_on(client.publishes());
+
+        Ok(MqttClient {
+            client,
+            runtime: handle,
+            connection_handle: Mutex::new(None),
+            publishes: Mutex::new(publishes),
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Connect to the server at the given connection URL, see
+    /// [`MqttConnectUrl`](super::connect_url::MqttConnectUrl) for the accepted format
+    pub fn connect(&self, url: &str) -> Result<ConnackPropertiesView, MqttBlockingClientError> {
+        let connector = self.runtime.block_on(MqttClientConnector::from_url(url))?;
+
+        self.connect_with(connector)
+    }
+
+    pub fn connect_with(
+        &self,
+        connector: MqttClientConnector,
+    ) -> Result<ConnackPropertiesView, MqttBlockingClientError> {
+        let mut connection_handle = self.connection_handle.lock().unwrap();
+        if connection_handle
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+        {
+            return Err(MqttBlockingClientError::AlreadyConnected);
+        }
+
+        let connected = self.runtime.block_on(self.client.connect(connector))?;
+        self.runtime.spawn(connected.background_task);
+        *connection_handle = Some(connected.connection_handle);
+
+        Ok(connected.connack_prop_view)
+    }
+
+    /// Publish a message and wait until the server acknowledged it, according to its QoS
+    ///
+    /// A message the server refused is returned as [`MqttBlockingClientError::Refused`] with its
+    /// acknowledgement.
+    pub fn publish(&self, publish: Publish) -> Result<(), MqttBlockingClientError> {
+        self.runtime.block_on(async {
+            let published = self.client.publish(publish).await?;
+
+            let acknowledgement = published.acknowledged().await?;
+            if acknowledgement.is_refused() {
+                return Err(MqttBlockingClientError::Refused(acknowledgement));
+            }
+            Ok(())
+        })
+    }
+
+    pub fn subscribe(&self, subscribe: Subscribe) -> Result<Suback, MqttBlockingClientError> {
+        Ok(self.runtime.block_on(self.client.subscribe(subscribe))?)
+    }
+
+    /// Wait for the next message the server sends to this client
+    ///
+    /// Messages that arrive while nobody is waiting are queued, none are lost between calls.
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<crate::packets::Publish, RecvTimeoutError> {
+        let mut publishes = self.publishes.lock().unwrap();
+
+        self.runtime
+            .block_on(async { tokio::time::timeout(timeout, publishes.next()).await })
+            .map_err(|_| RecvTimeoutError::Timeout)?
+            .ok_or(RecvTimeoutError::Disconnected)
+    }
+
+    /// Gracefully disconnect from the server
+    pub fn disconnect(&self) -> Result<(), MqttBlockingClientError> {
+        let connection_handle = self
+            .connection_handle
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(MqttBlockingClientError::NotConnected)?;
+
+        self.runtime.block_on(connection_handle.shutdown());
+        Ok(())
+    }
+}
+
+impl Drop for MqttClient {
+    /// Stop the runtime thread, a connection that is still open is closed without a DISCONNECT
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                tracing::error!("The runtime thread of the blocking client panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::puback::PubackReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::codec::Framed;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::MqttBlockingClientError;
+    use super::MqttClient;
+    use crate::client::connect::CleanStart;
+    use crate::client::connect::MqttClientConnector;
+    use crate::client::send::Acknowledgement;
+    use crate::client::send::Publish;
+    use crate::client::subscribe::Subscribe;
+    use crate::client::subscribe::Subscription;
+    use crate::client::tests::success_connack;
+    use crate::client_identifier::ProposedClientIdentifier;
+    use crate::codecs::MqttPacketCodec;
+    use crate::keep_alive::KeepAlive;
+    use crate::qos::QualityOfService;
+    use crate::transport::MqttConnectTransport;
+    use crate::transport::MqttConnection;
+
+    static_assertions::assert_impl_all!(MqttClient: Send, Sync);
+
+    #[test]
+    fn blocking_roundtrip() {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+        let server = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let mut server = Framed::new(
+                    MqttConnection::Duplex(server_stream.compat()),
+                    MqttPacketCodec,
+                );
+
+                let connect = server.next().await.unwrap().unwrap();
+                assert!(matches!(connect.get(), FormatMqttPacket::Connect(_)));
+                server
+                    .send(FormatMqttPacket::Connack(success_connack()))
+                    .await
+                    .unwrap();
+
+                let subscribe = server.next().await.unwrap().unwrap();
+                let FormatMqttPacket::Subscribe(subscribe) = subscribe.get() else {
+                    panic!("Expected SUBSCRIBE, got {:?}", subscribe.get());
+                };
+                server
+                    .send(FormatMqttPacket::Suback(
+                        mqtt_format::v5::packets::suback::MSuback {
+                            packet_identifier: subscribe.packet_identifier,
+                            properties: mqtt_format::v5::packets::suback::SubackProperties::new(),
+                            reasons: &[
+                                mqtt_format::v5::packets::suback::SubackReasonCode::GrantedQoS0,
+                            ],
+                        },
+                    ))
+                    .await
+                    .unwrap();
+
+                let publish = server.next().await.unwrap().unwrap();
+                let FormatMqttPacket::Publish(publish) = publish.get() else {
+                    panic!("Expected PUBLISH, got {:?}", publish.get());
+                };
+                server
+                    .send(FormatMqttPacket::Publish(publish.clone()))
+                    .await
+                    .unwrap();
+
+                let disconnect = server.next().await.unwrap().unwrap();
+                assert!(matches!(disconnect.get(), FormatMqttPacket::Disconnect(_)));
+            });
+        });
+
+        let client = MqttClient::new().unwrap();
+        client
+            .connect_with(MqttClientConnector::new(
+                MqttConnectTransport::TokioDuplex(client_stream),
+                ProposedClientIdentifier::new_minimal_required("test").unwrap(),
+                CleanStart::Yes,
+                KeepAlive::Disabled,
+            ))
+            .unwrap();
+
+        client
+            .subscribe(Subscribe::new(vec![Subscription::new(
+                "foo/#".parse().unwrap(),
+                QualityOfService::AtMostOnce,
+            )]))
+            .unwrap();
+
+        assert!(matches!(
+            client.recv_timeout(Duration::from_millis(10)),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout)
+        ));
+
+        client
+            .publish(Publish {
+                topic: "foo/bar".try_into().unwrap(),
+                qos: QualityOfService::AtMostOnce,
+                retain: false,
+                payload: vec![1, 2, 3].try_into().unwrap(),
+                on_packet_recv: None,
+            })
+            .unwrap();
+
+        let received = client.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received.topic(), "foo/bar");
+        assert_eq!(received.payload(), [1, 2, 3]);
+
+        client.disconnect().unwrap();
+        assert!(client.disconnect().is_err());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn refused_publishes_are_errors() {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+        let server = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let mut server = Framed::new(
+                    MqttConnection::Duplex(server_stream.compat()),
+                    MqttPacketCodec,
+                );
+
+                server.next().await.unwrap().unwrap();
+                server
+                    .send(FormatMqttPacket::Connack(success_connack()))
+                    .await
+                    .unwrap();
+
+                let publish = server.next().await.unwrap().unwrap();
+                let FormatMqttPacket::Publish(publish) = publish.get() else {
+                    panic!("Expected PUBLISH, got {:?}", publish.get());
+                };
+                server
+                    .send(FormatMqttPacket::Puback(
+                        mqtt_format::v5::packets::puback::MPuback {
+                            packet_identifier: publish.packet_identifier.unwrap(),
+                            reason: PubackReasonCode::NotAuthorized,
+                            properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                        },
+                    ))
+                    .await
+                    .unwrap();
+
+                let disconnect = server.next().await.unwrap().unwrap();
+                assert!(matches!(disconnect.get(), FormatMqttPacket::Disconnect(_)));
+            });
+        });
+
+        let client = MqttClient::new().unwrap();
+        client
+            .connect_with(MqttClientConnector::new(
+                MqttConnectTransport::TokioDuplex(client_stream),
+                ProposedClientIdentifier::new_minimal_required("test").unwrap(),
+                CleanStart::Yes,
+                KeepAlive::Disabled,
+            ))
+            .unwrap();
+
+        let error = client
+            .publish(Publish {
+                topic: "foo/bar".try_into().unwrap(),
+                qos: QualityOfService::AtLeastOnce,
+                retain: false,
+                payload: vec![1, 2, 3].try_into().unwrap(),
+                on_packet_recv: None,
+            })
+            .unwrap_err();
+        let MqttBlockingClientError::Refused(Acknowledgement::Puback(puback)) = error else {
+            panic!("Expected a refused PUBACK, got {error:?}");
+        };
+        assert_eq!(puback.reason_code(), PubackReasonCode::NotAuthorized);
+
+        client.disconnect().unwrap();
+        server.join().unwrap();
+    }
+}
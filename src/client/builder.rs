@@ -8,10 +8,12 @@
 
 use futures::lock::Mutex;
 
+use super::events::EventSenders;
 use super::send::Callbacks;
 use super::send::ClientHandlers;
 use super::send::OnPacketRecvFn;
 use super::send::OnQos1AcknowledgeFn;
+use super::subscribe::PublishSenders;
 use super::InnerClient;
 use super::MqttClient;
 
@@ -44,6 +46,9 @@ pub async fn build(self) -> Result<super::MqttClient, MqttClientBuilderError> {
                     session_state: None,
                     default_handlers: self.handlers,
                     outstanding_callbacks: Callbacks::new(),
+                    events: EventSenders::new(),
+                    publishes: PublishSenders::new(),
+                    server_reference: None,
                 })),
             }
         })
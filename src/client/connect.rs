@@ -5,18 +5,27 @@
 //
 
 use std::time::Duration;
+use std::time::Instant;
 
 use futures::select;
 use futures::FutureExt;
 use futures::SinkExt;
 use futures::StreamExt;
+use mqtt_format::v5::packets::MqttPacketKind;
+use tokio::io::AsyncWriteExt;
 use tokio_util::codec::FramedRead;
 use tokio_util::codec::FramedWrite;
 
+use super::events::ConnectedEvent;
+use super::events::DisconnectReason;
+use super::events::MqttClientEvent;
+use super::handle::handle_cancellation;
+use super::handle::Cancellation;
+use super::handle::ConnectionHandle;
 use super::MqttClient;
 use crate::bytes::MqttBytes;
 use crate::client::state::OutstandingPackets;
-use crate::client::state::TransportWriter;
+use crate::client::write::TransportWriter;
 use crate::client::ConnectState;
 use crate::client::SessionState;
 use crate::client_identifier::ProposedClientIdentifier;
@@ -83,6 +92,13 @@ pub enum MqttClientConnectError {
 
     #[error("The server sent a response with a protocol error: {reason}")]
     ServerProtocolError { reason: &'static str },
+
+    #[error("The server refused the connection: {reason_code:?}")]
+    Refused {
+        reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode,
+        /// Another server the client should use instead, if the server sent one
+        server_reference: Option<String>,
+    },
 }
 
 pub struct MqttClientConnector {
@@ -139,6 +155,12 @@ pub fn properties_mut(&mut self) -> &mut crate::packets::connect::ConnectPropert
 pub struct Connected {
     pub connack_prop_view: ConnackPropertiesView,
     pub background_task: futures::future::BoxFuture<'static, Result<(), ()>>,
+    pub connection_handle: ConnectionHandle,
+}
+
+enum BackgroundOutcome {
+    Finished(Result<DisconnectReason, ()>),
+    Cancelled(Cancellation),
 }
 
 impl MqttClient {
@@ -150,8 +172,15 @@ pub async fn connect(
 
         let inner_clone = self.inner.clone();
         let mut inner = self.inner.lock().await;
+
+        if inner.session_state.is_some() {
+            inner.events.emit(MqttClientEvent::Reconnecting);
+        } else {
+            inner.events.emit(MqttClientEvent::Connecting);
+        }
+
         let (read, write) = tokio::io::split(MqttConnection::from(connector.transport));
-        let mut conn_write = FramedWrite::new(write, crate::codecs::MqttPacketCodec);
+        let mut conn_write_framed = FramedWrite::new(write, crate::codecs::MqttPacketCodec);
         let mut conn_read = FramedRead::new(read, crate::codecs::MqttPacketCodec);
 
         let conn_packet = mqtt_format::v5::packets::connect::MConnect {
@@ -164,7 +193,8 @@ pub async fn connect(
             keep_alive: connector.keep_alive.as_u16(),
         };
 
-        conn_write
+        let connect_sent_at = Instant::now();
+        conn_write_framed
             .send(mqtt_format::v5::packets::MqttPacket::Connect(conn_packet))
             .await
             .map_err(Mcce::Send)?;
@@ -217,18 +247,14 @@ pub async fn connect(
                 });
             }
 
-            let (sender, heartbeat_receiver) = futures::channel::mpsc::channel(1);
-            let conn_write = TransportWriter::new(conn_write, sender);
-
-            let (conn_read_sender, conn_read_recv) = futures::channel::oneshot::channel();
+            let (heartbeat_sender, heartbeat_receiver) = futures::channel::mpsc::channel(1);
+            let (conn_write, write_requests) = TransportWriter::new();
+            let mut conn_write_half = conn_write_framed.into_inner();
 
+            let session_present = connack.session_present;
             let connect_client_state = ConnectState {
-                session_present: connack.session_present,
-                receive_maximum: connack.properties.receive_maximum().map(|rm| rm.0),
-                maximum_qos: connack.properties.maximum_qos().map(|mq| mq.0),
                 retain_available: connack.properties.retain_available().map(|ra| ra.0),
                 maximum_packet_size: connack.properties.maximum_packet_size().map(|mps| mps.0),
-                topic_alias_maximum: connack.properties.topic_alias_maximum().map(|tam| tam.0),
                 keep_alive: connack
                     .properties
                     .server_keep_alive()
@@ -239,8 +265,8 @@ pub async fn connect(
                     })
                     .unwrap_or(connector.keep_alive),
                 conn_write,
-                conn_read_recv,
                 next_packet_identifier: std::num::NonZeroU16::MIN,
+                connected_at: Instant::now(),
             };
 
             let assigned_client_identifier = connack.properties.assigned_client_identifier();
@@ -275,58 +301,189 @@ pub async fn connect(
 
             let keep_alive = connect_client_state.keep_alive;
 
-            inner.connection_state = Some(connect_client_state);
-            inner.session_state = Some(SessionState {
-                client_identifier,
-                outstanding_packets: OutstandingPackets::empty(),
+            // Unacknowledged packets are only sent again if the server kept the session. The
+            // previous state stays stored if that fails, so the next connection can resume it.
+            let resumed = inner.session_state.as_mut().filter(|previous| {
+                session_present && previous.client_identifier == client_identifier
             });
+            if let Some(previous) = resumed {
+                resend_outstanding(&mut conn_write_half, previous).await?;
+            } else {
+                inner.session_state = Some(SessionState {
+                    client_identifier,
+                    outstanding_packets: OutstandingPackets::empty(),
+                    received_qos2: Default::default(),
+                });
+            }
+
+            inner.connection_state = Some(connect_client_state);
 
             let connack_prop_view =
                 crate::packets::connack::ConnackPropertiesView::try_from(maybe_connack)
                     .expect("An already matched value suddenly changed?");
 
-            let background_task = async move {
-                let receiving_inner = inner_clone.clone();
-                let receiving = crate::client::receive::handle_background_receiving(
-                    receiving_inner,
-                    conn_read,
-                    conn_read_sender,
-                );
+            let connected_event = ConnectedEvent {
+                session_present,
+                connack_properties: connack_prop_view.clone(),
+                handshake_duration: connect_sent_at.elapsed(),
+                connected_at: Instant::now(),
+            };
+            inner
+                .events
+                .emit(MqttClientEvent::Connected(connected_event));
 
-                let heartbeat_inner = inner_clone;
+            let (cancel_sender, cancel_receiver) = futures::channel::oneshot::channel();
+            let (finished_sender, finished_receiver) = futures::channel::oneshot::channel();
+            let connection_handle =
+                ConnectionHandle::new(inner_clone.clone(), cancel_sender, finished_receiver);
 
-                let heartbeat = if let KeepAlive::Seconds(time) = keep_alive {
-                    handle_heartbeats(
-                        heartbeat_receiver,
-                        Duration::from_secs(time.get().into()),
-                        heartbeat_inner,
+            let background_task = async move {
+                // The writer is kept alive after the other futures are dropped, so that a
+                // DISCONNECT can still be sent on shutdown
+                let writing = crate::client::write::handle_background_writing(
+                    write_requests,
+                    conn_write_half,
+                    heartbeat_sender,
+                )
+                .fuse();
+                futures::pin_mut!(writing);
+
+                let outcome = {
+                    let receiving = crate::client::receive::handle_background_receiving(
+                        inner_clone.clone(),
+                        conn_read,
                     )
-                    .left_future()
-                } else {
-                    tracing::info!(
-                        "Keep Alive is disabled, will not send PingReq packets automatically"
-                    );
-                    futures::future::ok(()).right_future()
+                    .fuse();
+
+                    let heartbeat = if let KeepAlive::Seconds(time) = keep_alive {
+                        handle_heartbeats(
+                            heartbeat_receiver,
+                            Duration::from_secs(time.get().into()),
+                            inner_clone.clone(),
+                        )
+                        .left_future()
+                    } else {
+                        tracing::info!(
+                            "Keep Alive is disabled, will not send PingReq packets automatically"
+                        );
+                        futures::future::pending().right_future()
+                    }
+                    .fuse();
+
+                    // A dropped ConnectionHandle only detaches, it does not cancel the task
+                    let cancelled = async move {
+                        match cancel_receiver.await {
+                            Ok(cancellation) => cancellation,
+                            Err(_) => futures::future::pending().await,
+                        }
+                    }
+                    .fuse();
+
+                    futures::pin_mut!(receiving, heartbeat, cancelled);
+
+                    // The other futures are dropped at the end of this block, releasing any
+                    // locks they might still be holding
+                    select! {
+                        res = receiving => BackgroundOutcome::Finished(res),
+                        res = heartbeat => BackgroundOutcome::Finished(
+                            res.map(|()| DisconnectReason::TransportClosed)
+                        ),
+                        res = writing => BackgroundOutcome::Finished(
+                            res.map(|()| DisconnectReason::TransportClosed)
+                        ),
+                        cancellation = cancelled => BackgroundOutcome::Cancelled(cancellation),
+                    }
                 };
 
-                tokio::try_join!(receiving, heartbeat)
-                    .map(drop)
-                    .map_err(drop)
+                let (result, reason) = match outcome {
+                    BackgroundOutcome::Finished(Ok(reason)) => (Ok(()), reason),
+                    BackgroundOutcome::Finished(Err(())) => (Err(()), DisconnectReason::Error),
+                    BackgroundOutcome::Cancelled(cancellation) => {
+                        tracing::debug!(?cancellation, "Background task was cancelled");
+                        let cancelling = handle_cancellation(&inner_clone, cancellation).fuse();
+                        futures::pin_mut!(cancelling);
+
+                        // Keep writing until the cancellation went through
+                        loop {
+                            select! {
+                                () = cancelling => break,
+                                _ = writing => {},
+                            }
+                        }
+
+                        (Ok(()), DisconnectReason::from(cancellation))
+                    }
+                };
+
+                inner_clone.lock().await.reset_connection(reason);
+
+                if finished_sender.send(()).is_err() {
+                    tracing::trace!("ConnectionHandle was dropped before the task finished");
+                }
+
+                result
             }
             .boxed();
 
             return Ok(Connected {
                 connack_prop_view,
                 background_task,
+                connection_handle,
             });
         }
 
-        // TODO: Do something with error code
+        tracing::warn!(reason_code = ?connack.reason_code, "Server refused the connection");
 
-        todo!()
+        Err(MqttClientConnectError::Refused {
+            reason_code: connack.reason_code,
+            server_reference: connack
+                .properties
+                .server_reference()
+                .map(|reference| reference.0.to_owned()),
+        })
     }
 }
 
+/// Send the PUBLISH and PUBREL packets of a resumed session again, in their original order
+///
+/// Other outstanding packets belong to requests of the previous connection that were already
+/// failed, so they are dropped.
+async fn resend_outstanding(
+    conn_write: &mut tokio::io::WriteHalf<MqttConnection>,
+    session_state: &mut SessionState,
+) -> Result<(), MqttClientConnectError> {
+    let mut dropped = Vec::new();
+    for (packet_identifier, packet) in session_state.outstanding_packets.iter_in_send_order() {
+        if !matches!(
+            packet.kind(),
+            Some(MqttPacketKind::Publish | MqttPacketKind::Pubrel)
+        ) {
+            dropped.push(packet_identifier);
+            continue;
+        }
+
+        tracing::debug!(%packet_identifier, "Resending packet of resumed session");
+        for chunk in packet.with_duplicate().chunks() {
+            conn_write
+                .write_all(chunk)
+                .await
+                .map_err(|error| MqttClientConnectError::Send(error.into()))?;
+        }
+    }
+    conn_write
+        .flush()
+        .await
+        .map_err(|error| MqttClientConnectError::Send(error.into()))?;
+
+    for packet_identifier in dropped {
+        session_state
+            .outstanding_packets
+            .remove_by_id(packet_identifier);
+    }
+
+    Ok(())
+}
+
 async fn handle_heartbeats(
     mut heartbeat_receiver: futures::channel::mpsc::Receiver<()>,
     duration: Duration,
@@ -342,18 +499,244 @@ async fn handle_heartbeats(
                 },
             },
             _ = timeout => {
-                let mut inner = heartbeat_inner.lock().await;
-                let inner = &mut *inner;
-                let Some(conn_state) = inner.connection_state.as_mut() else {
-                    todo!();
+                let pingreq = mqtt_format::v5::packets::MqttPacket::Pingreq(
+                    mqtt_format::v5::packets::pingreq::MPingreq,
+                );
+                let queued = match heartbeat_inner.lock().await.connection_state.as_mut() {
+                    Some(conn_state) => conn_state.conn_write.queue(pingreq).await,
+                    None => {
+                        tracing::debug!("Connection was closed, stopping heartbeats");
+                        break;
+                    }
                 };
 
-                // We make sure that this won't deadlock in the send method
-                conn_state.conn_write.send(
-                    mqtt_format::v5::packets::MqttPacket::Pingreq(mqtt_format::v5::packets::pingreq::MPingreq)
-                ).await.unwrap();
+                let written = match queued {
+                    Ok(written) => written.written().await,
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = written {
+                    tracing::error!(%error, "Could not send PingReq, stopping heartbeats");
+                    return Err(());
+                }
             }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::codec::Framed;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::CleanStart;
+    use super::MqttClientConnectError;
+    use super::MqttClientConnector;
+    use crate::client::send::PublishError;
+    use crate::client::send::PublishQos1;
+    use crate::client::tests::success_connack;
+    use crate::client::tests::TestServer;
+    use crate::client::MqttClient;
+    use crate::client_identifier::ProposedClientIdentifier;
+    use crate::codecs::MqttPacketCodec;
+    use crate::keep_alive::KeepAlive;
+    use crate::transport::fault::FaultConfig;
+    use crate::transport::MqttConnectTransport;
+    use crate::transport::MqttConnection;
+
+    /// Connect without a clean start, the server answers with the given session present flag
+    async fn connect_resuming(
+        client: &MqttClient,
+        session_present: bool,
+    ) -> (super::Connected, TestServer) {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let mut server = Framed::new(
+            MqttConnection::Duplex(server_stream.compat()),
+            MqttPacketCodec,
+        );
+
+        let server_task = tokio::spawn(async move {
+            server.next().await.unwrap().unwrap();
+            let mut connack = success_connack();
+            connack.session_present = session_present;
+            server
+                .send(FormatMqttPacket::Connack(connack))
+                .await
+                .unwrap();
+            server
+        });
+
+        let connected = client
+            .connect(MqttClientConnector::new(
+                MqttConnectTransport::TokioDuplex(client_stream),
+                ProposedClientIdentifier::new_minimal_required("test").unwrap(),
+                CleanStart::No,
+                KeepAlive::Disabled,
+            ))
+            .await
+            .unwrap();
+        (connected, server_task.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn resumed_sessions_resend_unacknowledged_publishes() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, mut server) = connect_resuming(&client, false).await;
+        let background = tokio::spawn(connected.background_task);
+
+        let published = client
+            .publish_qos1(PublishQos1::new(
+                "foo/bar".try_into().unwrap(),
+                false,
+                vec![1, 2, 3].try_into().unwrap(),
+            ))
+            .await
+            .unwrap();
+        let publish = server.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(first) = publish.get() else {
+            panic!("Expected a PUBLISH");
+        };
+        assert!(!first.duplicate);
+
+        connected.connection_handle.abort().await;
+        background.await.unwrap().unwrap();
+        assert!(published.acknowledged().await.is_err());
+
+        let (connected, mut server) = connect_resuming(&client, true).await;
+        let background = tokio::spawn(connected.background_task);
+
+        let resent = server.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(resent) = resent.get() else {
+            panic!("Expected a PUBLISH");
+        };
+        assert!(resent.duplicate);
+        assert_eq!(resent.packet_identifier, first.packet_identifier);
+        assert_eq!(resent.payload, [1, 2, 3]);
+
+        // Without the session on the server, nothing is sent again
+        connected.connection_handle.abort().await;
+        background.await.unwrap().unwrap();
+        let (connected, mut server) = connect_resuming(&client, false).await;
+        let background = tokio::spawn(connected.background_task);
+        connected.connection_handle.shutdown().await;
+        assert!(matches!(
+            server.next().await.unwrap().unwrap().get(),
+            FormatMqttPacket::Disconnect(_)
+        ));
+        background.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_resend_keeps_session_state() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, mut server) = connect_resuming(&client, false).await;
+        let background = tokio::spawn(connected.background_task);
+
+        let _published = client
+            .publish_qos1(PublishQos1::new(
+                "foo/bar".try_into().unwrap(),
+                false,
+                vec![1, 2, 3].try_into().unwrap(),
+            ))
+            .await
+            .unwrap();
+        server.next().await.unwrap().unwrap();
+        connected.connection_handle.abort().await;
+        background.await.unwrap().unwrap();
+
+        // The server closes the connection right after resuming the session, so resending fails
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let server_task = tokio::spawn(async move {
+            let mut server = Framed::new(
+                MqttConnection::Duplex(server_stream.compat()),
+                MqttPacketCodec,
+            );
+            server.next().await.unwrap().unwrap();
+            let mut connack = success_connack();
+            connack.session_present = true;
+            server
+                .send(FormatMqttPacket::Connack(connack))
+                .await
+                .unwrap();
+        });
+        let result = client
+            .connect(MqttClientConnector::new(
+                MqttConnectTransport::TokioDuplex(client_stream),
+                ProposedClientIdentifier::new_minimal_required("test").unwrap(),
+                CleanStart::No,
+                KeepAlive::Disabled,
+            ))
+            .await;
+        server_task.await.unwrap();
+        assert!(matches!(result, Err(MqttClientConnectError::Send(_))));
+
+        // The PUBLISH is resent while connecting, before the DISCONNECT of the shutdown
+        let (connected, mut server) = connect_resuming(&client, true).await;
+        let background = tokio::spawn(connected.background_task);
+        connected.connection_handle.shutdown().await;
+        let resent = server.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(resent) = resent.get() else {
+            panic!("Expected a PUBLISH, got {:?}", resent.get());
+        };
+        assert!(resent.duplicate);
+        assert_eq!(resent.payload, [1, 2, 3]);
+        background.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_publishes_are_not_resent() {
+        let client = MqttClient::new_with_default_handlers();
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let (transport, fault) =
+            FaultConfig::new(0).inject(MqttConnectTransport::TokioDuplex(client_stream));
+        let server_task = tokio::spawn(async move {
+            let mut server = Framed::new(
+                MqttConnection::Duplex(server_stream.compat()),
+                MqttPacketCodec,
+            );
+            server.next().await.unwrap().unwrap();
+            server
+                .send(FormatMqttPacket::Connack(success_connack()))
+                .await
+                .unwrap();
+            server
+        });
+        let connected = client
+            .connect(MqttClientConnector::new(
+                transport,
+                ProposedClientIdentifier::new_minimal_required("test").unwrap(),
+                CleanStart::No,
+                KeepAlive::Disabled,
+            ))
+            .await
+            .unwrap();
+        let _server = server_task.await.unwrap();
+        let background = tokio::spawn(connected.background_task);
+
+        fault.disconnect();
+        let result = client
+            .publish_qos1(PublishQos1::new(
+                "foo/bar".try_into().unwrap(),
+                false,
+                vec![1, 2, 3].try_into().unwrap(),
+            ))
+            .await;
+        assert!(matches!(result, Err(PublishError::Send)));
+        let _ = background.await.unwrap();
+
+        // The caller was told that publishing failed, so resuming the session does not send it
+        let (connected, mut server) = connect_resuming(&client, true).await;
+        let background = tokio::spawn(connected.background_task);
+        connected.connection_handle.shutdown().await;
+        let next = server.next().await.unwrap().unwrap();
+        assert!(
+            matches!(next.get(), FormatMqttPacket::Disconnect(_)),
+            "Expected DISCONNECT, got {:?}",
+            next.get()
+        );
+        background.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::Stream;
+use mqtt_format::v5::packets::auth::AuthReasonCode;
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+
+use super::MqttClient;
+use crate::packets::auth::AuthPropertiesView;
+use crate::packets::connack::ConnackPropertiesView;
+use crate::packets::disconnect::DisconnectPropertiesView;
+
+/// Lifecycle events of a client connection
+///
+/// These are emitted in addition to the packet-level `on_packet_recv` handler, see
+/// [`MqttClient::events`].
+#[derive(Clone, Debug)]
+pub enum MqttClientEvent {
+    /// A connection attempt was started on a client that was not connected before
+    Connecting,
+
+    /// A connection attempt was started on a client that has been connected before
+    Reconnecting,
+
+    Connected(ConnectedEvent),
+
+    /// The server sent a DISCONNECT, the connection will be closed afterwards
+    ServerDisconnect(ServerDisconnectEvent),
+
+    /// The server sent an AUTH packet as part of a re-authentication
+    ReAuthentication(ReAuthenticationEvent),
+
+    Disconnected(DisconnectedEvent),
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectedEvent {
+    pub session_present: bool,
+    pub connack_properties: ConnackPropertiesView,
+    /// The time it took from sending the CONNECT to receiving the CONNACK
+    pub handshake_duration: Duration,
+    pub connected_at: Instant,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerDisconnectEvent {
+    pub reason_code: DisconnectReasonCode,
+    pub properties: DisconnectPropertiesView,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReAuthenticationEvent {
+    pub reason_code: AuthReasonCode,
+    pub properties: AuthPropertiesView,
+}
+
+#[derive(Clone, Debug)]
+pub struct DisconnectedEvent {
+    pub reason: DisconnectReason,
+    pub connected_for: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The connection was closed through [`ConnectionHandle::shutdown`](super::handle::ConnectionHandle::shutdown)
+    Shutdown,
+    /// The connection was closed through [`ConnectionHandle::abort`](super::handle::ConnectionHandle::abort)
+    Aborted,
+    /// The server sent a DISCONNECT with the given reason code
+    Server(DisconnectReasonCode),
+    /// The transport was closed without a DISCONNECT
+    TransportClosed,
+    /// An error occurred while sending or receiving
+    Error,
+}
+
+pub(crate) struct EventSenders {
+    senders: Vec<futures::channel::mpsc::UnboundedSender<MqttClientEvent>>,
+}
+
+impl EventSenders {
+    pub(crate) fn new() -> Self {
+        Self {
+            senders: Vec::new(),
+        }
+    }
+
+    pub(crate) fn subscribe(&mut self) -> MqttClientEvents {
+        let (sender, recv) = futures::channel::mpsc::unbounded();
+        self.senders.push(sender);
+        MqttClientEvents { recv }
+    }
+
+    pub(crate) fn emit(&mut self, event: MqttClientEvent) {
+        tracing::trace!(?event, "Emitting client event");
+        // Subscribers that dropped their stream are removed on the next event
+        self.senders
+            .retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// A stream of [`MqttClientEvent`]s, created with [`MqttClient::events`]
+pub struct MqttClientEvents {
+    recv: futures::channel::mpsc::UnboundedReceiver<MqttClientEvent>,
+}
+
+impl Stream for MqttClientEvents {
+    type Item = MqttClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.recv).poll_next(cx)
+    }
+}
+
+impl MqttClient {
+    /// Subscribe to the lifecycle events of this client
+    ///
+    /// Only events that happen after subscribing are delivered. The stream ends once the client and
+    /// its background task are dropped.
+    pub async fn events(&self) -> MqttClientEvents {
+        self.inner.lock().await.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+    use super::DisconnectReason;
+    use super::MqttClientEvent;
+    use crate::client::tests::connect_to_test_server;
+    use crate::client::tests::success_connack;
+    use crate::client::MqttClient;
+
+    #[tokio::test]
+    async fn server_disconnect_emits_lifecycle_events() {
+        let client = MqttClient::new_with_default_handlers();
+        let mut events = client.events().await;
+
+        let (connected, mut server) = connect_to_test_server(&client, success_connack()).await;
+        let background = tokio::spawn(connected.background_task);
+
+        assert!(matches!(
+            events.next().await,
+            Some(MqttClientEvent::Connecting)
+        ));
+        let Some(MqttClientEvent::Connected(connected_event)) = events.next().await else {
+            panic!("Expected a Connected event");
+        };
+        assert!(!connected_event.session_present);
+
+        server
+            .send(FormatMqttPacket::Disconnect(
+                mqtt_format::v5::packets::disconnect::MDisconnect {
+                    reason_code: DisconnectReasonCode::ServerShuttingDown,
+                    properties: mqtt_format::v5::packets::disconnect::DisconnectProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let Some(MqttClientEvent::ServerDisconnect(server_disconnect)) = events.next().await else {
+            panic!("Expected a ServerDisconnect event");
+        };
+        assert_eq!(
+            server_disconnect.reason_code,
+            DisconnectReasonCode::ServerShuttingDown
+        );
+
+        let Some(MqttClientEvent::Disconnected(disconnected)) = events.next().await else {
+            panic!("Expected a Disconnected event");
+        };
+        assert_eq!(
+            disconnected.reason,
+            DisconnectReason::Server(DisconnectReasonCode::ServerShuttingDown)
+        );
+
+        assert!(background.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,455 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Connecting to one of several brokers
+//!
+//! [`MqttFailoverConnector`] tries an ordered list of endpoints until one accepts the connection.
+//! A server reference sent by the server in a CONNACK or DISCONNECT is followed automatically.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use mqtt_format::v5::packets::connack::ConnackReasonCode;
+
+use super::connect::Connected;
+use super::connect::MqttClientConnectError;
+use super::connect::MqttClientConnector;
+use super::connect_url::MqttConnectUrlError;
+use super::connect_url::MqttUrlTransport;
+use super::MqttClient;
+#[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+use crate::runtime::DefaultRuntime;
+use crate::runtime::Runtime;
+use crate::transport::proxy::ProxyServer;
+use crate::transport::MqttConnectTransport;
+
+const DEFAULT_ENDPOINT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_REDIRECTS: usize = 3;
+
+pub type MakeConnectorFn = Box<dyn FnMut(MqttConnectTransport) -> MqttClientConnector + Send>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MqttFailoverError {
+    #[error("No endpoints were configured")]
+    NoEndpoints,
+
+    #[error("Could not connect to any of the endpoints")]
+    AllEndpointsFailed { failures: Vec<EndpointFailure> },
+}
+
+#[derive(Debug)]
+pub struct EndpointFailure {
+    pub endpoint: MqttUrlTransport,
+    pub error: EndpointError,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EndpointError {
+    #[error("Could not open the transport")]
+    Transport(#[from] MqttConnectUrlError),
+
+    #[error("Could not connect")]
+    Connect(#[from] MqttClientConnectError),
+
+    #[error("The endpoint did not respond in time")]
+    Timeout,
+}
+
+/// A server the client was told to use instead of the current one
+#[derive(Debug, Clone)]
+pub(crate) struct ServerReference {
+    pub(crate) reference: String,
+    /// Whether the server moved for good, rather than asking to use another server for now
+    pub(crate) permanent: bool,
+}
+
+pub struct MqttFailoverConnector {
+    endpoints: Vec<MqttUrlTransport>,
+    current_endpoint: usize,
+    endpoint_timeout: Duration,
+    max_redirects: usize,
+    make_connector: MakeConnectorFn,
+    runtime: Arc<dyn Runtime>,
+    proxy: Option<ProxyServer>,
+}
+
+impl MqttFailoverConnector {
+    /// Create a connector for the given endpoints, in order of preference
+    ///
+    /// `make_connector` is called for every connection attempt to set up the CONNECT options.
+    #[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+    pub fn new(endpoints: Vec<MqttUrlTransport>, make_connector: MakeConnectorFn) -> Self {
+        Self::new_with_runtime(
+            endpoints,
+            make_connector,
+            Arc::new(DefaultRuntime::default()),
+        )
+    }
+
+    /// Like [`MqttFailoverConnector::new`], but opens sockets and times out attempts with `runtime`
+    pub fn new_with_runtime(
+        endpoints: Vec<MqttUrlTransport>,
+        make_connector: MakeConnectorFn,
+        runtime: Arc<dyn Runtime>,
+    ) -> Self {
+        Self {
+            endpoints,
+            current_endpoint: 0,
+            endpoint_timeout: DEFAULT_ENDPOINT_TIMEOUT,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            make_connector,
+            runtime,
+            proxy: None,
+        }
+    }
+
+    /// How long to wait for the transport and the CONNACK of a single endpoint
+    pub fn with_endpoint_timeout(mut self, endpoint_timeout: Duration) -> Self {
+        self.endpoint_timeout = endpoint_timeout;
+        self
+    }
+
+    /// How many server references are followed in a row, `0` disables following them
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Connect to the endpoints through `proxy`, see [`MqttUrlTransport::connect_with_proxy`]
+    pub fn with_proxy(mut self, proxy: ProxyServer) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// The endpoint that was connected to last, and is tried first on the next connect
+    ///
+    /// If the server moved permanently this is the endpoint it moved to, a server that only asked
+    /// to use another server for now stays the current endpoint.
+    pub fn current_endpoint(&self) -> Option<&MqttUrlTransport> {
+        self.endpoints.get(self.current_endpoint)
+    }
+
+    async fn try_endpoint(
+        &mut self,
+        client: &MqttClient,
+        endpoint: &MqttUrlTransport,
+    ) -> Result<Connected, EndpointError> {
+        let attempt = async {
+            let transport = endpoint
+                .connect_with_proxy(&*self.runtime, self.proxy.as_ref())
+                .await?;
+            let connector = (self.make_connector)(transport);
+            Ok::<_, EndpointError>(client.connect(connector).await?)
+        }
+        .fuse();
+        let timeout = self.runtime.sleep(self.endpoint_timeout).fuse();
+        futures::pin_mut!(attempt, timeout);
+
+        futures::select! {
+            result = attempt => result,
+            () = timeout => Err(EndpointError::Timeout),
+        }
+    }
+}
+
+impl MqttClient {
+    /// Connect to the first endpoint of `failover` that accepts the connection
+    ///
+    /// Endpoints are tried starting with the one that was connected to last, so reconnecting only
+    /// moves on to the next endpoint if the current one fails. If the previous connection was
+    /// closed with a server reference, that server is tried first.
+    pub async fn connect_failover(
+        &self,
+        failover: &mut MqttFailoverConnector,
+    ) -> Result<Connected, MqttFailoverError> {
+        if failover.endpoints.is_empty() {
+            return Err(MqttFailoverError::NoEndpoints);
+        }
+
+        let mut failures = Vec::new();
+        let mut server_reference = self.inner.lock().await.server_reference.take();
+
+        for offset in 0..failover.endpoints.len() {
+            let index = (failover.current_endpoint + offset) % failover.endpoints.len();
+            let mut endpoint = failover.endpoints[index].clone();
+            let mut redirects = 0;
+            // Only an endpoint all followed references moved to permanently replaces this one
+            let mut moved = true;
+
+            loop {
+                if let Some(ServerReference {
+                    reference,
+                    permanent,
+                }) = server_reference.take()
+                {
+                    match endpoint.with_server_reference(&reference) {
+                        Some(redirected) if redirects < failover.max_redirects => {
+                            tracing::info!(?redirected, permanent, "Following server reference");
+                            redirects += 1;
+                            moved &= permanent;
+                            endpoint = redirected;
+                        }
+                        _ => tracing::warn!(%reference, "Not following server reference"),
+                    }
+                }
+
+                tracing::debug!(?endpoint, "Trying endpoint");
+                match failover.try_endpoint(self, &endpoint).await {
+                    Ok(connected) => {
+                        if redirects > 0 && moved {
+                            failover.endpoints[index] = endpoint;
+                        }
+                        failover.current_endpoint = index;
+                        return Ok(connected);
+                    }
+                    Err(EndpointError::Connect(MqttClientConnectError::Refused {
+                        reason_code,
+                        server_reference: Some(reference),
+                    })) if redirects < failover.max_redirects => {
+                        server_reference = Some(ServerReference {
+                            reference,
+                            permanent: reason_code == ConnackReasonCode::ServerMoved,
+                        });
+                    }
+                    Err(error) => {
+                        tracing::warn!(?endpoint, %error, "Could not connect to endpoint");
+                        failures.push(EndpointFailure { endpoint, error });
+                        break;
+                    }
+                }
+            }
+        }
+
+        Err(MqttFailoverError::AllEndpointsFailed { failures })
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::connack::ConnackReasonCode;
+    use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    use super::EndpointError;
+    use super::MqttFailoverConnector;
+    use super::MqttFailoverError;
+    use crate::client::connect::CleanStart;
+    use crate::client::connect::MqttClientConnector;
+    use crate::client::connect_url::MqttUrlTransport;
+    use crate::client::MqttClient;
+    use crate::client_identifier::ProposedClientIdentifier;
+    use crate::codecs::MqttPacketCodec;
+    use crate::keep_alive::KeepAlive;
+    use crate::packets::connack::ConnackProperties;
+    use crate::packets::disconnect::DisconnectProperties;
+
+    fn failover_connector(endpoints: Vec<MqttUrlTransport>) -> MqttFailoverConnector {
+        MqttFailoverConnector::new(
+            endpoints,
+            Box::new(|transport| {
+                MqttClientConnector::new(
+                    transport,
+                    ProposedClientIdentifier::new_minimal_required("test").unwrap(),
+                    CleanStart::Yes,
+                    KeepAlive::Disabled,
+                )
+            }),
+        )
+        .with_endpoint_timeout(std::time::Duration::from_secs(5))
+    }
+
+    fn endpoint(listener: &TcpListener) -> MqttUrlTransport {
+        MqttUrlTransport::Tcp {
+            host: "127.0.0.1".to_owned(),
+            port: listener.local_addr().unwrap().port(),
+        }
+    }
+
+    /// Accept one connection and answer its CONNECT with the given CONNACK
+    fn serve_connack(
+        listener: TcpListener,
+        reason_code: ConnackReasonCode,
+        properties: ConnackProperties,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = Framed::new(stream, MqttPacketCodec);
+
+            let connect = server.next().await.unwrap().unwrap();
+            assert!(matches!(connect.get(), FormatMqttPacket::Connect(_)));
+            server
+                .send(FormatMqttPacket::Connack(
+                    mqtt_format::v5::packets::connack::MConnack {
+                        session_present: false,
+                        reason_code,
+                        properties: properties.as_ref(),
+                    },
+                ))
+                .await
+                .unwrap();
+
+            // Keep the connection open until the client is done
+            let _ = server.next().await;
+        })
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_next_endpoint() {
+        let unavailable = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unavailable_endpoint = endpoint(&unavailable);
+        drop(unavailable);
+
+        let available = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let available_endpoint = endpoint(&available);
+        let server = serve_connack(
+            available,
+            ConnackReasonCode::Success,
+            ConnackProperties::new(),
+        );
+
+        let client = MqttClient::new_with_default_handlers();
+        let mut failover =
+            failover_connector(vec![unavailable_endpoint, available_endpoint.clone()]);
+
+        let connected = client.connect_failover(&mut failover).await.unwrap();
+        assert_eq!(failover.current_endpoint(), Some(&available_endpoint));
+
+        drop(connected.background_task);
+        connected.connection_handle.abort().await;
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn follows_server_reference_in_connack() {
+        for (reason_code, permanent) in [
+            (ConnackReasonCode::ServerMoved, true),
+            (ConnackReasonCode::UseAnotherServer, false),
+        ] {
+            let moved = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+            let mut properties = ConnackProperties::new();
+            properties.with_server_reference(format!(
+                "127.0.0.1:{}",
+                target.local_addr().unwrap().port()
+            ));
+            let moved_endpoint = endpoint(&moved);
+            let target_endpoint = endpoint(&target);
+            let moved_server = serve_connack(moved, reason_code, properties);
+            let target_server =
+                serve_connack(target, ConnackReasonCode::Success, ConnackProperties::new());
+
+            let client = MqttClient::new_with_default_handlers();
+            let mut failover = failover_connector(vec![moved_endpoint.clone()]);
+
+            let connected = client.connect_failover(&mut failover).await.unwrap();
+            let expected = if permanent {
+                target_endpoint
+            } else {
+                moved_endpoint
+            };
+            assert_eq!(
+                failover.current_endpoint(),
+                Some(&expected),
+                "{reason_code:?}"
+            );
+
+            drop(connected.background_task);
+            connected.connection_handle.abort().await;
+            moved_server.abort();
+            target_server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_server_reference_in_disconnect() {
+        let moved = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let moved_endpoint = endpoint(&moved);
+        let target_endpoint = endpoint(&target);
+        let reference = format!("127.0.0.1:{}", target.local_addr().unwrap().port());
+
+        let moved_server = tokio::spawn(async move {
+            let (stream, _) = moved.accept().await.unwrap();
+            let mut server = Framed::new(stream, MqttPacketCodec);
+
+            server.next().await.unwrap().unwrap();
+            server
+                .send(FormatMqttPacket::Connack(
+                    mqtt_format::v5::packets::connack::MConnack {
+                        session_present: false,
+                        reason_code: ConnackReasonCode::Success,
+                        properties: ConnackProperties::new().as_ref(),
+                    },
+                ))
+                .await
+                .unwrap();
+
+            let mut properties = DisconnectProperties::new();
+            properties.with_server_reference(reference);
+            server
+                .send(FormatMqttPacket::Disconnect(
+                    mqtt_format::v5::packets::disconnect::MDisconnect {
+                        reason_code: DisconnectReasonCode::ServerMoved,
+                        properties: properties.as_ref(),
+                    },
+                ))
+                .await
+                .unwrap();
+        });
+        let target_server =
+            serve_connack(target, ConnackReasonCode::Success, ConnackProperties::new());
+
+        let client = MqttClient::new_with_default_handlers();
+        let mut failover = failover_connector(vec![moved_endpoint.clone()]);
+
+        let connected = client.connect_failover(&mut failover).await.unwrap();
+        assert_eq!(failover.current_endpoint(), Some(&moved_endpoint));
+        let _ = connected.background_task.await;
+        moved_server.await.unwrap();
+
+        let connected = client.connect_failover(&mut failover).await.unwrap();
+        assert_eq!(failover.current_endpoint(), Some(&target_endpoint));
+
+        drop(connected.background_task);
+        connected.connection_handle.abort().await;
+        target_server.abort();
+    }
+
+    #[tokio::test]
+    async fn reports_all_failures() {
+        let client = MqttClient::new_with_default_handlers();
+        let mut failover = failover_connector(vec![]);
+        assert!(matches!(
+            client.connect_failover(&mut failover).await,
+            Err(MqttFailoverError::NoEndpoints)
+        ));
+
+        let refusing = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refusing_endpoint = endpoint(&refusing);
+        let server = serve_connack(
+            refusing,
+            ConnackReasonCode::NotAuthorized,
+            ConnackProperties::new(),
+        );
+
+        let mut failover = failover_connector(vec![refusing_endpoint]);
+        let Err(MqttFailoverError::AllEndpointsFailed { failures }) =
+            client.connect_failover(&mut failover).await
+        else {
+            panic!("Expected all endpoints to fail");
+        };
+        assert_eq!(failures.len(), 1);
+        assert!(matches!(failures[0].error, EndpointError::Connect(_)));
+
+        server.abort();
+    }
+}
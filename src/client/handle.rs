@@ -0,0 +1,192 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::sync::Arc;
+
+use futures::lock::Mutex;
+
+use super::events::DisconnectReason;
+use super::InnerClient;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Cancellation {
+    /// Send a DISCONNECT to the server before closing the connection
+    Shutdown,
+    /// Close the connection without notifying the server
+    Abort,
+}
+
+impl From<Cancellation> for DisconnectReason {
+    fn from(value: Cancellation) -> Self {
+        match value {
+            Cancellation::Shutdown => DisconnectReason::Shutdown,
+            Cancellation::Abort => DisconnectReason::Aborted,
+        }
+    }
+}
+
+/// A handle to the background task of a connected client
+///
+/// Dropping the handle detaches it, the background task keeps running until the connection is
+/// closed.
+pub struct ConnectionHandle {
+    inner: Arc<Mutex<InnerClient>>,
+    cancel: futures::channel::oneshot::Sender<Cancellation>,
+    finished: futures::channel::oneshot::Receiver<()>,
+}
+
+impl ConnectionHandle {
+    pub(super) fn new(
+        inner: Arc<Mutex<InnerClient>>,
+        cancel: futures::channel::oneshot::Sender<Cancellation>,
+        finished: futures::channel::oneshot::Receiver<()>,
+    ) -> Self {
+        Self {
+            inner,
+            cancel,
+            finished,
+        }
+    }
+
+    /// Gracefully disconnect from the server and stop the background task
+    ///
+    /// All pending acknowledgements are failed with [`ConnectionClosed`](super::send::ConnectionClosed)
+    /// and the client can be used to connect again afterwards.
+    pub async fn shutdown(self) {
+        self.cancel_with(Cancellation::Shutdown).await
+    }
+
+    /// Stop the background task without sending a DISCONNECT to the server
+    pub async fn abort(self) {
+        self.cancel_with(Cancellation::Abort).await
+    }
+
+    /// Whether the background task finished, or was dropped without being run
+    pub fn is_finished(&self) -> bool {
+        self.cancel.is_canceled()
+    }
+
+    async fn cancel_with(self, cancellation: Cancellation) {
+        let ConnectionHandle {
+            inner,
+            cancel,
+            finished,
+        } = self;
+
+        if cancel.send(cancellation).is_ok() && finished.await.is_ok() {
+            tracing::debug!(?cancellation, "Background task finished");
+            return;
+        }
+
+        // The background task was dropped before it could clean up after itself, so we need to
+        // do it ourselves.
+        tracing::debug!(
+            ?cancellation,
+            "Background task is gone, cleaning up connection state"
+        );
+        handle_cancellation(&inner, cancellation).await;
+        inner
+            .lock()
+            .await
+            .reset_connection(DisconnectReason::from(cancellation));
+    }
+}
+
+pub(super) async fn handle_cancellation(
+    inner: &Arc<Mutex<InnerClient>>,
+    cancellation: Cancellation,
+) {
+    if cancellation == Cancellation::Abort {
+        return;
+    }
+
+    let disconnect = mqtt_format::v5::packets::MqttPacket::Disconnect(
+        mqtt_format::v5::packets::disconnect::MDisconnect {
+            reason_code:
+                mqtt_format::v5::packets::disconnect::DisconnectReasonCode::NormalDisconnection,
+            properties: mqtt_format::v5::packets::disconnect::DisconnectProperties::new(),
+        },
+    );
+
+    let queued = match inner.lock().await.connection_state.as_mut() {
+        Some(conn_state) => conn_state.conn_write.queue(disconnect).await,
+        None => {
+            tracing::debug!("Connection is already closed, not sending DISCONNECT");
+            return;
+        }
+    };
+
+    let written = match queued {
+        Ok(written) => written.written().await,
+        Err(error) => Err(error),
+    };
+    if let Err(error) = written {
+        tracing::warn!(%error, "Could not send DISCONNECT to the server");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+    use crate::client::send::Publish;
+    use crate::client::tests::connect_to_test_server;
+    use crate::client::tests::success_connack;
+    use crate::client::MqttClient;
+    use crate::qos::QualityOfService;
+
+    #[tokio::test]
+    async fn shutdown_fails_pending_acknowledgements() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, mut server) = connect_to_test_server(&client, success_connack()).await;
+        let background = tokio::spawn(connected.background_task);
+
+        let published = client
+            .publish(Publish {
+                topic: "foo/bar".try_into().unwrap(),
+                qos: QualityOfService::AtLeastOnce,
+                retain: false,
+                payload: vec![1, 2, 3].try_into().unwrap(),
+                on_packet_recv: None,
+            })
+            .await
+            .unwrap();
+
+        connected.connection_handle.shutdown().await;
+
+        assert!(published.acknowledged().await.is_err());
+        assert!(background.await.unwrap().is_ok());
+
+        let publish = server.next().await.unwrap().unwrap();
+        assert!(matches!(publish.get(), FormatMqttPacket::Publish(_)));
+        let disconnect = server.next().await.unwrap().unwrap();
+        assert!(matches!(disconnect.get(), FormatMqttPacket::Disconnect(_)));
+
+        assert!(client.inner.lock().await.connection_state.is_none());
+    }
+
+    #[tokio::test]
+    async fn abort_without_running_background_task() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, _server) = connect_to_test_server(&client, success_connack()).await;
+        drop(connected.background_task);
+
+        // Without the background task nothing is written anymore
+        assert!(client.ping().await.is_err());
+
+        connected.connection_handle.abort().await;
+
+        assert!(client
+            .inner
+            .lock()
+            .await
+            .outstanding_callbacks
+            .take_ping_req()
+            .is_none());
+        assert!(client.inner.lock().await.connection_state.is_none());
+    }
+}
@@ -4,27 +4,70 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
+#[cfg(feature = "tokio")]
+pub mod blocking;
 pub mod builder;
 pub mod connect;
+pub mod connect_url;
+pub mod events;
+pub mod failover;
+pub mod handle;
 mod receive;
 pub mod send;
 mod state;
+pub mod subscribe;
+mod write;
 
 use std::sync::Arc;
 
 use futures::lock::Mutex;
 
-use self::send::Acknowledge;
+use self::events::DisconnectReason;
+use self::events::DisconnectedEvent;
+use self::events::EventSenders;
+use self::events::MqttClientEvent;
+use self::failover::ServerReference;
 use self::send::Callbacks;
 use self::send::ClientHandlers;
 use self::state::ConnectState;
 use self::state::SessionState;
+use self::subscribe::PublishSenders;
+use crate::packet_identifier::PacketIdentifier;
 
 struct InnerClient {
     connection_state: Option<ConnectState>,
     session_state: Option<SessionState>,
     default_handlers: ClientHandlers,
     outstanding_callbacks: Callbacks,
+    events: EventSenders,
+    publishes: PublishSenders,
+    /// The server reference of the last DISCONNECT, used by [`failover`] to reconnect
+    server_reference: Option<ServerReference>,
+}
+
+impl InnerClient {
+    /// Drop the current connection and fail all callbacks still waiting on it
+    fn reset_connection(&mut self, reason: DisconnectReason) {
+        self.outstanding_callbacks = Callbacks::new();
+
+        if let Some(conn_state) = self.connection_state.take() {
+            self.events
+                .emit(MqttClientEvent::Disconnected(DisconnectedEvent {
+                    reason,
+                    connected_for: conn_state.connected_at.elapsed(),
+                }));
+        }
+    }
+
+    /// Forget a request whose packet could not be sent, so that it is not sent again on resume
+    fn forget_request(&mut self, packet_identifier: PacketIdentifier) {
+        if let Some(session_state) = &mut self.session_state {
+            session_state
+                .outstanding_packets
+                .remove_by_id(packet_identifier);
+        }
+        self.outstanding_callbacks.remove(packet_identifier);
+    }
 }
 
 pub struct MqttClient {
@@ -39,6 +82,9 @@ pub fn new_with_default_handlers() -> MqttClient {
                 session_state: None,
                 default_handlers: ClientHandlers::default(),
                 outstanding_callbacks: Callbacks::new(),
+                events: EventSenders::new(),
+                publishes: PublishSenders::new(),
+                server_reference: None,
             })),
         }
     }
@@ -50,9 +96,65 @@ pub fn builder() -> builder::MqttClientBuilder {
 
 #[cfg(test)]
 mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::codec::Framed;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use crate::client::connect::CleanStart;
+    use crate::client::connect::Connected;
+    use crate::client::connect::MqttClientConnector;
     use crate::client::ClientHandlers;
     use crate::client::MqttClient;
+    use crate::client_identifier::ProposedClientIdentifier;
+    use crate::codecs::MqttPacketCodec;
+    use crate::keep_alive::KeepAlive;
+    use crate::transport::MqttConnectTransport;
+    use crate::transport::MqttConnection;
 
     static_assertions::assert_impl_all!(MqttClient: Send, Sync);
     static_assertions::assert_impl_all!(ClientHandlers: Send);
+
+    pub(crate) type TestServer = Framed<MqttConnection, MqttPacketCodec>;
+
+    /// Connect the client to an in-memory server that accepts the CONNECT with the given CONNACK
+    pub(crate) async fn connect_to_test_server(
+        client: &MqttClient,
+        connack: mqtt_format::v5::packets::connack::MConnack<'static>,
+    ) -> (Connected, TestServer) {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let mut server = Framed::new(
+            MqttConnection::Duplex(server_stream.compat()),
+            MqttPacketCodec,
+        );
+
+        let connector = MqttClientConnector::new(
+            MqttConnectTransport::TokioDuplex(client_stream),
+            ProposedClientIdentifier::new_minimal_required("test").unwrap(),
+            CleanStart::Yes,
+            KeepAlive::Disabled,
+        );
+
+        let server_task = tokio::spawn(async move {
+            let connect = server.next().await.unwrap().unwrap();
+            assert!(matches!(connect.get(), FormatMqttPacket::Connect(_)));
+            server
+                .send(FormatMqttPacket::Connack(connack))
+                .await
+                .unwrap();
+            server
+        });
+
+        let connected = client.connect(connector).await.unwrap();
+        (connected, server_task.await.unwrap())
+    }
+
+    pub(crate) fn success_connack() -> mqtt_format::v5::packets::connack::MConnack<'static> {
+        mqtt_format::v5::packets::connack::MConnack {
+            session_present: false,
+            reason_code: mqtt_format::v5::packets::connack::ConnackReasonCode::Success,
+            properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+        }
+    }
 }
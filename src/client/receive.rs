@@ -10,25 +10,31 @@
 use futures::StreamExt;
 use tokio_util::codec::FramedRead;
 use tracing::Instrument;
-use yoke::Yoke;
 
+use super::events::DisconnectReason;
+use super::events::MqttClientEvent;
+use super::events::ReAuthenticationEvent;
+use super::events::ServerDisconnectEvent;
+use super::failover::ServerReference;
 use super::InnerClient;
 use crate::codecs::MqttPacketCodec;
 use crate::packet_identifier::PacketIdentifier;
+use crate::packets::auth::AuthPropertiesView;
+use crate::packets::disconnect::DisconnectPropertiesView;
+use crate::packets::EncodedPacket;
 use crate::packets::MqttPacket;
-use crate::packets::MqttWriter;
-use crate::packets::StableBytes;
+use crate::packets::Publish;
+use crate::packets::Suback;
+use crate::qos::QualityOfService;
 use crate::transport::MqttConnection;
 
 pub(super) async fn handle_background_receiving(
     inner_clone: Arc<Mutex<InnerClient>>,
     mut conn_read: FramedRead<tokio::io::ReadHalf<MqttConnection>, MqttPacketCodec>,
-    conn_read_sender: futures::channel::oneshot::Sender<
-        FramedRead<tokio::io::ReadHalf<MqttConnection>, MqttPacketCodec>,
-    >,
-) -> Result<(), ()> {
+) -> Result<DisconnectReason, ()> {
     tracing::info!("Starting background task");
     let inner: Arc<Mutex<InnerClient>> = inner_clone;
+    let mut reason = DisconnectReason::TransportClosed;
 
     while let Some(next) = conn_read.next().await {
         let process_span = tracing::debug_span!(
@@ -39,7 +45,10 @@ pub(super) async fn handle_background_receiving(
         tracing::debug!(parent: &process_span, valid = next.is_ok(), "Received packet");
         let packet = match next {
             Ok(packet) => packet,
-            Err(e) => panic!("Received err: {e}"),
+            Err(error) => {
+                tracing::error!(%error, "Could not receive packet");
+                return Err(());
+            }
         };
         process_span.record(
             "packet_kind",
@@ -50,8 +59,18 @@ pub(super) async fn handle_background_receiving(
         (inner.lock().await.default_handlers.on_packet_recv)(packet.clone());
 
         match packet.get() {
-            mqtt_format::v5::packets::MqttPacket::Auth(_) => todo!(),
-            mqtt_format::v5::packets::MqttPacket::Disconnect(_) => todo!(),
+            mqtt_format::v5::packets::MqttPacket::Auth(auth) => {
+                handle_auth(auth, &inner, &packet)
+                    .instrument(process_span)
+                    .await?
+            }
+            mqtt_format::v5::packets::MqttPacket::Disconnect(disconnect) => {
+                handle_disconnect(disconnect, &inner, &packet)
+                    .instrument(process_span)
+                    .await?;
+                reason = DisconnectReason::Server(disconnect.reason_code);
+                break;
+            }
             mqtt_format::v5::packets::MqttPacket::Pingreq(pingreq) => {
                 handle_pingreq(pingreq).instrument(process_span).await?
             }
@@ -61,7 +80,7 @@ pub(super) async fn handle_background_receiving(
                     .await?
             }
             mqtt_format::v5::packets::MqttPacket::Puback(_mpuback) => {
-                handle_puback(&packet.try_into().unwrap(), &inner)
+                handle_puback(&packet.clone().try_into().map_err(drop)?, &inner, &packet)
                     .instrument(process_span)
                     .await?
             }
@@ -75,9 +94,25 @@ pub(super) async fn handle_background_receiving(
                     .instrument(process_span)
                     .await?
             }
-            mqtt_format::v5::packets::MqttPacket::Publish(_) => todo!(),
-            mqtt_format::v5::packets::MqttPacket::Pubrel(_) => todo!(),
-            mqtt_format::v5::packets::MqttPacket::Suback(_) => todo!(),
+            mqtt_format::v5::packets::MqttPacket::Publish(_) => {
+                handle_publish(
+                    packet.clone().try_into().map_err(drop)?,
+                    &inner,
+                    &process_span,
+                )
+                .instrument(process_span.clone())
+                .await?
+            }
+            mqtt_format::v5::packets::MqttPacket::Pubrel(pubrel) => {
+                handle_pubrel(pubrel, &inner)
+                    .instrument(process_span)
+                    .await?
+            }
+            mqtt_format::v5::packets::MqttPacket::Suback(_) => {
+                handle_suback(packet.clone().try_into().map_err(drop)?, &inner)
+                    .instrument(process_span)
+                    .await?
+            }
             mqtt_format::v5::packets::MqttPacket::Unsuback(_) => todo!(),
 
             mqtt_format::v5::packets::MqttPacket::Connack(_)
@@ -89,11 +124,52 @@ pub(super) async fn handle_background_receiving(
         }
     }
 
-    tracing::debug!("Finished processing, returning reader");
-    if let Err(_conn_read) = conn_read_sender.send(conn_read) {
-        tracing::error!("Failed to return reader");
-        todo!()
-    }
+    tracing::debug!("Finished processing");
+    Ok(reason)
+}
+
+async fn handle_auth(
+    auth: &mqtt_format::v5::packets::auth::MAuth<'_>,
+    inner: &Arc<Mutex<InnerClient>>,
+    packet: &MqttPacket,
+) -> Result<(), ()> {
+    tracing::debug!(reason = ?auth.reason, "Received AUTH from the server");
+
+    let properties = AuthPropertiesView::try_from(packet.clone()).map_err(drop)?;
+    inner
+        .lock()
+        .await
+        .events
+        .emit(MqttClientEvent::ReAuthentication(ReAuthenticationEvent {
+            reason_code: auth.reason,
+            properties,
+        }));
+
+    Ok(())
+}
+
+async fn handle_disconnect(
+    disconnect: &mqtt_format::v5::packets::disconnect::MDisconnect<'_>,
+    inner: &Arc<Mutex<InnerClient>>,
+    packet: &MqttPacket,
+) -> Result<(), ()> {
+    tracing::info!(reason = ?disconnect.reason_code, "Server sent DISCONNECT");
+
+    let properties = DisconnectPropertiesView::try_from(packet.clone()).map_err(drop)?;
+    let mut inner = inner.lock().await;
+    inner.server_reference = properties
+        .server_reference()
+        .map(|reference| ServerReference {
+            reference: reference.to_owned(),
+            permanent: disconnect.reason_code
+                == mqtt_format::v5::packets::disconnect::DisconnectReasonCode::ServerMoved,
+        });
+    inner
+        .events
+        .emit(MqttClientEvent::ServerDisconnect(ServerDisconnectEvent {
+            reason_code: disconnect.reason_code,
+            properties,
+        }));
 
     Ok(())
 }
@@ -127,34 +203,37 @@ async fn handle_pubcomp(
     inner: &Arc<Mutex<InnerClient>>,
     packet: &MqttPacket,
 ) -> Result<(), ()> {
-    match pubcomp.reason {
-        mqtt_format::v5::packets::pubcomp::PubcompReasonCode::Success => {
-            let mut inner = inner.lock().await;
-            let inner = &mut *inner;
-            let Some(ref mut session_state) = inner.session_state else {
-                tracing::error!("No session state found");
-                todo!()
-            };
-            let pident = PacketIdentifier::from(pubcomp.packet_identifier);
-            tracing::Span::current().record("packet_identifier", tracing::field::display(pident));
-
-            if session_state
-                .outstanding_packets
-                .exists_outstanding_packet(pident)
-            {
-                session_state.outstanding_packets.remove_by_id(pident);
-                tracing::trace!("Removed packet id from outstanding packets");
-
-                if let Some(callback) = inner.outstanding_callbacks.take_qos2_complete(pident) {
-                    if let Err(_) = callback.on_complete.send(packet.clone()) {
-                        tracing::trace!("Could not send ack, receiver was dropped.")
-                    }
-                } else {
-                    todo!("Invariant broken: Received on_complete for unknown packet")
-                }
-            }
+    // Every reason code finishes the QoS 2 flow, the caller gets the PUBCOMP to check it
+    let mut inner = inner.lock().await;
+    let inner = &mut *inner;
+    let Some(ref mut session_state) = inner.session_state else {
+        tracing::warn!("No session state found, ignoring PUBCOMP");
+        return Ok(());
+    };
+    let pident = PacketIdentifier::from(pubcomp.packet_identifier);
+    tracing::Span::current().record("packet_identifier", tracing::field::display(pident));
+
+    if !session_state
+        .outstanding_packets
+        .exists_outstanding_packet(pident)
+    {
+        tracing::warn!("Received PUBCOMP for an unknown packet identifier, ignoring it");
+        return Ok(());
+    }
+
+    session_state.outstanding_packets.remove_by_id(pident);
+    tracing::trace!("Removed packet id from outstanding packets");
+
+    inner
+        .outstanding_callbacks
+        .call_on_packet_recv(pident, packet);
+    inner.outstanding_callbacks.remove_on_packet_recv(pident);
+
+    if let Some(callback) = inner.outstanding_callbacks.take_qos2_complete(pident) {
+        let pubcomp = crate::packets::Pubcomp::try_from(packet.clone()).map_err(drop)?;
+        if callback.on_complete.send(pubcomp).is_err() {
+            tracing::trace!("Could not send ack, receiver was dropped.")
         }
-        _ => todo!("Handle errors"),
     }
 
     Ok(())
@@ -163,46 +242,42 @@ async fn handle_pubcomp(
 async fn handle_puback(
     puback: &crate::packets::Puback,
     inner: &Arc<Mutex<InnerClient>>,
+    packet: &MqttPacket,
 ) -> Result<(), ()> {
     tracing::trace!("Calling on_qos1_acknowledge handler");
     (inner.lock().await.default_handlers.on_qos1_acknowledge)(puback.clone());
-    let mpuback = puback.get();
-
-    match mpuback.reason {
-        mqtt_format::v5::packets::puback::PubackReasonCode::Success
-        | mqtt_format::v5::packets::puback::PubackReasonCode::NoMatchingSubscribers => {
-            let mut inner = inner.lock().await;
-            let inner = &mut *inner;
-            let Some(ref mut session_state) = inner.session_state else {
-                tracing::error!("No session state found");
-                todo!()
-            };
-
-            let pident = PacketIdentifier::try_from(mpuback.packet_identifier)
-                .expect("Zero PacketIdentifier not valid here");
-            tracing::Span::current().record("packet_identifier", tracing::field::display(pident));
-
-            if session_state
-                .outstanding_packets
-                .exists_outstanding_packet(pident)
-            {
-                session_state.outstanding_packets.remove_by_id(pident);
-                tracing::trace!("Removed packet id from outstanding packets");
-
-                if let Some(callback) = inner.outstanding_callbacks.take_qos1(pident) {
-                    if let Err(_) = callback.on_acknowledge.send(puback.clone()) {
-                        tracing::trace!("Could not send ack, receiver was dropped.")
-                    }
-                }
-            } else {
-                tracing::error!("Packet id does not exist in outstanding packets");
-                todo!()
-            }
 
-            // TODO: Forward mpuback.properties etc to the user
-        }
+    // Every reason code finishes the QoS 1 flow, the caller gets the PUBACK to check it
+    let mut inner = inner.lock().await;
+    let inner = &mut *inner;
+    let Some(ref mut session_state) = inner.session_state else {
+        tracing::warn!("No session state found, ignoring PUBACK");
+        return Ok(());
+    };
+
+    let pident = puback.packet_identifier();
+    tracing::Span::current().record("packet_identifier", tracing::field::display(pident));
+
+    if !session_state
+        .outstanding_packets
+        .exists_outstanding_packet(pident)
+    {
+        tracing::warn!("Received PUBACK for an unknown packet identifier, ignoring it");
+        return Ok(());
+    }
+
+    session_state.outstanding_packets.remove_by_id(pident);
+    tracing::trace!("Removed packet id from outstanding packets");
 
-        _ => todo!("Handle errors"),
+    inner
+        .outstanding_callbacks
+        .call_on_packet_recv(pident, packet);
+    inner.outstanding_callbacks.remove_on_packet_recv(pident);
+
+    if let Some(callback) = inner.outstanding_callbacks.take_qos1(pident) {
+        if callback.on_acknowledge.send(puback.clone()).is_err() {
+            tracing::trace!("Could not send ack, receiver was dropped.")
+        }
     }
 
     Ok(())
@@ -213,59 +288,206 @@ async fn handle_pubrec(
     inner: &Arc<Mutex<InnerClient>>,
     packet: &MqttPacket,
 ) -> Result<(), ()> {
-    match pubrec.reason {
-        mqtt_format::v5::packets::pubrec::PubrecReasonCode::Success => {
-            let mut inner = inner.lock().await;
-            let inner = &mut *inner;
-            let Some(ref mut session_state) = inner.session_state else {
-                tracing::error!("No session state found");
-                todo!()
-            };
-            let Some(ref mut conn_state) = inner.connection_state else {
-                tracing::error!("No session state found");
-                todo!()
-            };
-            let pident = PacketIdentifier::try_from(pubrec.packet_identifier)
-                .expect("zero PacketIdentifier not valid here");
-            tracing::Span::current().record("packet_identifier", tracing::field::display(pident));
-
-            if session_state
+    let pident = PacketIdentifier::from(pubrec.packet_identifier);
+    tracing::Span::current().record("packet_identifier", tracing::field::display(pident));
+
+    let (pubrel, callback) = {
+        let mut inner = inner.lock().await;
+        let inner = &mut *inner;
+        let (Some(conn_state), Some(session_state)) =
+            (&mut inner.connection_state, &mut inner.session_state)
+        else {
+            tracing::warn!("No connection or session state found, ignoring PUBREC");
+            return Ok(());
+        };
+
+        if !session_state
+            .outstanding_packets
+            .exists_outstanding_packet(pident)
+        {
+            tracing::warn!("Received PUBREC for an unknown packet identifier, ignoring it");
+            return Ok(());
+        }
+
+        inner
+            .outstanding_callbacks
+            .call_on_packet_recv(pident, packet);
+        let callback = inner.outstanding_callbacks.take_qos2_receive(pident);
+
+        // A PUBREC with an error reason code ends the flow, no PUBREL is sent
+        let pubrel = if u8::from(pubrec.reason) >= 0x80 {
+            tracing::debug!(reason = ?pubrec.reason, "Server refused QoS 2 message");
+            session_state.outstanding_packets.remove_by_id(pident);
+            inner.outstanding_callbacks.remove_on_packet_recv(pident);
+            inner.outstanding_callbacks.take_qos2_complete(pident);
+            None
+        } else {
+            let pubrel = EncodedPacket::encode(&mqtt_format::v5::packets::MqttPacket::Pubrel(
+                mqtt_format::v5::packets::pubrel::MPubrel {
+                    packet_identifier: pubrec.packet_identifier,
+                    reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+                },
+            ))
+            .map_err(drop)?;
+            session_state
                 .outstanding_packets
-                .exists_outstanding_packet(pident)
-            {
-                let pubrel = mqtt_format::v5::packets::MqttPacket::Pubrel(
-                    mqtt_format::v5::packets::pubrel::MPubrel {
-                        packet_identifier: pubrec.packet_identifier,
-                        reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
-                        properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+                .update_by_id(pident, pubrel.clone());
+            tracing::trace!("Update packet from outstanding packets");
+
+            Some(
+                conn_state
+                    .conn_write
+                    .queue_encoded(pubrel)
+                    .await
+                    .map_err(drop)?,
+            )
+        };
+
+        (pubrel, callback)
+    };
+
+    if let Some(pubrel) = pubrel {
+        pubrel.written().await.map_err(drop)?;
+    }
+
+    if let Some(callback) = callback {
+        let pubrec = crate::packets::Pubrec::try_from(packet.clone()).map_err(drop)?;
+        if callback.on_receive.send(pubrec).is_err() {
+            tracing::trace!("Could not send ack, receiver was dropped.")
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_publish(
+    publish: Publish,
+    inner: &Arc<Mutex<InnerClient>>,
+    process_span: &tracing::Span,
+) -> Result<(), ()> {
+    let response = {
+        let mut inner = inner.lock().await;
+        let inner = &mut *inner;
+        let (Some(conn_state), Some(session_state)) =
+            (&mut inner.connection_state, &mut inner.session_state)
+        else {
+            tracing::error!("No connection or session state found");
+            return Err(());
+        };
+
+        let response = match (publish.qos(), publish.packet_identifier()) {
+            (QualityOfService::AtMostOnce, _) => {
+                inner.publishes.deliver(publish);
+                None
+            }
+            (QualityOfService::AtLeastOnce, Some(pident)) => {
+                process_span.record("packet_identifier", tracing::field::display(pident));
+                inner.publishes.deliver(publish);
+
+                Some(mqtt_format::v5::packets::MqttPacket::Puback(
+                    mqtt_format::v5::packets::puback::MPuback {
+                        packet_identifier: pident.into(),
+                        reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                        properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
                     },
-                );
-
-                let mut bytes = tokio_util::bytes::BytesMut::new();
-                bytes.reserve(pubrel.binary_size() as usize);
-                pubrel.write(&mut MqttWriter(&mut bytes)).map_err(drop)?;
-                let pubrel_packet = MqttPacket {
-                    packet: Yoke::try_attach_to_cart(StableBytes(bytes.freeze()), |bytes| {
-                        mqtt_format::v5::packets::MqttPacket::parse_complete(bytes)
-                    })
-                    .unwrap(),
-                };
-                session_state
-                    .outstanding_packets
-                    .update_by_id(pident, pubrel_packet);
-                tracing::trace!("Update packet from outstanding packets");
-                conn_state.conn_write.send(pubrel).await.map_err(drop)?;
-
-                if let Some(callback) = inner.outstanding_callbacks.take_qos2_receive(pident) {
-                    if let Err(_) = callback.on_receive.send(packet.clone()) {
-                        tracing::trace!("Could not send ack, receiver was dropped.")
-                    }
+                ))
+            }
+            (QualityOfService::ExactlyOnce, Some(pident)) => {
+                process_span.record("packet_identifier", tracing::field::display(pident));
+
+                // A retransmission of a message that was not released yet must not be delivered again
+                if session_state.received_qos2.insert(pident) {
+                    inner.publishes.deliver(publish);
                 } else {
-                    todo!("Invariant broken: Receive PubRec for unawaited {pident}")
+                    tracing::debug!("Received duplicate QoS 2 message, not delivering it again");
                 }
+
+                Some(mqtt_format::v5::packets::MqttPacket::Pubrec(
+                    mqtt_format::v5::packets::pubrec::MPubrec {
+                        packet_identifier: pident.into(),
+                        reason: mqtt_format::v5::packets::pubrec::PubrecReasonCode::Success,
+                        properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                    },
+                ))
+            }
+            (_, None) => {
+                tracing::error!("Received a QoS 1 or 2 PUBLISH without a packet identifier");
+                return Err(());
+            }
+        };
+
+        match response {
+            Some(response) => Some(conn_state.conn_write.queue(response).await.map_err(drop)?),
+            None => None,
+        }
+    };
+
+    if let Some(response) = response {
+        response.written().await.map_err(drop)?;
+    }
+
+    Ok(())
+}
+
+async fn handle_pubrel(
+    pubrel: &mqtt_format::v5::packets::pubrel::MPubrel<'_>,
+    inner: &Arc<Mutex<InnerClient>>,
+) -> Result<(), ()> {
+    let pident = PacketIdentifier::from(pubrel.packet_identifier);
+    tracing::Span::current().record("packet_identifier", tracing::field::display(pident));
+
+    let pubcomp = {
+        let mut inner = inner.lock().await;
+        let inner = &mut *inner;
+        let (Some(conn_state), Some(session_state)) =
+            (&mut inner.connection_state, &mut inner.session_state)
+        else {
+            tracing::error!("No connection or session state found");
+            return Err(());
+        };
+
+        let reason = if session_state.received_qos2.remove(&pident) {
+            mqtt_format::v5::packets::pubcomp::PubcompReasonCode::Success
+        } else {
+            tracing::warn!("Received PUBREL for an unknown packet identifier");
+            mqtt_format::v5::packets::pubcomp::PubcompReasonCode::PacketIdentifierNotFound
+        };
+
+        let pubcomp = mqtt_format::v5::packets::MqttPacket::Pubcomp(
+            mqtt_format::v5::packets::pubcomp::MPubcomp {
+                packet_identifier: pubrel.packet_identifier,
+                reason,
+                properties: mqtt_format::v5::packets::pubcomp::PubcompProperties::new(),
+            },
+        );
+
+        conn_state.conn_write.queue(pubcomp).await.map_err(drop)?
+    };
+
+    pubcomp.written().await.map_err(drop)
+}
+
+async fn handle_suback(suback: Suback, inner: &Arc<Mutex<InnerClient>>) -> Result<(), ()> {
+    let pident = suback.packet_identifier();
+    tracing::Span::current().record("packet_identifier", tracing::field::display(pident));
+
+    let mut inner = inner.lock().await;
+    let inner = &mut *inner;
+    let Some(ref mut session_state) = inner.session_state else {
+        tracing::error!("No session state found");
+        return Err(());
+    };
+
+    session_state.outstanding_packets.remove_by_id(pident);
+
+    match inner.outstanding_callbacks.take_suback(pident) {
+        Some(callback) => {
+            if callback.send(suback).is_err() {
+                tracing::trace!("Could not send suback, receiver was dropped.")
             }
         }
-        _ => todo!("Handle errors"),
+        None => tracing::warn!("Received SUBACK for an unknown packet identifier"),
     }
 
     Ok(())
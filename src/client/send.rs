@@ -7,19 +7,28 @@
 use std::collections::HashMap;
 use std::collections::VecDeque;
 
-use futures::FutureExt;
 use mqtt_format::v5::integers::VARIABLE_INTEGER_MAX;
 use mqtt_format::v5::packets::publish::MPublish;
 use tracing::Instrument;
 
 use super::state::OutstandingPackets;
 use super::MqttClient;
+use crate::packet_identifier::next_free_packet_identifier;
 use crate::packet_identifier::PacketIdentifier;
+use crate::packets::EncodedPacket;
 use crate::packets::MqttPacket;
+use crate::packets::Puback;
+use crate::packets::Pubcomp;
+use crate::packets::Pubrec;
 use crate::payload::MqttPayload;
 use crate::qos::QualityOfService;
 
 impl MqttClient {
+    /// Publish a message, returning once it was written to the connection
+    ///
+    /// QoS 1 and 2 messages are kept in the session until they are acknowledged, and are sent
+    /// again when the session is resumed. If the message could not be written, it is forgotten
+    /// instead and [`PublishError::Send`] is returned: it is up to the caller to publish it again.
     #[tracing::instrument(skip_all, fields(payload_length = payload.as_ref().len()))]
     pub async fn publish(
         &self,
@@ -28,111 +37,124 @@ pub async fn publish(
             qos,
             retain,
             payload,
-            on_packet_recv: _,
+            on_packet_recv,
         }: Publish,
-    ) -> Result<Published, ()> {
-        let mut inner = self.inner.lock().await;
-        let inner = &mut *inner;
-
-        let Some(conn_state) = &mut inner.connection_state else {
-            tracing::error!("No connection state found");
-            return Err(());
-        };
-
-        let Some(sess_state) = &mut inner.session_state else {
-            tracing::error!("No session state found");
-            return Err(());
-        };
-
-        if conn_state.retain_available.unwrap_or(true) && retain {
-            tracing::warn!("Retain not available, but requested");
-            return Err(());
-        }
-
-        let packet_identifier = if qos > QualityOfService::AtMostOnce {
-            get_next_packet_ident(
-                &mut conn_state.next_packet_identifier,
-                &sess_state.outstanding_packets,
-            )
-            .map(Some)
-            .map_err(|_| ())? // TODO
-        } else {
-            None
-        };
-        tracing::debug!(?packet_identifier, "Packet identifier computed");
+    ) -> Result<Published, PublishError> {
+        // The lock is only held for the bookkeeping and queueing, the actual write happens in the
+        // background writer so that concurrent publishers don't wait on each other's network I/O
+        let (written, packet_identifier, published_recv) = {
+            let mut inner = self.inner.lock().await;
+            let inner = &mut *inner;
+
+            let (Some(conn_state), Some(sess_state)) =
+                (&mut inner.connection_state, &mut inner.session_state)
+            else {
+                tracing::error!("No connection or session state found");
+                return Err(PublishError::NotConnected);
+            };
 
-        let publish = MPublish {
-            duplicate: false,
-            quality_of_service: qos.into(),
-            retain,
-            topic_name: topic.as_ref(),
-            packet_identifier: packet_identifier
-                .map(mqtt_format::v5::variable_header::PacketIdentifier::from),
-            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
-            payload: payload.as_ref(),
-        };
+            if !conn_state.retain_available.unwrap_or(true) && retain {
+                tracing::warn!("Retain not available, but requested");
+                return Err(PublishError::RetainNotAvailable);
+            }
 
-        let packet = mqtt_format::v5::packets::MqttPacket::Publish(publish);
+            let packet_identifier = if qos > QualityOfService::AtMostOnce {
+                get_next_packet_ident(
+                    &mut conn_state.next_packet_identifier,
+                    &sess_state.outstanding_packets,
+                )
+                .map(Some)?
+            } else {
+                None
+            };
+            tracing::debug!(?packet_identifier, "Packet identifier computed");
 
-        let maximum_packet_size = conn_state
-            .maximum_packet_size
-            .unwrap_or(VARIABLE_INTEGER_MAX);
+            let publish = MPublish {
+                duplicate: false,
+                quality_of_service: qos.into(),
+                retain,
+                topic_name: topic.as_ref(),
+                packet_identifier: packet_identifier
+                    .map(mqtt_format::v5::variable_header::PacketIdentifier::from),
+                properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                // The payload is not copied into the encoded packet, but kept as its own buffer
+                payload: &[],
+            };
 
-        if packet.binary_size() > maximum_packet_size {
-            tracing::error!("Binary size bigger than maximum packet size");
-            return Err(());
-        }
+            let packet = EncodedPacket::encode_publish(publish, payload.into_bytes())
+                .map_err(|_| PublishError::Encode)?;
 
-        tracing::trace!(%maximum_packet_size, packet_size = packet.binary_size(), "Packet size");
+            let maximum_packet_size = conn_state
+                .maximum_packet_size
+                .unwrap_or(VARIABLE_INTEGER_MAX);
 
-        let published_recv;
+            if packet.len() > maximum_packet_size as usize {
+                tracing::error!("Binary size bigger than maximum packet size");
+                return Err(PublishError::PacketTooLarge);
+            }
 
-        if let Some(pi) = packet_identifier {
-            let mut bytes = tokio_util::bytes::BytesMut::new();
-            bytes.reserve(packet.binary_size() as usize);
-            let mut writer = crate::packets::MqttWriter(&mut bytes);
-            packet.write(&mut writer).map_err(drop)?; // TODO
-            let mqtt_packet = crate::packets::MqttPacket {
-                packet: yoke::Yoke::try_attach_to_cart(
-                    crate::packets::StableBytes(bytes.freeze()),
-                    |bytes: &[u8]| mqtt_format::v5::packets::MqttPacket::parse_complete(bytes),
-                )
-                .unwrap(), // TODO
+            tracing::trace!(%maximum_packet_size, packet_size = packet.len(), "Packet size");
+
+            // Queued while holding the lock, so that packets go out in the order of their
+            // identifiers, which is the order they are sent again in when resuming the session
+            let written = match conn_state
+                .conn_write
+                .queue_encoded(packet.clone())
+                .in_current_span()
+                .await
+            {
+                Ok(written) => written,
+                Err(error) => {
+                    tracing::error!(%error, "Could not publish");
+                    return Err(PublishError::Send);
+                }
             };
 
-            sess_state.outstanding_packets.insert(pi, mqtt_packet);
-            match qos {
-                QualityOfService::AtMostOnce => unreachable!(),
-                QualityOfService::AtLeastOnce => {
-                    let (on_acknowledge, recv) = futures::channel::oneshot::channel();
+            let published_recv;
+
+            if let Some(pi) = packet_identifier {
+                sess_state.outstanding_packets.insert(pi, packet.clone());
+                if let Some(on_packet_recv) = on_packet_recv {
                     inner
                         .outstanding_callbacks
-                        .add_qos1(pi, Qos1Callbacks { on_acknowledge });
-                    published_recv = PublishedReceiver::Once(PublishedQos1 { recv });
+                        .add_on_packet_recv(pi, on_packet_recv);
                 }
-                QualityOfService::ExactlyOnce => {
-                    let (on_receive, recv) = futures::channel::oneshot::channel();
-                    let (on_complete, comp_recv) = futures::channel::oneshot::channel();
-                    inner.outstanding_callbacks.add_qos2(
-                        pi,
-                        Qos2ReceiveCallback { on_receive },
-                        Qos2CompleteCallback { on_complete },
-                    );
-                    published_recv =
-                        PublishedReceiver::Twice(PublishedQos2Received { recv, comp_recv });
+                match qos {
+                    QualityOfService::AtMostOnce => unreachable!(),
+                    QualityOfService::AtLeastOnce => {
+                        let (on_acknowledge, recv) = futures::channel::oneshot::channel();
+                        inner
+                            .outstanding_callbacks
+                            .add_qos1(pi, Qos1Callbacks { on_acknowledge });
+                        published_recv = PublishedReceiver::Once(PublishedQos1 { recv });
+                    }
+                    QualityOfService::ExactlyOnce => {
+                        let (on_receive, recv) = futures::channel::oneshot::channel();
+                        let (on_complete, comp_recv) = futures::channel::oneshot::channel();
+                        inner.outstanding_callbacks.add_qos2(
+                            pi,
+                            Qos2ReceiveCallback { on_receive },
+                            Qos2CompleteCallback { on_complete },
+                        );
+                        published_recv =
+                            PublishedReceiver::Twice(PublishedQos2Received { recv, comp_recv });
+                    }
                 }
+            } else {
+                published_recv = PublishedReceiver::None;
             }
-        } else {
-            published_recv = PublishedReceiver::None;
-        }
+
+            (written, packet_identifier, published_recv)
+        };
 
         tracing::trace!("Publishing");
-        conn_state
-            .conn_write
-            .send(packet)
-            .in_current_span()
-            .await
-            .unwrap();
+        if let Err(error) = written.written().in_current_span().await {
+            tracing::error!(%error, "Could not publish");
+            if let Some(packet_identifier) = packet_identifier {
+                self.inner.lock().await.forget_request(packet_identifier);
+            }
+            return Err(PublishError::Send);
+        }
         tracing::trace!("Finished publishing");
 
         Ok(Published {
@@ -140,6 +162,7 @@ pub async fn publish(
         })
     }
 
+    /// Publish a message with [`QualityOfService::AtLeastOnce`]
     pub async fn publish_qos1(
         &self,
         PublishQos1 {
@@ -148,20 +171,24 @@ pub async fn publish_qos1(
             payload,
             on_packet_recv,
         }: PublishQos1,
-    ) -> Result<(), ()> {
-        let _res = self
+    ) -> Result<PublishedQos1, PublishError> {
+        let published = self
             .publish(Publish {
                 topic,
-                qos: QualityOfService::AtMostOnce,
+                qos: QualityOfService::AtLeastOnce,
                 retain,
                 payload,
                 on_packet_recv,
             })
             .await?;
 
-        Ok(()) // TODO
+        match published.recv {
+            PublishedReceiver::Once(qos1) => Ok(qos1),
+            PublishedReceiver::None | PublishedReceiver::Twice(_) => unreachable!(),
+        }
     }
 
+    /// Publish a message with [`QualityOfService::ExactlyOnce`]
     pub async fn publish_qos2(
         &self,
         PublishQos2 {
@@ -170,8 +197,8 @@ pub async fn publish_qos2(
             payload,
             on_packet_recv,
         }: PublishQos2,
-    ) -> Result<(), ()> {
-        let _res = self
+    ) -> Result<PublishedQos2Received, PublishError> {
+        let published = self
             .publish(Publish {
                 topic,
                 qos: QualityOfService::ExactlyOnce,
@@ -181,37 +208,61 @@ pub async fn publish_qos2(
             })
             .await?;
 
-        Ok(()) // TODO
+        match published.recv {
+            PublishedReceiver::Twice(qos2) => Ok(qos2),
+            PublishedReceiver::None | PublishedReceiver::Once(_) => unreachable!(),
+        }
     }
 }
 
-fn get_next_packet_ident(
+pub(super) fn get_next_packet_ident(
     next_packet_ident: &mut std::num::NonZeroU16,
     outstanding_packets: &OutstandingPackets,
 ) -> Result<PacketIdentifier, PacketIdentifierExhausted> {
-    let start = *next_packet_ident;
+    next_free_packet_identifier(next_packet_ident, |ident| {
+        outstanding_packets.exists_outstanding_packet(ident)
+    })
+    .ok_or(PacketIdentifierExhausted)
+}
 
-    loop {
-        let next = PacketIdentifier::from(*next_packet_ident);
+#[derive(Debug, thiserror::Error)]
+#[error("No free packet identifiers available")]
+pub struct PacketIdentifierExhausted;
 
-        if !outstanding_packets.exists_outstanding_packet(next) {
-            return Ok(next);
-        }
+#[derive(Debug, thiserror::Error)]
+pub enum PublishError {
+    #[error("The client is not connected")]
+    NotConnected,
 
-        match next_packet_ident.checked_add(1) {
-            Some(n) => *next_packet_ident = n,
-            None => *next_packet_ident = std::num::NonZeroU16::MIN,
-        }
+    #[error("The server does not support retained messages")]
+    RetainNotAvailable,
 
-        if start == *next_packet_ident {
-            return Err(PacketIdentifierExhausted);
-        }
+    #[error(transparent)]
+    PacketIdentifierExhausted(#[from] PacketIdentifierExhausted),
+
+    #[error("Could not encode the PUBLISH")]
+    Encode,
+
+    #[error("The PUBLISH is larger than the maximum packet size of the server")]
+    PacketTooLarge,
+
+    #[error("Could not send the PUBLISH")]
+    Send,
+}
+
+impl PublishError {
+    /// Whether publishing the same message again, even on another connection, fails the same way
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            PublishError::RetainNotAvailable | PublishError::Encode | PublishError::PacketTooLarge
+        )
     }
 }
 
 #[derive(Debug, thiserror::Error)]
-#[error("No free packet identifiers available")]
-pub struct PacketIdentifierExhausted;
+#[error("The connection was closed before a response was received")]
+pub struct ConnectionClosed;
 
 pub(crate) struct ClientHandlers {
     pub(crate) on_packet_recv: OnPacketRecvFn,
@@ -222,6 +273,7 @@ pub(crate) struct ClientHandlers {
 
 pub type OnPacketRecvFn = Box<dyn Fn(crate::packets::MqttPacket) + Send>;
 pub type OnQos1AcknowledgeFn = Box<dyn Fn(crate::packets::Puback) + Send>;
+pub type OnPublishPacketRecvFn = Box<dyn Fn(&crate::packets::MqttPacket) + Send>;
 
 impl Default for ClientHandlers {
     fn default() -> Self {
@@ -244,6 +296,8 @@ pub(crate) struct Callbacks {
     qos1: HashMap<PacketIdentifier, Qos1Callbacks>,
     qos2_receive: HashMap<PacketIdentifier, Qos2ReceiveCallback>,
     qos2_complete: HashMap<PacketIdentifier, Qos2CompleteCallback>,
+    on_packet_recv: HashMap<PacketIdentifier, OnPublishPacketRecvFn>,
+    suback: HashMap<PacketIdentifier, futures::channel::oneshot::Sender<crate::packets::Suback>>,
 }
 
 impl Callbacks {
@@ -253,6 +307,8 @@ pub(crate) fn new() -> Callbacks {
             qos1: HashMap::default(),
             qos2_receive: HashMap::default(),
             qos2_complete: HashMap::default(),
+            on_packet_recv: HashMap::default(),
+            suback: HashMap::default(),
         }
     }
 
@@ -274,6 +330,45 @@ pub(crate) fn add_qos2(
         self.qos2_complete.insert(id, comp);
     }
 
+    pub(crate) fn add_suback(
+        &mut self,
+        id: PacketIdentifier,
+        cb: futures::channel::oneshot::Sender<crate::packets::Suback>,
+    ) {
+        self.suback.insert(id, cb);
+    }
+
+    pub(crate) fn take_suback(
+        &mut self,
+        id: PacketIdentifier,
+    ) -> Option<futures::channel::oneshot::Sender<crate::packets::Suback>> {
+        self.suback.remove(&id)
+    }
+
+    pub(crate) fn add_on_packet_recv(&mut self, id: PacketIdentifier, cb: OnPublishPacketRecvFn) {
+        self.on_packet_recv.insert(id, cb);
+    }
+
+    /// Call the `on_packet_recv` handler of the publish with the given identifier, if it has one
+    pub(crate) fn call_on_packet_recv(&self, id: PacketIdentifier, packet: &MqttPacket) {
+        if let Some(cb) = self.on_packet_recv.get(&id) {
+            cb(packet);
+        }
+    }
+
+    pub(crate) fn remove_on_packet_recv(&mut self, id: PacketIdentifier) {
+        self.on_packet_recv.remove(&id);
+    }
+
+    /// Drop all callbacks of the request with the given identifier
+    pub(crate) fn remove(&mut self, id: PacketIdentifier) {
+        self.qos1.remove(&id);
+        self.qos2_receive.remove(&id);
+        self.qos2_complete.remove(&id);
+        self.on_packet_recv.remove(&id);
+        self.suback.remove(&id);
+    }
+
     pub(crate) fn take_ping_req(&mut self) -> Option<futures::channel::oneshot::Sender<()>> {
         self.ping_req.pop_front()
     }
@@ -298,14 +393,14 @@ pub(crate) fn take_qos2_complete(
 }
 
 pub(crate) struct Qos1Callbacks {
-    pub(crate) on_acknowledge: futures::channel::oneshot::Sender<crate::packets::Puback>,
+    pub(crate) on_acknowledge: futures::channel::oneshot::Sender<Puback>,
 }
 
 pub(crate) struct Qos2ReceiveCallback {
-    pub(crate) on_receive: futures::channel::oneshot::Sender<crate::packets::MqttPacket>,
+    pub(crate) on_receive: futures::channel::oneshot::Sender<crate::packets::Pubrec>,
 }
 pub(crate) struct Qos2CompleteCallback {
-    pub(crate) on_complete: futures::channel::oneshot::Sender<crate::packets::MqttPacket>,
+    pub(crate) on_complete: futures::channel::oneshot::Sender<crate::packets::Pubcomp>,
 }
 
 pub struct Publish {
@@ -313,7 +408,7 @@ pub struct Publish {
     pub qos: QualityOfService,
     pub retain: bool,
     pub payload: MqttPayload,
-    pub on_packet_recv: Option<Box<dyn Fn(&crate::packets::MqttPacket) + Send>>,
+    pub on_packet_recv: Option<OnPublishPacketRecvFn>,
 }
 
 pub struct Published {
@@ -321,19 +416,50 @@ pub struct Published {
 }
 
 impl Published {
-    pub async fn acknowledged(self) {
+    /// Wait until the server acknowledged the message, according to its QoS
+    ///
+    /// The acknowledgement is returned for all reason codes, check
+    /// [`Acknowledgement::is_refused`] to see whether the server accepted the message.
+    pub async fn acknowledged(self) -> Result<Acknowledgement, ConnectionClosed> {
         match self.recv {
-            PublishedReceiver::None => (),
-            PublishedReceiver::Once(qos1) => {
-                qos1.acknowledged().await;
-            }
+            PublishedReceiver::None => Ok(Acknowledgement::None),
+            PublishedReceiver::Once(qos1) => qos1.acknowledged().await.map(Acknowledgement::Puback),
             PublishedReceiver::Twice(qos2) => {
-                qos2.received().await.completed().await;
+                let received = qos2.received().await?;
+                // A PUBREC with an error reason code ends the flow, no PUBCOMP follows
+                if u8::from(received.pubrec().reason_code()) >= 0x80 {
+                    return Ok(Acknowledgement::Pubrec(received.pubrec));
+                }
+                received.completed().await.map(Acknowledgement::Pubcomp)
             }
         }
     }
 }
 
+/// How the server answered a published message
+#[derive(Clone, Debug)]
+pub enum Acknowledgement {
+    /// QoS 0 messages are not acknowledged
+    None,
+    /// The PUBACK of a QoS 1 message, which the server accepted or refused
+    Puback(Puback),
+    /// The PUBREC of a QoS 2 message the server refused, no PUBCOMP follows it
+    Pubrec(Pubrec),
+    /// The PUBCOMP of a QoS 2 message the server accepted
+    Pubcomp(Pubcomp),
+}
+
+impl Acknowledgement {
+    /// Whether the server refused the message with an error reason code
+    pub fn is_refused(&self) -> bool {
+        match self {
+            Acknowledgement::None | Acknowledgement::Pubcomp(_) => false,
+            Acknowledgement::Puback(puback) => u8::from(puback.reason_code()) >= 0x80,
+            Acknowledgement::Pubrec(_) => true,
+        }
+    }
+}
+
 enum PublishedReceiver {
     None,
     Once(PublishedQos1),
@@ -341,37 +467,52 @@ enum PublishedReceiver {
 }
 
 pub struct PublishedQos1 {
-    recv: futures::channel::oneshot::Receiver<crate::packets::Puback>,
+    recv: futures::channel::oneshot::Receiver<Puback>,
 }
 
 impl PublishedQos1 {
-    pub async fn acknowledged(self) {
-        self.recv.await.unwrap();
+    /// Wait for the PUBACK of the server
+    ///
+    /// The PUBACK is returned for all reason codes, check [`Puback::reason_code`] to see whether
+    /// the server accepted the message.
+    pub async fn acknowledged(self) -> Result<Puback, ConnectionClosed> {
+        self.recv.await.map_err(|_| ConnectionClosed)
     }
 }
 
 pub struct PublishedQos2Received {
-    recv: futures::channel::oneshot::Receiver<MqttPacket>,
-    comp_recv: futures::channel::oneshot::Receiver<MqttPacket>,
+    recv: futures::channel::oneshot::Receiver<Pubrec>,
+    comp_recv: futures::channel::oneshot::Receiver<Pubcomp>,
 }
 
 impl PublishedQos2Received {
-    pub async fn received(self) -> PublishedQos2Completed {
-        self.recv.await.unwrap();
-
-        PublishedQos2Completed {
+    /// Wait for the PUBREC of the server, the PUBREL is sent before this resolves
+    ///
+    /// The PUBREC is returned for all reason codes. If it has an error reason code, the flow ends
+    /// there: no PUBREL is sent and [`PublishedQos2Completed::completed`] fails.
+    pub async fn received(self) -> Result<PublishedQos2Completed, ConnectionClosed> {
+        let pubrec = self.recv.await.map_err(|_| ConnectionClosed)?;
+
+        Ok(PublishedQos2Completed {
+            pubrec,
             recv: self.comp_recv,
-        }
+        })
     }
 }
 
 pub struct PublishedQos2Completed {
-    recv: futures::channel::oneshot::Receiver<MqttPacket>,
+    pubrec: Pubrec,
+    recv: futures::channel::oneshot::Receiver<Pubcomp>,
 }
 
 impl PublishedQos2Completed {
-    pub async fn completed(self) {
-        self.recv.await.unwrap();
+    pub fn pubrec(&self) -> &Pubrec {
+        &self.pubrec
+    }
+
+    /// Wait for the PUBCOMP of the server
+    pub async fn completed(self) -> Result<Pubcomp, ConnectionClosed> {
+        self.recv.await.map_err(|_| ConnectionClosed)
     }
 }
 
@@ -379,14 +520,21 @@ pub struct PublishQos1 {
     pub topic: crate::topic::MqttTopic,
     pub retain: bool,
     pub payload: MqttPayload,
-    on_packet_recv: Option<Box<dyn Fn(&crate::packets::MqttPacket) + Send>>,
+    on_packet_recv: Option<OnPublishPacketRecvFn>,
 }
 
 impl PublishQos1 {
-    pub fn with_on_packet_recv(
-        mut self,
-        on_packet_recv: Box<dyn Fn(&crate::packets::MqttPacket) + Send>,
-    ) -> Self {
+    pub fn new(topic: crate::topic::MqttTopic, retain: bool, payload: MqttPayload) -> Self {
+        Self {
+            topic,
+            retain,
+            payload,
+            on_packet_recv: None,
+        }
+    }
+
+    /// Set a handler that is called with every acknowledgement packet received for this publish
+    pub fn with_on_packet_recv(mut self, on_packet_recv: OnPublishPacketRecvFn) -> Self {
         self.on_packet_recv = Some(on_packet_recv);
         self
     }
@@ -396,14 +544,21 @@ pub struct PublishQos2 {
     pub topic: crate::topic::MqttTopic,
     pub retain: bool,
     pub payload: MqttPayload,
-    on_packet_recv: Option<Box<dyn Fn(&crate::packets::MqttPacket) + Send>>,
+    on_packet_recv: Option<OnPublishPacketRecvFn>,
 }
 
 impl PublishQos2 {
-    pub fn with_on_packet_recv(
-        mut self,
-        on_packet_recv: Box<dyn Fn(&crate::packets::MqttPacket) + Send>,
-    ) -> Self {
+    pub fn new(topic: crate::topic::MqttTopic, retain: bool, payload: MqttPayload) -> Self {
+        Self {
+            topic,
+            retain,
+            payload,
+            on_packet_recv: None,
+        }
+    }
+
+    /// Set a handler that is called with every acknowledgement packet received for this publish
+    pub fn with_on_packet_recv(mut self, on_packet_recv: OnPublishPacketRecvFn) -> Self {
         self.on_packet_recv = Some(on_packet_recv);
         self
     }
@@ -411,23 +566,28 @@ pub fn with_on_packet_recv(
 
 impl MqttClient {
     pub async fn ping(&self) -> Result<Ping, ()> {
-        let mut inner = self.inner.lock().await;
-        let inner = &mut *inner;
+        let (written, recv) = {
+            let mut inner = self.inner.lock().await;
+            let inner = &mut *inner;
 
-        let Some(conn_state) = &mut inner.connection_state else {
-            tracing::error!("No connection state found");
-            return Err(());
-        };
+            let Some(conn_state) = &mut inner.connection_state else {
+                tracing::error!("No connection state found");
+                return Err(());
+            };
 
-        let packet = mqtt_format::v5::packets::MqttPacket::Pingreq(
-            mqtt_format::v5::packets::pingreq::MPingreq,
-        );
+            let packet = mqtt_format::v5::packets::MqttPacket::Pingreq(
+                mqtt_format::v5::packets::pingreq::MPingreq,
+            );
+            let written = conn_state.conn_write.queue(packet).await.map_err(drop)?;
+
+            let (sender, recv) = futures::channel::oneshot::channel();
 
-        let (sender, recv) = futures::channel::oneshot::channel();
+            inner.outstanding_callbacks.add_ping_req(sender);
 
-        inner.outstanding_callbacks.add_ping_req(sender);
+            (written, recv)
+        };
 
-        conn_state.conn_write.send(packet).await.map_err(drop)?;
+        written.written().await.map_err(drop)?;
 
         Ok(Ping { recv })
     }
@@ -438,7 +598,308 @@ pub struct Ping {
 }
 
 impl Ping {
-    pub async fn response(self) {
-        self.recv.await.unwrap()
+    pub async fn response(self) -> Result<(), ConnectionClosed> {
+        self.recv.await.map_err(|_| ConnectionClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::puback::PubackReasonCode;
+    use mqtt_format::v5::packets::pubcomp::PubcompReasonCode;
+    use mqtt_format::v5::packets::pubrec::PubrecReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+    use super::Acknowledgement;
+    use super::Publish;
+    use super::PublishQos1;
+    use super::PublishQos2;
+    use crate::client::tests::connect_to_test_server;
+    use crate::client::tests::success_connack;
+    use crate::client::MqttClient;
+    use crate::packet_identifier::PacketIdentifier;
+    use crate::qos::QualityOfService;
+
+    #[tokio::test]
+    async fn publish_qos1_resolves_with_puback() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, mut server) = connect_to_test_server(&client, success_connack()).await;
+        let background = tokio::spawn(connected.background_task);
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let published = client
+            .publish_qos1(
+                PublishQos1::new(
+                    "foo/bar".try_into().unwrap(),
+                    false,
+                    vec![1, 2, 3].try_into().unwrap(),
+                )
+                .with_on_packet_recv(Box::new({
+                    let received = received.clone();
+                    move |_| {
+                        received.fetch_add(1, Ordering::SeqCst);
+                    }
+                })),
+            )
+            .await
+            .unwrap();
+
+        let publish = server.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish) = publish.get() else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(
+            publish.quality_of_service,
+            mqtt_format::v5::qos::QualityOfService::AtLeastOnce
+        );
+        let packet_identifier = publish.packet_identifier.unwrap();
+
+        server
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier,
+                    reason: PubackReasonCode::NoMatchingSubscribers,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let puback = published.acknowledged().await.unwrap();
+        assert_eq!(
+            puback.packet_identifier(),
+            PacketIdentifier::from(packet_identifier)
+        );
+        assert_eq!(
+            puback.reason_code(),
+            PubackReasonCode::NoMatchingSubscribers
+        );
+        assert!(puback.properties().reason_string().is_none());
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        connected.connection_handle.shutdown().await;
+        assert!(background.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn publish_qos2_resolves_in_two_stages() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, mut server) = connect_to_test_server(&client, success_connack()).await;
+        let background = tokio::spawn(connected.background_task);
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let published = client
+            .publish_qos2(
+                PublishQos2::new(
+                    "foo/bar".try_into().unwrap(),
+                    false,
+                    vec![1, 2, 3].try_into().unwrap(),
+                )
+                .with_on_packet_recv(Box::new({
+                    let received = received.clone();
+                    move |_| {
+                        received.fetch_add(1, Ordering::SeqCst);
+                    }
+                })),
+            )
+            .await
+            .unwrap();
+
+        let publish = server.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish) = publish.get() else {
+            panic!("Expected a PUBLISH");
+        };
+        let packet_identifier = publish.packet_identifier.unwrap();
+
+        server
+            .send(FormatMqttPacket::Pubrec(
+                mqtt_format::v5::packets::pubrec::MPubrec {
+                    packet_identifier,
+                    reason: PubrecReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let published = published.received().await.unwrap();
+        assert_eq!(published.pubrec().reason_code(), PubrecReasonCode::Success);
+
+        let pubrel = server.next().await.unwrap().unwrap();
+        assert!(matches!(pubrel.get(), FormatMqttPacket::Pubrel(_)));
+
+        server
+            .send(FormatMqttPacket::Pubcomp(
+                mqtt_format::v5::packets::pubcomp::MPubcomp {
+                    packet_identifier,
+                    reason: PubcompReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubcomp::PubcompProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let pubcomp = published.completed().await.unwrap();
+        assert_eq!(
+            pubcomp.packet_identifier(),
+            PacketIdentifier::from(packet_identifier)
+        );
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+
+        connected.connection_handle.shutdown().await;
+        assert!(background.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn publish_qos2_ends_on_refused_pubrec() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, mut server) = connect_to_test_server(&client, success_connack()).await;
+        let background = tokio::spawn(connected.background_task);
+
+        let published = client
+            .publish_qos2(PublishQos2::new(
+                "foo/bar".try_into().unwrap(),
+                false,
+                vec![1, 2, 3].try_into().unwrap(),
+            ))
+            .await
+            .unwrap();
+
+        let publish = server.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish) = publish.get() else {
+            panic!("Expected a PUBLISH");
+        };
+        let packet_identifier = publish.packet_identifier.unwrap();
+
+        // Acknowledgements of unknown packets are ignored
+        server
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                        std::num::NonZeroU16::new(4242).unwrap(),
+                    ),
+                    reason: PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+        server
+            .send(FormatMqttPacket::Pubrec(
+                mqtt_format::v5::packets::pubrec::MPubrec {
+                    packet_identifier,
+                    reason: PubrecReasonCode::QuotaExceeded,
+                    properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let published = published.received().await.unwrap();
+        assert_eq!(
+            published.pubrec().reason_code(),
+            PubrecReasonCode::QuotaExceeded
+        );
+        assert!(published.completed().await.is_err());
+
+        // No PUBREL was sent for the refused message, the next packet is the next PUBLISH
+        client
+            .publish_qos1(PublishQos1::new(
+                "foo/bar".try_into().unwrap(),
+                false,
+                vec![4].try_into().unwrap(),
+            ))
+            .await
+            .unwrap();
+        let next = server.next().await.unwrap().unwrap();
+        assert!(matches!(next.get(), FormatMqttPacket::Publish(_)));
+
+        connected.connection_handle.shutdown().await;
+        assert!(background.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn acknowledgements_tell_refused_messages_apart() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, mut server) = connect_to_test_server(&client, success_connack()).await;
+        let background = tokio::spawn(connected.background_task);
+
+        let publish = |qos| Publish {
+            topic: "foo/bar".try_into().unwrap(),
+            qos,
+            retain: false,
+            payload: vec![1, 2, 3].try_into().unwrap(),
+            on_packet_recv: None,
+        };
+
+        let published = client
+            .publish(publish(QualityOfService::AtLeastOnce))
+            .await
+            .unwrap();
+        let packet = server.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish_packet) = packet.get() else {
+            panic!("Expected a PUBLISH");
+        };
+        server
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: publish_packet.packet_identifier.unwrap(),
+                    reason: PubackReasonCode::NotAuthorized,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let acknowledgement = published.acknowledged().await.unwrap();
+        assert!(acknowledgement.is_refused());
+        let Acknowledgement::Puback(puback) = acknowledgement else {
+            panic!("Expected a PUBACK, got {acknowledgement:?}");
+        };
+        assert_eq!(puback.reason_code(), PubackReasonCode::NotAuthorized);
+
+        let published = client
+            .publish(publish(QualityOfService::ExactlyOnce))
+            .await
+            .unwrap();
+        let packet = server.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish_packet) = packet.get() else {
+            panic!("Expected a PUBLISH");
+        };
+        server
+            .send(FormatMqttPacket::Pubrec(
+                mqtt_format::v5::packets::pubrec::MPubrec {
+                    packet_identifier: publish_packet.packet_identifier.unwrap(),
+                    reason: PubrecReasonCode::NotAuthorized,
+                    properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let acknowledgement = published.acknowledged().await.unwrap();
+        assert!(acknowledgement.is_refused());
+        let Acknowledgement::Pubrec(pubrec) = acknowledgement else {
+            panic!("Expected a PUBREC, got {acknowledgement:?}");
+        };
+        assert_eq!(pubrec.reason_code(), PubrecReasonCode::NotAuthorized);
+
+        let published = client
+            .publish(publish(QualityOfService::AtMostOnce))
+            .await
+            .unwrap();
+        server.next().await.unwrap().unwrap();
+        let acknowledgement = published.acknowledged().await.unwrap();
+        assert!(matches!(acknowledgement, Acknowledgement::None));
+        assert!(!acknowledgement.is_refused());
+
+        connected.connection_handle.shutdown().await;
+        assert!(background.await.unwrap().is_ok());
     }
 }
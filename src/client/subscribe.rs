@@ -0,0 +1,347 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::Stream;
+pub use mqtt_format::v5::packets::subscribe::RetainHandling;
+use tracing::Instrument;
+
+use super::send::get_next_packet_ident;
+use super::send::ConnectionClosed;
+use super::send::PacketIdentifierExhausted;
+use super::MqttClient;
+use crate::packets::subscribe::SubscribeProperties;
+use crate::packets::EncodedPacket;
+use crate::packets::Publish;
+use crate::packets::Suback;
+use crate::packets::VecWriter;
+use crate::qos::QualityOfService;
+use crate::topic::MqttTopicFilter;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SubscribeError {
+    #[error("The client is not connected")]
+    NotConnected,
+
+    #[error("A SUBSCRIBE has to contain at least one subscription")]
+    NoSubscriptions,
+
+    #[error(transparent)]
+    PacketIdentifierExhausted(#[from] PacketIdentifierExhausted),
+
+    #[error("Could not encode the SUBSCRIBE")]
+    Encode,
+
+    #[error("Could not send the SUBSCRIBE")]
+    Send,
+
+    #[error(transparent)]
+    ConnectionClosed(#[from] ConnectionClosed),
+}
+
+impl SubscribeError {
+    /// Whether subscribing the same way again, even on another connection, fails the same way
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            SubscribeError::NoSubscriptions
+                | SubscribeError::PacketIdentifierExhausted(_)
+                | SubscribeError::Encode
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub topic_filter: MqttTopicFilter,
+    pub qos: QualityOfService,
+    /// Do not receive messages published by this client
+    pub no_local: bool,
+    /// Keep the retain flag of forwarded messages as it was published
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+}
+
+impl Subscription {
+    pub fn new(topic_filter: MqttTopicFilter, qos: QualityOfService) -> Self {
+        Self {
+            topic_filter,
+            qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendRetainedMessagesAlways,
+        }
+    }
+
+    fn as_ref(&self) -> mqtt_format::v5::packets::subscribe::Subscription<'_> {
+        mqtt_format::v5::packets::subscribe::Subscription {
+            topic_filter: self.topic_filter.as_ref(),
+            options: mqtt_format::v5::packets::subscribe::SubscriptionOptions {
+                quality_of_service: self.qos.into(),
+                no_local: self.no_local,
+                retain_as_published: self.retain_as_published,
+                retain_handling: self.retain_handling,
+            },
+        }
+    }
+}
+
+pub struct Subscribe {
+    pub subscriptions: Vec<Subscription>,
+    pub properties: SubscribeProperties,
+}
+
+impl Subscribe {
+    pub fn new(subscriptions: Vec<Subscription>) -> Self {
+        Self {
+            subscriptions,
+            properties: SubscribeProperties::new(),
+        }
+    }
+}
+
+impl MqttClient {
+    /// Send a SUBSCRIBE and wait for the SUBACK of the server
+    ///
+    /// The SUBACK is returned for all reason codes, check [`Suback::reason_codes`] to see which
+    /// subscriptions were granted. Messages are delivered through [`MqttClient::publishes`].
+    #[tracing::instrument(skip_all, fields(subscriptions = subscribe.subscriptions.len()))]
+    pub async fn subscribe(&self, subscribe: Subscribe) -> Result<Suback, SubscribeError> {
+        if subscribe.subscriptions.is_empty() {
+            return Err(SubscribeError::NoSubscriptions);
+        }
+
+        let mut encoded_subscriptions = Vec::new();
+        for subscription in &subscribe.subscriptions {
+            subscription
+                .as_ref()
+                .write(&mut VecWriter(&mut encoded_subscriptions))
+                .map_err(|_| SubscribeError::Encode)?;
+        }
+        let subscriptions = mqtt_format::v5::packets::subscribe::Subscriptions::from_encoded(
+            &encoded_subscriptions,
+        )
+        .map_err(|_| SubscribeError::Encode)?;
+
+        let (written, packet_identifier, suback_recv) = {
+            let mut inner = self.inner.lock().await;
+            let inner = &mut *inner;
+
+            let (Some(conn_state), Some(sess_state)) =
+                (&mut inner.connection_state, &mut inner.session_state)
+            else {
+                return Err(SubscribeError::NotConnected);
+            };
+
+            let packet_identifier = get_next_packet_ident(
+                &mut conn_state.next_packet_identifier,
+                &sess_state.outstanding_packets,
+            )?;
+            tracing::debug!(%packet_identifier, "Packet identifier computed");
+
+            let packet = EncodedPacket::encode(&mqtt_format::v5::packets::MqttPacket::Subscribe(
+                mqtt_format::v5::packets::subscribe::MSubscribe {
+                    packet_identifier: packet_identifier.into(),
+                    properties: subscribe.properties.as_ref(),
+                    subscriptions,
+                },
+            ))
+            .map_err(|_| SubscribeError::Encode)?;
+
+            let written = match conn_state
+                .conn_write
+                .queue_encoded(packet.clone())
+                .in_current_span()
+                .await
+            {
+                Ok(written) => written,
+                Err(error) => {
+                    tracing::error!(%error, "Could not subscribe");
+                    return Err(SubscribeError::Send);
+                }
+            };
+
+            // The identifier stays in use until the SUBACK arrives
+            sess_state
+                .outstanding_packets
+                .insert(packet_identifier, packet.clone());
+
+            let (on_suback, suback_recv) = futures::channel::oneshot::channel();
+            inner
+                .outstanding_callbacks
+                .add_suback(packet_identifier, on_suback);
+
+            (written, packet_identifier, suback_recv)
+        };
+
+        if let Err(error) = written.written().in_current_span().await {
+            tracing::error!(%error, "Could not subscribe");
+            self.inner.lock().await.forget_request(packet_identifier);
+            return Err(SubscribeError::Send);
+        }
+
+        Ok(suback_recv.await.map_err(|_| ConnectionClosed)?)
+    }
+
+    /// Receive the messages the server sends to this client
+    ///
+    /// Only messages that arrive after calling this are delivered, so it should be called before
+    /// subscribing. QoS 1 and 2 messages are acknowledged by the client, regardless of whether the
+    /// stream is read.
+    pub async fn publishes(&self) -> ReceivedPublishes {
+        self.inner.lock().await.publishes.subscribe()
+    }
+}
+
+pub(crate) struct PublishSenders {
+    senders: Vec<futures::channel::mpsc::UnboundedSender<Publish>>,
+}
+
+impl PublishSenders {
+    pub(crate) fn new() -> Self {
+        Self {
+            senders: Vec::new(),
+        }
+    }
+
+    fn subscribe(&mut self) -> ReceivedPublishes {
+        let (sender, recv) = futures::channel::mpsc::unbounded();
+        self.senders.push(sender);
+        ReceivedPublishes { recv }
+    }
+
+    pub(crate) fn deliver(&mut self, publish: Publish) {
+        self.senders
+            .retain(|sender| sender.unbounded_send(publish.clone()).is_ok());
+    }
+}
+
+/// A stream of received [`Publish`] packets, created with [`MqttClient::publishes`]
+pub struct ReceivedPublishes {
+    recv: futures::channel::mpsc::UnboundedReceiver<Publish>,
+}
+
+impl Stream for ReceivedPublishes {
+    type Item = Publish;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.recv).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+    use super::Subscribe;
+    use super::Subscription;
+    use crate::client::tests::connect_to_test_server;
+    use crate::client::tests::success_connack;
+    use crate::client::MqttClient;
+    use crate::qos::QualityOfService;
+
+    #[tokio::test]
+    async fn subscribe_and_receive_qos2_once() {
+        let client = MqttClient::new_with_default_handlers();
+        let (connected, mut server) = connect_to_test_server(&client, success_connack()).await;
+        let background = tokio::spawn(connected.background_task);
+        let mut publishes = client.publishes().await;
+
+        let server_task = tokio::spawn(async move {
+            let subscribe = server.next().await.unwrap().unwrap();
+            let FormatMqttPacket::Subscribe(subscribe) = subscribe.get() else {
+                panic!("Expected SUBSCRIBE, got {:?}", subscribe.get());
+            };
+            let filters = subscribe
+                .subscriptions
+                .iter()
+                .map(|sub| sub.topic_filter.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(filters, ["foo/+"]);
+
+            server
+                .send(FormatMqttPacket::Suback(
+                    mqtt_format::v5::packets::suback::MSuback {
+                        packet_identifier: subscribe.packet_identifier,
+                        properties: mqtt_format::v5::packets::suback::SubackProperties::new(),
+                        reasons: &[mqtt_format::v5::packets::suback::SubackReasonCode::GrantedQoS2],
+                    },
+                ))
+                .await
+                .unwrap();
+
+            // The second PUBLISH is a retransmission and must only be acknowledged
+            for duplicate in [false, true] {
+                server
+                    .send(FormatMqttPacket::Publish(
+                        mqtt_format::v5::packets::publish::MPublish {
+                            duplicate,
+                            quality_of_service: mqtt_format::v5::qos::QualityOfService::ExactlyOnce,
+                            retain: false,
+                            topic_name: "foo/bar",
+                            packet_identifier: Some(
+                                mqtt_format::v5::variable_header::PacketIdentifier(
+                                    std::num::NonZeroU16::new(7).unwrap(),
+                                ),
+                            ),
+                            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                            payload: &[1, 2, 3],
+                        },
+                    ))
+                    .await
+                    .unwrap();
+                let pubrec = server.next().await.unwrap().unwrap();
+                assert!(matches!(pubrec.get(), FormatMqttPacket::Pubrec(_)));
+            }
+
+            server
+                .send(FormatMqttPacket::Pubrel(
+                    mqtt_format::v5::packets::pubrel::MPubrel {
+                        packet_identifier: mqtt_format::v5::variable_header::PacketIdentifier(
+                            std::num::NonZeroU16::new(7).unwrap(),
+                        ),
+                        reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
+                        properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+            let pubcomp = server.next().await.unwrap().unwrap();
+            assert!(matches!(pubcomp.get(), FormatMqttPacket::Pubcomp(_)));
+            server
+        });
+
+        let suback = client
+            .subscribe(Subscribe::new(vec![Subscription::new(
+                "foo/+".parse().unwrap(),
+                QualityOfService::ExactlyOnce,
+            )]))
+            .await
+            .unwrap();
+        assert_eq!(
+            suback.reason_codes(),
+            [mqtt_format::v5::packets::suback::SubackReasonCode::GrantedQoS2]
+        );
+
+        let publish = publishes.next().await.unwrap();
+        assert_eq!(publish.topic(), "foo/bar");
+        assert_eq!(publish.payload(), [1, 2, 3]);
+        assert_eq!(publish.qos(), QualityOfService::ExactlyOnce);
+
+        let _server = server_task.await.unwrap();
+        connected.connection_handle.shutdown().await;
+        assert!(background.await.unwrap().is_ok());
+
+        // The duplicate was acknowledged before the server finished, but not delivered
+        assert!(publishes.next().now_or_never().is_none());
+    }
+}
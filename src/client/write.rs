@@ -0,0 +1,231 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use futures::SinkExt;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::packets::EncodedPacket;
+use crate::packets::MqttWriterError;
+use crate::transport::MqttConnection;
+
+/// How many write requests can be queued before senders have to wait for the writer
+const WRITE_QUEUE_CAPACITY: usize = 64;
+
+/// Small packets are collected in a buffer of this size before being written out together, larger
+/// chunks are written directly
+const WRITE_BUFFER_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransportWriterError {
+    #[error("Could not encode the packet")]
+    Encode(#[from] MqttWriterError),
+
+    #[error("The connection is closed")]
+    Closed,
+
+    #[error("Could not write to the transport: {}", .0)]
+    Io(std::io::ErrorKind),
+}
+
+pub(super) struct WriteRequest {
+    packet: EncodedPacket,
+    written: futures::channel::oneshot::Sender<Result<(), TransportWriterError>>,
+}
+
+/// A handle to the writer of the current connection
+///
+/// Packets are handed to the background writer, which owns the transport. This way no lock has to
+/// be held while waiting on the network, and concurrently sent packets can be written together.
+///
+/// There is a single handle per connection, kept in its connection state. Packets are queued while
+/// holding the client lock, so they are written in the order in which their packet identifiers
+/// were assigned and the queue capacity holds for all senders together.
+pub(super) struct TransportWriter {
+    requests: futures::channel::mpsc::Sender<WriteRequest>,
+}
+
+impl TransportWriter {
+    pub(super) fn new() -> (Self, futures::channel::mpsc::Receiver<WriteRequest>) {
+        let (requests, recv) = futures::channel::mpsc::channel(WRITE_QUEUE_CAPACITY);
+        (Self { requests }, recv)
+    }
+
+    pub(super) async fn queue(
+        &mut self,
+        packet: mqtt_format::v5::packets::MqttPacket<'_>,
+    ) -> Result<Written, TransportWriterError> {
+        let packet = EncodedPacket::encode(&packet)?;
+
+        self.queue_encoded(packet).await
+    }
+
+    /// Queue `packet` behind the packets queued before, waiting while the queue is full
+    ///
+    /// The returned [`Written`] completes once the packet was written to the transport.
+    pub(super) async fn queue_encoded(
+        &mut self,
+        packet: EncodedPacket,
+    ) -> Result<Written, TransportWriterError> {
+        let (written, written_recv) = futures::channel::oneshot::channel();
+
+        // Feeding only waits for a free slot, sending would also wait for the queue to drain
+        self.requests
+            .feed(WriteRequest { packet, written })
+            .await
+            .map_err(|_| TransportWriterError::Closed)?;
+
+        Ok(Written { recv: written_recv })
+    }
+}
+
+/// A packet queued with a [`TransportWriter`]
+#[must_use]
+pub(super) struct Written {
+    recv: futures::channel::oneshot::Receiver<Result<(), TransportWriterError>>,
+}
+
+impl Written {
+    /// Wait until the packet was written to the transport
+    pub(super) async fn written(self) -> Result<(), TransportWriterError> {
+        self.recv.await.map_err(|_| TransportWriterError::Closed)?
+    }
+}
+
+pub(super) async fn handle_background_writing(
+    mut requests: futures::channel::mpsc::Receiver<WriteRequest>,
+    conn: tokio::io::WriteHalf<MqttConnection>,
+    mut notify: futures::channel::mpsc::Sender<()>,
+) -> Result<(), ()> {
+    let mut conn = tokio::io::BufWriter::with_capacity(WRITE_BUFFER_CAPACITY, conn);
+    let mut pending = Vec::new();
+
+    while let Some(request) = requests.next().await {
+        let mut result = write_packet(&mut conn, &request.packet).await;
+        pending.push(request.written);
+
+        // Coalesce everything that is already queued into a single flush
+        while result.is_ok() {
+            let Ok(Some(request)) = requests.try_next() else {
+                break;
+            };
+            result = write_packet(&mut conn, &request.packet).await;
+            pending.push(request.written);
+        }
+
+        tracing::trace!(packets = pending.len(), "Flushing batch of packets");
+
+        if result.is_ok() {
+            result = conn.flush().await;
+        }
+
+        if let Err(error) = result {
+            tracing::error!(%error, "Could not write to the transport");
+            for written in pending.drain(..) {
+                let _ = written.send(Err(TransportWriterError::Io(error.kind())));
+            }
+            return Err(());
+        }
+
+        for written in pending.drain(..) {
+            if written.send(Ok(())).is_err() {
+                tracing::trace!("Sender of packet does not wait for it to be written");
+            }
+        }
+
+        if let Err(e) = notify.try_send(()) {
+            if e.is_full() {
+                // This is fine, we are already notifying of a send
+            }
+            if e.is_disconnected() {
+                tracing::trace!("Heartbeat is not running anymore, not notifying it");
+            }
+        }
+    }
+
+    tracing::debug!("All writers are gone, stopping");
+    Ok(())
+}
+
+async fn write_packet(
+    conn: &mut tokio::io::BufWriter<tokio::io::WriteHalf<MqttConnection>>,
+    packet: &EncodedPacket,
+) -> std::io::Result<()> {
+    for chunk in packet.chunks() {
+        // Chunks larger than the buffer are written directly without copying them first
+        conn.write_all(chunk).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::pingreq::MPingreq;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::codec::FramedRead;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::handle_background_writing;
+    use super::TransportWriter;
+    use super::Written;
+    use super::WRITE_QUEUE_CAPACITY;
+    use crate::codecs::MqttPacketCodec;
+    use crate::transport::MqttConnection;
+
+    #[tokio::test]
+    async fn queued_packets_are_all_written() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (_read, write) = tokio::io::split(MqttConnection::Duplex(client.compat()));
+        let mut server = FramedRead::new(MqttConnection::Duplex(server.compat()), MqttPacketCodec);
+
+        let (notify, mut notified) = futures::channel::mpsc::channel(1);
+        let (mut writer, requests) = TransportWriter::new();
+        let writing = tokio::spawn(handle_background_writing(requests, write, notify));
+
+        let sending = async {
+            let mut queued = Vec::new();
+            for _ in 0..100 {
+                queued.push(
+                    writer
+                        .queue(FormatMqttPacket::Pingreq(MPingreq))
+                        .await
+                        .unwrap(),
+                );
+            }
+            futures::future::join_all(queued.into_iter().map(Written::written)).await
+        };
+        let reading = async {
+            for _ in 0..100 {
+                let packet = server.next().await.unwrap().unwrap();
+                assert_eq!(*packet.get(), FormatMqttPacket::Pingreq(MPingreq));
+            }
+        };
+
+        let (results, ()) = futures::join!(sending, reading);
+        assert!(results.iter().all(Result::is_ok));
+        assert!(notified.next().await.is_some());
+
+        drop(writer);
+        assert!(writing.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn queueing_waits_while_the_queue_is_full() {
+        let (mut writer, _requests) = TransportWriter::new();
+
+        // The sender has one slot of its own in addition to the capacity of the queue
+        for _ in 0..=WRITE_QUEUE_CAPACITY {
+            let queued = writer.queue(FormatMqttPacket::Pingreq(MPingreq)).await;
+            assert!(queued.is_ok());
+        }
+
+        let queueing = writer.queue(FormatMqttPacket::Pingreq(MPingreq));
+        assert!(queueing.now_or_never().is_none());
+    }
+}
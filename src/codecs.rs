@@ -26,6 +26,9 @@ pub enum MqttPacketCodecError {
 
     #[error("Could not parse during decoding due to: {:?}", .0)]
     Parsing(winnow::error::ErrMode<winnow::error::ContextError>),
+
+    #[error("The packet of {size} bytes is larger than the maximum packet size")]
+    PacketTooLarge { size: usize },
 }
 
 pub(crate) struct MqttPacketCodec;
@@ -39,52 +42,86 @@ fn decode(
         &mut self,
         src: &mut tokio_util::bytes::BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
-        // 1. Byte: FixedHeader
-        // 2-5. Byte: Variable-Size
-
-        if src.len() < 2 {
-            src.reserve(2 - src.len());
-            return Ok(None);
-        }
-
-        let remaining_length =
-            match mqtt_format::v5::integers::parse_variable_u32(&mut Partial::new(&src[1..])) {
-                Ok(size) => size as usize,
-                Err(winnow::error::ErrMode::Incomplete(winnow::error::Needed::Size(needed))) => {
-                    src.reserve(needed.into());
-                    return Ok(None);
-                }
-                Err(winnow::error::ErrMode::Incomplete(winnow::error::Needed::Unknown)) => {
-                    src.reserve(1);
-                    return Ok(None);
-                }
-                _ => {
-                    return Err(MqttPacketCodecError::Protocol);
-                }
-            };
-
-        let total_packet_length = 1
-            + mqtt_format::v5::integers::variable_u32_binary_size(remaining_length as u32) as usize
-            + remaining_length;
-
-        if src.len() < total_packet_length {
-            src.reserve(total_packet_length - src.len());
-            return Ok(None);
-        }
-
-        let cart = src.split_to(total_packet_length).freeze();
-
-        let packet = Yoke::try_attach_to_cart(
-            crate::packets::StableBytes(cart),
-            |data| -> Result<_, MqttPacketCodecError> {
-                FormatMqttPacket::parse_complete(data).map_err(MqttPacketCodecError::Parsing)
-            },
-        )?;
-
-        Ok(Some(MqttPacket { packet }))
+        decode_packet(src, None)
+    }
+}
+
+/// Decodes packets like [`MqttPacketCodec`], refusing packets larger than a maximum packet size
+///
+/// The size is checked as soon as the remaining length of a packet was read, so a peer cannot
+/// make the read buffer grow by announcing a large packet.
+pub(crate) struct MqttServerCodec {
+    pub(crate) maximum_packet_size: Option<u32>,
+}
+
+impl Decoder for MqttServerCodec {
+    type Item = MqttPacket;
+
+    type Error = MqttPacketCodecError;
+
+    fn decode(
+        &mut self,
+        src: &mut tokio_util::bytes::BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        decode_packet(src, self.maximum_packet_size)
     }
 }
 
+fn decode_packet(
+    src: &mut tokio_util::bytes::BytesMut,
+    maximum_packet_size: Option<u32>,
+) -> Result<Option<MqttPacket>, MqttPacketCodecError> {
+    // 1. Byte: FixedHeader
+    // 2-5. Byte: Variable-Size
+
+    if src.len() < 2 {
+        src.reserve(2 - src.len());
+        return Ok(None);
+    }
+
+    let remaining_length =
+        match mqtt_format::v5::integers::parse_variable_u32(&mut Partial::new(&src[1..])) {
+            Ok(size) => size as usize,
+            Err(winnow::error::ErrMode::Incomplete(winnow::error::Needed::Size(needed))) => {
+                src.reserve(needed.into());
+                return Ok(None);
+            }
+            Err(winnow::error::ErrMode::Incomplete(winnow::error::Needed::Unknown)) => {
+                src.reserve(1);
+                return Ok(None);
+            }
+            _ => {
+                return Err(MqttPacketCodecError::Protocol);
+            }
+        };
+
+    let total_packet_length = 1
+        + mqtt_format::v5::integers::variable_u32_binary_size(remaining_length as u32) as usize
+        + remaining_length;
+
+    if maximum_packet_size.is_some_and(|maximum| total_packet_length > maximum as usize) {
+        return Err(MqttPacketCodecError::PacketTooLarge {
+            size: total_packet_length,
+        });
+    }
+
+    if src.len() < total_packet_length {
+        src.reserve(total_packet_length - src.len());
+        return Ok(None);
+    }
+
+    let cart = src.split_to(total_packet_length).freeze();
+
+    let packet = Yoke::try_attach_to_cart(
+        crate::packets::StableBytes(cart),
+        |data| -> Result<_, MqttPacketCodecError> {
+            FormatMqttPacket::parse_complete(data).map_err(MqttPacketCodecError::Parsing)
+        },
+    )?;
+
+    Ok(Some(MqttPacket { packet }))
+}
+
 impl Encoder<FormatMqttPacket<'_>> for MqttPacketCodec {
     type Error = MqttPacketCodecError;
 
@@ -112,10 +149,14 @@ mod tests {
     use mqtt_format::v5::packets::connect::MConnect;
     use mqtt_format::v5::packets::pingreq::MPingreq;
     use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::Decoder;
     use tokio_util::codec::Framed;
     use tokio_util::compat::TokioAsyncReadCompatExt;
 
     use super::MqttPacketCodec;
+    use super::MqttPacketCodecError;
+    use super::MqttServerCodec;
     use crate::transport::MqttConnection;
 
     #[tokio::test]
@@ -164,4 +205,19 @@ async fn test_connect_codec() {
 
         assert_eq!(packet, *recv_packet.get());
     }
+
+    #[test]
+    fn oversized_packets_do_not_grow_the_buffer() {
+        let mut codec = MqttServerCodec {
+            maximum_packet_size: Some(1024),
+        };
+        let mut buffer = BytesMut::from(&[0x10, 0xFF, 0xFF, 0xFF, 0x7F][..]);
+        let capacity = buffer.capacity();
+
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(MqttPacketCodecError::PacketTooLarge { size: 268_435_460 })
+        ));
+        assert_eq!(buffer.capacity(), capacity);
+    }
 }
@@ -17,6 +17,8 @@
 pub mod payload;
 mod properties;
 pub mod qos;
+pub mod runtime;
+pub mod server;
 pub mod string;
 pub mod topic;
 pub mod transport;
@@ -37,3 +37,30 @@ fn from(value: std::num::NonZeroU16) -> Self {
         Self(value)
     }
 }
+
+/// Find the first packet identifier from `next` on that is not `in_use`, wrapping around
+///
+/// `next` is left at the returned identifier. Returns `None` if all identifiers are in use.
+pub(crate) fn next_free_packet_identifier(
+    next: &mut std::num::NonZeroU16,
+    in_use: impl Fn(PacketIdentifier) -> bool,
+) -> Option<PacketIdentifier> {
+    let start = *next;
+
+    loop {
+        let candidate = PacketIdentifier::from(*next);
+
+        if !in_use(candidate) {
+            return Some(candidate);
+        }
+
+        match next.checked_add(1) {
+            Some(n) => *next = n,
+            None => *next = std::num::NonZeroU16::MIN,
+        }
+
+        if start == *next {
+            return None;
+        }
+    }
+}
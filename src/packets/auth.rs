@@ -4,20 +4,23 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
+use crate::properties::UserPropertiesView;
+
 crate::properties::define_properties! {
     properties_type: mqtt_format::v5::packets::auth::AuthProperties,
+    from packet variant: Auth,
     anker: "_Toc3901221",
     pub struct AuthProperties {
         (anker: "_Toc3901223")
-        authentication_method: AuthenticationMethod<'a> with setter = String,
+        authentication_method: AuthenticationMethod<'a> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901224")
-        authentication_data: AuthenticationData<'a> with setter = Vec<u8>,
+        authentication_data: AuthenticationData<'a> with setter = Vec<u8>; with viewer = &[u8],
 
         (anker: "_Toc3901225")
-        reason_string: ReasonString<'a> with setter = String,
+        reason_string: ReasonString<'a> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901226")
-        user_properties: UserProperties<'a> with setter = crate::properties::UserProperty,
+        user_properties: UserProperties<'a> with setter = crate::properties::UserProperty; with viewer = UserPropertiesView,
     }
 }
@@ -54,7 +54,7 @@ pub struct ConnackProperties {
         response_information: ResponseInformation<'i> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901096")
-        server_reference: ServerReference<'i> with setter = String,
+        server_reference: ServerReference<'i> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901097")
         authentication_method: AuthenticationMethod<'i> with setter = String; with viewer = &str,
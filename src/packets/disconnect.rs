@@ -4,20 +4,23 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
+use crate::properties::UserPropertiesView;
+
 crate::properties::define_properties! {
     properties_type: mqtt_format::v5::packets::disconnect::DisconnectProperties,
+    from packet variant: Disconnect,
     anker: "_Toc3901209",
     pub struct DisconnectProperties {
         (anker: "_Toc3901211")
-        session_expiry_interval: SessionExpiryInterval with setter = u32,
+        session_expiry_interval: SessionExpiryInterval with setter = u32; with viewer = u32,
 
         (anker: "_Toc3901212")
-        reason_string: ReasonString<'i> with setter = String,
+        reason_string: ReasonString<'i> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901213")
-        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty,
+        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty; with viewer = UserPropertiesView,
 
         (anker: "_Toc3901214")
-        server_reference: ServerReference<'i> with setter = String,
+        server_reference: ServerReference<'i> with setter = String; with viewer = &str,
     }
 }
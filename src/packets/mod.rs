@@ -7,6 +7,7 @@
 use std::ops::Deref;
 
 use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+use mqtt_format::v5::packets::MqttPacketKind;
 use mqtt_format::v5::write::MqttWriteError;
 use mqtt_format::v5::write::WriteMqttPacket;
 use stable_deref_trait::StableDeref;
@@ -33,6 +34,10 @@
 pub mod unsubscribe;
 
 pub use self::puback::Puback;
+pub use self::pubcomp::Pubcomp;
+pub use self::publish::Publish;
+pub use self::pubrec::Pubrec;
+pub use self::suback::Suback;
 
 #[derive(Debug, thiserror::Error)]
 #[error("Could not convert into the required packet type")]
@@ -70,6 +75,115 @@ impl MqttPacket {
     pub fn get(&self) -> &FormatMqttPacket<'_> {
         self.packet.get()
     }
+
+    /// The size of the packet as it was received
+    pub(crate) fn encoded_len(&self) -> usize {
+        self.packet.backing_cart().0.len()
+    }
+}
+
+/// An MQTT packet encoded for sending
+///
+/// The payload of a PUBLISH is kept as a separate buffer, so that it does not have to be copied
+/// when encoding, storing or retransmitting the packet.
+#[derive(Debug, Clone)]
+pub(crate) struct EncodedPacket {
+    header: Bytes,
+    payload: Bytes,
+}
+
+impl EncodedPacket {
+    pub(crate) fn encode(packet: &FormatMqttPacket<'_>) -> Result<Self, MqttWriterError> {
+        let mut bytes = BytesMut::with_capacity(packet.binary_size() as usize);
+        packet.write(&mut MqttWriter(&mut bytes))?;
+
+        Ok(EncodedPacket {
+            header: bytes.freeze(),
+            payload: Bytes::new(),
+        })
+    }
+
+    /// Encode a PUBLISH packet, with the payload taken from `payload` instead of the packet
+    pub(crate) fn encode_publish(
+        publish: mqtt_format::v5::packets::publish::MPublish<'_>,
+        payload: Bytes,
+    ) -> Result<Self, MqttWriterError> {
+        debug_assert!(
+            publish.payload.is_empty(),
+            "The payload is passed separately"
+        );
+
+        let without_payload = FormatMqttPacket::Publish(publish);
+        let mut encoded = BytesMut::with_capacity(without_payload.binary_size() as usize);
+        without_payload.write(&mut MqttWriter(&mut encoded))?;
+
+        // The remaining length has to account for the payload as well, so we replace it
+        let remaining_length = mqtt_format::v5::integers::parse_variable_u32(&mut &encoded[1..])
+            .map_err(|_| MqttWriterError::MqttWrite(MqttWriteError::Invariant))?;
+        let remaining_length_size =
+            mqtt_format::v5::integers::variable_u32_binary_size(remaining_length) as usize;
+
+        let mut header = BytesMut::with_capacity(encoded.len() + 4);
+        header.put_u8(encoded[0]);
+        mqtt_format::v5::integers::write_variable_u32(
+            &mut MqttWriter(&mut header),
+            remaining_length + payload.len() as u32,
+        )?;
+        header.put_slice(&encoded[1 + remaining_length_size..]);
+
+        Ok(EncodedPacket {
+            header: header.freeze(),
+            payload,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.header.len() + self.payload.len()
+    }
+
+    /// The kind of packet, from the control packet type in the fixed header
+    pub(crate) fn kind(&self) -> Option<MqttPacketKind> {
+        let kind = match self.header[0] >> 4 {
+            1 => MqttPacketKind::Connect,
+            2 => MqttPacketKind::Connack,
+            3 => MqttPacketKind::Publish,
+            4 => MqttPacketKind::Puback,
+            5 => MqttPacketKind::Pubrec,
+            6 => MqttPacketKind::Pubrel,
+            7 => MqttPacketKind::Pubcomp,
+            8 => MqttPacketKind::Subscribe,
+            9 => MqttPacketKind::Suback,
+            10 => MqttPacketKind::Unsubscribe,
+            11 => MqttPacketKind::Unsuback,
+            12 => MqttPacketKind::Pingreq,
+            13 => MqttPacketKind::Pingresp,
+            14 => MqttPacketKind::Disconnect,
+            15 => MqttPacketKind::Auth,
+            _ => return None,
+        };
+
+        Some(kind)
+    }
+
+    /// The same packet with the DUP flag set if it is a PUBLISH, the payload is not copied
+    pub(crate) fn with_duplicate(&self) -> Self {
+        if !matches!(self.kind(), Some(MqttPacketKind::Publish)) {
+            return self.clone();
+        }
+
+        let mut header = BytesMut::from(&self.header[..]);
+        header[0] |= 0b1000;
+        EncodedPacket {
+            header: header.freeze(),
+            payload: self.payload.clone(),
+        }
+    }
+
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = &Bytes> {
+        [&self.header, &self.payload]
+            .into_iter()
+            .filter(|chunk| !chunk.is_empty())
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -118,3 +232,49 @@ fn write_slice(&mut self, u: &[u8]) -> mqtt_format::v5::write::WResult<Self> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mqtt_format::v5::packets::publish::MPublish;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::bytes::Bytes;
+
+    use super::EncodedPacket;
+    use super::VecWriter;
+
+    #[test]
+    fn encode_publish_matches_full_encoding() {
+        // The sizes cross the boundaries of the variable length remaining length
+        for payload_size in [0, 10, 120, 200, 16_380, 20_000] {
+            let payload = Bytes::from(vec![0xAB; payload_size]);
+            let publish = MPublish {
+                duplicate: false,
+                quality_of_service: mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                retain: false,
+                topic_name: "foo/bar",
+                packet_identifier: Some(mqtt_format::v5::variable_header::PacketIdentifier(
+                    std::num::NonZeroU16::MIN,
+                )),
+                properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+                payload: &[],
+            };
+
+            let mut expected = Vec::new();
+            FormatMqttPacket::Publish(MPublish {
+                payload: &payload,
+                ..publish.clone()
+            })
+            .write(&mut VecWriter(&mut expected))
+            .unwrap();
+
+            let encoded = EncodedPacket::encode_publish(publish, payload.clone()).unwrap();
+            let actual = encoded
+                .chunks()
+                .flat_map(|chunk| chunk.iter().copied())
+                .collect::<Vec<u8>>();
+
+            assert_eq!(encoded.len(), expected.len());
+            assert_eq!(actual, expected);
+        }
+    }
+}
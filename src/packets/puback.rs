@@ -6,18 +6,22 @@
 
 use yoke::Yoke;
 
+use super::InvalidPacketType;
 use super::MqttPacket;
 use super::StableBytes;
+use crate::packet_identifier::PacketIdentifier;
+use crate::properties::UserPropertiesView;
 
 crate::properties::define_properties! {
     properties_type: mqtt_format::v5::packets::puback::PubackProperties,
+    from packet variant: Puback,
     anker: "_Toc3901125",
     pub struct PubackProperties {
         (anker: "_Toc3901127")
-        reason_string: ReasonString<'i> with setter = String,
+        reason_string: ReasonString<'i> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901128")
-        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty,
+        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty; with viewer = UserPropertiesView,
     }
 }
 
@@ -30,15 +34,29 @@ impl Puback {
     pub(crate) fn get(&self) -> &mqtt_format::v5::packets::puback::MPuback<'_> {
         self.packet.get()
     }
+
+    pub fn packet_identifier(&self) -> PacketIdentifier {
+        PacketIdentifier::from(self.get().packet_identifier)
+    }
+
+    pub fn reason_code(&self) -> mqtt_format::v5::packets::puback::PubackReasonCode {
+        self.get().reason
+    }
+
+    pub fn properties(&self) -> PubackPropertiesView {
+        PubackPropertiesView {
+            packet: self.packet.clone().map_project(|p, _| p.properties),
+        }
+    }
 }
 
 impl TryFrom<MqttPacket> for Puback {
-    type Error = ();
+    type Error = InvalidPacketType;
 
     fn try_from(value: MqttPacket) -> Result<Self, Self::Error> {
         let packet = value.packet.try_map_project(|p, _| match p {
             mqtt_format::v5::packets::MqttPacket::Puback(puback) => Ok(puback),
-            _ => Err(()),
+            _ => Err(InvalidPacketType),
         })?;
 
         Ok(Puback { packet })
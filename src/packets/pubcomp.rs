@@ -4,14 +4,61 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
+use yoke::Yoke;
+
+use super::InvalidPacketType;
+use super::MqttPacket;
+use super::StableBytes;
+use crate::packet_identifier::PacketIdentifier;
+use crate::properties::UserPropertiesView;
+
 crate::properties::define_properties! {
     properties_type: mqtt_format::v5::packets::pubcomp::PubcompProperties,
+    from packet variant: Pubcomp,
     anker: "_Toc3901153",
     pub struct PubcompProperties {
         (anker: "_Toc3901154")
-        reason_string: ReasonString<'i> with setter = String,
+        reason_string: ReasonString<'i> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901155")
-        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty,
+        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty; with viewer = UserPropertiesView,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pubcomp {
+    packet: Yoke<mqtt_format::v5::packets::pubcomp::MPubcomp<'static>, StableBytes>,
+}
+
+impl Pubcomp {
+    pub(crate) fn get(&self) -> &mqtt_format::v5::packets::pubcomp::MPubcomp<'_> {
+        self.packet.get()
+    }
+
+    pub fn packet_identifier(&self) -> PacketIdentifier {
+        PacketIdentifier::from(self.get().packet_identifier)
+    }
+
+    pub fn reason_code(&self) -> mqtt_format::v5::packets::pubcomp::PubcompReasonCode {
+        self.get().reason
+    }
+
+    pub fn properties(&self) -> PubcompPropertiesView {
+        PubcompPropertiesView {
+            packet: self.packet.clone().map_project(|p, _| p.properties),
+        }
+    }
+}
+
+impl TryFrom<MqttPacket> for Pubcomp {
+    type Error = InvalidPacketType;
+
+    fn try_from(value: MqttPacket) -> Result<Self, Self::Error> {
+        let packet = value.packet.try_map_project(|p, _| match p {
+            mqtt_format::v5::packets::MqttPacket::Pubcomp(pubcomp) => Ok(pubcomp),
+            _ => Err(InvalidPacketType),
+        })?;
+
+        Ok(Pubcomp { packet })
     }
 }
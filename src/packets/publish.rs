@@ -4,32 +4,131 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
+use mqtt_format::v5::variable_header::MqttProperties;
+use yoke::Yoke;
+
+use super::InvalidPacketType;
+use super::MqttPacket;
+use super::StableBytes;
+use super::VecWriter;
+use crate::packet_identifier::PacketIdentifier;
+use crate::properties::SubscriptionIdentifiersView;
+use crate::properties::UserPropertiesView;
+use crate::qos::QualityOfService;
+
 crate::properties::define_properties! {
     properties_type: mqtt_format::v5::packets::publish::PublishProperties,
+    from packet variant: Publish,
     anker: "_Toc3901109",
     pub struct PublishProperties {
         (anker: "_Toc3901111")
-        payload_format_indicator: PayloadFormatIndicator with setter = u8,
+        payload_format_indicator: PayloadFormatIndicator with setter = u8; with viewer = u8,
 
         (anker: "_Toc3901112")
-        message_expiry_interval: MessageExpiryInterval with setter = u32,
+        message_expiry_interval: MessageExpiryInterval with setter = u32; with viewer = u32,
 
         (anker: "_Toc3901113")
-        topic_alias: TopicAlias with setter = core::num::NonZeroU16,
+        topic_alias: TopicAlias with setter = core::num::NonZeroU16; with viewer = core::num::NonZeroU16,
 
         (anker: "_Toc3901114")
-        response_topic: ResponseTopic<'i> with setter = String,
+        response_topic: ResponseTopic<'i> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901115")
-        correlation_data: CorrelationData<'i> with setter = Vec<u8>,
+        correlation_data: CorrelationData<'i> with setter = Vec<u8>; with viewer = &[u8],
 
         (anker: "_Toc3901116")
-        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty,
+        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty; with viewer = UserPropertiesView,
 
         (anker: "_Toc3901117")
-        subscription_identifier: SubscriptionIdentifier with setter = u32,
+        subscription_identifiers: SubscriptionIdentifiers<'i> with setter = u32; with viewer = SubscriptionIdentifiersView,
 
         (anker: "_Toc3901118")
-        content_type: ContentType<'i> with setter = String,
+        content_type: ContentType<'i> with setter = String; with viewer = &str,
+    }
+}
+
+impl PublishProperties {
+    /// Copy the properties of a received PUBLISH
+    pub(crate) fn from_format(
+        properties: &mqtt_format::v5::packets::publish::PublishProperties<'_>,
+    ) -> Self {
+        Self {
+            payload_format_indicator: properties.payload_format_indicator().map(|p| p.0),
+            message_expiry_interval: properties.message_expiry_interval().map(|p| p.0),
+            topic_alias: properties.topic_alias().map(|p| p.0),
+            response_topic: properties.response_topic().map(|p| p.0.to_string()),
+            correlation_data: properties.correlation_data().map(|p| p.0.to_vec()),
+            user_properties: properties.user_properties().map(|user_properties| {
+                let mut buffer = Vec::new();
+                user_properties
+                    .write(&mut VecWriter(&mut buffer))
+                    .expect("Writing to a Vec does not fail");
+                buffer
+            }),
+            subscription_identifiers: properties.subscription_identifiers().map(|identifiers| {
+                let mut buffer = Vec::new();
+                identifiers
+                    .write(&mut VecWriter(&mut buffer))
+                    .expect("Writing to a Vec does not fail");
+                buffer
+            }),
+            content_type: properties.content_type().map(|p| p.0.to_string()),
+        }
+    }
+}
+
+/// A PUBLISH packet received from the server
+#[derive(Clone, Debug)]
+pub struct Publish {
+    packet: Yoke<mqtt_format::v5::packets::publish::MPublish<'static>, StableBytes>,
+}
+
+impl Publish {
+    pub(crate) fn get(&self) -> &mqtt_format::v5::packets::publish::MPublish<'_> {
+        self.packet.get()
+    }
+
+    pub fn topic(&self) -> &str {
+        self.get().topic_name
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        self.get().payload
+    }
+
+    pub fn qos(&self) -> QualityOfService {
+        self.get().quality_of_service.into()
+    }
+
+    pub fn retain(&self) -> bool {
+        self.get().retain
+    }
+
+    pub fn duplicate(&self) -> bool {
+        self.get().duplicate
+    }
+
+    /// The packet identifier, only set for QoS 1 and 2
+    pub fn packet_identifier(&self) -> Option<PacketIdentifier> {
+        self.get().packet_identifier.map(PacketIdentifier::from)
+    }
+
+    pub fn properties(&self) -> PublishPropertiesView {
+        PublishPropertiesView {
+            packet: self.packet.clone().map_project(|p, _| p.properties),
+        }
+    }
+}
+
+impl TryFrom<MqttPacket> for Publish {
+    type Error = InvalidPacketType;
+
+    fn try_from(value: MqttPacket) -> Result<Self, Self::Error> {
+        let packet = value.packet.try_map_project(|p, _| match p {
+            mqtt_format::v5::packets::MqttPacket::Publish(publish) => Ok(publish),
+            _ => Err(InvalidPacketType),
+        })?;
+
+        Ok(Publish { packet })
     }
 }
@@ -4,14 +4,61 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
+use yoke::Yoke;
+
+use super::InvalidPacketType;
+use super::MqttPacket;
+use super::StableBytes;
+use crate::packet_identifier::PacketIdentifier;
+use crate::properties::UserPropertiesView;
+
 crate::properties::define_properties! {
     properties_type: mqtt_format::v5::packets::pubrec::PubrecProperties,
+    from packet variant: Pubrec,
     anker: "_Toc3901135",
     pub struct PubrecProperties {
         (anker: "_Toc3901137")
-        reason_string: ReasonString<'i> with setter = String,
+        reason_string: ReasonString<'i> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901138")
-        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty,
+        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty; with viewer = UserPropertiesView,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pubrec {
+    packet: Yoke<mqtt_format::v5::packets::pubrec::MPubrec<'static>, StableBytes>,
+}
+
+impl Pubrec {
+    pub(crate) fn get(&self) -> &mqtt_format::v5::packets::pubrec::MPubrec<'_> {
+        self.packet.get()
+    }
+
+    pub fn packet_identifier(&self) -> PacketIdentifier {
+        PacketIdentifier::from(self.get().packet_identifier)
+    }
+
+    pub fn reason_code(&self) -> mqtt_format::v5::packets::pubrec::PubrecReasonCode {
+        self.get().reason
+    }
+
+    pub fn properties(&self) -> PubrecPropertiesView {
+        PubrecPropertiesView {
+            packet: self.packet.clone().map_project(|p, _| p.properties),
+        }
+    }
+}
+
+impl TryFrom<MqttPacket> for Pubrec {
+    type Error = InvalidPacketType;
+
+    fn try_from(value: MqttPacket) -> Result<Self, Self::Error> {
+        let packet = value.packet.try_map_project(|p, _| match p {
+            mqtt_format::v5::packets::MqttPacket::Pubrec(pubrec) => Ok(pubrec),
+            _ => Err(InvalidPacketType),
+        })?;
+
+        Ok(Pubrec { packet })
     }
 }
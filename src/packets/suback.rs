@@ -4,14 +4,62 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
+use yoke::Yoke;
+
+use super::InvalidPacketType;
+use super::MqttPacket;
+use super::StableBytes;
+use crate::packet_identifier::PacketIdentifier;
+use crate::properties::UserPropertiesView;
+
 crate::properties::define_properties! {
     properties_type: mqtt_format::v5::packets::suback::SubackProperties,
+    from packet variant: Suback,
     anker: "_Toc3901174",
     pub struct SubackProperties {
         (anker: "_Toc3901175")
-        reason_string: ReasonString<'i> with setter = String,
+        reason_string: ReasonString<'i> with setter = String; with viewer = &str,
 
         (anker: "_Toc3901176")
-        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty,
+        user_properties: UserProperties<'i> with setter = crate::properties::UserProperty; with viewer = UserPropertiesView,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Suback {
+    packet: Yoke<mqtt_format::v5::packets::suback::MSuback<'static>, StableBytes>,
+}
+
+impl Suback {
+    pub(crate) fn get(&self) -> &mqtt_format::v5::packets::suback::MSuback<'_> {
+        self.packet.get()
+    }
+
+    pub fn packet_identifier(&self) -> PacketIdentifier {
+        PacketIdentifier::from(self.get().packet_identifier)
+    }
+
+    /// The reason codes in the same order as the subscriptions of the SUBSCRIBE
+    pub fn reason_codes(&self) -> &[mqtt_format::v5::packets::suback::SubackReasonCode] {
+        self.get().reasons
+    }
+
+    pub fn properties(&self) -> SubackPropertiesView {
+        SubackPropertiesView {
+            packet: self.packet.clone().map_project(|p, _| p.properties),
+        }
+    }
+}
+
+impl TryFrom<MqttPacket> for Suback {
+    type Error = InvalidPacketType;
+
+    fn try_from(value: MqttPacket) -> Result<Self, Self::Error> {
+        let packet = value.packet.try_map_project(|p, _| match p {
+            mqtt_format::v5::packets::MqttPacket::Suback(suback) => Ok(suback),
+            _ => Err(InvalidPacketType),
+        })?;
+
+        Ok(Suback { packet })
     }
 }
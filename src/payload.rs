@@ -5,9 +5,16 @@
 //
 
 use mqtt_format::v5::integers::VARIABLE_INTEGER_MAX;
+use tokio_util::bytes::Bytes;
 
-#[derive(Debug)]
-pub struct MqttPayload(Vec<u8>);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttPayload(Bytes);
+
+impl MqttPayload {
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+}
 
 impl AsRef<[u8]> for MqttPayload {
     fn as_ref(&self) -> &[u8] {
@@ -24,9 +31,9 @@ pub enum MqttPayloadError {
     Length { given: usize },
 }
 
-impl TryFrom<Vec<u8>> for MqttPayload {
+impl TryFrom<Bytes> for MqttPayload {
     type Error = MqttPayloadError;
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+    fn try_from(value: Bytes) -> Result<Self, Self::Error> {
         if value.len() > VARIABLE_INTEGER_MAX as usize {
             Err(MqttPayloadError::Length { given: value.len() })
         } else {
@@ -34,3 +41,17 @@ fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
         }
     }
 }
+
+impl TryFrom<Vec<u8>> for MqttPayload {
+    type Error = MqttPayloadError;
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::try_from(Bytes::from(value))
+    }
+}
+
+impl TryFrom<&'static [u8]> for MqttPayload {
+    type Error = MqttPayloadError;
+    fn try_from(value: &'static [u8]) -> Result<Self, Self::Error> {
+        Self::try_from(Bytes::from_static(value))
+    }
+}
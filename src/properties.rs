@@ -44,6 +44,7 @@ pub struct $name {
             crate::properties::define_properties!(@optional $($packet_variant)? {
 
                 #[allow(dead_code)]
+                #[derive(Clone, Debug)]
                 pub struct [<$name View>] {
                     pub(crate) packet: yoke::Yoke<$packettypename<'static>, crate::packets::StableBytes>,
                 }
@@ -116,6 +117,7 @@ pub fn as_ref(&self) -> $packettypename {
 use std::collections::HashMap;
 
 pub(crate) use define_properties;
+use mqtt_format::v5::variable_header::SubscriptionIdentifiers;
 use mqtt_format::v5::variable_header::UserProperties;
 
 use crate::packets::VecWriter;
@@ -165,6 +167,29 @@ fn get(inner: &Self::Inner) -> Self::Outer<'_> {
     }
 }
 
+impl<'i> FormatProperty for mqtt_format::v5::variable_header::SubscriptionIdentifiers<'i> {
+    type Inner = Vec<u8>;
+    type Setter = u32;
+    type Outer<'a> = &'a [u8];
+
+    fn apply(inner: &mut Option<Self::Inner>, identifier: impl Into<Self::Setter>) {
+        let inner = inner.get_or_insert_with(Default::default);
+        if !inner.is_empty() {
+            mqtt_format::v5::integers::write_variable_u32(
+                &mut VecWriter(inner),
+                <mqtt_format::v5::variable_header::SubscriptionIdentifiers as mqtt_format::v5::variable_header::MqttProperties>::IDENTIFIER,
+            )
+            .expect("Writing a u32 should not fail")
+        }
+        mqtt_format::v5::integers::write_variable_u32(&mut VecWriter(inner), identifier.into())
+            .expect("Writing a u32 should not fail");
+    }
+
+    fn get(inner: &Self::Inner) -> Self::Outer<'_> {
+        inner.as_ref()
+    }
+}
+
 macro_rules! define_property_types {
     (@access_pattern ref $value:ident) => {
         $value.as_ref()
@@ -253,6 +278,25 @@ pub fn values(&self) -> impl Iterator<Item = &str> {
     }
 }
 
+pub struct SubscriptionIdentifiersView<'a> {
+    subscription_identifiers: SubscriptionIdentifiers<'a>,
+}
+
+impl<'a> SubscriptionIdentifiersView<'a> {
+    /// We explicitely do not implement From::from because we want this impl to stay private
+    pub(crate) fn from(identifiers: &'a [u8]) -> Self {
+        SubscriptionIdentifiersView {
+            subscription_identifiers: SubscriptionIdentifiers(identifiers),
+        }
+    }
+}
+
+impl SubscriptionIdentifiersView<'_> {
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.subscription_identifiers.iter()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
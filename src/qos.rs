@@ -4,7 +4,7 @@
 //   file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub enum QualityOfService {
     AtMostOnce,
     AtLeastOnce,
@@ -20,3 +20,13 @@ fn from(value: QualityOfService) -> Self {
         }
     }
 }
+
+impl From<mqtt_format::v5::qos::QualityOfService> for QualityOfService {
+    fn from(value: mqtt_format::v5::qos::QualityOfService) -> Self {
+        match value {
+            mqtt_format::v5::qos::QualityOfService::AtMostOnce => QualityOfService::AtMostOnce,
+            mqtt_format::v5::qos::QualityOfService::AtLeastOnce => QualityOfService::AtLeastOnce,
+            mqtt_format::v5::qos::QualityOfService::ExactlyOnce => QualityOfService::ExactlyOnce,
+        }
+    }
+}
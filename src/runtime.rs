@@ -0,0 +1,230 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Abstraction over the async runtime
+//!
+//! The client itself does not depend on a runtime: it only uses `futures` primitives, and its
+//! background task is handed to the caller to be spawned. A [`Runtime`] is needed where sockets
+//! are opened, timers are raced against connection attempts, or tasks are spawned by the library.
+//!
+//! Implementations are provided for tokio (feature `tokio`, enabled by default), async-std
+//! (feature `async-std`) and smol (feature `smol`). [`DefaultRuntime`] is the first of these that
+//! is enabled, in that order.
+
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+
+use crate::transport::MqttTransport;
+
+pub trait Runtime: Send + Sync + 'static {
+    /// Run `future` in the background, without waiting for it to finish
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    fn connect_tcp<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn MqttTransport>>>;
+
+    #[cfg(unix)]
+    fn connect_unix<'a>(
+        &'a self,
+        path: &'a std::path::Path,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn MqttTransport>>>;
+}
+
+#[cfg(feature = "tokio")]
+pub type DefaultRuntime = TokioRuntime;
+
+#[cfg(all(not(feature = "tokio"), feature = "async-std"))]
+pub type DefaultRuntime = AsyncStdRuntime;
+
+#[cfg(all(not(feature = "tokio"), not(feature = "async-std"), feature = "smol"))]
+pub type DefaultRuntime = SmolRuntime;
+
+/// The tokio runtime, tasks are spawned onto the runtime of the caller
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "tokio")]
+impl Runtime for TokioRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn connect_tcp<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn MqttTransport>>> {
+        Box::pin(async move {
+            let stream = tokio::net::TcpStream::connect((host, port)).await?;
+            Ok(Box::new(stream) as Box<dyn MqttTransport>)
+        })
+    }
+
+    #[cfg(unix)]
+    fn connect_unix<'a>(
+        &'a self,
+        path: &'a std::path::Path,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn MqttTransport>>> {
+        Box::pin(async move {
+            let stream = tokio::net::UnixStream::connect(path).await?;
+            Ok(Box::new(stream) as Box<dyn MqttTransport>)
+        })
+    }
+}
+
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std")]
+impl Runtime for AsyncStdRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        // Dropping the handle detaches the task
+        async_std::task::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+
+    fn connect_tcp<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn MqttTransport>>> {
+        Box::pin(async move {
+            let stream = async_std::net::TcpStream::connect((host, port)).await?;
+            Ok(crate::transport::from_futures_io(stream))
+        })
+    }
+
+    #[cfg(unix)]
+    fn connect_unix<'a>(
+        &'a self,
+        path: &'a std::path::Path,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn MqttTransport>>> {
+        Box::pin(async move {
+            let stream = async_std::os::unix::net::UnixStream::connect(path).await?;
+            Ok(crate::transport::from_futures_io(stream))
+        })
+    }
+}
+
+#[cfg(feature = "smol")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmolRuntime;
+
+#[cfg(feature = "smol")]
+impl Runtime for SmolRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        smol::spawn(future).detach();
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            smol::Timer::after(duration).await;
+        })
+    }
+
+    fn connect_tcp<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn MqttTransport>>> {
+        Box::pin(async move {
+            let stream = smol::net::TcpStream::connect((host, port)).await?;
+            Ok(crate::transport::from_futures_io(stream))
+        })
+    }
+
+    #[cfg(unix)]
+    fn connect_unix<'a>(
+        &'a self,
+        path: &'a std::path::Path,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn MqttTransport>>> {
+        Box::pin(async move {
+            let stream = smol::net::unix::UnixStream::connect(path).await?;
+            Ok(crate::transport::from_futures_io(stream))
+        })
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "async-std", feature = "smol")))]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::pingreq::MPingreq;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::codec::Framed;
+
+    use super::Runtime;
+    use crate::codecs::MqttPacketCodec;
+    use crate::transport::MqttConnectTransport;
+    use crate::transport::MqttConnection;
+
+    /// Connect to a listener on a separate thread and send a PINGREQ through the runtime's socket
+    async fn assert_tcp_roundtrip(runtime: &dyn Runtime) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            use std::io::Read;
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [0xC0, 0x00]);
+        });
+
+        let (sent_send, sent) = futures::channel::oneshot::channel();
+        runtime.spawn(Box::pin(async move {
+            let _ = sent_send.send(());
+        }));
+        sent.await.unwrap();
+
+        let stream = runtime.connect_tcp("127.0.0.1", port).await.unwrap();
+        let mut client = Framed::new(
+            MqttConnection::from(MqttConnectTransport::Custom(stream)),
+            MqttPacketCodec,
+        );
+        client
+            .send(FormatMqttPacket::Pingreq(MPingreq))
+            .await
+            .unwrap();
+
+        runtime.sleep(std::time::Duration::from_millis(1)).await;
+        server.join().unwrap();
+        assert!(client.next().await.is_none());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_runtime() {
+        assert_tcp_roundtrip(&super::TokioRuntime).await;
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn async_std_runtime() {
+        async_std::task::block_on(assert_tcp_roundtrip(&super::AsyncStdRuntime));
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn smol_runtime() {
+        smol::block_on(assert_tcp_roundtrip(&super::SmolRuntime));
+    }
+}
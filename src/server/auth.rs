@@ -0,0 +1,979 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Authentication of connecting clients and authorization of what they do
+//!
+//! An [`Authenticator`] decides whether a CONNECT is accepted, possibly after an enhanced
+//! authentication exchange of AUTH packets. An [`Authorizer`] is asked before every PUBLISH and
+//! SUBSCRIBE of an accepted client.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use futures::future::BoxFuture;
+use futures::select;
+use futures::FutureExt;
+use futures::SinkExt;
+use futures::StreamExt;
+use mqtt_format::v5::packets::auth::AuthReasonCode;
+use mqtt_format::v5::packets::auth::MAuth;
+use mqtt_format::v5::packets::connack::ConnackReasonCode;
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+use super::connection::ConnectionReader;
+use super::connection::ConnectionWriter;
+use super::connection::MqttServerConnectionError;
+use crate::codecs::MqttPacketCodecError;
+use crate::topic::MqttTopicFilter;
+
+/// What is known about the other end of a connection before its CONNECT arrives
+///
+/// It is handed to the server with
+/// [`MqttServer::serve_with_peers`](super::MqttServer::serve_with_peers) or
+/// [`MqttServer::serve_connection_with_peer`](super::MqttServer::serve_connection_with_peer).
+#[derive(Debug, Clone, Default)]
+pub struct PeerInfo {
+    /// The DER encoded certificate chain the client presented, if TLS was terminated in front
+    /// of the server
+    pub certificates: Vec<Vec<u8>>,
+}
+
+/// The credentials of a CONNECT
+#[derive(Debug)]
+pub struct AuthenticationRequest<'a> {
+    pub client_identifier: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+    pub authentication_method: Option<&'a str>,
+    pub authentication_data: Option<&'a [u8]>,
+    pub peer: &'a PeerInfo,
+}
+
+/// The decision of an [`Authenticator`]
+pub enum Authentication {
+    /// Accept the client
+    Accepted {
+        /// The user name the client is authorized as, usually the one it sent
+        username: Option<String>,
+        /// Sent to the client in the CONNACK, for enhanced authentication methods
+        authentication_data: Option<Vec<u8>>,
+    },
+    /// Refuse the connection with the given CONNACK reason code
+    Rejected(ConnackReasonCode),
+    /// Send `authentication_data` to the client in an AUTH packet and continue with its answer
+    Continue {
+        exchange: Box<dyn AuthenticationExchange>,
+        authentication_data: Vec<u8>,
+    },
+}
+
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate<'a>(
+        &'a self,
+        request: &'a AuthenticationRequest<'a>,
+    ) -> BoxFuture<'a, Authentication>;
+}
+
+/// The state of an enhanced authentication that takes more than one round trip
+pub trait AuthenticationExchange: Send {
+    /// Handle the authentication data of the next AUTH packet of the client
+    fn step<'a>(
+        &'a mut self,
+        authentication_data: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Authentication>;
+}
+
+/// A client that was accepted by the [`Authenticator`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedClient {
+    pub client_identifier: String,
+    pub username: Option<String>,
+}
+
+/// Something a client wants to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action<'a> {
+    Publish {
+        topic: &'a str,
+    },
+    /// Subscribe to `topic_filter`
+    ///
+    /// For shared subscriptions this is the filter without the `$share/<share_name>/` prefix.
+    Subscribe {
+        topic_filter: &'a str,
+        share_name: Option<&'a str>,
+    },
+}
+
+pub trait Authorizer: Send + Sync + 'static {
+    fn authorize<'a>(
+        &'a self,
+        client: &'a AuthenticatedClient,
+        action: Action<'a>,
+    ) -> BoxFuture<'a, bool>;
+}
+
+/// Accepts every client that does not ask for enhanced authentication
+///
+/// This is the default authenticator of the server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAnonymous;
+
+impl Authenticator for AllowAnonymous {
+    fn authenticate<'a>(
+        &'a self,
+        request: &'a AuthenticationRequest<'a>,
+    ) -> BoxFuture<'a, Authentication> {
+        let authentication = if request.authentication_method.is_some() {
+            Authentication::Rejected(ConnackReasonCode::BadAuthenticationMethod)
+        } else {
+            Authentication::Accepted {
+                username: request.username.map(String::from),
+                authentication_data: None,
+            }
+        };
+
+        Box::pin(futures::future::ready(authentication))
+    }
+}
+
+/// Allows every client to do everything
+///
+/// This is the default authorizer of the server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize<'a>(
+        &'a self,
+        _client: &'a AuthenticatedClient,
+        _action: Action<'a>,
+    ) -> BoxFuture<'a, bool> {
+        Box::pin(futures::future::ready(true))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthFileError {
+    #[error("Could not read the file")]
+    Io(#[from] std::io::Error),
+
+    #[error("Line {line} is invalid: {reason}")]
+    Invalid { line: usize, reason: &'static str },
+}
+
+/// Authenticates clients by user name and password
+///
+/// The file has one `username:password` entry per line, empty lines and lines starting with `#`
+/// are ignored. Passwords are stored in plain text, so the file has to be protected accordingly.
+#[derive(Debug, Clone, Default)]
+pub struct PasswordFile {
+    passwords: HashMap<String, Vec<u8>>,
+}
+
+impl PasswordFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AuthFileError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    pub fn with_user(mut self, username: impl Into<String>, password: impl Into<Vec<u8>>) -> Self {
+        self.passwords.insert(username.into(), password.into());
+        self
+    }
+}
+
+impl FromStr for PasswordFile {
+    type Err = AuthFileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut passwords = HashMap::new();
+
+        for (index, line) in s.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((username, password)) = line.split_once(':') else {
+                return Err(AuthFileError::Invalid {
+                    line: index + 1,
+                    reason: "Expected 'username:password'",
+                });
+            };
+            passwords.insert(username.to_string(), password.as_bytes().to_vec());
+        }
+
+        Ok(Self { passwords })
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn authenticate<'a>(
+        &'a self,
+        request: &'a AuthenticationRequest<'a>,
+    ) -> BoxFuture<'a, Authentication> {
+        let authentication = if request.authentication_method.is_some() {
+            Authentication::Rejected(ConnackReasonCode::BadAuthenticationMethod)
+        } else {
+            let known = request
+                .username
+                .and_then(|username| self.passwords.get(username))
+                .zip(request.password)
+                .is_some_and(|(expected, given)| constant_time_eq(expected, given));
+
+            if known {
+                Authentication::Accepted {
+                    username: request.username.map(String::from),
+                    authentication_data: None,
+                }
+            } else {
+                Authentication::Rejected(ConnackReasonCode::BadUsernameOrPassword)
+            }
+        };
+
+        Box::pin(futures::future::ready(authentication))
+    }
+}
+
+/// Compare without returning early, so that the time taken does not reveal the password
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |difference, (l, r)| difference | (l ^ r))
+            == 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn allows(self, action: &Action<'_>) -> bool {
+        match action {
+            Action::Publish { .. } => matches!(self, Access::Write | Access::ReadWrite),
+            Action::Subscribe { .. } => matches!(self, Access::Read | Access::ReadWrite),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AclRule {
+    /// Only applies to this user if set
+    username: Option<String>,
+    access: Access,
+    pattern: String,
+}
+
+/// Authorizes clients with topic patterns
+///
+/// Patterns are topic filters in which `%c` is replaced by the client identifier and `%u` by the
+/// user name of the client. A rule with `%u` does not apply to clients without a user name, and
+/// rules do not apply to clients whose identifier or user name contains wildcards or `/`.
+///
+/// Publishing is allowed to topics matching a pattern, subscribing to filters that only match
+/// topics a pattern matches as well. Everything that no rule allows is denied.
+///
+/// The file format has one rule per line, empty lines and lines starting with `#` are ignored:
+///
+/// ```text
+/// # Rules for everyone
+/// pattern readwrite clients/%c/#
+/// pattern read status/#
+/// # Rules for a single user
+/// user admin
+/// topic readwrite #
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TopicAcl {
+    rules: Vec<AclRule>,
+}
+
+impl TopicAcl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AuthFileError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Allow `access` to `pattern` for all clients
+    pub fn with_rule(self, access: Access, pattern: impl Into<String>) -> Self {
+        self.with_rule_for(None, access, pattern.into())
+    }
+
+    /// Allow `access` to `pattern` for the clients with the user name `username`
+    pub fn with_user_rule(
+        self,
+        username: impl Into<String>,
+        access: Access,
+        pattern: impl Into<String>,
+    ) -> Self {
+        self.with_rule_for(Some(username.into()), access, pattern.into())
+    }
+
+    fn with_rule_for(mut self, username: Option<String>, access: Access, pattern: String) -> Self {
+        self.rules.push(AclRule {
+            username,
+            access,
+            pattern,
+        });
+        self
+    }
+
+    fn allows(&self, client: &AuthenticatedClient, action: &Action<'_>) -> bool {
+        self.rules.iter().any(|rule| {
+            if rule
+                .username
+                .as_ref()
+                .is_some_and(|username| Some(username) != client.username.as_ref())
+            {
+                return false;
+            }
+
+            if !rule.access.allows(action) {
+                return false;
+            }
+
+            let Some(pattern) = substitute(&rule.pattern, client) else {
+                return false;
+            };
+
+            match action {
+                Action::Publish { topic } => pattern.matches(topic),
+                Action::Subscribe { topic_filter, .. } => covers(&pattern, topic_filter),
+            }
+        })
+    }
+}
+
+impl FromStr for TopicAcl {
+    type Err = AuthFileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut acl = TopicAcl::new();
+        let mut username = None;
+
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |reason| AuthFileError::Invalid {
+                line: index + 1,
+                reason,
+            };
+
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match keyword {
+                "user" if !rest.trim().is_empty() => username = Some(rest.trim().to_string()),
+                "topic" | "pattern" => {
+                    let (access, pattern) = rest
+                        .trim()
+                        .split_once(' ')
+                        .ok_or_else(|| invalid("Expected an access and a pattern"))?;
+                    let access = match access {
+                        "read" => Access::Read,
+                        "write" => Access::Write,
+                        "readwrite" => Access::ReadWrite,
+                        _ => return Err(invalid("Access has to be read, write or readwrite")),
+                    };
+
+                    let pattern = pattern.trim();
+                    if MqttTopicFilter::from_str(&pattern.replace('%', "x")).is_err() {
+                        return Err(invalid("Pattern is not a valid topic filter"));
+                    }
+
+                    let rule_username = if keyword == "pattern" {
+                        None
+                    } else {
+                        username.clone()
+                    };
+                    acl = acl.with_rule_for(rule_username, access, pattern.to_string());
+                }
+                _ => return Err(invalid("Expected 'user', 'topic' or 'pattern'")),
+            }
+        }
+
+        Ok(acl)
+    }
+}
+
+impl Authorizer for TopicAcl {
+    fn authorize<'a>(
+        &'a self,
+        client: &'a AuthenticatedClient,
+        action: Action<'a>,
+    ) -> BoxFuture<'a, bool> {
+        Box::pin(futures::future::ready(self.allows(client, &action)))
+    }
+}
+
+/// Replace `%c` and `%u` in `pattern`
+///
+/// Returns `None` if the rule cannot apply to the client.
+fn substitute(pattern: &str, client: &AuthenticatedClient) -> Option<MqttTopicFilter> {
+    let usable = |value: &str| !value.contains(['+', '#', '/']);
+
+    let mut substituted = pattern.to_string();
+    if substituted.contains("%c") {
+        if !usable(&client.client_identifier) {
+            return None;
+        }
+        substituted = substituted.replace("%c", &client.client_identifier);
+    }
+    if substituted.contains("%u") {
+        let username = client
+            .username
+            .as_deref()
+            .filter(|username| usable(username))?;
+        substituted = substituted.replace("%u", username);
+    }
+
+    MqttTopicFilter::from_str(&substituted).ok()
+}
+
+/// Whether every topic matched by `topic_filter` is matched by `pattern` as well
+fn covers(pattern: &MqttTopicFilter, topic_filter: &str) -> bool {
+    let mut pattern_levels = pattern.as_ref().split('/');
+    let mut filter_levels = topic_filter.split('/');
+
+    // Wildcards at the start of a pattern do not match topics starting with `$`
+    if topic_filter.starts_with('$') && pattern.as_ref().starts_with(['+', '#']) {
+        return false;
+    }
+
+    loop {
+        match (pattern_levels.next(), filter_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some(_), Some("#")) => return false,
+            (Some("+"), Some(_)) => {}
+            (Some(_), Some("+")) => return false,
+            (Some(pattern_level), Some(filter_level)) if pattern_level == filter_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// The outcome of a successful authentication
+pub(super) struct Authenticated {
+    pub(super) username: Option<String>,
+    pub(super) authentication_data: Option<Vec<u8>>,
+}
+
+/// Authenticate a client, exchanging AUTH packets if the authenticator asks for it
+///
+/// Returns the reason code to refuse the connection with if authentication failed.
+pub(super) async fn authenticate(
+    authenticator: &dyn Authenticator,
+    request: &AuthenticationRequest<'_>,
+    reader: &mut ConnectionReader,
+    writer: &mut ConnectionWriter,
+    timeout: std::time::Duration,
+) -> Result<Result<Authenticated, ConnackReasonCode>, MqttServerConnectionError> {
+    let mut authentication = authenticator.authenticate(request).await;
+
+    loop {
+        let (mut exchange, authentication_data) = match authentication {
+            Authentication::Accepted {
+                username,
+                authentication_data,
+            } => {
+                return Ok(Ok(Authenticated {
+                    username,
+                    authentication_data,
+                }))
+            }
+            Authentication::Rejected(reason_code) => return Ok(Err(reason_code)),
+            Authentication::Continue {
+                exchange,
+                authentication_data,
+            } => (exchange, authentication_data),
+        };
+
+        let Some(authentication_method) = request.authentication_method else {
+            tracing::warn!("Authenticator continued without an authentication method");
+            return Ok(Err(ConnackReasonCode::BadAuthenticationMethod));
+        };
+
+        let mut properties = mqtt_format::v5::packets::auth::AuthProperties::new();
+        properties.authentication_method = Some(
+            mqtt_format::v5::variable_header::AuthenticationMethod(authentication_method),
+        );
+        properties.authentication_data = Some(
+            mqtt_format::v5::variable_header::AuthenticationData(&authentication_data),
+        );
+        writer
+            .send(FormatMqttPacket::Auth(MAuth {
+                reason: AuthReasonCode::ContinueAuthentication,
+                properties,
+            }))
+            .await
+            .map_err(MqttServerConnectionError::Send)?;
+
+        let packet = {
+            let reading = reader.next().fuse();
+            let timing_out = futures_timer::Delay::new(timeout).fuse();
+            futures::pin_mut!(reading, timing_out);
+
+            select! {
+                packet = reading => packet,
+                () = timing_out => return Err(MqttServerConnectionError::ConnectTimeout),
+            }
+        };
+        let packet = match packet {
+            Some(Ok(packet)) => packet,
+            Some(Err(MqttPacketCodecError::PacketTooLarge { .. })) => {
+                return Ok(Err(ConnackReasonCode::PacketTooLarge))
+            }
+            Some(Err(error)) => return Err(MqttServerConnectionError::Receive(error)),
+            None => return Err(MqttServerConnectionError::TransportClosed),
+        };
+
+        let FormatMqttPacket::Auth(auth) = packet.get() else {
+            return Err(MqttServerConnectionError::ProtocolError {
+                reason: "MQTT-4.12.0-2",
+            });
+        };
+        if auth.reason != AuthReasonCode::ContinueAuthentication
+            || auth.properties.authentication_method().map(|am| am.0) != Some(authentication_method)
+        {
+            return Err(MqttServerConnectionError::ProtocolError {
+                reason: "MQTT-4.12.0-5",
+            });
+        }
+
+        authentication = exchange
+            .step(auth.properties.authentication_data().map(|ad| ad.0))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::BoxFuture;
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::auth::AuthReasonCode;
+    use mqtt_format::v5::packets::connack::ConnackReasonCode;
+    use mqtt_format::v5::packets::connect::ConnectProperties;
+    use mqtt_format::v5::packets::puback::PubackReasonCode;
+    use mqtt_format::v5::packets::suback::SubackReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::qos::QualityOfService;
+    use mqtt_format::v5::variable_header::AuthenticationData;
+    use mqtt_format::v5::variable_header::AuthenticationMethod;
+    use tokio_util::codec::Framed;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::Access;
+    use super::Action;
+    use super::AuthenticatedClient;
+    use super::Authentication;
+    use super::AuthenticationExchange;
+    use super::AuthenticationRequest;
+    use super::Authenticator;
+    use super::PasswordFile;
+    use super::PeerInfo;
+    use super::TopicAcl;
+    use crate::codecs::MqttPacketCodec;
+    use crate::runtime::DefaultRuntime;
+    use crate::server::tests::open_connection;
+    use crate::server::tests::publish_packet;
+    use crate::server::tests::subscribe;
+    use crate::server::tests::subscription_options;
+    use crate::server::tests::TestClient;
+    use crate::server::MqttServer;
+    use crate::transport::MqttConnectTransport;
+    use crate::transport::MqttConnection;
+
+    async fn send_connect(
+        client: &mut TestClient,
+        username: Option<&str>,
+        password: Option<&[u8]>,
+        properties: ConnectProperties<'_>,
+    ) {
+        client
+            .send(FormatMqttPacket::Connect(
+                mqtt_format::v5::packets::connect::MConnect {
+                    client_identifier: "phone",
+                    username,
+                    password,
+                    clean_start: true,
+                    will: None,
+                    properties,
+                    keep_alive: 0,
+                },
+            ))
+            .await
+            .unwrap();
+    }
+
+    async fn expect_connack(client: &mut TestClient) -> (ConnackReasonCode, Option<Vec<u8>>) {
+        let packet = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Connack(connack) = packet.get() else {
+            panic!("Expected CONNACK, got {:?}", packet.get());
+        };
+        (
+            connack.reason_code,
+            connack
+                .properties
+                .authentication_data()
+                .map(|ad| ad.0.to_vec()),
+        )
+    }
+
+    fn client(client_identifier: &str, username: Option<&str>) -> AuthenticatedClient {
+        AuthenticatedClient {
+            client_identifier: client_identifier.to_string(),
+            username: username.map(String::from),
+        }
+    }
+
+    #[test]
+    fn password_file_is_parsed() {
+        let file: PasswordFile = "# comment\n\nalice:secret\nbob:pass:word\n"
+            .parse()
+            .unwrap();
+        assert_eq!(file.passwords["alice"], b"secret");
+        assert_eq!(file.passwords["bob"], b"pass:word");
+
+        assert!("nopassword".parse::<PasswordFile>().is_err());
+    }
+
+    #[test]
+    fn acl_patterns_are_substituted() {
+        let acl: TopicAcl = "
+            pattern readwrite clients/%c/#
+            pattern read users/%u/+
+            user admin
+            topic readwrite #
+        "
+        .parse()
+        .unwrap();
+
+        let alice = client("phone", Some("alice"));
+        let allowed = |client: &AuthenticatedClient, action| acl.allows(client, &action);
+
+        assert!(allowed(
+            &alice,
+            Action::Publish {
+                topic: "clients/phone/x"
+            }
+        ));
+        assert!(!allowed(
+            &alice,
+            Action::Publish {
+                topic: "clients/other/x"
+            }
+        ));
+        assert!(allowed(
+            &alice,
+            Action::Subscribe {
+                topic_filter: "users/alice/+",
+                share_name: None
+            }
+        ));
+        assert!(allowed(
+            &alice,
+            Action::Subscribe {
+                topic_filter: "users/alice/a",
+                share_name: None
+            }
+        ));
+        assert!(!allowed(
+            &alice,
+            Action::Subscribe {
+                topic_filter: "users/alice/#",
+                share_name: None
+            }
+        ));
+        assert!(!allowed(
+            &alice,
+            Action::Publish {
+                topic: "users/alice/a"
+            }
+        ));
+
+        // Rules with %u do not apply without a user name, nor with wildcards in the substitution
+        assert!(!allowed(
+            &client("phone", None),
+            Action::Subscribe {
+                topic_filter: "users/+/a",
+                share_name: None
+            }
+        ));
+        assert!(!allowed(
+            &client("#", None),
+            Action::Publish {
+                topic: "clients/x/y"
+            }
+        ));
+
+        let admin = client("console", Some("admin"));
+        assert!(allowed(
+            &admin,
+            Action::Subscribe {
+                topic_filter: "#",
+                share_name: None
+            }
+        ));
+        assert!(!allowed(
+            &admin,
+            Action::Subscribe {
+                topic_filter: "$SYS/#",
+                share_name: None
+            }
+        ));
+
+        let acl = TopicAcl::new().with_user_rule("admin", Access::Read, "$SYS/#");
+        assert!(acl.allows(
+            &admin,
+            &Action::Subscribe {
+                topic_filter: "$SYS/broker/+",
+                share_name: None
+            }
+        ));
+        assert!(!acl.allows(
+            &alice,
+            &Action::Subscribe {
+                topic_filter: "$SYS/broker/+",
+                share_name: None
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn password_file_and_acl_are_enforced() {
+        let server = MqttServer::builder()
+            .with_authenticator(PasswordFile::default().with_user("alice", "secret"))
+            .with_authorizer(TopicAcl::new().with_rule(Access::ReadWrite, "clients/%c/#"))
+            .build();
+
+        let (mut client, _serving) = open_connection(&server);
+        send_connect(
+            &mut client,
+            Some("alice"),
+            Some(b"wrong"),
+            ConnectProperties::new(),
+        )
+        .await;
+        assert_eq!(
+            expect_connack(&mut client).await.0,
+            ConnackReasonCode::BadUsernameOrPassword
+        );
+
+        let (mut client, _serving) = open_connection(&server);
+        send_connect(
+            &mut client,
+            Some("alice"),
+            Some(b"secret"),
+            ConnectProperties::new(),
+        )
+        .await;
+        assert_eq!(
+            expect_connack(&mut client).await.0,
+            ConnackReasonCode::Success
+        );
+
+        for (topic, expected) in [
+            ("elsewhere", PubackReasonCode::NotAuthorized),
+            ("clients/phone/a", PubackReasonCode::NoMatchingSubscribers),
+        ] {
+            client
+                .send(publish_packet(
+                    topic,
+                    b"hello",
+                    QualityOfService::AtLeastOnce,
+                    false,
+                ))
+                .await
+                .unwrap();
+            let packet = client.next().await.unwrap().unwrap();
+            let FormatMqttPacket::Puback(puback) = packet.get() else {
+                panic!("Expected PUBACK, got {:?}", packet.get());
+            };
+            assert_eq!(puback.reason, expected);
+        }
+
+        let options = subscription_options(QualityOfService::AtMostOnce);
+        assert_eq!(
+            subscribe(&mut client, "#", &options).await,
+            SubackReasonCode::NotAuthorized
+        );
+        assert_eq!(
+            subscribe(&mut client, "clients/phone/#", &options).await,
+            SubackReasonCode::GrantedQoS0
+        );
+
+        // Shared subscriptions are authorized by their topic filter
+        assert_eq!(
+            subscribe(&mut client, "$share/group/#", &options).await,
+            SubackReasonCode::NotAuthorized
+        );
+        assert_eq!(
+            subscribe(&mut client, "$share/group/clients/phone/#", &options).await,
+            SubackReasonCode::GrantedQoS0
+        );
+    }
+
+    /// Asks the client to answer a challenge before accepting it
+    struct ChallengeAuthenticator;
+
+    struct Challenge;
+
+    impl Authenticator for ChallengeAuthenticator {
+        fn authenticate<'a>(
+            &'a self,
+            request: &'a AuthenticationRequest<'a>,
+        ) -> BoxFuture<'a, Authentication> {
+            let authentication = match request.authentication_method {
+                Some("challenge") => Authentication::Continue {
+                    exchange: Box::new(Challenge),
+                    authentication_data: b"question".to_vec(),
+                },
+                _ => Authentication::Rejected(ConnackReasonCode::BadAuthenticationMethod),
+            };
+            Box::pin(futures::future::ready(authentication))
+        }
+    }
+
+    impl AuthenticationExchange for Challenge {
+        fn step<'a>(
+            &'a mut self,
+            authentication_data: Option<&'a [u8]>,
+        ) -> BoxFuture<'a, Authentication> {
+            let authentication = if authentication_data == Some(b"answer") {
+                Authentication::Accepted {
+                    username: None,
+                    authentication_data: Some(b"welcome".to_vec()),
+                }
+            } else {
+                Authentication::Rejected(ConnackReasonCode::NotAuthorized)
+            };
+            Box::pin(futures::future::ready(authentication))
+        }
+    }
+
+    #[tokio::test]
+    async fn enhanced_authentication_exchanges_auth_packets() {
+        let server = MqttServer::builder()
+            .with_authenticator(ChallengeAuthenticator)
+            .build();
+
+        for (answer, expected) in [
+            (&b"wrong"[..], (ConnackReasonCode::NotAuthorized, None)),
+            (
+                &b"answer"[..],
+                (ConnackReasonCode::Success, Some(b"welcome".to_vec())),
+            ),
+        ] {
+            let (mut client, _serving) = open_connection(&server);
+            let mut properties = ConnectProperties::new();
+            properties.authentication_method = Some(AuthenticationMethod("challenge"));
+            send_connect(&mut client, None, None, properties).await;
+
+            let packet = client.next().await.unwrap().unwrap();
+            let FormatMqttPacket::Auth(auth) = packet.get() else {
+                panic!("Expected AUTH, got {:?}", packet.get());
+            };
+            assert_eq!(auth.reason, AuthReasonCode::ContinueAuthentication);
+            assert_eq!(
+                auth.properties.authentication_data().unwrap().0,
+                b"question"
+            );
+
+            let mut properties = mqtt_format::v5::packets::auth::AuthProperties::new();
+            properties.authentication_method = Some(AuthenticationMethod("challenge"));
+            properties.authentication_data = Some(AuthenticationData(answer));
+            client
+                .send(FormatMqttPacket::Auth(
+                    mqtt_format::v5::packets::auth::MAuth {
+                        reason: AuthReasonCode::ContinueAuthentication,
+                        properties,
+                    },
+                ))
+                .await
+                .unwrap();
+
+            assert_eq!(expect_connack(&mut client).await, expected);
+        }
+    }
+
+    /// Accepts clients presenting the certificate `trusted`
+    struct CertificateAuthenticator;
+
+    impl Authenticator for CertificateAuthenticator {
+        fn authenticate<'a>(
+            &'a self,
+            request: &'a AuthenticationRequest<'a>,
+        ) -> BoxFuture<'a, Authentication> {
+            let trusted = request
+                .peer
+                .certificates
+                .first()
+                .is_some_and(|certificate| certificate == b"trusted");
+            let authentication = if trusted {
+                Authentication::Accepted {
+                    username: None,
+                    authentication_data: None,
+                }
+            } else {
+                Authentication::Rejected(ConnackReasonCode::NotAuthorized)
+            };
+
+            Box::pin(futures::future::ready(authentication))
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_certificates_reach_the_authenticator() {
+        let server = MqttServer::builder()
+            .with_authenticator(CertificateAuthenticator)
+            .build();
+        let (mut incoming, listener) = futures::channel::mpsc::unbounded();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_with_peers(listener, &DefaultRuntime::default())
+                    .await
+            }
+        });
+
+        for (certificates, expected) in [
+            (vec![b"trusted".to_vec()], ConnackReasonCode::Success),
+            (vec![b"unknown".to_vec()], ConnackReasonCode::NotAuthorized),
+            (vec![], ConnackReasonCode::NotAuthorized),
+        ] {
+            let (client_stream, server_stream) = tokio::io::duplex(4096);
+            incoming
+                .send(Ok((
+                    MqttConnectTransport::TokioDuplex(server_stream),
+                    PeerInfo { certificates },
+                )))
+                .await
+                .unwrap();
+
+            let mut client = Framed::new(
+                MqttConnection::Duplex(client_stream.compat()),
+                MqttPacketCodec,
+            );
+            send_connect(&mut client, None, None, ConnectProperties::new()).await;
+            assert_eq!(expect_connack(&mut client).await.0, expected);
+        }
+
+        incoming.close_channel();
+        serving.await.unwrap();
+    }
+}
@@ -0,0 +1,1007 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Forwarding topics between the server and an upstream broker
+//!
+//! An [`MqttBridge`] connects to its local [`MqttServer`] over an in-memory connection and to the
+//! upstream broker with an [`MqttClient`]. For every [`BridgeTopic`] it subscribes on one side and
+//! publishes what it receives on the other, optionally replacing a topic prefix on the way.
+//!
+//! QoS 1 and 2 messages forwarded upstream are kept until the upstream broker acknowledged them,
+//! and are published again after reconnecting.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::future::Fuse;
+use futures::select;
+use futures::stream;
+use futures::stream::FuturesUnordered;
+use futures::FutureExt;
+use futures::StreamExt;
+use mqtt_format::v5::packets::puback::PubackReasonCode;
+use mqtt_format::v5::packets::pubrec::PubrecReasonCode;
+
+use super::MqttServer;
+use crate::client::connect::CleanStart;
+use crate::client::connect::MqttClientConnectError;
+use crate::client::connect::MqttClientConnector;
+use crate::client::failover::MqttFailoverConnector;
+use crate::client::send::Acknowledgement;
+use crate::client::send::ConnectionClosed;
+use crate::client::send::Publish;
+use crate::client::subscribe::ReceivedPublishes;
+use crate::client::subscribe::Subscribe;
+use crate::client::subscribe::SubscribeError;
+use crate::client::subscribe::Subscription;
+use crate::client::MqttClient;
+use crate::client_identifier::ClientIdentifierError;
+use crate::client_identifier::ProposedClientIdentifier;
+use crate::keep_alive::KeepAlive;
+use crate::payload::MqttPayload;
+use crate::qos::QualityOfService;
+#[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+use crate::runtime::DefaultRuntime;
+use crate::runtime::Runtime;
+use crate::topic::MqttTopic;
+use crate::topic::MqttTopicFilter;
+use crate::topic::MqttTopicFilterError;
+use crate::transport::MqttConnectTransport;
+
+const DEFAULT_LOCAL_CLIENT_IDENTIFIER: &str = "cloudmqtt-bridge";
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum MqttBridgeError {
+    #[error("A prefixed topic pattern is not a valid topic filter")]
+    InvalidTopic(#[from] MqttTopicFilterError),
+
+    #[error("The local client identifier is not valid")]
+    ClientIdentifier(#[from] ClientIdentifierError),
+
+    #[error("Could not connect to the local server")]
+    LocalConnect(#[from] MqttClientConnectError),
+
+    #[error("Could not subscribe on the local server")]
+    LocalSubscribe(#[from] SubscribeError),
+
+    #[error("The connection to the local server was closed")]
+    LocalConnectionClosed,
+
+    #[error("Could not subscribe on the upstream broker")]
+    UpstreamSubscribe(#[source] SubscribeError),
+}
+
+/// Which way the messages of a [`BridgeTopic`] are forwarded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeDirection {
+    /// From the upstream broker to the local server
+    In,
+    /// From the local server to the upstream broker
+    Out,
+    /// Both ways, messages are not sent back to where they came from
+    Both,
+}
+
+/// A topic pattern forwarded by an [`MqttBridge`]
+///
+/// Locally the pattern is prefixed with the local prefix, upstream with the remote prefix. A
+/// forwarded message has the prefix of its origin replaced by the one of its destination.
+#[derive(Debug, Clone)]
+pub struct BridgeTopic {
+    pattern: MqttTopicFilter,
+    direction: BridgeDirection,
+    qos: QualityOfService,
+    local_prefix: String,
+    remote_prefix: String,
+}
+
+impl BridgeTopic {
+    /// Forward the topics matching `pattern` with QoS 0 and without prefixes
+    pub fn new(pattern: MqttTopicFilter, direction: BridgeDirection) -> Self {
+        Self {
+            pattern,
+            direction,
+            qos: QualityOfService::AtMostOnce,
+            local_prefix: String::new(),
+            remote_prefix: String::new(),
+        }
+    }
+
+    /// The QoS the bridge subscribes with on both sides, which limits the QoS of forwarded messages
+    pub fn with_qos(mut self, qos: QualityOfService) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Put `prefix` in front of the pattern on the local server, e.g. `"edge/"`
+    pub fn with_local_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.local_prefix = prefix.into();
+        self
+    }
+
+    /// Put `prefix` in front of the pattern on the upstream broker, e.g. `"sites/one/"`
+    pub fn with_remote_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.remote_prefix = prefix.into();
+        self
+    }
+
+    fn forwards_in(&self) -> bool {
+        matches!(self.direction, BridgeDirection::In | BridgeDirection::Both)
+    }
+
+    fn forwards_out(&self) -> bool {
+        matches!(self.direction, BridgeDirection::Out | BridgeDirection::Both)
+    }
+
+    fn subscription(&self, prefix: &str) -> Result<Subscription, MqttTopicFilterError> {
+        let filter = MqttTopicFilter::from_str(&format!("{prefix}{}", self.pattern.as_ref()))?;
+
+        let mut subscription = Subscription::new(filter, self.qos);
+        subscription.no_local = true;
+        subscription.retain_as_published = true;
+        Ok(subscription)
+    }
+
+    /// Replace the prefix `from` of `topic` with `to`, if the rest of it matches the pattern
+    fn remap(&self, topic: &str, from: &str, to: &str) -> Option<String> {
+        let rest = topic.strip_prefix(from)?;
+        self.pattern.matches(rest).then(|| format!("{to}{rest}"))
+    }
+}
+
+/// A message received on one side of the bridge, to be published on the other
+struct Forwarded {
+    topic: String,
+    qos: QualityOfService,
+    retain: bool,
+    payload: MqttPayload,
+}
+
+impl Forwarded {
+    fn publish(&self) -> Option<Publish> {
+        let topic = match MqttTopic::from_str(&self.topic) {
+            Ok(topic) => topic,
+            Err(error) => {
+                tracing::warn!(%error, topic = self.topic, "Not forwarding to an invalid topic");
+                return None;
+            }
+        };
+
+        Some(Publish {
+            topic,
+            qos: self.qos,
+            retain: self.retain,
+            payload: self.payload.clone(),
+            on_packet_recv: None,
+        })
+    }
+}
+
+/// Messages forwarded upstream that were not acknowledged yet, in the order they were received
+#[derive(Default)]
+struct Pending {
+    messages: BTreeMap<u64, Forwarded>,
+    next_sequence: u64,
+}
+
+type Acknowledgements =
+    FuturesUnordered<BoxFuture<'static, (u64, Result<Acknowledgement, ConnectionClosed>)>>;
+
+/// Pending messages to publish again once a delay passed
+type Retries = FuturesUnordered<BoxFuture<'static, u64>>;
+
+/// The clients of a running bridge and what they received, kept across upstream connections
+struct BridgeState {
+    local: MqttClient,
+    upstream: MqttClient,
+    local_publishes: stream::Fuse<ReceivedPublishes>,
+    upstream_publishes: stream::Fuse<ReceivedPublishes>,
+    /// Completes once the connection to the local server is closed
+    local_closed: Fuse<oneshot::Receiver<()>>,
+    /// Completes once the current upstream connection is closed
+    disconnected: Fuse<oneshot::Receiver<()>>,
+    pending: Pending,
+}
+
+/// Why forwarding over an upstream connection stopped
+enum Forwarding {
+    /// The upstream connection was closed
+    Disconnected,
+    /// Subscribing upstream failed, the connection may still be open
+    SubscribeFailed,
+}
+
+/// Forwards topics between an [`MqttServer`] and an upstream broker
+pub struct MqttBridge {
+    upstream: MqttFailoverConnector,
+    config: BridgeConfig,
+}
+
+/// Everything but the upstream connector, which is only used to connect and is not `Sync`
+struct BridgeConfig {
+    server: MqttServer,
+    topics: Vec<BridgeTopic>,
+    local_client_identifier: String,
+    reconnect_delay: Duration,
+    runtime: Arc<dyn Runtime>,
+}
+
+impl MqttBridge {
+    /// Create a bridge from `server` to the upstream broker reached through `upstream`
+    ///
+    /// The CONNECT options of the upstream connection, including its client identifier, are set up
+    /// by the connector.
+    #[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+    pub fn new(server: MqttServer, upstream: MqttFailoverConnector) -> Self {
+        Self::new_with_runtime(server, upstream, Arc::new(DefaultRuntime::default()))
+    }
+
+    /// Like [`MqttBridge::new`], but spawns its tasks and waits between reconnects with `runtime`
+    pub fn new_with_runtime(
+        server: MqttServer,
+        upstream: MqttFailoverConnector,
+        runtime: Arc<dyn Runtime>,
+    ) -> Self {
+        Self {
+            upstream,
+            config: BridgeConfig {
+                server,
+                topics: Vec::new(),
+                local_client_identifier: DEFAULT_LOCAL_CLIENT_IDENTIFIER.to_owned(),
+                reconnect_delay: DEFAULT_RECONNECT_DELAY,
+                runtime,
+            },
+        }
+    }
+
+    /// Forward the topics of `topic`, a message is forwarded with the first topic matching it
+    pub fn with_topic(mut self, topic: BridgeTopic) -> Self {
+        self.config.topics.push(topic);
+        self
+    }
+
+    /// How long to wait before connecting again after the upstream connection failed
+    ///
+    /// Messages the upstream broker refused for the time being are published again after the same
+    /// delay.
+    pub fn with_reconnect_delay(mut self, reconnect_delay: Duration) -> Self {
+        self.config.reconnect_delay = reconnect_delay;
+        self
+    }
+
+    /// The client identifier the bridge connects to the local server with
+    pub fn with_local_client_identifier(mut self, client_identifier: impl Into<String>) -> Self {
+        self.config.local_client_identifier = client_identifier.into();
+        self
+    }
+
+    /// Forward messages until the connection to the local server is closed
+    ///
+    /// Failing upstream connections are retried after the reconnect delay, the future only
+    /// completes with an error if the local side fails.
+    pub async fn run(self) -> Result<(), MqttBridgeError> {
+        let MqttBridge {
+            upstream: mut failover,
+            config,
+        } = self;
+
+        let local = MqttClient::new_with_default_handlers();
+        let local_publishes = local.publishes().await.fuse();
+        let local_closed = config.connect_local(&local).await?.fuse();
+
+        let upstream = MqttClient::new_with_default_handlers();
+        let upstream_publishes = upstream.publishes().await.fuse();
+        let mut state = BridgeState {
+            local,
+            upstream,
+            local_publishes,
+            upstream_publishes,
+            local_closed,
+            disconnected: Fuse::terminated(),
+            pending: Pending::default(),
+        };
+
+        loop {
+            let connected = match state.upstream.connect_failover(&mut failover).await {
+                Ok(connected) => connected,
+                Err(error) => {
+                    tracing::warn!(%error, "Could not connect to the upstream broker");
+                    config.runtime.sleep(config.reconnect_delay).await;
+                    continue;
+                }
+            };
+            tracing::info!("Connected to the upstream broker");
+
+            state.disconnected = config.spawn_background(connected.background_task).fuse();
+            match config.forward(&mut state).await {
+                Ok(Forwarding::Disconnected) => {
+                    tracing::warn!(
+                        pending = state.pending.messages.len(),
+                        "Lost the connection to the upstream broker"
+                    );
+                }
+                Ok(Forwarding::SubscribeFailed) => {
+                    connected.connection_handle.shutdown().await;
+                }
+                Err(error) => {
+                    connected.connection_handle.shutdown().await;
+                    return Err(error);
+                }
+            }
+
+            config.runtime.sleep(config.reconnect_delay).await;
+        }
+    }
+}
+
+impl BridgeConfig {
+    /// Connect `local` to the server and subscribe to the topics forwarded out
+    ///
+    /// The returned receiver completes once the local connection is closed.
+    async fn connect_local(
+        &self,
+        local: &MqttClient,
+    ) -> Result<oneshot::Receiver<()>, MqttBridgeError> {
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let server = self.server.clone();
+        self.runtime.spawn(
+            async move {
+                if let Err(error) = server
+                    .serve_connection(MqttConnectTransport::TokioDuplex(server_stream))
+                    .await
+                {
+                    tracing::warn!(%error, "Local bridge connection failed");
+                }
+            }
+            .boxed(),
+        );
+
+        let connected = local
+            .connect(MqttClientConnector::new(
+                MqttConnectTransport::TokioDuplex(client_stream),
+                ProposedClientIdentifier::new_potetially_accepted(
+                    self.local_client_identifier.clone(),
+                )?,
+                CleanStart::Yes,
+                KeepAlive::Disabled,
+            ))
+            .await?;
+        let closed = self.spawn_background(connected.background_task);
+
+        let subscriptions = self
+            .topics
+            .iter()
+            .filter(|topic| topic.forwards_out())
+            .map(|topic| topic.subscription(&topic.local_prefix))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !subscriptions.is_empty() {
+            let suback = local.subscribe(Subscribe::new(subscriptions)).await?;
+            tracing::debug!(reasons = ?suback.reason_codes(), "Subscribed on the local server");
+        }
+
+        Ok(closed)
+    }
+
+    /// Run a background task of a client, the returned receiver completes when it finished
+    fn spawn_background(
+        &self,
+        background_task: BoxFuture<'static, Result<(), ()>>,
+    ) -> oneshot::Receiver<()> {
+        let (finished, finished_recv) = oneshot::channel();
+        self.runtime.spawn(
+            async move {
+                if background_task.await.is_err() {
+                    tracing::debug!("Bridge connection closed with an error");
+                }
+                let _ = finished.send(());
+            }
+            .boxed(),
+        );
+        finished_recv
+    }
+
+    /// Forward messages over the current upstream connection until it is closed
+    ///
+    /// Messages that are still pending from previous connections are published again first.
+    /// Subscribing upstream is retried on the next connection, unless it can never succeed.
+    async fn forward(&self, state: &mut BridgeState) -> Result<Forwarding, MqttBridgeError> {
+        let BridgeState {
+            local,
+            upstream,
+            local_publishes,
+            upstream_publishes,
+            local_closed,
+            disconnected,
+            pending,
+        } = state;
+
+        let subscriptions = self
+            .topics
+            .iter()
+            .filter(|topic| topic.forwards_in())
+            .map(|topic| topic.subscription(&topic.remote_prefix))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !subscriptions.is_empty() {
+            match upstream.subscribe(Subscribe::new(subscriptions)).await {
+                Ok(suback) => {
+                    tracing::debug!(reasons = ?suback.reason_codes(), "Subscribed upstream");
+                }
+                Err(error) if error.is_permanent() => {
+                    return Err(MqttBridgeError::UpstreamSubscribe(error));
+                }
+                Err(error) => {
+                    tracing::warn!(%error, "Could not subscribe upstream");
+                    return Ok(Forwarding::SubscribeFailed);
+                }
+            }
+        }
+
+        let mut acknowledgements = Acknowledgements::new();
+        let mut retries = Retries::new();
+        let sequences = pending.messages.keys().copied().collect::<Vec<_>>();
+        for sequence in sequences {
+            publish_pending(upstream, pending, sequence, &mut acknowledgements).await;
+        }
+
+        loop {
+            select! {
+                publish = local_publishes.next() => {
+                    let Some(publish) = publish else {
+                        return Err(MqttBridgeError::LocalConnectionClosed);
+                    };
+                    let Some(forwarded) = self.outgoing(&publish) else {
+                        continue;
+                    };
+
+                    if forwarded.qos == QualityOfService::AtMostOnce {
+                        if let Some(publish) = forwarded.publish() {
+                            if let Err(error) = upstream.publish(publish).await {
+                                tracing::debug!(%error, "Could not forward QoS 0 message upstream");
+                            }
+                        }
+                    } else {
+                        let sequence = pending.next_sequence;
+                        pending.next_sequence += 1;
+                        pending.messages.insert(sequence, forwarded);
+                        publish_pending(upstream, pending, sequence, &mut acknowledgements).await;
+                    }
+                },
+                publish = upstream_publishes.next() => {
+                    let Some(forwarded) = publish.and_then(|publish| self.incoming(&publish)) else {
+                        continue;
+                    };
+                    if let Some(publish) = forwarded.publish() {
+                        if let Err(error) = local.publish(publish).await {
+                            tracing::warn!(%error, topic = forwarded.topic, "Could not forward message to the local server");
+                        }
+                    }
+                },
+                (sequence, acknowledgement) = acknowledgements.select_next_some() => {
+                    if settle(pending, sequence, acknowledgement) {
+                        retries.push(
+                            self.runtime
+                                .sleep(self.reconnect_delay)
+                                .map(move |()| sequence)
+                                .boxed(),
+                        );
+                    }
+                },
+                sequence = retries.select_next_some() => {
+                    publish_pending(upstream, pending, sequence, &mut acknowledgements).await;
+                },
+                _ = &mut *disconnected => {
+                    // Acknowledgements received before the connection closed still count, all
+                    // messages that are still pending are published again on the next connection
+                    while let Some(Some((sequence, acknowledgement))) =
+                        acknowledgements.next().now_or_never()
+                    {
+                        settle(pending, sequence, acknowledgement);
+                    }
+                    return Ok(Forwarding::Disconnected);
+                },
+                _ = &mut *local_closed => return Err(MqttBridgeError::LocalConnectionClosed),
+            }
+        }
+    }
+
+    /// The upstream message for a message received from the local server, if it is forwarded
+    fn outgoing(&self, publish: &crate::packets::Publish) -> Option<Forwarded> {
+        let topic = self
+            .topics
+            .iter()
+            .filter(|topic| topic.forwards_out())
+            .find_map(|topic| {
+                topic.remap(publish.topic(), &topic.local_prefix, &topic.remote_prefix)
+            })?;
+        forwarded(topic, publish)
+    }
+
+    /// The local message for a message received from the upstream broker, if it is forwarded
+    fn incoming(&self, publish: &crate::packets::Publish) -> Option<Forwarded> {
+        let topic = self
+            .topics
+            .iter()
+            .filter(|topic| topic.forwards_in())
+            .find_map(|topic| {
+                topic.remap(publish.topic(), &topic.remote_prefix, &topic.local_prefix)
+            })?;
+        forwarded(topic, publish)
+    }
+}
+
+fn forwarded(topic: String, publish: &crate::packets::Publish) -> Option<Forwarded> {
+    let payload = MqttPayload::try_from(publish.payload().to_vec()).ok()?;
+
+    Some(Forwarded {
+        topic,
+        qos: publish.qos(),
+        retain: publish.retain(),
+        payload,
+    })
+}
+
+/// Publish a pending message upstream and wait for its acknowledgement in `acknowledgements`
+///
+/// A message that cannot be published because the connection is gone stays pending for the next
+/// connection, only messages the upstream broker can never take are dropped. The client does not
+/// keep messages it failed to send, so publishing them again does not duplicate them.
+async fn publish_pending(
+    upstream: &MqttClient,
+    pending: &mut Pending,
+    sequence: u64,
+    acknowledgements: &mut Acknowledgements,
+) {
+    let Some(message) = pending.messages.get(&sequence) else {
+        return;
+    };
+    let Some(publish) = message.publish() else {
+        pending.messages.remove(&sequence);
+        return;
+    };
+
+    match upstream.publish(publish).await {
+        Ok(published) => {
+            acknowledgements.push(async move { (sequence, published.acknowledged().await) }.boxed())
+        }
+        Err(error) if error.is_permanent() => {
+            tracing::warn!(
+                %error,
+                topic = message.topic,
+                "Dropping message the upstream broker cannot take"
+            );
+            pending.messages.remove(&sequence);
+        }
+        Err(error) => {
+            tracing::debug!(%error, "Could not publish upstream, keeping message for reconnecting");
+        }
+    }
+}
+
+/// Handle the acknowledgement of a pending message, returns whether to publish it again
+///
+/// Messages the upstream broker accepted or refused for good are no longer pending. Messages of a
+/// closed connection stay pending for the next one.
+fn settle(
+    pending: &mut Pending,
+    sequence: u64,
+    acknowledgement: Result<Acknowledgement, ConnectionClosed>,
+) -> bool {
+    let Ok(acknowledgement) = acknowledgement else {
+        return false;
+    };
+
+    if !acknowledgement.is_refused() {
+        pending.messages.remove(&sequence);
+        return false;
+    }
+
+    if is_refused_for_now(&acknowledgement) {
+        tracing::debug!(
+            ?acknowledgement,
+            "Upstream broker refused message for now, publishing it again"
+        );
+        return pending.messages.contains_key(&sequence);
+    }
+
+    if let Some(message) = pending.messages.remove(&sequence) {
+        tracing::warn!(
+            ?acknowledgement,
+            topic = message.topic,
+            "Dropping message the upstream broker refused"
+        );
+    }
+    false
+}
+
+/// Whether the upstream broker might take a message it refused when it is published again
+fn is_refused_for_now(acknowledgement: &Acknowledgement) -> bool {
+    match acknowledgement {
+        Acknowledgement::Puback(puback) => matches!(
+            puback.reason_code(),
+            PubackReasonCode::UnspecifiedError
+                | PubackReasonCode::ImplementationSpecificError
+                | PubackReasonCode::PacketIdentifierInUse
+                | PubackReasonCode::QuotaExceeded
+        ),
+        Acknowledgement::Pubrec(pubrec) => matches!(
+            pubrec.reason_code(),
+            PubrecReasonCode::UnspecifiedError
+                | PubrecReasonCode::ImplementationSpecificError
+                | PubrecReasonCode::PacketIdentifierInUse
+                | PubrecReasonCode::QuotaExceeded
+        ),
+        Acknowledgement::None | Acknowledgement::Pubcomp(_) => false,
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::connack::ConnackReasonCode;
+    use mqtt_format::v5::packets::puback::PubackReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+
+    use super::BridgeDirection;
+    use super::BridgeTopic;
+    use super::MqttBridge;
+    use crate::client::connect::CleanStart;
+    use crate::client::connect::MqttClientConnector;
+    use crate::client::connect_url::MqttUrlTransport;
+    use crate::client::failover::MqttFailoverConnector;
+    use crate::client_identifier::ProposedClientIdentifier;
+    use crate::codecs::MqttPacketCodec;
+    use crate::keep_alive::KeepAlive;
+    use crate::packets::connack::ConnackProperties;
+    use crate::qos::QualityOfService;
+    use crate::server::tests::connect;
+    use crate::server::tests::expect_publish;
+    use crate::server::tests::packet_identifier;
+    use crate::server::tests::puback;
+    use crate::server::tests::publish_packet;
+    use crate::server::tests::subscribe;
+    use crate::server::tests::subscription_options;
+    use crate::server::MqttServer;
+    use crate::topic::MqttTopicFilter;
+    use crate::transport::MqttConnectTransport;
+
+    fn upstream_connector(listener: &TcpListener) -> MqttFailoverConnector {
+        MqttFailoverConnector::new(
+            vec![MqttUrlTransport::Tcp {
+                host: "127.0.0.1".to_owned(),
+                port: listener.local_addr().unwrap().port(),
+            }],
+            Box::new(|transport| {
+                MqttClientConnector::new(
+                    transport,
+                    ProposedClientIdentifier::new_minimal_required("bridge").unwrap(),
+                    CleanStart::Yes,
+                    KeepAlive::Disabled,
+                )
+            }),
+        )
+    }
+
+    fn bridge_topic(pattern: &str, direction: BridgeDirection) -> BridgeTopic {
+        BridgeTopic::new(MqttTopicFilter::from_str(pattern).unwrap(), direction)
+            .with_qos(QualityOfService::AtLeastOnce)
+    }
+
+    #[tokio::test]
+    async fn topics_are_forwarded_with_remapped_prefixes() {
+        let central = MqttServer::builder().build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connector = upstream_connector(&listener);
+        let central_clone = central.clone();
+        let accepting = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let central = central_clone.clone();
+                tokio::spawn(async move {
+                    let _ = central
+                        .serve_connection(MqttConnectTransport::TokioTcp(stream))
+                        .await;
+                });
+            }
+        });
+
+        let edge = MqttServer::builder().build();
+        let options = subscription_options(mqtt_format::v5::qos::QualityOfService::AtLeastOnce);
+        let (mut operator, _operating, _) = connect(&central, "operator", true).await;
+        subscribe(&mut operator, "sites/one/sensors/#", &options).await;
+        let (mut device, _device_serving, _) = connect(&edge, "device", true).await;
+        subscribe(&mut device, "commands/#", &options).await;
+
+        // Retained upstream, so it reaches the bridge whenever it subscribes
+        operator
+            .send(publish_packet(
+                "sites/one/commands/open",
+                b"now",
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                true,
+            ))
+            .await
+            .unwrap();
+        operator.next().await.unwrap().unwrap();
+
+        let bridge = MqttBridge::new(edge.clone(), connector)
+            .with_topic(
+                bridge_topic("sensors/#", BridgeDirection::Out).with_remote_prefix("sites/one/"),
+            )
+            .with_topic(
+                bridge_topic("commands/#", BridgeDirection::In).with_remote_prefix("sites/one/"),
+            );
+        let bridging = tokio::spawn(bridge.run());
+
+        let received = expect_publish(&mut device).await;
+        assert_eq!(received.topic, "commands/open");
+        assert_eq!(received.payload, b"now");
+
+        // The bridge subscribed locally before connecting upstream, so this is forwarded
+        device
+            .send(publish_packet(
+                "sensors/temperature",
+                b"21",
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        let received = expect_publish(&mut operator).await;
+        assert_eq!(received.topic, "sites/one/sensors/temperature");
+        assert_eq!(received.payload, b"21");
+
+        bridging.abort();
+        accepting.abort();
+    }
+
+    /// Accept a connection of the bridge and answer its CONNECT
+    async fn accept_bridge(listener: &TcpListener) -> Framed<TcpStream, MqttPacketCodec> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut upstream = Framed::new(stream, MqttPacketCodec);
+
+        let connect = upstream.next().await.unwrap().unwrap();
+        assert!(matches!(connect.get(), FormatMqttPacket::Connect(_)));
+        upstream
+            .send(FormatMqttPacket::Connack(
+                mqtt_format::v5::packets::connack::MConnack {
+                    session_present: false,
+                    reason_code: ConnackReasonCode::Success,
+                    properties: ConnackProperties::new().as_ref(),
+                },
+            ))
+            .await
+            .unwrap();
+        upstream
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_messages_are_sent_again_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let edge = MqttServer::builder().build();
+        let bridge = MqttBridge::new(edge.clone(), upstream_connector(&listener))
+            .with_topic(bridge_topic("a/#", BridgeDirection::Out))
+            .with_reconnect_delay(Duration::from_millis(10));
+        let bridging = tokio::spawn(bridge.run());
+
+        let upstream = accept_bridge(&listener).await;
+        let (mut device, _device_serving, _) = connect(&edge, "device", true).await;
+        device
+            .send(publish_packet(
+                "a/b",
+                b"important",
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        device.next().await.unwrap().unwrap();
+
+        // The connection is lost before the message was acknowledged
+        let mut upstream = {
+            let mut upstream = upstream;
+            expect_publish(&mut upstream).await;
+            drop(upstream);
+            accept_bridge(&listener).await
+        };
+
+        let received = expect_publish(&mut upstream).await;
+        assert_eq!(received.topic, "a/b");
+        assert_eq!(received.payload, b"important");
+        assert_eq!(
+            received.qos,
+            mqtt_format::v5::qos::QualityOfService::AtLeastOnce
+        );
+
+        bridging.abort();
+    }
+
+    #[tokio::test]
+    async fn messages_survive_repeated_disconnects_before_acknowledgement() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let edge = MqttServer::builder().build();
+        let bridge = MqttBridge::new(edge.clone(), upstream_connector(&listener))
+            .with_topic(bridge_topic("a/#", BridgeDirection::Out))
+            .with_reconnect_delay(Duration::from_millis(10));
+        let bridging = tokio::spawn(bridge.run());
+
+        let mut upstream = accept_bridge(&listener).await;
+        let (mut device, _device_serving, _) = connect(&edge, "device", true).await;
+        device
+            .send(publish_packet(
+                "a/b",
+                b"important",
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        device.next().await.unwrap().unwrap();
+        upstream.next().await.unwrap().unwrap();
+
+        // Dropped once after receiving the message, once right after accepting the connection
+        drop(upstream);
+        drop(accept_bridge(&listener).await);
+
+        let mut upstream = accept_bridge(&listener).await;
+        let received = expect_publish(&mut upstream).await;
+        assert_eq!(received.payload, b"important");
+        puback(&mut upstream, received.packet_identifier.unwrap()).await;
+
+        // Once acknowledged, the message is not sent again after reconnecting
+        drop(upstream);
+        let mut upstream = accept_bridge(&listener).await;
+        device
+            .send(publish_packet(
+                "a/c",
+                b"next",
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(expect_publish(&mut upstream).await.payload, b"next");
+
+        bridging.abort();
+    }
+
+    async fn refuse(
+        upstream: &mut Framed<TcpStream, MqttPacketCodec>,
+        id: u16,
+        reason: PubackReasonCode,
+    ) {
+        upstream
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: packet_identifier(id),
+                    reason,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_refused_for_now_are_published_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let edge = MqttServer::builder().build();
+        let bridge = MqttBridge::new(edge.clone(), upstream_connector(&listener))
+            .with_topic(bridge_topic("a/#", BridgeDirection::Out))
+            .with_reconnect_delay(Duration::from_millis(10));
+        let bridging = tokio::spawn(bridge.run());
+
+        let mut upstream = accept_bridge(&listener).await;
+        let (mut device, _device_serving, _) = connect(&edge, "device", true).await;
+        device
+            .send(publish_packet(
+                "a/b",
+                b"important",
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        device.next().await.unwrap().unwrap();
+
+        let received = expect_publish(&mut upstream).await;
+        refuse(
+            &mut upstream,
+            received.packet_identifier.unwrap(),
+            PubackReasonCode::QuotaExceeded,
+        )
+        .await;
+
+        // Published again on the same connection
+        let received = expect_publish(&mut upstream).await;
+        assert_eq!(received.topic, "a/b");
+        assert_eq!(received.payload, b"important");
+
+        bridging.abort();
+    }
+
+    #[tokio::test]
+    async fn messages_refused_for_good_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let edge = MqttServer::builder().build();
+        let bridge = MqttBridge::new(edge.clone(), upstream_connector(&listener))
+            .with_topic(bridge_topic("a/#", BridgeDirection::Out))
+            .with_reconnect_delay(Duration::from_millis(10));
+        let bridging = tokio::spawn(bridge.run());
+
+        let mut upstream = accept_bridge(&listener).await;
+        let (mut device, _device_serving, _) = connect(&edge, "device", true).await;
+        device
+            .send(publish_packet(
+                "a/b",
+                b"forbidden",
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        device.next().await.unwrap().unwrap();
+
+        let received = expect_publish(&mut upstream).await;
+        refuse(
+            &mut upstream,
+            received.packet_identifier.unwrap(),
+            PubackReasonCode::NotAuthorized,
+        )
+        .await;
+
+        // Neither published again on the same connection nor after reconnecting
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(upstream);
+        let mut upstream = accept_bridge(&listener).await;
+        device
+            .send(publish_packet(
+                "a/c",
+                b"next",
+                mqtt_format::v5::qos::QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(expect_publish(&mut upstream).await.payload, b"next");
+
+        bridging.abort();
+    }
+
+    #[tokio::test]
+    async fn failed_upstream_subscriptions_are_retried_on_a_new_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let edge = MqttServer::builder().build();
+        let bridge = MqttBridge::new(edge, upstream_connector(&listener))
+            .with_topic(bridge_topic("a/#", BridgeDirection::In))
+            .with_reconnect_delay(Duration::from_millis(10));
+        let bridging = tokio::spawn(bridge.run());
+
+        for _ in 0..2 {
+            let mut upstream = accept_bridge(&listener).await;
+            let packet = upstream.next().await.unwrap().unwrap();
+            assert!(
+                matches!(packet.get(), FormatMqttPacket::Subscribe(_)),
+                "Expected SUBSCRIBE, got {:?}",
+                packet.get()
+            );
+        }
+
+        assert!(!bridging.is_finished());
+        bridging.abort();
+    }
+}
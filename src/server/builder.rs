@@ -0,0 +1,232 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::num::NonZeroU16;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::lock::Mutex;
+
+use super::auth::AllowAll;
+use super::auth::AllowAnonymous;
+use super::auth::Authenticator;
+use super::auth::Authorizer;
+use super::retained::MemoryRetainedStore;
+use super::retained::RetainedStore;
+use super::session::SessionRegistry;
+use super::shared::SharedSubscriptionStrategy;
+use super::statistics::Statistics;
+use super::subscriptions::SubscriptionTree;
+use super::InnerServer;
+use super::MqttServer;
+use crate::qos::QualityOfService;
+
+/// The capabilities and limits of a server, advertised to clients in the CONNACK
+#[derive(Debug, Clone)]
+pub(crate) struct ServerConfig {
+    pub(crate) maximum_qos: QualityOfService,
+    pub(crate) retain_available: bool,
+    pub(crate) wildcard_subscription_available: bool,
+    pub(crate) subscription_identifiers_available: bool,
+    pub(crate) shared_subscription_available: bool,
+    pub(crate) shared_subscription_strategy: SharedSubscriptionStrategy,
+    pub(crate) maximum_packet_size: Option<u32>,
+    pub(crate) receive_maximum: NonZeroU16,
+    pub(crate) maximum_session_expiry_interval: u32,
+    pub(crate) maximum_queued_messages: usize,
+    pub(crate) assign_client_identifiers: bool,
+    pub(crate) strict_client_identifiers: bool,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) minimum_keep_alive: u16,
+    pub(crate) maximum_keep_alive: Option<NonZeroU16>,
+    pub(crate) statistics_interval: Option<Duration>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            maximum_qos: QualityOfService::ExactlyOnce,
+            retain_available: true,
+            wildcard_subscription_available: true,
+            subscription_identifiers_available: true,
+            shared_subscription_available: true,
+            shared_subscription_strategy: SharedSubscriptionStrategy::RoundRobin,
+            maximum_packet_size: None,
+            receive_maximum: NonZeroU16::MAX,
+            maximum_session_expiry_interval: u32::MAX,
+            maximum_queued_messages: 1000,
+            assign_client_identifiers: true,
+            strict_client_identifiers: false,
+            connect_timeout: Duration::from_secs(10),
+            minimum_keep_alive: 0,
+            maximum_keep_alive: None,
+            statistics_interval: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+pub struct MqttServerBuilder {
+    config: ServerConfig,
+    retained: Box<dyn RetainedStore>,
+    authenticator: Arc<dyn Authenticator>,
+    authorizer: Arc<dyn Authorizer>,
+}
+
+impl MqttServerBuilder {
+    pub(super) fn new() -> Self {
+        Self {
+            config: ServerConfig::default(),
+            retained: Box::new(MemoryRetainedStore::new()),
+            authenticator: Arc::new(AllowAnonymous),
+            authorizer: Arc::new(AllowAll),
+        }
+    }
+
+    pub fn with_maximum_qos(mut self, maximum_qos: QualityOfService) -> Self {
+        self.config.maximum_qos = maximum_qos;
+        self
+    }
+
+    pub fn with_retain_available(mut self, retain_available: bool) -> Self {
+        self.config.retain_available = retain_available;
+        self
+    }
+
+    pub fn with_wildcard_subscription_available(mut self, available: bool) -> Self {
+        self.config.wildcard_subscription_available = available;
+        self
+    }
+
+    pub fn with_subscription_identifiers_available(mut self, available: bool) -> Self {
+        self.config.subscription_identifiers_available = available;
+        self
+    }
+
+    pub fn with_shared_subscription_available(mut self, available: bool) -> Self {
+        self.config.shared_subscription_available = available;
+        self
+    }
+
+    /// How messages of shared subscriptions are distributed among the members of a group
+    pub fn with_shared_subscription_strategy(
+        mut self,
+        strategy: SharedSubscriptionStrategy,
+    ) -> Self {
+        self.config.shared_subscription_strategy = strategy;
+        self
+    }
+
+    /// The largest packet the server accepts from clients
+    pub fn with_maximum_packet_size(mut self, maximum_packet_size: u32) -> Self {
+        self.config.maximum_packet_size = Some(maximum_packet_size);
+        self
+    }
+
+    /// How many unacknowledged QoS 1 and 2 messages a client may send concurrently
+    pub fn with_receive_maximum(mut self, receive_maximum: NonZeroU16) -> Self {
+        self.config.receive_maximum = receive_maximum;
+        self
+    }
+
+    /// Cap the session expiry interval clients may request, in seconds
+    pub fn with_maximum_session_expiry_interval(mut self, seconds: u32) -> Self {
+        self.config.maximum_session_expiry_interval = seconds;
+        self
+    }
+
+    /// How many QoS 1 and 2 messages are queued per session while they cannot be sent
+    ///
+    /// Messages wait in the queue while the client is offline or has as many unacknowledged
+    /// messages as its receive maximum allows. Once the queue is full, the oldest message is
+    /// dropped.
+    pub fn with_maximum_queued_messages(mut self, maximum: usize) -> Self {
+        self.config.maximum_queued_messages = maximum;
+        self
+    }
+
+    /// Assign a client identifier to clients that connect with an empty one
+    ///
+    /// Enabled by default, otherwise such clients are rejected.
+    pub fn with_assign_client_identifiers(mut self, assign: bool) -> Self {
+        self.config.assign_client_identifiers = assign;
+        self
+    }
+
+    /// Only accept client identifiers of 1 to 23 alphanumeric characters
+    ///
+    /// These are the identifiers every server has to accept, longer ones or ones with other
+    /// characters are allowed by default.
+    pub fn with_strict_client_identifiers(mut self, strict: bool) -> Self {
+        self.config.strict_client_identifiers = strict;
+        self
+    }
+
+    /// How long a new connection may take to send its CONNECT
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// The shortest keep alive interval clients may use, in seconds
+    ///
+    /// Clients asking for a shorter one are told to use this one instead. Clients that disable
+    /// the keep alive mechanism are not affected.
+    pub fn with_minimum_keep_alive(mut self, seconds: u16) -> Self {
+        self.config.minimum_keep_alive = seconds;
+        self
+    }
+
+    /// The longest keep alive interval clients may use, in seconds
+    ///
+    /// Clients asking for a longer one or disabling the keep alive mechanism are told to use this
+    /// one instead.
+    pub fn with_maximum_keep_alive(mut self, seconds: NonZeroU16) -> Self {
+        self.config.maximum_keep_alive = Some(seconds);
+        self
+    }
+
+    /// How often [`MqttServer::serve`] publishes the statistics of the server
+    ///
+    /// The statistics are published as retained messages to `$SYS/broker/...` topics, every ten
+    /// seconds by default. `None` disables publishing them.
+    pub fn with_statistics_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.statistics_interval = interval;
+        self
+    }
+
+    /// Where retained messages are kept, in memory by default
+    pub fn with_retained_store(mut self, store: impl RetainedStore + 'static) -> Self {
+        self.retained = Box::new(store);
+        self
+    }
+
+    /// Decide which clients may connect, all clients without an authentication method by default
+    pub fn with_authenticator(mut self, authenticator: impl Authenticator) -> Self {
+        self.authenticator = Arc::new(authenticator);
+        self
+    }
+
+    /// Decide where clients may publish and subscribe, everywhere by default
+    pub fn with_authorizer(mut self, authorizer: impl Authorizer) -> Self {
+        self.authorizer = Arc::new(authorizer);
+        self
+    }
+
+    pub fn build(self) -> MqttServer {
+        MqttServer {
+            inner: Arc::new(Mutex::new(InnerServer {
+                config: self.config,
+                sessions: SessionRegistry::new(),
+                subscriptions: SubscriptionTree::new(),
+                retained: self.retained,
+                authenticator: self.authenticator,
+                authorizer: self.authorizer,
+                statistics: Arc::new(Statistics::new()),
+                next_assigned_identifier: 0,
+            })),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::str::FromStr;
+
+use mqtt_format::v5::packets::connack::ConnackReasonCode;
+use mqtt_format::v5::packets::connect::MConnect;
+use tokio::io::AsyncReadExt;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use super::builder::ServerConfig;
+use super::connection::MqttServerConnectionError;
+use crate::client_identifier::ProposedClientIdentifier;
+use crate::codecs::MqttPacketCodecError;
+use crate::codecs::MqttServerCodec;
+use crate::packets::connack::ConnackProperties;
+use crate::packets::MqttPacket;
+use crate::qos::QualityOfService;
+use crate::topic::MqttTopic;
+use crate::transport::MqttConnection;
+
+/// The first bytes of every MQTT v5 CONNECT variable header: protocol name and version
+const PROTOCOL_NAME: [u8; 6] = [0x00, 0x04, b'M', b'Q', b'T', b'T'];
+const PROTOCOL_VERSION: u8 = 5;
+
+/// A CONNECT that was accepted by the server
+#[derive(Debug)]
+pub(crate) struct AcceptedConnect {
+    pub(crate) client_identifier: String,
+    /// Whether the client identifier was assigned by the server
+    pub(crate) assigned: bool,
+    pub(crate) session_expiry_interval: u32,
+    /// Set if the server uses a different session expiry interval than the client requested
+    pub(crate) session_expiry_override: Option<u32>,
+    /// Seconds of silence after which the connection is considered broken, zero disables it
+    pub(crate) keep_alive: u16,
+    /// Set if the server uses a different keep alive than the client requested
+    pub(crate) keep_alive_override: Option<u16>,
+}
+
+/// Read from `conn` until a complete CONNECT was received
+///
+/// The protocol name and version are checked as soon as they arrive, so that clients speaking
+/// another protocol version can be answered before their packet is parsed. Bytes following the
+/// CONNECT are left in `buffer`. A CONNECT larger than `maximum_packet_size` is refused as soon
+/// as its remaining length arrived.
+pub(crate) async fn read_connect(
+    conn: &mut tokio::io::ReadHalf<MqttConnection>,
+    buffer: &mut BytesMut,
+    maximum_packet_size: Option<u32>,
+) -> Result<MqttPacket, MqttServerConnectionError> {
+    let mut codec = MqttServerCodec {
+        maximum_packet_size,
+    };
+
+    loop {
+        check_protocol(buffer)?;
+
+        match codec.decode(buffer) {
+            Ok(Some(packet)) => return Ok(packet),
+            Ok(None) => {}
+            Err(MqttPacketCodecError::PacketTooLarge { .. }) => {
+                return Err(MqttServerConnectionError::Rejected {
+                    reason_code: ConnackReasonCode::PacketTooLarge,
+                })
+            }
+            Err(error) => return Err(MqttServerConnectionError::Receive(error)),
+        }
+
+        let read = conn
+            .read_buf(buffer)
+            .await
+            .map_err(|error| MqttServerConnectionError::Receive(error.into()))?;
+
+        if read == 0 {
+            return Err(MqttServerConnectionError::TransportClosed);
+        }
+    }
+}
+
+fn check_protocol(buffer: &[u8]) -> Result<(), MqttServerConnectionError> {
+    let Some(&first_byte) = buffer.first() else {
+        return Ok(());
+    };
+
+    if first_byte != 0x10 {
+        return Err(MqttServerConnectionError::ProtocolError {
+            reason: "MQTT-3.1.0-1",
+        });
+    }
+
+    // Skip the remaining length, at most four bytes
+    let Some(length_size) = buffer[1..]
+        .iter()
+        .take(4)
+        .position(|byte| byte & 0x80 == 0)
+        .map(|position| position + 1)
+    else {
+        return Ok(());
+    };
+
+    let variable_header = &buffer[1 + length_size..];
+    let checked = variable_header.len().min(PROTOCOL_NAME.len());
+    if variable_header[..checked] != PROTOCOL_NAME[..checked] {
+        return Err(MqttServerConnectionError::ProtocolError {
+            reason: "MQTT-3.1.2-1",
+        });
+    }
+
+    match variable_header.get(PROTOCOL_NAME.len()) {
+        Some(&version) if version != PROTOCOL_VERSION => Err(MqttServerConnectionError::Rejected {
+            reason_code: ConnackReasonCode::UnsupportedProtocolVersion,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Check a CONNECT against the capabilities of the server
+///
+/// `assign_identifier` is called to create a client identifier for clients that did not send
+/// one.
+pub(crate) fn validate_connect(
+    connect: &MConnect<'_>,
+    config: &ServerConfig,
+    assign_identifier: impl FnOnce() -> String,
+) -> Result<AcceptedConnect, ConnackReasonCode> {
+    let (client_identifier, assigned) = if connect.client_identifier.is_empty() {
+        if !config.assign_client_identifiers {
+            return Err(ConnackReasonCode::ClientIdentifierNotValid);
+        }
+
+        (assign_identifier(), true)
+    } else {
+        if config.strict_client_identifiers
+            && ProposedClientIdentifier::new_minimal_required(connect.client_identifier).is_err()
+        {
+            return Err(ConnackReasonCode::ClientIdentifierNotValid);
+        }
+
+        (connect.client_identifier.to_string(), false)
+    };
+
+    if let Some(will) = &connect.will {
+        if MqttTopic::from_str(will.topic).is_err() {
+            return Err(ConnackReasonCode::TopicNameInvalid);
+        }
+
+        if QualityOfService::from(will.will_qos) > config.maximum_qos {
+            return Err(ConnackReasonCode::QoSNotSupported);
+        }
+
+        if will.will_retain && !config.retain_available {
+            return Err(ConnackReasonCode::RetainNotSupported);
+        }
+    }
+
+    let requested_expiry = connect
+        .properties
+        .session_expiry_interval()
+        .map(|sei| sei.0)
+        .unwrap_or(0);
+    let session_expiry_interval = requested_expiry.min(config.maximum_session_expiry_interval);
+    let keep_alive = keep_alive(connect.keep_alive, config);
+
+    Ok(AcceptedConnect {
+        client_identifier,
+        assigned,
+        session_expiry_interval,
+        session_expiry_override: (session_expiry_interval != requested_expiry)
+            .then_some(session_expiry_interval),
+        keep_alive,
+        keep_alive_override: (keep_alive != connect.keep_alive).then_some(keep_alive),
+    })
+}
+
+/// Fit the keep alive a client requested into the limits of the server
+///
+/// A keep alive of zero disables the mechanism, which counts as longer than any maximum.
+fn keep_alive(requested: u16, config: &ServerConfig) -> u16 {
+    let keep_alive = match config.maximum_keep_alive {
+        Some(maximum) if requested == 0 || requested > maximum.get() => maximum.get(),
+        _ => requested,
+    };
+
+    if keep_alive != 0 && keep_alive < config.minimum_keep_alive {
+        config.minimum_keep_alive
+    } else {
+        keep_alive
+    }
+}
+
+/// The CONNACK properties for an accepted connection
+pub(crate) fn connack_properties(
+    config: &ServerConfig,
+    accepted: &AcceptedConnect,
+) -> ConnackProperties {
+    let mut properties = ConnackProperties::new();
+
+    // Only values that differ from what clients assume when a property is absent are sent
+    match config.maximum_qos {
+        QualityOfService::AtMostOnce => {
+            properties.with_maximum_qos(mqtt_format::v5::qos::MaximumQualityOfService::AtMostOnce);
+        }
+        QualityOfService::AtLeastOnce => {
+            properties.with_maximum_qos(mqtt_format::v5::qos::MaximumQualityOfService::AtLeastOnce);
+        }
+        QualityOfService::ExactlyOnce => {}
+    }
+
+    if !config.retain_available {
+        properties.with_retain_available(false);
+    }
+    if !config.wildcard_subscription_available {
+        properties.with_wildcard_subscription_available(0);
+    }
+    if !config.subscription_identifiers_available {
+        properties.with_subscription_identifiers_available(0);
+    }
+    if !config.shared_subscription_available {
+        properties.with_shared_scubscription_available(0);
+    }
+    if config.receive_maximum != std::num::NonZeroU16::MAX {
+        properties.with_receive_maximum(config.receive_maximum);
+    }
+    if let Some(maximum_packet_size) = config.maximum_packet_size {
+        properties.with_maximum_packet_size(maximum_packet_size);
+    }
+    if let Some(session_expiry_interval) = accepted.session_expiry_override {
+        properties.with_session_expiry_interval(session_expiry_interval);
+    }
+    if let Some(keep_alive) = accepted.keep_alive_override {
+        properties.with_server_keep_alive(keep_alive);
+    }
+    if accepted.assigned {
+        properties.with_assigned_client_identifier(accepted.client_identifier.clone());
+    }
+
+    properties
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_format::v5::packets::connack::ConnackReasonCode;
+
+    use super::check_protocol;
+    use super::keep_alive;
+    use super::validate_connect;
+    use crate::server::builder::ServerConfig;
+    use crate::server::connection::MqttServerConnectionError;
+
+    #[test]
+    fn protocol_is_checked_incrementally() {
+        let connect = [0x10, 0x10, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05];
+        for len in 0..=connect.len() {
+            assert!(check_protocol(&connect[..len]).is_ok());
+        }
+
+        let v311 = [0x10, 0x10, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04];
+        assert!(matches!(
+            check_protocol(&v311),
+            Err(MqttServerConnectionError::Rejected {
+                reason_code: ConnackReasonCode::UnsupportedProtocolVersion
+            })
+        ));
+
+        let v31 = [
+            0x10, 0x10, 0x00, 0x06, b'M', b'Q', b'I', b's', b'd', b'p', 0x03,
+        ];
+        assert!(matches!(
+            check_protocol(&v31),
+            Err(MqttServerConnectionError::ProtocolError { .. })
+        ));
+    }
+
+    #[test]
+    fn client_identifier_rules() {
+        let mut connect = mqtt_format::v5::packets::connect::MConnect {
+            client_identifier: "",
+            username: None,
+            password: None,
+            clean_start: true,
+            will: None,
+            properties: mqtt_format::v5::packets::connect::ConnectProperties::new(),
+            keep_alive: 0,
+        };
+
+        let config = ServerConfig::default();
+        let accepted = validate_connect(&connect, &config, || "assigned".to_string()).unwrap();
+        assert_eq!(accepted.client_identifier, "assigned");
+        assert!(accepted.assigned);
+
+        let config = ServerConfig {
+            assign_client_identifiers: false,
+            strict_client_identifiers: true,
+            ..ServerConfig::default()
+        };
+        assert_eq!(
+            validate_connect(&connect, &config, || unreachable!()).unwrap_err(),
+            ConnackReasonCode::ClientIdentifierNotValid
+        );
+
+        connect.client_identifier = "not/minimal";
+        assert_eq!(
+            validate_connect(&connect, &config, || unreachable!()).unwrap_err(),
+            ConnackReasonCode::ClientIdentifierNotValid
+        );
+
+        connect.client_identifier = "minimal";
+        assert!(validate_connect(&connect, &config, || unreachable!()).is_ok());
+    }
+
+    #[test]
+    fn keep_alive_is_fit_into_limits() {
+        let config = ServerConfig::default();
+        assert_eq!(keep_alive(0, &config), 0);
+        assert_eq!(keep_alive(30, &config), 30);
+
+        let config = ServerConfig {
+            minimum_keep_alive: 10,
+            maximum_keep_alive: std::num::NonZeroU16::new(60),
+            ..ServerConfig::default()
+        };
+        assert_eq!(keep_alive(0, &config), 60);
+        assert_eq!(keep_alive(5, &config), 10);
+        assert_eq!(keep_alive(30, &config), 30);
+        assert_eq!(keep_alive(600, &config), 60);
+    }
+}
@@ -0,0 +1,831 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::num::NonZeroU16;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::lock::Mutex;
+use futures::select;
+use futures::FutureExt;
+use futures::SinkExt;
+use futures::StreamExt;
+use mqtt_format::v5::packets::connack::ConnackReasonCode;
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+use mqtt_format::v5::packets::MqttPacketKind;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::FramedRead;
+use tokio_util::codec::FramedWrite;
+
+use super::auth::authenticate;
+use super::auth::Action;
+use super::auth::AuthenticatedClient;
+use super::auth::AuthenticationRequest;
+use super::auth::Authorizer;
+use super::auth::PeerInfo;
+use super::connect::connack_properties;
+use super::connect::read_connect;
+use super::connect::validate_connect;
+use super::message::Message;
+use super::session::ConnectionCommand;
+use super::session::ConnectionId;
+use super::session::ConnectionLink;
+use super::session::PendingWill;
+use super::statistics::Statistics;
+use super::InnerServer;
+use crate::codecs::MqttPacketCodec;
+use crate::codecs::MqttPacketCodecError;
+use crate::codecs::MqttServerCodec;
+use crate::packets::MqttPacket;
+use crate::transport::MqttConnectTransport;
+use crate::transport::MqttConnection;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MqttServerConnectionError {
+    #[error("The client did not send a CONNECT within the connect timeout")]
+    ConnectTimeout,
+
+    #[error("The transport closed before the client connected")]
+    TransportClosed,
+
+    #[error("An error occured while decoding or receiving an MQTT Packet")]
+    Receive(#[source] MqttPacketCodecError),
+
+    #[error("An error occured while encoding or sending an MQTT Packet")]
+    Send(#[source] MqttPacketCodecError),
+
+    #[error("The server refused the connection: {reason_code:?}")]
+    Rejected { reason_code: ConnackReasonCode },
+
+    #[error("The client sent a packet with a protocol error: {reason}")]
+    ProtocolError { reason: &'static str },
+
+    #[error("The client sent a packet of {size} bytes, larger than the maximum packet size")]
+    PacketTooLarge { size: usize },
+
+    #[error("The client sent a {kind:?} packet, which the server does not support")]
+    UnsupportedPacket { kind: MqttPacketKind },
+
+    #[error("The client published to the invalid topic name {topic:?}")]
+    TopicNameInvalid { topic: String },
+
+    #[error("The client exceeded a capability of the server: {reason_code:?}")]
+    CapabilityExceeded { reason_code: DisconnectReasonCode },
+
+    #[error("The client did not send a packet for one and a half times its keep alive")]
+    KeepAliveTimeout,
+}
+
+pub(super) type ConnectionWriter =
+    FramedWrite<tokio::io::WriteHalf<MqttConnection>, MqttPacketCodec>;
+pub(super) type ConnectionReader = FramedRead<tokio::io::ReadHalf<MqttConnection>, MqttServerCodec>;
+
+pub(super) enum Flow {
+    Continue,
+    Disconnected(Closed),
+}
+
+/// How a connection that did not fail ended
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Closed {
+    /// The session expiry interval the client sent with its DISCONNECT, if any
+    session_expiry_interval: Option<u32>,
+    publish_will: bool,
+}
+
+impl Closed {
+    /// The connection ended without a DISCONNECT from the client
+    const ABNORMALLY: Closed = Closed {
+        session_expiry_interval: None,
+        publish_will: true,
+    };
+}
+
+/// The will message of a connection
+struct Will {
+    message: Message,
+    /// Seconds to wait before publishing the will
+    delay_interval: u32,
+}
+
+pub(super) async fn serve_connection(
+    inner: Arc<Mutex<InnerServer>>,
+    transport: MqttConnectTransport,
+    peer: PeerInfo,
+) -> Result<(), MqttServerConnectionError> {
+    let (mut read, write) = tokio::io::split(MqttConnection::from(transport));
+    let mut writer = FramedWrite::new(write, MqttPacketCodec);
+
+    let (connect_timeout, maximum_packet_size) = {
+        let inner = inner.lock().await;
+        (
+            inner.config.connect_timeout,
+            inner.config.maximum_packet_size,
+        )
+    };
+    let mut buffer = BytesMut::new();
+    let connect = {
+        let reading = read_connect(&mut read, &mut buffer, maximum_packet_size).fuse();
+        let timeout = futures_timer::Delay::new(connect_timeout).fuse();
+        futures::pin_mut!(reading, timeout);
+
+        select! {
+            connect = reading => connect,
+            () = timeout => Err(MqttServerConnectionError::ConnectTimeout),
+        }
+    };
+
+    let connect = match connect {
+        Ok(connect) => connect,
+        Err(MqttServerConnectionError::Rejected { reason_code }) => {
+            return reject(&mut writer, reason_code).await;
+        }
+        Err(error) => return Err(error),
+    };
+
+    let FormatMqttPacket::Connect(mconnect) = connect.get() else {
+        return Err(MqttServerConnectionError::ProtocolError {
+            reason: "MQTT-3.1.0-1",
+        });
+    };
+
+    let (accepted, authenticator, authorizer, statistics) = {
+        let mut inner = inner.lock().await;
+        let inner = &mut *inner;
+        inner.purge_expired_sessions(Instant::now());
+
+        let accepted = validate_connect(mconnect, &inner.config, || {
+            super::assign_client_identifier(&mut inner.next_assigned_identifier, &inner.sessions)
+        });
+
+        (
+            accepted,
+            inner.authenticator.clone(),
+            inner.authorizer.clone(),
+            inner.statistics.clone(),
+        )
+    };
+    statistics.received(connect.encoded_len(), false);
+
+    let accepted = match accepted {
+        Ok(accepted) => accepted,
+        Err(reason_code) => return reject(&mut writer, reason_code).await,
+    };
+
+    let mut reader = FramedRead::new(
+        read,
+        MqttServerCodec {
+            maximum_packet_size,
+        },
+    );
+    reader.read_buffer_mut().extend_from_slice(&buffer);
+
+    let authentication_method = mconnect.properties.authentication_method().map(|am| am.0);
+    let request = AuthenticationRequest {
+        client_identifier: &accepted.client_identifier,
+        username: mconnect.username,
+        password: mconnect.password,
+        authentication_method,
+        authentication_data: mconnect.properties.authentication_data().map(|ad| ad.0),
+        peer: &peer,
+    };
+    let authenticated = match authenticate(
+        &*authenticator,
+        &request,
+        &mut reader,
+        &mut writer,
+        connect_timeout,
+    )
+    .await?
+    {
+        Ok(authenticated) => authenticated,
+        Err(reason_code) => return reject(&mut writer, reason_code).await,
+    };
+
+    let client = AuthenticatedClient {
+        client_identifier: accepted.client_identifier.clone(),
+        username: authenticated.username,
+    };
+
+    if let Some(will) = &mconnect.will {
+        if !authorizer
+            .authorize(&client, Action::Publish { topic: will.topic })
+            .await
+        {
+            return reject(&mut writer, ConnackReasonCode::NotAuthorized).await;
+        }
+    }
+
+    let (commands_send, commands) = futures::channel::mpsc::unbounded();
+
+    let (id, session, session_present, mut properties) = {
+        let mut inner = inner.lock().await;
+        let inner = &mut *inner;
+        let now = Instant::now();
+
+        let id = inner.sessions.next_connection_id();
+        let existed = inner.sessions.contains(&accepted.client_identifier);
+        // Connecting before the will delay passed cancels the will, ending the session does not
+        let pending_will = inner
+            .sessions
+            .get_mut(&accepted.client_identifier)
+            .and_then(|session| session.pending_will.take());
+        let session_present = inner.sessions.attach(
+            &accepted.client_identifier,
+            mconnect.clean_start,
+            accepted.session_expiry_interval,
+            ConnectionLink {
+                id,
+                commands: commands_send,
+            },
+            now,
+        );
+        if existed && !session_present {
+            inner
+                .subscriptions
+                .unsubscribe_all(&accepted.client_identifier);
+
+            if let Some(will) = pending_will {
+                inner.publish_will(&accepted.client_identifier, will.message);
+            }
+        }
+        let properties = connack_properties(&inner.config, &accepted);
+        let session = inner
+            .sessions
+            .get(&accepted.client_identifier)
+            .map_or(id, |session| session.started_by);
+
+        (id, session, session_present, properties)
+    };
+
+    if let Some(authentication_method) = authentication_method {
+        properties.with_authentication_method(authentication_method.to_string());
+    }
+    if let Some(authentication_data) = authenticated.authentication_data {
+        properties.with_authentication_data(authentication_data);
+    }
+
+    tracing::debug!(
+        client_identifier = accepted.client_identifier,
+        session_present,
+        "Client connected"
+    );
+
+    let mut connection = Connection {
+        inner: inner.clone(),
+        id,
+        session,
+        client,
+        authorizer,
+        statistics,
+        session_expiry_interval: accepted.session_expiry_interval,
+        keep_alive: accepted.keep_alive,
+        client_maximum_packet_size: mconnect.properties.maximum_packet_size().map(|mps| mps.0),
+        client_receive_maximum: mconnect
+            .properties
+            .receive_maximum()
+            .map(|rm| rm.0)
+            .unwrap_or(NonZeroU16::MAX),
+        will: mconnect.will.as_ref().map(|will| Will {
+            message: Message::from_will(will),
+            delay_interval: will
+                .properties
+                .will_delay_interval()
+                .map_or(0, |interval| interval.0),
+        }),
+        writer,
+    };
+
+    let connack = FormatMqttPacket::Connack(mqtt_format::v5::packets::connack::MConnack {
+        session_present,
+        reason_code: ConnackReasonCode::Success,
+        properties: properties.as_ref(),
+    });
+
+    let result = match connection.send(connack).await {
+        Ok(()) => match connection.resume().await {
+            Ok(()) => connection.run(reader, commands).await,
+            Err(error) => Err(error),
+        },
+        Err(error) => Err(error),
+    };
+
+    let delayed_will = connection
+        .close(result.as_ref().ok().copied().unwrap_or(Closed::ABNORMALLY))
+        .await;
+
+    if let Some(delay) = delayed_will {
+        futures_timer::Delay::new(delay).await;
+        inner
+            .lock()
+            .await
+            .publish_delayed_will(&connection.client.client_identifier, connection.id);
+    }
+
+    result.map(drop)
+}
+
+async fn reject(
+    writer: &mut ConnectionWriter,
+    reason_code: ConnackReasonCode,
+) -> Result<(), MqttServerConnectionError> {
+    tracing::debug!(?reason_code, "Refusing connection");
+
+    writer
+        .send(FormatMqttPacket::Connack(
+            mqtt_format::v5::packets::connack::MConnack {
+                session_present: false,
+                reason_code,
+                properties: mqtt_format::v5::packets::connack::ConnackProperties::new(),
+            },
+        ))
+        .await
+        .map_err(MqttServerConnectionError::Send)?;
+
+    Err(MqttServerConnectionError::Rejected { reason_code })
+}
+
+pub(super) struct Connection {
+    pub(super) inner: Arc<Mutex<InnerServer>>,
+    pub(super) id: ConnectionId,
+    /// The connection that started the session this connection uses
+    session: ConnectionId,
+    pub(super) client: AuthenticatedClient,
+    pub(super) authorizer: Arc<dyn Authorizer>,
+    pub(super) statistics: Arc<Statistics>,
+    pub(super) session_expiry_interval: u32,
+    /// Seconds the client may stay silent, zero disables the keep alive mechanism
+    keep_alive: u16,
+    /// The largest packet the client accepts
+    pub(super) client_maximum_packet_size: Option<u32>,
+    /// How many unacknowledged QoS 1 and 2 messages the client accepts
+    pub(super) client_receive_maximum: NonZeroU16,
+    will: Option<Will>,
+    pub(super) writer: ConnectionWriter,
+}
+
+impl Connection {
+    /// Handle packets until the connection ends
+    async fn run(
+        &mut self,
+        mut reader: ConnectionReader,
+        mut commands: futures::channel::mpsc::UnboundedReceiver<ConnectionCommand>,
+    ) -> Result<Closed, MqttServerConnectionError> {
+        // The client has one and a half times its keep alive to send its next packet
+        let keep_alive = (self.keep_alive != 0)
+            .then(|| Duration::from_millis(u64::from(self.keep_alive) * 1500));
+        let mut last_packet = Instant::now();
+
+        loop {
+            let timeout = async move {
+                match keep_alive {
+                    Some(keep_alive) => {
+                        futures_timer::Delay::new(keep_alive.saturating_sub(last_packet.elapsed()))
+                            .await
+                    }
+                    None => futures::future::pending().await,
+                }
+            };
+
+            select! {
+                packet = reader.next().fuse() => {
+                    last_packet = Instant::now();
+                    let packet = match packet {
+                        Some(Ok(packet)) => packet,
+                        Some(Err(MqttPacketCodecError::PacketTooLarge { size })) => {
+                            self.disconnect(DisconnectReasonCode::PacketTooLarge).await;
+                            return Err(MqttServerConnectionError::PacketTooLarge { size });
+                        }
+                        Some(Err(error)) => {
+                            self.disconnect(DisconnectReasonCode::MalformedPacket).await;
+                            return Err(MqttServerConnectionError::Receive(error));
+                        }
+                        None => {
+                            tracing::debug!("Client closed the connection without DISCONNECT");
+                            return Ok(Closed::ABNORMALLY);
+                        }
+                    };
+
+                    match self.handle_packet(packet).await {
+                        Ok(Flow::Continue) => {}
+                        Ok(Flow::Disconnected(closed)) => return Ok(closed),
+                        Err(error) => {
+                            let reason_code = match error {
+                                MqttServerConnectionError::PacketTooLarge { .. } => {
+                                    DisconnectReasonCode::PacketTooLarge
+                                }
+                                MqttServerConnectionError::UnsupportedPacket { .. } => {
+                                    DisconnectReasonCode::ImplementationSpecificError
+                                }
+                                MqttServerConnectionError::TopicNameInvalid { .. } => {
+                                    DisconnectReasonCode::TopicNameInvalid
+                                }
+                                MqttServerConnectionError::CapabilityExceeded { reason_code } => {
+                                    reason_code
+                                }
+                                _ => DisconnectReasonCode::ProtocolError,
+                            };
+                            self.disconnect(reason_code).await;
+                            return Err(error);
+                        }
+                    }
+                }
+                command = commands.next() => match command {
+                    Some(ConnectionCommand::Deliver(delivery)) => {
+                        self.deliver(delivery).await?;
+                    }
+                    Some(ConnectionCommand::SendQueued) => {
+                        self.send_queued().await?;
+                    }
+                    Some(ConnectionCommand::TakenOver) | None => {
+                        self.disconnect(DisconnectReasonCode::SessionTakenOver).await;
+                        return Ok(Closed::ABNORMALLY);
+                    }
+                },
+                () = timeout.fuse() => {
+                    tracing::debug!(
+                        client_identifier = self.client.client_identifier,
+                        keep_alive = self.keep_alive,
+                        "Client exceeded its keep alive"
+                    );
+                    self.disconnect(DisconnectReasonCode::KeepAliveTimeout).await;
+                    return Err(MqttServerConnectionError::KeepAliveTimeout);
+                }
+            }
+        }
+    }
+
+    async fn handle_packet(
+        &mut self,
+        packet: MqttPacket,
+    ) -> Result<Flow, MqttServerConnectionError> {
+        self.statistics.received(
+            packet.encoded_len(),
+            matches!(packet.get(), FormatMqttPacket::Publish(_)),
+        );
+
+        match packet.get() {
+            FormatMqttPacket::Pingreq(_) => {
+                self.send(FormatMqttPacket::Pingresp(
+                    mqtt_format::v5::packets::pingresp::MPingresp,
+                ))
+                .await?;
+            }
+            FormatMqttPacket::Disconnect(disconnect) => {
+                let session_expiry_interval = disconnect
+                    .properties
+                    .session_expiry_interval()
+                    .map(|sei| sei.0);
+
+                if self.session_expiry_interval == 0
+                    && session_expiry_interval.is_some_and(|sei| sei != 0)
+                {
+                    return Err(MqttServerConnectionError::ProtocolError {
+                        reason: "MQTT-3.14.2-2",
+                    });
+                }
+
+                tracing::debug!(reason_code = ?disconnect.reason_code, "Client disconnected");
+                return Ok(Flow::Disconnected(Closed {
+                    session_expiry_interval,
+                    // Only a normal disconnection discards the will
+                    publish_will: disconnect.reason_code
+                        != DisconnectReasonCode::NormalDisconnection,
+                }));
+            }
+            FormatMqttPacket::Connect(_) => {
+                return Err(MqttServerConnectionError::ProtocolError {
+                    reason: "MQTT-3.1.0-2",
+                });
+            }
+            FormatMqttPacket::Connack(_)
+            | FormatMqttPacket::Suback(_)
+            | FormatMqttPacket::Unsuback(_)
+            | FormatMqttPacket::Pingresp(_) => {
+                return Err(MqttServerConnectionError::ProtocolError {
+                    reason: "Packet type is only sent by servers",
+                });
+            }
+            FormatMqttPacket::Publish(publish) => self.handle_publish(publish).await?,
+            FormatMqttPacket::Pubrel(pubrel) => self.handle_pubrel(pubrel).await?,
+            FormatMqttPacket::Subscribe(subscribe) => self.handle_subscribe(subscribe).await?,
+            FormatMqttPacket::Unsubscribe(unsubscribe) => {
+                self.handle_unsubscribe(unsubscribe).await?
+            }
+            FormatMqttPacket::Puback(puback) => self.handle_puback(puback).await?,
+            FormatMqttPacket::Pubrec(pubrec) => self.handle_pubrec(pubrec).await?,
+            FormatMqttPacket::Pubcomp(pubcomp) => self.handle_pubcomp(pubcomp).await?,
+            FormatMqttPacket::Auth(_) => {
+                return Err(MqttServerConnectionError::UnsupportedPacket {
+                    kind: packet.get().get_kind(),
+                });
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    pub(super) async fn send(
+        &mut self,
+        packet: FormatMqttPacket<'_>,
+    ) -> Result<(), MqttServerConnectionError> {
+        self.statistics.sent(
+            packet.binary_size() as usize,
+            matches!(packet, FormatMqttPacket::Publish(_)),
+        );
+        self.writer
+            .send(packet)
+            .await
+            .map_err(MqttServerConnectionError::Send)
+    }
+
+    async fn disconnect(&mut self, reason_code: DisconnectReasonCode) {
+        let disconnect =
+            FormatMqttPacket::Disconnect(mqtt_format::v5::packets::disconnect::MDisconnect {
+                reason_code,
+                properties: mqtt_format::v5::packets::disconnect::DisconnectProperties::new(),
+            });
+
+        if let Err(error) = self.send(disconnect).await {
+            tracing::debug!(%error, "Could not send DISCONNECT to the client");
+        }
+    }
+
+    /// Detach the connection from its session and take care of its will
+    ///
+    /// Returns how long to wait before publishing a delayed will.
+    async fn close(&mut self, closed: Closed) -> Option<Duration> {
+        let mut inner = self.inner.lock().await;
+        let maximum = inner.config.maximum_session_expiry_interval;
+
+        // Messages of shared subscriptions go to other members instead of waiting for the client
+        let shared = match inner.sessions.get_mut(&self.client.client_identifier) {
+            Some(session) if session.connection.as_ref().map(|link| link.id) == Some(self.id) => {
+                session.outbound.take_shared()
+            }
+            _ => Vec::new(),
+        };
+
+        let removed = inner.sessions.detach(
+            &self.client.client_identifier,
+            self.id,
+            closed.session_expiry_interval.map(|sei| sei.min(maximum)),
+            Instant::now(),
+        );
+        if removed {
+            inner
+                .subscriptions
+                .unsubscribe_all(&self.client.client_identifier);
+        }
+        inner.redistribute(&self.client.client_identifier, shared);
+
+        tracing::debug!(
+            client_identifier = self.client.client_identifier,
+            "Connection closed"
+        );
+
+        let will = self.will.take().filter(|_| closed.publish_will)?;
+        if will.delay_interval == 0 || removed {
+            inner.publish_will(&self.client.client_identifier, will.message);
+            return None;
+        }
+
+        match inner.sessions.get_mut(&self.client.client_identifier) {
+            Some(session) if session.started_by != self.session => {
+                // A new connection took over and started a new session, the old one ended
+                inner.publish_will(&self.client.client_identifier, will.message);
+                None
+            }
+            Some(session) if session.connection.is_none() => {
+                // The will is published when the session ends, if that happens first
+                let delay = will.delay_interval.min(session.expiry_interval);
+                session.pending_will = Some(PendingWill {
+                    connection: self.id,
+                    message: will.message,
+                });
+                Some(Duration::from_secs(delay.into()))
+            }
+            // A new connection resumed the session before the will delay passed
+            Some(_) => None,
+            None => {
+                inner.publish_will(&self.client.client_identifier, will.message);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::connack::ConnackReasonCode;
+    use mqtt_format::v5::packets::connect::ConnectProperties;
+    use mqtt_format::v5::packets::connect::ConnectWillProperties;
+    use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::qos::QualityOfService;
+    use mqtt_format::v5::variable_header::SessionExpiryInterval;
+    use mqtt_format::v5::variable_header::WillDelayInterval;
+
+    use super::MqttServerConnectionError;
+    use crate::server::tests::connect;
+    use crate::server::tests::expect_nothing_pending;
+    use crate::server::tests::open_connection;
+    use crate::server::tests::subscribe;
+    use crate::server::tests::subscription_options;
+    use crate::server::tests::TestClient;
+    use crate::server::MqttServer;
+
+    /// Connect a client with a will on `will/<client_identifier>` and a session expiry of 60s
+    async fn connect_with_will(
+        server: &MqttServer,
+        client_identifier: &str,
+        clean_start: bool,
+        will_delay_interval: u32,
+        keep_alive: u16,
+    ) -> (
+        TestClient,
+        tokio::task::JoinHandle<Result<(), MqttServerConnectionError>>,
+    ) {
+        let topic = format!("will/{client_identifier}");
+        let mut will_properties = ConnectWillProperties::new();
+        will_properties.will_delay_interval = Some(WillDelayInterval(will_delay_interval));
+        let mut properties = ConnectProperties::new();
+        properties.session_expiry_interval = Some(SessionExpiryInterval(60));
+
+        let (mut client, serving) = open_connection(server);
+        client
+            .send(FormatMqttPacket::Connect(
+                mqtt_format::v5::packets::connect::MConnect {
+                    client_identifier,
+                    username: None,
+                    password: None,
+                    clean_start,
+                    will: Some(mqtt_format::v5::packets::connect::Will {
+                        properties: will_properties,
+                        topic: &topic,
+                        payload: b"gone",
+                        will_qos: QualityOfService::AtMostOnce,
+                        will_retain: false,
+                    }),
+                    properties,
+                    keep_alive,
+                },
+            ))
+            .await
+            .unwrap();
+
+        let connack = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Connack(connack) = connack.get() else {
+            panic!("Expected CONNACK, got {:?}", connack.get());
+        };
+        assert_eq!(connack.reason_code, ConnackReasonCode::Success);
+
+        (client, serving)
+    }
+
+    async fn disconnect(client: &mut TestClient, reason_code: DisconnectReasonCode) {
+        client
+            .send(FormatMqttPacket::Disconnect(
+                mqtt_format::v5::packets::disconnect::MDisconnect {
+                    reason_code,
+                    properties: mqtt_format::v5::packets::disconnect::DisconnectProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+    }
+
+    async fn expect_will(client: &mut TestClient, topic: &str) {
+        let packet = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish) = packet.get() else {
+            panic!("Expected PUBLISH, got {:?}", packet.get());
+        };
+        assert_eq!(publish.topic_name, topic);
+        assert_eq!(publish.payload, b"gone");
+    }
+
+    #[tokio::test]
+    async fn will_follows_the_disconnect_reason() {
+        let server = MqttServer::builder().build();
+        let (mut subscriber, _subscribing, _) = connect(&server, "subscriber", true).await;
+        subscribe(
+            &mut subscriber,
+            "will/#",
+            &subscription_options(QualityOfService::AtMostOnce),
+        )
+        .await;
+
+        let (client, serving) = connect_with_will(&server, "dropped", true, 0, 0).await;
+        drop(client);
+        serving.await.unwrap().unwrap();
+        expect_will(&mut subscriber, "will/dropped").await;
+
+        let (mut client, serving) = connect_with_will(&server, "normal", true, 0, 0).await;
+        disconnect(&mut client, DisconnectReasonCode::NormalDisconnection).await;
+        serving.await.unwrap().unwrap();
+        expect_nothing_pending(&mut subscriber).await;
+
+        let (mut client, serving) = connect_with_will(&server, "with-will", true, 0, 0).await;
+        disconnect(&mut client, DisconnectReasonCode::DisconnectWithWillMessage).await;
+        serving.await.unwrap().unwrap();
+        expect_will(&mut subscriber, "will/with-will").await;
+    }
+
+    #[tokio::test]
+    async fn delayed_will_is_cancelled_by_resuming_the_session() {
+        let server = MqttServer::builder().build();
+        let (mut subscriber, _subscribing, _) = connect(&server, "subscriber", true).await;
+        subscribe(
+            &mut subscriber,
+            "will/#",
+            &subscription_options(QualityOfService::AtMostOnce),
+        )
+        .await;
+
+        let (client, first_serving) = connect_with_will(&server, "client", true, 1, 0).await;
+        drop(client);
+        let (client, second_serving) = connect_with_will(&server, "client", false, 1, 0).await;
+
+        first_serving.await.unwrap().unwrap();
+        expect_nothing_pending(&mut subscriber).await;
+
+        drop(client);
+        second_serving.await.unwrap().unwrap();
+        expect_will(&mut subscriber, "will/client").await;
+
+        // Starting a new session ends the old one, which publishes its will right away
+        let (_third, third_serving) = connect_with_will(&server, "client", false, 60, 0).await;
+        let (_fourth, _fourth_serving) = connect_with_will(&server, "client", true, 60, 0).await;
+        third_serving.await.unwrap().unwrap();
+        expect_will(&mut subscriber, "will/client").await;
+    }
+
+    #[tokio::test]
+    async fn silent_clients_are_disconnected() {
+        let server = MqttServer::builder().build();
+        let (mut subscriber, _subscribing, _) = connect(&server, "subscriber", true).await;
+        subscribe(
+            &mut subscriber,
+            "will/#",
+            &subscription_options(QualityOfService::AtMostOnce),
+        )
+        .await;
+
+        let (mut client, serving) = connect_with_will(&server, "silent", true, 0, 1).await;
+        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        client
+            .send(FormatMqttPacket::Pingreq(
+                mqtt_format::v5::packets::pingreq::MPingreq,
+            ))
+            .await
+            .unwrap();
+        let packet = client.next().await.unwrap().unwrap();
+        assert!(matches!(packet.get(), FormatMqttPacket::Pingresp(_)));
+
+        let packet = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Disconnect(disconnect) = packet.get() else {
+            panic!("Expected DISCONNECT, got {:?}", packet.get());
+        };
+        assert_eq!(
+            disconnect.reason_code,
+            DisconnectReasonCode::KeepAliveTimeout
+        );
+        assert!(matches!(
+            serving.await.unwrap(),
+            Err(MqttServerConnectionError::KeepAliveTimeout)
+        ));
+        expect_will(&mut subscriber, "will/silent").await;
+    }
+
+    #[tokio::test]
+    async fn oversized_packets_are_refused_before_they_are_buffered() {
+        use tokio::io::AsyncWriteExt;
+
+        let server = MqttServer::builder().with_maximum_packet_size(1024).build();
+        let (mut client, serving, _) = connect(&server, "client", true).await;
+
+        // A PUBLISH announcing a remaining length of about 256 MiB, its body never follows
+        client
+            .get_mut()
+            .write_all(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F])
+            .await
+            .unwrap();
+
+        let packet = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Disconnect(disconnect) = packet.get() else {
+            panic!("Expected DISCONNECT, got {:?}", packet.get());
+        };
+        assert_eq!(disconnect.reason_code, DisconnectReasonCode::PacketTooLarge);
+        assert!(matches!(
+            serving.await.unwrap(),
+            Err(MqttServerConnectionError::PacketTooLarge { size: 268_435_460 })
+        ));
+    }
+}
@@ -0,0 +1,361 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::time::Duration;
+use std::time::SystemTime;
+
+use mqtt_format::v5::packets::connect::Will;
+use mqtt_format::v5::packets::publish::MPublish;
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+use tokio_util::bytes::BufMut;
+use tokio_util::bytes::Bytes;
+use tokio_util::bytes::BytesMut;
+
+use crate::packet_identifier::PacketIdentifier;
+use crate::packets::publish::PublishProperties;
+use crate::packets::MqttWriter;
+use crate::packets::MqttWriterError;
+use crate::packets::VecWriter;
+use crate::payload::MqttPayload;
+use crate::qos::QualityOfService;
+use crate::topic::MqttTopic;
+
+/// An application message as it is routed and stored by the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    topic: String,
+    payload: Bytes,
+    qos: QualityOfService,
+    retain: bool,
+    properties: PublishProperties,
+    expires_at: Option<SystemTime>,
+}
+
+impl Message {
+    pub fn new(topic: &MqttTopic, payload: MqttPayload, qos: QualityOfService) -> Self {
+        Self {
+            topic: topic.as_ref().to_string(),
+            payload: payload.into_bytes(),
+            qos,
+            retain: false,
+            properties: PublishProperties::new(),
+            expires_at: None,
+        }
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Set the properties sent with the message
+    ///
+    /// A message expiry interval in the properties starts counting now. Topic aliases and
+    /// subscription identifiers are chosen per subscriber and ignored here.
+    pub fn with_properties(mut self, properties: PublishProperties) -> Self {
+        self.properties = properties;
+        self.properties.topic_alias = None;
+        self.properties.subscription_identifiers = None;
+        self.expiring_from(SystemTime::now())
+    }
+
+    /// Take the message out of a PUBLISH received from a client
+    pub(crate) fn from_publish(publish: &MPublish<'_>, now: SystemTime) -> Self {
+        let mut properties = PublishProperties::from_format(&publish.properties);
+        properties.topic_alias = None;
+        properties.subscription_identifiers = None;
+
+        Self {
+            topic: publish.topic_name.to_string(),
+            payload: Bytes::copy_from_slice(publish.payload),
+            qos: publish.quality_of_service.into(),
+            retain: publish.retain,
+            properties,
+            expires_at: None,
+        }
+        .expiring_from(now)
+    }
+
+    /// Take the will message out of a CONNECT
+    ///
+    /// The message expiry interval only starts once the will is published, see
+    /// [`Message::expiring_from`].
+    pub(crate) fn from_will(will: &Will<'_>) -> Self {
+        let properties = mqtt_format::v5::packets::publish::PublishProperties {
+            payload_format_indicator: will.properties.payload_format_indicator.clone(),
+            message_expiry_interval: will.properties.message_expiry_interval.clone(),
+            topic_alias: None,
+            response_topic: will.properties.response_topic.clone(),
+            correlation_data: will.properties.correlation_data.clone(),
+            user_properties: will.properties.user_properties.clone(),
+            subscription_identifiers: None,
+            content_type: will.properties.content_type.clone(),
+        };
+
+        Self {
+            topic: will.topic.to_string(),
+            payload: Bytes::copy_from_slice(will.payload),
+            qos: will.will_qos.into(),
+            retain: will.will_retain,
+            properties: PublishProperties::from_format(&properties),
+            expires_at: None,
+        }
+    }
+
+    /// Start the message expiry interval of the message at `now`
+    pub(crate) fn expiring_from(mut self, now: SystemTime) -> Self {
+        self.expires_at = self
+            .properties
+            .message_expiry_interval
+            .map(|interval| now + Duration::from_secs(interval.into()));
+        self
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// The QoS the message was published with
+    pub fn qos(&self) -> QualityOfService {
+        self.qos
+    }
+
+    /// Whether the message was published with the retain flag
+    pub fn retain(&self) -> bool {
+        self.retain
+    }
+
+    pub fn properties(&self) -> &PublishProperties {
+        &self.properties
+    }
+
+    /// The point in time after which the message must not be delivered anymore
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Encode the message for storage, see [`Message::decode`]
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.topic.len() + self.payload.len() + 32);
+
+        buffer.put_u16(self.topic.len() as u16);
+        buffer.put_slice(self.topic.as_bytes());
+        buffer.put_u8(match self.qos {
+            QualityOfService::AtMostOnce => 0,
+            QualityOfService::AtLeastOnce => 1,
+            QualityOfService::ExactlyOnce => 2,
+        });
+        buffer.put_u8(self.retain.into());
+        // Expiry as milliseconds since the UNIX epoch, zero for messages that do not expire
+        buffer.put_u64(
+            self.expires_at
+                .map(|expires_at| {
+                    expires_at
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64
+                })
+                .unwrap_or(0),
+        );
+        self.properties
+            .as_ref()
+            .write(&mut VecWriter(&mut buffer))
+            .expect("Writing to a Vec does not fail");
+        buffer.put_slice(&self.payload);
+
+        buffer
+    }
+
+    /// Decode a message encoded with [`Message::encode`]
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        use tokio_util::bytes::Buf;
+
+        let mut input = bytes;
+        if input.remaining() < 2 {
+            return None;
+        }
+        let topic_len = input.get_u16() as usize;
+        if input.remaining() < topic_len + 10 {
+            return None;
+        }
+        let topic = std::str::from_utf8(&input[..topic_len]).ok()?.to_string();
+        input.advance(topic_len);
+
+        let qos = match input.get_u8() {
+            0 => QualityOfService::AtMostOnce,
+            1 => QualityOfService::AtLeastOnce,
+            2 => QualityOfService::ExactlyOnce,
+            _ => return None,
+        };
+        let retain = input.get_u8() != 0;
+        let expires_at = match input.get_u64() {
+            0 => None,
+            millis => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis)),
+        };
+
+        let mut properties_input = winnow::Bytes::new(input);
+        let properties =
+            mqtt_format::v5::packets::publish::PublishProperties::parse(&mut properties_input)
+                .ok()?;
+        let properties = PublishProperties::from_format(&properties);
+        let payload = Bytes::copy_from_slice(properties_input);
+
+        Some(Self {
+            topic,
+            payload,
+            qos,
+            retain,
+            properties,
+            expires_at,
+        })
+    }
+}
+
+/// A message on its way to one subscriber
+#[derive(Debug, Clone)]
+pub(crate) struct Delivery {
+    pub(crate) message: Message,
+    pub(crate) qos: QualityOfService,
+    pub(crate) retain: bool,
+    pub(crate) subscription_identifiers: Vec<u32>,
+    /// The shared subscription the message was delivered through, if any
+    pub(crate) shared_filter: Option<String>,
+}
+
+impl Delivery {
+    /// Encode the PUBLISH packet of this delivery
+    ///
+    /// The remaining message expiry interval is computed from `now`.
+    pub(crate) fn encode(
+        &self,
+        packet_identifier: Option<PacketIdentifier>,
+        duplicate: bool,
+        now: SystemTime,
+    ) -> Result<BytesMut, MqttWriterError> {
+        let mut properties = self.message.properties.clone();
+        if let Some(expires_at) = self.message.expires_at {
+            let remaining = expires_at.duration_since(now).unwrap_or_default();
+            properties.message_expiry_interval = Some(remaining.as_secs() as u32);
+        }
+
+        properties.subscription_identifiers = None;
+        for identifier in &self.subscription_identifiers {
+            properties.with_subscription_identifiers(*identifier);
+        }
+
+        let publish = FormatMqttPacket::Publish(MPublish {
+            duplicate,
+            quality_of_service: self.qos.into(),
+            retain: self.retain,
+            topic_name: &self.message.topic,
+            packet_identifier: packet_identifier.map(Into::into),
+            properties: properties.as_ref(),
+            payload: &self.message.payload,
+        });
+
+        let mut encoded = BytesMut::with_capacity(publish.binary_size() as usize);
+        publish.write(&mut MqttWriter(&mut encoded))?;
+
+        Ok(encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio_util::codec::Decoder;
+
+    use super::Delivery;
+    use super::Message;
+    use crate::codecs::MqttPacketCodec;
+    use crate::packets::publish::PublishProperties;
+    use crate::qos::QualityOfService;
+
+    fn message() -> Message {
+        let mut properties = PublishProperties::new();
+        properties
+            .with_content_type("text/plain".to_string())
+            .with_message_expiry_interval(60);
+
+        Message::new(
+            &"a/b".parse().unwrap(),
+            b"hello".as_slice().try_into().unwrap(),
+            QualityOfService::AtLeastOnce,
+        )
+        .with_retain(true)
+        .with_properties(properties)
+    }
+
+    #[test]
+    fn storage_roundtrip() {
+        let message = message();
+        let decoded = Message::decode(&message.encode()).unwrap();
+
+        assert_eq!(decoded.topic(), "a/b");
+        assert_eq!(decoded.payload(), b"hello");
+        assert_eq!(decoded.qos(), QualityOfService::AtLeastOnce);
+        assert!(decoded.retain());
+        assert_eq!(decoded.properties(), message.properties());
+        // Expiry is stored with millisecond precision
+        let difference = message
+            .expires_at()
+            .unwrap()
+            .duration_since(decoded.expires_at().unwrap())
+            .unwrap();
+        assert!(difference < Duration::from_millis(1));
+
+        assert!(Message::decode(&message.encode()[..5]).is_none());
+    }
+
+    #[test]
+    fn delivery_carries_all_subscription_identifiers() {
+        let delivery = Delivery {
+            message: message(),
+            qos: QualityOfService::AtLeastOnce,
+            retain: false,
+            subscription_identifiers: vec![1, 200, 70000],
+            shared_filter: None,
+        };
+
+        let now = message().expires_at().unwrap() - Duration::from_secs(30);
+        let mut encoded = delivery
+            .encode(
+                Some(std::num::NonZeroU16::new(7).unwrap().into()),
+                false,
+                now,
+            )
+            .unwrap();
+        let encoded_len = encoded.len();
+
+        let packet = MqttPacketCodec.decode(&mut encoded).unwrap().unwrap();
+        assert_eq!(packet.encoded_len(), encoded_len);
+        let mqtt_format::v5::packets::MqttPacket::Publish(publish) = packet.get() else {
+            panic!("Expected PUBLISH, got {:?}", packet.get());
+        };
+        assert_eq!(publish.payload, b"hello");
+        assert_eq!(publish.properties.content_type().unwrap().0, "text/plain");
+        assert!(publish.properties.message_expiry_interval().unwrap().0 <= 30);
+
+        assert_eq!(
+            publish
+                .properties
+                .subscription_identifiers()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![1, 200, 70000]
+        );
+    }
+}
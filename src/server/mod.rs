@@ -0,0 +1,881 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! An MQTT v5 server
+//!
+//! [`MqttServer`] accepts connections on any [`MqttConnectTransport`], validates their CONNECT
+//! and keeps track of the session of every client. Each connection is served by its own future,
+//! either driven by the caller through [`MqttServer::serve_connection`] or spawned onto a
+//! [`Runtime`] by [`MqttServer::serve`].
+
+pub mod auth;
+pub mod bridge;
+pub mod builder;
+mod connect;
+pub mod connection;
+pub mod message;
+mod outbound;
+mod publish;
+pub mod retained;
+mod session;
+pub mod shared;
+mod statistics;
+mod subscribe;
+pub mod subscriptions;
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use futures::lock::Mutex;
+use futures::select;
+use futures::FutureExt;
+use futures::Stream;
+use futures::StreamExt;
+
+use self::auth::Authenticator;
+use self::auth::Authorizer;
+use self::auth::PeerInfo;
+use self::builder::MqttServerBuilder;
+use self::builder::ServerConfig;
+use self::connection::MqttServerConnectionError;
+use self::message::Delivery;
+use self::message::Message;
+use self::retained::RetainedStore;
+use self::session::ConnectionCommand;
+use self::session::ConnectionId;
+use self::session::SessionRegistry;
+use self::statistics::Statistics;
+use self::subscriptions::SubscriptionOptions;
+use self::subscriptions::SubscriptionTree;
+use crate::payload::MqttPayload;
+use crate::qos::QualityOfService;
+use crate::runtime::Runtime;
+use crate::topic::MqttTopic;
+use crate::transport::MqttConnectTransport;
+
+struct InnerServer {
+    config: ServerConfig,
+    sessions: SessionRegistry,
+    subscriptions: SubscriptionTree<String>,
+    retained: Box<dyn RetainedStore>,
+    authenticator: Arc<dyn Authenticator>,
+    authorizer: Arc<dyn Authorizer>,
+    statistics: Arc<Statistics>,
+    next_assigned_identifier: u64,
+}
+
+impl InnerServer {
+    /// Remove expired sessions together with their subscriptions, publishing pending wills
+    fn purge_expired_sessions(&mut self, now: Instant) {
+        for session in self.sessions.purge_expired(now) {
+            self.subscriptions
+                .unsubscribe_all(&session.client_identifier);
+
+            if let Some(will) = session.pending_will {
+                self.publish_will(&session.client_identifier, will.message);
+            }
+        }
+    }
+
+    /// Publish the will left behind by `connection`, unless the session was resumed since
+    fn publish_delayed_will(&mut self, client_identifier: &str, connection: ConnectionId) {
+        self.purge_expired_sessions(Instant::now());
+
+        let Some(session) = self.sessions.get_mut(client_identifier) else {
+            return;
+        };
+        if session
+            .pending_will
+            .as_ref()
+            .is_some_and(|will| will.connection == connection)
+        {
+            if let Some(will) = session.pending_will.take() {
+                self.publish_will(client_identifier, will.message);
+            }
+        }
+    }
+
+    fn publish_will(&mut self, client_identifier: &str, message: Message) {
+        tracing::debug!(
+            client_identifier,
+            topic = message.topic(),
+            "Publishing will message"
+        );
+        self.publish(&message.expiring_from(SystemTime::now()), client_identifier);
+    }
+
+    /// Publish the statistics of the server as retained messages to `$SYS/broker/...` topics
+    fn publish_statistics(&mut self, now: Instant) {
+        let gauges = [
+            (
+                "$SYS/broker/clients/connected",
+                self.sessions.connected().count(),
+            ),
+            ("$SYS/broker/clients/total", self.sessions.len()),
+            ("$SYS/broker/subscriptions/count", self.subscriptions.len()),
+            ("$SYS/broker/retained messages/count", self.retained.len()),
+        ]
+        .map(|(topic, value)| (topic, value as u64));
+
+        for (topic, value) in self.statistics.topics(now).into_iter().chain(gauges) {
+            let (Ok(topic), Ok(payload)) = (
+                MqttTopic::from_str(topic),
+                MqttPayload::try_from(value.to_string().into_bytes()),
+            ) else {
+                continue;
+            };
+
+            let message =
+                Message::new(&topic, payload, QualityOfService::AtMostOnce).with_retain(true);
+            // The server itself is the publisher, which no client identifier can be confused with
+            self.publish(&message, "");
+        }
+    }
+
+    /// Store `message` if it is retained, then route it to its subscribers
+    ///
+    /// Returns whether any subscription matched.
+    fn publish(&mut self, message: &Message, publisher: &str) -> bool {
+        if message.retain() {
+            // A retained message without payload only clears the retained message
+            let stored = if message.payload().is_empty() {
+                self.retained.remove(message.topic())
+            } else {
+                self.retained.insert(message.clone())
+            };
+
+            if let Err(error) = stored {
+                tracing::warn!(%error, topic = message.topic(), "Could not store retained message");
+            }
+        }
+
+        self.route(message, publisher)
+    }
+
+    /// Hand `message` to the sessions of all subscribers matching its topic
+    ///
+    /// Shared subscriptions hand it to one of their members. Returns whether any subscription
+    /// matched.
+    fn route(&mut self, message: &Message, publisher: &str) -> bool {
+        let matches = self.subscriptions.matches(message.topic(), Some(publisher));
+
+        for subscription_match in &matches {
+            let delivery = Delivery {
+                message: message.clone(),
+                qos: message.qos().min(subscription_match.qos),
+                retain: message.retain() && subscription_match.retain_as_published,
+                subscription_identifiers: subscription_match.subscription_identifiers.clone(),
+                shared_filter: None,
+            };
+            self.deliver_to(&subscription_match.subscriber, delivery);
+        }
+
+        let shared_matches = self.subscriptions.shared_matches(message.topic());
+        for shared_match in &shared_matches {
+            let Some((member, options)) =
+                self.choose_member(&shared_match.members, shared_match.turn, publisher, None)
+            else {
+                continue;
+            };
+
+            let delivery = Delivery {
+                message: message.clone(),
+                qos: message.qos().min(options.qos),
+                retain: message.retain() && options.retain_as_published,
+                subscription_identifiers: options.subscription_identifier.into_iter().collect(),
+                shared_filter: Some(shared_match.shared_filter.clone()),
+            };
+            let member = member.clone();
+            self.deliver_to(&member, delivery);
+        }
+
+        !matches.is_empty() || !shared_matches.is_empty()
+    }
+
+    /// Hand messages of shared subscriptions that `client_identifier` did not receive to other
+    /// members of the subscriptions
+    ///
+    /// Messages no other member is left for stay in the session of the client, if it still
+    /// exists.
+    fn redistribute(&mut self, client_identifier: &str, deliveries: Vec<Delivery>) {
+        for mut delivery in deliveries {
+            let member = delivery
+                .shared_filter
+                .as_deref()
+                .and_then(|shared_filter| self.subscriptions.shared_group(shared_filter))
+                .and_then(|group| {
+                    self.choose_member(
+                        &group.members,
+                        group.turn,
+                        client_identifier,
+                        Some(client_identifier),
+                    )
+                    .cloned()
+                });
+
+            match member {
+                Some((member, options)) => {
+                    tracing::trace!(
+                        client_identifier,
+                        member,
+                        topic = delivery.message.topic(),
+                        "Handing message of shared subscription to another member"
+                    );
+                    delivery.qos = delivery.message.qos().min(options.qos);
+                    delivery.retain = delivery.message.retain() && options.retain_as_published;
+                    delivery.subscription_identifiers =
+                        options.subscription_identifier.into_iter().collect();
+                    self.deliver_to(&member, delivery);
+                }
+                None => {
+                    let maximum = self.config.maximum_queued_messages;
+                    if let Some(session) = self.sessions.get_mut(client_identifier) {
+                        session.outbound.push(delivery, maximum);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Choose the member of a shared subscription that receives the next message
+    ///
+    /// Connected members are preferred, members without a session and `excluded` are never
+    /// chosen.
+    fn choose_member<'a>(
+        &self,
+        members: &'a [(String, SubscriptionOptions)],
+        turn: usize,
+        publisher: &str,
+        excluded: Option<&str>,
+    ) -> Option<&'a (String, SubscriptionOptions)> {
+        let eligible = members
+            .iter()
+            .filter(|(member, _)| Some(member.as_str()) != excluded)
+            .filter(|(member, _)| self.sessions.contains(member))
+            .collect::<Vec<_>>();
+        let connected = eligible
+            .iter()
+            .copied()
+            .filter(|(member, _)| {
+                self.sessions
+                    .get(member)
+                    .is_some_and(|session| session.connection.is_some())
+            })
+            .collect::<Vec<_>>();
+
+        let candidates = if connected.is_empty() {
+            eligible
+        } else {
+            connected
+        };
+        self.config.shared_subscription_strategy.choose(
+            &candidates,
+            turn,
+            publisher,
+            |(member, _)| {
+                self.sessions
+                    .get(member)
+                    .map_or(0, |session| session.outbound.unacknowledged())
+            },
+        )
+    }
+
+    /// Hand a message to the session of `subscriber`
+    ///
+    /// QoS 0 messages are sent to connected subscribers only, QoS 1 and 2 messages are queued in
+    /// the session until its client can receive them.
+    fn deliver_to(&mut self, subscriber: &str, delivery: Delivery) {
+        let Some(session) = self.sessions.get_mut(subscriber) else {
+            return;
+        };
+
+        let command = if delivery.qos == QualityOfService::AtMostOnce {
+            ConnectionCommand::Deliver(delivery)
+        } else {
+            session
+                .outbound
+                .push(delivery, self.config.maximum_queued_messages);
+            ConnectionCommand::SendQueued
+        };
+
+        match &session.connection {
+            Some(link) => {
+                let _ = link.commands.unbounded_send(command);
+            }
+            None => tracing::trace!(
+                client_identifier = subscriber,
+                queued = matches!(command, ConnectionCommand::SendQueued),
+                "Subscriber is not connected"
+            ),
+        }
+    }
+}
+
+/// Create a client identifier that no session uses yet
+fn assign_client_identifier(next: &mut u64, sessions: &SessionRegistry) -> String {
+    loop {
+        *next += 1;
+        let client_identifier = format!("cloudmqtt-{next}");
+        if !sessions.contains(&client_identifier) {
+            return client_identifier;
+        }
+    }
+}
+
+/// A handle to the server, cloning it is cheap and all clones share the same sessions
+#[derive(Clone)]
+pub struct MqttServer {
+    inner: Arc<Mutex<InnerServer>>,
+}
+
+impl MqttServer {
+    pub fn builder() -> MqttServerBuilder {
+        MqttServerBuilder::new()
+    }
+
+    /// Serve a single client until its connection is closed
+    ///
+    /// If the client left a will message with a will delay interval, the future completes once
+    /// the will was published or cancelled by the client resuming its session.
+    pub async fn serve_connection(
+        &self,
+        transport: MqttConnectTransport,
+    ) -> Result<(), MqttServerConnectionError> {
+        self.serve_connection_with_peer(transport, PeerInfo::default())
+            .await
+    }
+
+    /// Serve a single client, passing what is known about its peer to the authenticator
+    pub async fn serve_connection_with_peer(
+        &self,
+        transport: MqttConnectTransport,
+        peer: PeerInfo,
+    ) -> Result<(), MqttServerConnectionError> {
+        connection::serve_connection(self.inner.clone(), transport, peer).await
+    }
+
+    /// Serve every connection from `incoming` on its own task, until `incoming` ends
+    ///
+    /// Errors of the listener are logged and otherwise ignored. While serving, the statistics of
+    /// the server are published at the configured interval.
+    pub async fn serve<S>(&self, incoming: S, runtime: &dyn Runtime)
+    where
+        S: Stream<Item = std::io::Result<MqttConnectTransport>> + Send,
+    {
+        let incoming =
+            incoming.map(|transport| transport.map(|transport| (transport, PeerInfo::default())));
+        self.serve_with_peers(incoming, runtime).await
+    }
+
+    /// Like [`MqttServer::serve`], passing what is known about the peer of every connection to
+    /// the authenticator
+    ///
+    /// Listeners terminating TLS use this to hand the certificates of their clients to the
+    /// server.
+    pub async fn serve_with_peers<S>(&self, incoming: S, runtime: &dyn Runtime)
+    where
+        S: Stream<Item = std::io::Result<(MqttConnectTransport, PeerInfo)>> + Send,
+    {
+        let accepting = async {
+            futures::pin_mut!(incoming);
+
+            while let Some(connection) = incoming.next().await {
+                let (transport, peer) = match connection {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        tracing::warn!(%error, "Could not accept connection");
+                        continue;
+                    }
+                };
+
+                let server = self.clone();
+                runtime.spawn(Box::pin(async move {
+                    if let Err(error) = server.serve_connection_with_peer(transport, peer).await {
+                        tracing::debug!(%error, "Connection ended with an error");
+                    }
+                }));
+            }
+        };
+
+        let accepting = accepting.fuse();
+        let publishing = self.publish_statistics_periodically().fuse();
+        futures::pin_mut!(accepting, publishing);
+
+        select! {
+            () = accepting => {},
+            () = publishing => {},
+        }
+    }
+
+    /// Publish the statistics of the server to its `$SYS/broker/...` topics right away
+    ///
+    /// [`MqttServer::serve`] does this periodically, servers driving their connections through
+    /// [`MqttServer::serve_connection`] can call it themselves.
+    pub async fn publish_statistics(&self) {
+        self.inner.lock().await.publish_statistics(Instant::now());
+    }
+
+    /// Publish the statistics at the configured interval, forever
+    async fn publish_statistics_periodically(&self) {
+        let Some(interval) = self.inner.lock().await.config.statistics_interval else {
+            return futures::future::pending().await;
+        };
+
+        loop {
+            self.publish_statistics().await;
+            futures_timer::Delay::new(interval).await;
+        }
+    }
+
+    /// The client identifiers of all currently connected clients
+    pub async fn connected_clients(&self) -> Vec<String> {
+        self.inner
+            .lock()
+            .await
+            .sessions
+            .connected()
+            .map(String::from)
+            .collect()
+    }
+
+    /// The number of sessions the server keeps, including those of disconnected clients
+    pub async fn session_count(&self) -> usize {
+        self.inner.lock().await.sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::connack::ConnackReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::codec::Framed;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::connection::MqttServerConnectionError;
+    use super::MqttServer;
+    use crate::codecs::MqttPacketCodec;
+    use crate::transport::MqttConnectTransport;
+    use crate::transport::MqttConnection;
+
+    pub(crate) type TestClient = Framed<MqttConnection, MqttPacketCodec>;
+
+    /// Open an in-memory connection to `server`, served on a separate task
+    pub(crate) fn open_connection(
+        server: &MqttServer,
+    ) -> (
+        TestClient,
+        tokio::task::JoinHandle<Result<(), MqttServerConnectionError>>,
+    ) {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let server = server.clone();
+        let serving = tokio::spawn(async move {
+            server
+                .serve_connection(MqttConnectTransport::TokioDuplex(server_stream))
+                .await
+        });
+
+        (
+            Framed::new(
+                MqttConnection::Duplex(client_stream.compat()),
+                MqttPacketCodec,
+            ),
+            serving,
+        )
+    }
+
+    pub(crate) fn connect_packet(
+        client_identifier: &str,
+        clean_start: bool,
+    ) -> FormatMqttPacket<'_> {
+        connect_packet_with_properties(
+            client_identifier,
+            clean_start,
+            mqtt_format::v5::packets::connect::ConnectProperties::new(),
+        )
+    }
+
+    pub(crate) fn connect_packet_with_properties<'a>(
+        client_identifier: &'a str,
+        clean_start: bool,
+        properties: mqtt_format::v5::packets::connect::ConnectProperties<'a>,
+    ) -> FormatMqttPacket<'a> {
+        FormatMqttPacket::Connect(mqtt_format::v5::packets::connect::MConnect {
+            client_identifier,
+            username: None,
+            password: None,
+            clean_start,
+            will: None,
+            properties,
+            keep_alive: 0,
+        })
+    }
+
+    /// Connect a test client and assert that the server accepted it
+    pub(crate) async fn connect(
+        server: &MqttServer,
+        client_identifier: &str,
+        clean_start: bool,
+    ) -> (
+        TestClient,
+        tokio::task::JoinHandle<Result<(), MqttServerConnectionError>>,
+        bool,
+    ) {
+        connect_with_properties(
+            server,
+            client_identifier,
+            clean_start,
+            mqtt_format::v5::packets::connect::ConnectProperties::new(),
+        )
+        .await
+    }
+
+    pub(crate) async fn connect_with_properties(
+        server: &MqttServer,
+        client_identifier: &str,
+        clean_start: bool,
+        properties: mqtt_format::v5::packets::connect::ConnectProperties<'_>,
+    ) -> (
+        TestClient,
+        tokio::task::JoinHandle<Result<(), MqttServerConnectionError>>,
+        bool,
+    ) {
+        let (mut client, serving) = open_connection(server);
+        client
+            .send(connect_packet_with_properties(
+                client_identifier,
+                clean_start,
+                properties,
+            ))
+            .await
+            .unwrap();
+
+        let connack = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Connack(connack) = connack.get() else {
+            panic!("Expected CONNACK, got {:?}", connack.get());
+        };
+        assert_eq!(connack.reason_code, ConnackReasonCode::Success);
+        let session_present = connack.session_present;
+
+        (client, serving, session_present)
+    }
+
+    pub(crate) async fn subscribe(
+        client: &mut TestClient,
+        topic_filter: &str,
+        options: &mqtt_format::v5::packets::subscribe::SubscriptionOptions,
+    ) -> mqtt_format::v5::packets::suback::SubackReasonCode {
+        let mut encoded = Vec::new();
+        mqtt_format::v5::packets::subscribe::Subscription {
+            topic_filter,
+            options: options.clone(),
+        }
+        .write(&mut crate::packets::VecWriter(&mut encoded))
+        .unwrap();
+
+        client
+            .send(FormatMqttPacket::Subscribe(
+                mqtt_format::v5::packets::subscribe::MSubscribe {
+                    packet_identifier: packet_identifier(1),
+                    properties: mqtt_format::v5::packets::subscribe::SubscribeProperties::new(),
+                    subscriptions:
+                        mqtt_format::v5::packets::subscribe::Subscriptions::from_encoded(&encoded)
+                            .unwrap(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        let suback = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Suback(suback) = suback.get() else {
+            panic!("Expected SUBACK, got {:?}", suback.get());
+        };
+        suback.reasons[0]
+    }
+
+    pub(crate) fn subscription_options(
+        quality_of_service: mqtt_format::v5::qos::QualityOfService,
+    ) -> mqtt_format::v5::packets::subscribe::SubscriptionOptions {
+        mqtt_format::v5::packets::subscribe::SubscriptionOptions {
+            quality_of_service,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling:
+                mqtt_format::v5::packets::subscribe::RetainHandling::SendRetainedMessagesAlways,
+        }
+    }
+
+    pub(crate) fn packet_identifier(id: u16) -> mqtt_format::v5::variable_header::PacketIdentifier {
+        mqtt_format::v5::variable_header::PacketIdentifier(std::num::NonZeroU16::new(id).unwrap())
+    }
+
+    pub(crate) fn publish_packet<'a>(
+        topic_name: &'a str,
+        payload: &'a [u8],
+        quality_of_service: mqtt_format::v5::qos::QualityOfService,
+        retain: bool,
+    ) -> FormatMqttPacket<'a> {
+        FormatMqttPacket::Publish(mqtt_format::v5::packets::publish::MPublish {
+            duplicate: false,
+            quality_of_service,
+            retain,
+            topic_name,
+            packet_identifier: (quality_of_service
+                != mqtt_format::v5::qos::QualityOfService::AtMostOnce)
+                .then(|| packet_identifier(1)),
+            properties: mqtt_format::v5::packets::publish::PublishProperties::new(),
+            payload,
+        })
+    }
+
+    /// The parts of a PUBLISH received by a test client
+    #[derive(Debug)]
+    pub(crate) struct ReceivedPublish {
+        pub(crate) topic: String,
+        pub(crate) payload: Vec<u8>,
+        pub(crate) qos: mqtt_format::v5::qos::QualityOfService,
+        pub(crate) packet_identifier: Option<u16>,
+        pub(crate) duplicate: bool,
+        pub(crate) retain: bool,
+    }
+
+    /// Receive the next packet on `client`, asserting that it is a PUBLISH
+    pub(crate) async fn expect_publish<T>(
+        client: &mut Framed<T, MqttPacketCodec>,
+    ) -> ReceivedPublish
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin,
+    {
+        let packet = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish) = packet.get() else {
+            panic!("Expected PUBLISH, got {:?}", packet.get());
+        };
+
+        ReceivedPublish {
+            topic: publish.topic_name.to_string(),
+            payload: publish.payload.to_vec(),
+            qos: publish.quality_of_service,
+            packet_identifier: publish.packet_identifier.map(|id| id.0.get()),
+            duplicate: publish.duplicate,
+            retain: publish.retain,
+        }
+    }
+
+    /// Acknowledge the QoS 1 message with the packet identifier `id`
+    pub(crate) async fn puback<T>(client: &mut Framed<T, MqttPacketCodec>, id: u16)
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin,
+    {
+        client
+            .send(FormatMqttPacket::Puback(
+                mqtt_format::v5::packets::puback::MPuback {
+                    packet_identifier: packet_identifier(id),
+                    reason: mqtt_format::v5::packets::puback::PubackReasonCode::Success,
+                    properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+    }
+
+    /// Assert that the server sent nothing else to `client`, by waiting for the answer to a ping
+    pub(crate) async fn expect_nothing_pending(client: &mut TestClient) {
+        client
+            .send(FormatMqttPacket::Pingreq(
+                mqtt_format::v5::packets::pingreq::MPingreq,
+            ))
+            .await
+            .unwrap();
+        let packet = client.next().await.unwrap().unwrap();
+        assert!(
+            matches!(packet.get(), FormatMqttPacket::Pingresp(_)),
+            "Expected PINGRESP, got {:?}",
+            packet.get()
+        );
+    }
+
+    #[tokio::test]
+    async fn connack_advertises_capabilities() {
+        let server = MqttServer::builder()
+            .with_maximum_qos(crate::qos::QualityOfService::AtLeastOnce)
+            .with_retain_available(false)
+            .with_receive_maximum(std::num::NonZeroU16::new(10).unwrap())
+            .with_shared_subscription_available(false)
+            .with_maximum_keep_alive(std::num::NonZeroU16::new(60).unwrap())
+            .build();
+
+        let (mut client, _serving) = open_connection(&server);
+        client.send(connect_packet("", true)).await.unwrap();
+
+        let connack = client.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Connack(connack) = connack.get() else {
+            panic!("Expected CONNACK, got {:?}", connack.get());
+        };
+        assert_eq!(connack.reason_code, ConnackReasonCode::Success);
+
+        let properties = &connack.properties;
+        assert_eq!(
+            properties.maximum_qos().unwrap().0,
+            mqtt_format::v5::qos::MaximumQualityOfService::AtLeastOnce
+        );
+        assert!(!properties.retain_available().unwrap().0);
+        assert_eq!(properties.receive_maximum().unwrap().0.get(), 10);
+        assert_eq!(properties.shared_scubscription_available().unwrap().0, 0);
+        assert_eq!(properties.server_keep_alive().unwrap().0, 60);
+
+        let assigned = properties.assigned_client_identifier().unwrap().0;
+        assert_eq!(server.connected_clients().await, [assigned]);
+    }
+
+    #[tokio::test]
+    async fn unsupported_protocol_version_is_refused() {
+        use tokio::io::AsyncWriteExt;
+
+        let server = MqttServer::builder().build();
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_connection(MqttConnectTransport::TokioDuplex(server_stream))
+                    .await
+            }
+        });
+
+        // An MQTT 3.1.1 CONNECT with client identifier "a"
+        client_write
+            .write_all(&[
+                0x10, 0x0D, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3C, 0x00, 0x01,
+                b'a',
+            ])
+            .await
+            .unwrap();
+
+        let mut connack = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut client_read, &mut connack)
+            .await
+            .unwrap();
+        assert_eq!(connack[0], 0x20);
+        assert_eq!(connack[3], 0x84);
+
+        assert!(matches!(
+            serving.await.unwrap(),
+            Err(MqttServerConnectionError::Rejected {
+                reason_code: ConnackReasonCode::UnsupportedProtocolVersion
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_connect_is_refused_before_it_is_buffered() {
+        use tokio::io::AsyncWriteExt;
+
+        let server = MqttServer::builder().with_maximum_packet_size(1024).build();
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_connection(MqttConnectTransport::TokioDuplex(server_stream))
+                    .await
+            }
+        });
+
+        // A CONNECT announcing the largest possible remaining length, about 256 MiB
+        client_write
+            .write_all(&[0x10, 0xFF, 0xFF, 0xFF, 0x7F])
+            .await
+            .unwrap();
+
+        let mut connack = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut client_read, &mut connack)
+            .await
+            .unwrap();
+        assert_eq!(connack[0], 0x20);
+        assert_eq!(connack[3], 0x95);
+
+        assert!(matches!(
+            serving.await.unwrap(),
+            Err(MqttServerConnectionError::Rejected {
+                reason_code: ConnackReasonCode::PacketTooLarge
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn session_takeover() {
+        let server = MqttServer::builder().build();
+
+        let (mut first, first_serving, session_present) = connect(&server, "client", false).await;
+        assert!(!session_present);
+
+        let (mut second, _second_serving, session_present) =
+            connect(&server, "client", false).await;
+        assert!(session_present);
+
+        let disconnect = first.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Disconnect(disconnect) = disconnect.get() else {
+            panic!("Expected DISCONNECT, got {:?}", disconnect.get());
+        };
+        assert_eq!(
+            disconnect.reason_code,
+            mqtt_format::v5::packets::disconnect::DisconnectReasonCode::SessionTakenOver
+        );
+        assert!(first_serving.await.unwrap().is_ok());
+
+        // The new connection is still served after the old one closed
+        second
+            .send(FormatMqttPacket::Pingreq(
+                mqtt_format::v5::packets::pingreq::MPingreq,
+            ))
+            .await
+            .unwrap();
+        let pingresp = second.next().await.unwrap().unwrap();
+        assert!(matches!(pingresp.get(), FormatMqttPacket::Pingresp(_)));
+        assert_eq!(server.connected_clients().await, ["client"]);
+    }
+
+    #[tokio::test]
+    async fn client_connects_to_server() {
+        let server = MqttServer::builder().build();
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_connection(MqttConnectTransport::TokioDuplex(server_stream))
+                    .await
+            }
+        });
+
+        let client = crate::client::MqttClient::new_with_default_handlers();
+        let connected = client
+            .connect(crate::client::connect::MqttClientConnector::new(
+                MqttConnectTransport::TokioDuplex(client_stream),
+                crate::client_identifier::ProposedClientIdentifier::new_minimal_required("client")
+                    .unwrap(),
+                crate::client::connect::CleanStart::Yes,
+                crate::keep_alive::KeepAlive::Disabled,
+            ))
+            .await
+            .unwrap();
+        let background = tokio::spawn(connected.background_task);
+
+        client.ping().await.unwrap().response().await.unwrap();
+
+        connected.connection_handle.shutdown().await;
+        assert!(background.await.unwrap().is_ok());
+        assert!(serving.await.unwrap().is_ok());
+        assert_eq!(server.session_count().await, 0);
+    }
+}
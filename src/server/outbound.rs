@@ -0,0 +1,719 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Messages on their way from the server to a client
+//!
+//! QoS 1 and 2 messages are kept in the [`Outbound`] state of the session until the client
+//! acknowledged them, so that they survive the connection and are sent again when the client
+//! resumes its session.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::num::NonZeroU16;
+use std::time::SystemTime;
+
+use futures::SinkExt;
+use mqtt_format::v5::packets::puback::MPuback;
+use mqtt_format::v5::packets::pubcomp::MPubcomp;
+use mqtt_format::v5::packets::pubrec::MPubrec;
+use mqtt_format::v5::packets::pubrec::PubrecReasonCode;
+use mqtt_format::v5::packets::pubrel::MPubrel;
+use mqtt_format::v5::packets::pubrel::PubrelReasonCode;
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+use tokio_util::bytes::BytesMut;
+
+use super::connection::Connection;
+use super::connection::MqttServerConnectionError;
+use super::message::Delivery;
+use crate::codecs::MqttPacketCodecError;
+use crate::packet_identifier::next_free_packet_identifier;
+use crate::packet_identifier::PacketIdentifier;
+use crate::packets::MqttWriter;
+use crate::packets::MqttWriterError;
+use crate::qos::QualityOfService;
+
+struct Inflight {
+    delivery: Delivery,
+    /// Whether the client sent a PUBREC and the server its PUBREL, for QoS 2 messages
+    released: bool,
+    /// Whether the message has to be sent again because the client resumed its session
+    resend: bool,
+}
+
+/// The QoS 1 and 2 messages of a session that were not acknowledged by the client yet
+pub(crate) struct Outbound {
+    next_packet_identifier: NonZeroU16,
+    /// Messages waiting for room in the inflight window
+    queue: VecDeque<Delivery>,
+    inflight: BTreeMap<PacketIdentifier, Inflight>,
+    inflight_order: Vec<PacketIdentifier>,
+}
+
+impl Outbound {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_packet_identifier: NonZeroU16::MIN,
+            queue: VecDeque::new(),
+            inflight: BTreeMap::new(),
+            inflight_order: Vec::new(),
+        }
+    }
+
+    /// Queue a message, dropping the oldest queued one if `maximum` messages are queued already
+    pub(crate) fn push(&mut self, delivery: Delivery, maximum: usize) {
+        debug_assert_ne!(delivery.qos, QualityOfService::AtMostOnce);
+
+        if self.queue.len() >= maximum {
+            if let Some(dropped) = self.queue.pop_front() {
+                tracing::debug!(
+                    topic = dropped.message.topic(),
+                    "Queue of the session is full, dropping the oldest message"
+                );
+            }
+        }
+
+        self.queue.push_back(delivery);
+    }
+
+    /// Send inflight messages again, then move queued messages into the inflight window while it
+    /// is smaller than `receive_maximum`
+    ///
+    /// Returns the encoded packets. Messages marked by [`Outbound::resend`] come first and keep
+    /// their packet identifier. Messages that expired while they were queued or that are larger
+    /// than `maximum_packet_size` are dropped.
+    pub(crate) fn fill_window(
+        &mut self,
+        receive_maximum: NonZeroU16,
+        maximum_packet_size: Option<u32>,
+        now: SystemTime,
+    ) -> Result<Vec<BytesMut>, MqttWriterError> {
+        let receive_maximum = receive_maximum.get() as usize;
+        let mut packets = Vec::new();
+        let mut window = self
+            .inflight
+            .values()
+            .filter(|inflight| !inflight.resend)
+            .count();
+
+        for packet_identifier in self.inflight_order.clone() {
+            let Some(inflight) = self.inflight.get_mut(&packet_identifier) else {
+                continue;
+            };
+            if !inflight.resend {
+                continue;
+            }
+
+            // The PUBLISH was received already, the PUBREL has to be sent regardless of the window
+            if inflight.released {
+                inflight.resend = false;
+                window += 1;
+                packets.push(encode_pubrel(packet_identifier, PubrelReasonCode::Success)?);
+                continue;
+            }
+
+            if window >= receive_maximum {
+                continue;
+            }
+
+            let Some(encoded) = encode_publish(
+                &inflight.delivery,
+                Some(packet_identifier),
+                true,
+                maximum_packet_size,
+                now,
+            )?
+            else {
+                self.remove(packet_identifier);
+                continue;
+            };
+
+            inflight.resend = false;
+            window += 1;
+            packets.push(encoded);
+        }
+
+        while window < receive_maximum {
+            let Some(delivery) = self.queue.pop_front() else {
+                break;
+            };
+
+            if delivery.message.is_expired(now) {
+                tracing::trace!(topic = delivery.message.topic(), "Dropping expired message");
+                continue;
+            }
+
+            let Some(packet_identifier) =
+                next_free_packet_identifier(&mut self.next_packet_identifier, |ident| {
+                    self.inflight.contains_key(&ident)
+                })
+            else {
+                self.queue.push_front(delivery);
+                break;
+            };
+
+            let Some(encoded) = encode_publish(
+                &delivery,
+                Some(packet_identifier),
+                false,
+                maximum_packet_size,
+                now,
+            )?
+            else {
+                continue;
+            };
+
+            self.next_packet_identifier = self
+                .next_packet_identifier
+                .checked_add(1)
+                .unwrap_or(NonZeroU16::MIN);
+            self.inflight.insert(
+                packet_identifier,
+                Inflight {
+                    delivery,
+                    released: false,
+                    resend: false,
+                },
+            );
+            self.inflight_order.push(packet_identifier);
+            window += 1;
+            packets.push(encoded);
+        }
+
+        Ok(packets)
+    }
+
+    /// Mark all inflight messages to be sent again by [`Outbound::fill_window`]
+    ///
+    /// Unacknowledged PUBLISH packets are sent as duplicates, released QoS 2 messages as PUBREL.
+    /// Returns the number of marked messages.
+    pub(crate) fn resend(&mut self) -> usize {
+        for inflight in self.inflight.values_mut() {
+            inflight.resend = true;
+        }
+        self.inflight.len()
+    }
+
+    /// Handle a PUBACK, returning whether it acknowledged a QoS 1 message
+    pub(crate) fn acknowledge(&mut self, packet_identifier: PacketIdentifier) -> bool {
+        let known = self
+            .inflight
+            .get(&packet_identifier)
+            .is_some_and(|inflight| inflight.delivery.qos == QualityOfService::AtLeastOnce);
+        if known {
+            self.remove(packet_identifier);
+        }
+        known
+    }
+
+    /// Handle a PUBREC, returning whether it belongs to a QoS 2 message
+    ///
+    /// A message the client accepted has to be released with a PUBREL, one it refused is done.
+    pub(crate) fn receive(&mut self, packet_identifier: PacketIdentifier, accepted: bool) -> bool {
+        let Some(inflight) = self.inflight.get_mut(&packet_identifier) else {
+            return false;
+        };
+        if inflight.delivery.qos != QualityOfService::ExactlyOnce {
+            return false;
+        }
+
+        if accepted {
+            inflight.released = true;
+        } else {
+            self.remove(packet_identifier);
+        }
+        true
+    }
+
+    /// Handle a PUBCOMP, returning whether it completed a released QoS 2 message
+    pub(crate) fn complete(&mut self, packet_identifier: PacketIdentifier) -> bool {
+        let known = self
+            .inflight
+            .get(&packet_identifier)
+            .is_some_and(|inflight| inflight.released);
+        if known {
+            self.remove(packet_identifier);
+        }
+        known
+    }
+
+    /// The number of messages the client has not acknowledged yet, including queued ones
+    pub(crate) fn unacknowledged(&self) -> usize {
+        self.queue.len() + self.inflight.len()
+    }
+
+    /// Remove the messages of shared subscriptions the client has not received yet
+    ///
+    /// Released QoS 2 messages stay, the client received them already.
+    pub(crate) fn take_shared(&mut self) -> Vec<Delivery> {
+        let mut taken = Vec::new();
+
+        for packet_identifier in self.inflight_order.clone() {
+            let inflight = &self.inflight[&packet_identifier];
+            if !inflight.released && inflight.delivery.shared_filter.is_some() {
+                if let Some(inflight) = self.inflight.remove(&packet_identifier) {
+                    taken.push(inflight.delivery);
+                }
+            }
+        }
+        self.inflight_order
+            .retain(|packet_identifier| self.inflight.contains_key(packet_identifier));
+
+        let (shared, own) = self
+            .queue
+            .drain(..)
+            .partition(|delivery| delivery.shared_filter.is_some());
+        self.queue = own;
+        taken.extend::<VecDeque<_>>(shared);
+
+        taken
+    }
+
+    fn remove(&mut self, packet_identifier: PacketIdentifier) {
+        self.inflight_order.retain(|&id| id != packet_identifier);
+        self.inflight.remove(&packet_identifier);
+    }
+}
+
+/// Encode the PUBLISH of `delivery`, unless it is larger than `maximum_packet_size`
+fn encode_publish(
+    delivery: &Delivery,
+    packet_identifier: Option<PacketIdentifier>,
+    duplicate: bool,
+    maximum_packet_size: Option<u32>,
+    now: SystemTime,
+) -> Result<Option<BytesMut>, MqttWriterError> {
+    let encoded = delivery.encode(packet_identifier, duplicate, now)?;
+
+    if maximum_packet_size.is_some_and(|maximum| encoded.len() > maximum as usize) {
+        tracing::debug!(
+            topic = delivery.message.topic(),
+            size = encoded.len(),
+            "Dropping message larger than the maximum packet size of the client"
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(encoded))
+}
+
+fn encode_pubrel(
+    packet_identifier: PacketIdentifier,
+    reason: PubrelReasonCode,
+) -> Result<BytesMut, MqttWriterError> {
+    let pubrel = FormatMqttPacket::Pubrel(MPubrel {
+        packet_identifier: packet_identifier.into(),
+        reason,
+        properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+    });
+
+    let mut encoded = BytesMut::with_capacity(pubrel.binary_size() as usize);
+    pubrel.write(&mut MqttWriter(&mut encoded))?;
+    Ok(encoded)
+}
+
+impl Connection {
+    /// Send a message to the client
+    ///
+    /// QoS 0 messages are written right away, others are queued in the session and sent once
+    /// the inflight window has room for them.
+    pub(super) async fn deliver(
+        &mut self,
+        delivery: Delivery,
+    ) -> Result<(), MqttServerConnectionError> {
+        let now = SystemTime::now();
+
+        if delivery.qos != QualityOfService::AtMostOnce {
+            {
+                let mut inner = self.inner.lock().await;
+                let maximum = inner.config.maximum_queued_messages;
+                if let Some(session) = inner.sessions.get_mut(&self.client.client_identifier) {
+                    session.outbound.push(delivery, maximum);
+                }
+            }
+            return self.send_queued().await;
+        }
+
+        if delivery.message.is_expired(now) {
+            tracing::trace!(topic = delivery.message.topic(), "Dropping expired message");
+            return Ok(());
+        }
+
+        let encoded = encode_publish(&delivery, None, false, self.client_maximum_packet_size, now)
+            .map_err(|error| MqttServerConnectionError::Send(MqttPacketCodecError::from(error)))?;
+
+        self.write_encoded(encoded.into_iter().collect()).await
+    }
+
+    /// Send as many queued messages of the session as the inflight window allows
+    pub(super) async fn send_queued(&mut self) -> Result<(), MqttServerConnectionError> {
+        let packets = {
+            let mut inner = self.inner.lock().await;
+            let Some(session) = inner.sessions.get_mut(&self.client.client_identifier) else {
+                return Ok(());
+            };
+
+            session.outbound.fill_window(
+                self.client_receive_maximum,
+                self.client_maximum_packet_size,
+                SystemTime::now(),
+            )
+        };
+
+        let packets = packets
+            .map_err(|error| MqttServerConnectionError::Send(MqttPacketCodecError::from(error)))?;
+        self.write_encoded(packets).await
+    }
+
+    /// Send the messages the client did not acknowledge before it resumed its session
+    pub(super) async fn resume(&mut self) -> Result<(), MqttServerConnectionError> {
+        let resent = self
+            .inner
+            .lock()
+            .await
+            .sessions
+            .get_mut(&self.client.client_identifier)
+            .map_or(0, |session| session.outbound.resend());
+        if resent > 0 {
+            tracing::debug!(count = resent, "Resending unacknowledged messages");
+        }
+
+        self.send_queued().await
+    }
+
+    pub(super) async fn handle_puback(
+        &mut self,
+        puback: &MPuback<'_>,
+    ) -> Result<(), MqttServerConnectionError> {
+        let packet_identifier = PacketIdentifier::from(puback.packet_identifier);
+        let acknowledged = self
+            .inner
+            .lock()
+            .await
+            .sessions
+            .get_mut(&self.client.client_identifier)
+            .is_some_and(|session| session.outbound.acknowledge(packet_identifier));
+
+        if !acknowledged {
+            tracing::debug!(%packet_identifier, "Received PUBACK for unknown packet identifier");
+            return Ok(());
+        }
+
+        self.send_queued().await
+    }
+
+    pub(super) async fn handle_pubrec(
+        &mut self,
+        pubrec: &MPubrec<'_>,
+    ) -> Result<(), MqttServerConnectionError> {
+        let packet_identifier = PacketIdentifier::from(pubrec.packet_identifier);
+        let accepted = matches!(
+            pubrec.reason,
+            PubrecReasonCode::Success | PubrecReasonCode::NoMatchingSubscribers
+        );
+
+        let known = self
+            .inner
+            .lock()
+            .await
+            .sessions
+            .get_mut(&self.client.client_identifier)
+            .is_some_and(|session| session.outbound.receive(packet_identifier, accepted));
+
+        if !accepted {
+            tracing::debug!(%packet_identifier, reason = ?pubrec.reason, "Client refused message");
+            return self.send_queued().await;
+        }
+
+        let reason = if known {
+            PubrelReasonCode::Success
+        } else {
+            PubrelReasonCode::PacketIdentifierNotFound
+        };
+        self.send(FormatMqttPacket::Pubrel(MPubrel {
+            packet_identifier: pubrec.packet_identifier,
+            reason,
+            properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+        }))
+        .await
+    }
+
+    pub(super) async fn handle_pubcomp(
+        &mut self,
+        pubcomp: &MPubcomp<'_>,
+    ) -> Result<(), MqttServerConnectionError> {
+        let packet_identifier = PacketIdentifier::from(pubcomp.packet_identifier);
+        let completed = self
+            .inner
+            .lock()
+            .await
+            .sessions
+            .get_mut(&self.client.client_identifier)
+            .is_some_and(|session| session.outbound.complete(packet_identifier));
+
+        if !completed {
+            tracing::debug!(%packet_identifier, "Received PUBCOMP for unknown packet identifier");
+            return Ok(());
+        }
+
+        self.send_queued().await
+    }
+
+    async fn write_encoded(
+        &mut self,
+        packets: Vec<BytesMut>,
+    ) -> Result<(), MqttServerConnectionError> {
+        if packets.is_empty() {
+            return Ok(());
+        }
+
+        for packet in packets {
+            // The packet type is in the upper four bits of the first byte, 3 is PUBLISH
+            let publish = packet.first().is_some_and(|byte| byte >> 4 == 3);
+            self.statistics.sent(packet.len(), publish);
+            self.writer.write_buffer_mut().extend_from_slice(&packet);
+        }
+        self.writer
+            .flush()
+            .await
+            .map_err(MqttServerConnectionError::Send)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::connect::ConnectProperties;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::qos::QualityOfService;
+    use mqtt_format::v5::variable_header::MaximumPacketSize;
+    use mqtt_format::v5::variable_header::ReceiveMaximum;
+    use mqtt_format::v5::variable_header::SessionExpiryInterval;
+
+    use crate::server::tests::connect;
+    use crate::server::tests::connect_with_properties;
+    use crate::server::tests::expect_nothing_pending;
+    use crate::server::tests::expect_publish;
+    use crate::server::tests::packet_identifier;
+    use crate::server::tests::puback;
+    use crate::server::tests::publish_packet;
+    use crate::server::tests::subscribe;
+    use crate::server::tests::subscription_options;
+    use crate::server::tests::TestClient;
+    use crate::server::MqttServer;
+
+    /// Publish a message and wait for the server to acknowledge it
+    async fn publish(client: &mut TestClient, payload: &[u8], qos: QualityOfService) {
+        client
+            .send(publish_packet("a", payload, qos, false))
+            .await
+            .unwrap();
+
+        if qos == QualityOfService::ExactlyOnce {
+            client.next().await.unwrap().unwrap();
+            client
+                .send(FormatMqttPacket::Pubrel(
+                    mqtt_format::v5::packets::pubrel::MPubrel {
+                        packet_identifier: packet_identifier(1),
+                        reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
+                        properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+        }
+        if qos != QualityOfService::AtMostOnce {
+            client.next().await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn inflight_window_follows_receive_maximum() {
+        let server = MqttServer::builder().build();
+        let (mut publisher, _publishing, _) = connect(&server, "publisher", true).await;
+
+        let mut properties = ConnectProperties::new();
+        properties.receive_maximum = Some(ReceiveMaximum(std::num::NonZeroU16::new(1).unwrap()));
+        let (mut subscriber, _subscribing, _) =
+            connect_with_properties(&server, "subscriber", true, properties).await;
+        subscribe(
+            &mut subscriber,
+            "a",
+            &subscription_options(QualityOfService::AtLeastOnce),
+        )
+        .await;
+
+        // Messages are downgraded to the granted QoS
+        publish(&mut publisher, b"first", QualityOfService::ExactlyOnce).await;
+        publish(&mut publisher, b"second", QualityOfService::AtLeastOnce).await;
+        publish(&mut publisher, b"third", QualityOfService::AtMostOnce).await;
+
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"first");
+        assert_eq!(received.qos, QualityOfService::AtLeastOnce);
+        assert_eq!(received.packet_identifier, Some(1));
+        assert!(!received.duplicate);
+        // QoS 0 messages are not limited by the window
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"third");
+        assert_eq!(received.qos, QualityOfService::AtMostOnce);
+        assert_eq!(received.packet_identifier, None);
+        assert!(!received.duplicate);
+        expect_nothing_pending(&mut subscriber).await;
+
+        puback(&mut subscriber, 1).await;
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"second");
+        assert_eq!(received.qos, QualityOfService::AtLeastOnce);
+        assert_eq!(received.packet_identifier, Some(2));
+        assert!(!received.duplicate);
+        puback(&mut subscriber, 2).await;
+        expect_nothing_pending(&mut subscriber).await;
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_messages_are_resent_on_resume() {
+        let server = MqttServer::builder().build();
+        let (mut publisher, _publishing, _) = connect(&server, "publisher", true).await;
+
+        let persistent = || {
+            let mut properties = ConnectProperties::new();
+            properties.session_expiry_interval = Some(SessionExpiryInterval(60));
+            properties
+        };
+
+        let (mut subscriber, subscribing, _) =
+            connect_with_properties(&server, "subscriber", true, persistent()).await;
+        subscribe(
+            &mut subscriber,
+            "a",
+            &subscription_options(QualityOfService::ExactlyOnce),
+        )
+        .await;
+
+        publish(&mut publisher, b"once", QualityOfService::AtLeastOnce).await;
+        publish(&mut publisher, b"exactly", QualityOfService::ExactlyOnce).await;
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"once");
+        assert_eq!(received.qos, QualityOfService::AtLeastOnce);
+        assert_eq!(received.packet_identifier, Some(1));
+        assert!(!received.duplicate);
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"exactly");
+        assert_eq!(received.qos, QualityOfService::ExactlyOnce);
+        assert_eq!(received.packet_identifier, Some(2));
+        assert!(!received.duplicate);
+
+        subscriber
+            .send(FormatMqttPacket::Pubrec(
+                mqtt_format::v5::packets::pubrec::MPubrec {
+                    packet_identifier: packet_identifier(2),
+                    reason: mqtt_format::v5::packets::pubrec::PubrecReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+        let pubrel = subscriber.next().await.unwrap().unwrap();
+        assert!(matches!(pubrel.get(), FormatMqttPacket::Pubrel(_)));
+
+        drop(subscriber);
+        subscribing.await.unwrap().unwrap();
+
+        // Messages for the offline session are queued
+        publish(&mut publisher, b"offline", QualityOfService::AtLeastOnce).await;
+
+        let (mut subscriber, _subscribing, session_present) =
+            connect_with_properties(&server, "subscriber", false, persistent()).await;
+        assert!(session_present);
+
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"once");
+        assert_eq!(received.qos, QualityOfService::AtLeastOnce);
+        assert_eq!(received.packet_identifier, Some(1));
+        assert!(received.duplicate);
+        let pubrel = subscriber.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Pubrel(pubrel) = pubrel.get() else {
+            panic!("Expected PUBREL, got {:?}", pubrel.get());
+        };
+        assert_eq!(pubrel.packet_identifier, packet_identifier(2));
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"offline");
+        assert_eq!(received.qos, QualityOfService::AtLeastOnce);
+        assert_eq!(received.packet_identifier, Some(3));
+        assert!(!received.duplicate);
+
+        puback(&mut subscriber, 1).await;
+        subscriber
+            .send(FormatMqttPacket::Pubcomp(
+                mqtt_format::v5::packets::pubcomp::MPubcomp {
+                    packet_identifier: packet_identifier(2),
+                    reason: mqtt_format::v5::packets::pubcomp::PubcompReasonCode::Success,
+                    properties: mqtt_format::v5::packets::pubcomp::PubcompProperties::new(),
+                },
+            ))
+            .await
+            .unwrap();
+        puback(&mut subscriber, 3).await;
+        expect_nothing_pending(&mut subscriber).await;
+    }
+
+    #[tokio::test]
+    async fn resent_messages_follow_the_limits_of_the_new_connection() {
+        let server = MqttServer::builder().build();
+        let (mut publisher, _publishing, _) = connect(&server, "publisher", true).await;
+
+        let mut properties = ConnectProperties::new();
+        properties.session_expiry_interval = Some(SessionExpiryInterval(60));
+        let (mut subscriber, subscribing, _) =
+            connect_with_properties(&server, "subscriber", true, properties).await;
+        subscribe(
+            &mut subscriber,
+            "a",
+            &subscription_options(QualityOfService::AtLeastOnce),
+        )
+        .await;
+
+        let large = [0; 100];
+        publish(&mut publisher, b"first", QualityOfService::AtLeastOnce).await;
+        publish(&mut publisher, &large, QualityOfService::AtLeastOnce).await;
+        publish(&mut publisher, b"second", QualityOfService::AtLeastOnce).await;
+        for id in 1..=3 {
+            assert_eq!(
+                expect_publish(&mut subscriber).await.packet_identifier,
+                Some(id)
+            );
+        }
+
+        drop(subscriber);
+        subscribing.await.unwrap().unwrap();
+
+        let mut properties = ConnectProperties::new();
+        properties.session_expiry_interval = Some(SessionExpiryInterval(60));
+        properties.receive_maximum = Some(ReceiveMaximum(std::num::NonZeroU16::new(1).unwrap()));
+        properties.maximum_packet_size = Some(MaximumPacketSize(50));
+        let (mut subscriber, _subscribing, session_present) =
+            connect_with_properties(&server, "subscriber", false, properties).await;
+        assert!(session_present);
+
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"first");
+        assert_eq!(received.qos, QualityOfService::AtLeastOnce);
+        assert_eq!(received.packet_identifier, Some(1));
+        assert!(received.duplicate);
+        expect_nothing_pending(&mut subscriber).await;
+
+        // The large message does not fit the maximum packet size anymore and is dropped
+        puback(&mut subscriber, 1).await;
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.payload, b"second");
+        assert_eq!(received.qos, QualityOfService::AtLeastOnce);
+        assert_eq!(received.packet_identifier, Some(3));
+        assert!(received.duplicate);
+        puback(&mut subscriber, 3).await;
+        expect_nothing_pending(&mut subscriber).await;
+    }
+}
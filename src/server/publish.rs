@@ -0,0 +1,338 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+use mqtt_format::v5::packets::puback::MPuback;
+use mqtt_format::v5::packets::puback::PubackReasonCode;
+use mqtt_format::v5::packets::pubcomp::MPubcomp;
+use mqtt_format::v5::packets::pubcomp::PubcompReasonCode;
+use mqtt_format::v5::packets::publish::MPublish;
+use mqtt_format::v5::packets::pubrec::MPubrec;
+use mqtt_format::v5::packets::pubrec::PubrecReasonCode;
+use mqtt_format::v5::packets::pubrel::MPubrel;
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+use super::auth::Action;
+use super::connection::Connection;
+use super::connection::MqttServerConnectionError;
+use super::message::Message;
+use crate::packet_identifier::PacketIdentifier;
+use crate::qos::QualityOfService;
+use crate::topic::MqttTopic;
+
+/// What became of a message published by the client
+enum Outcome {
+    Routed,
+    NoMatchingSubscribers,
+    NotAuthorized,
+}
+
+impl Connection {
+    /// Route a message published by the client and acknowledge it
+    pub(super) async fn handle_publish(
+        &mut self,
+        publish: &MPublish<'_>,
+    ) -> Result<(), MqttServerConnectionError> {
+        // The server does not announce a topic alias maximum, so clients may not use any
+        if publish.properties.topic_alias().is_some() {
+            return Err(MqttServerConnectionError::CapabilityExceeded {
+                reason_code: DisconnectReasonCode::TopicAliasInvalid,
+            });
+        }
+
+        if publish.topic_name.is_empty() {
+            return Err(MqttServerConnectionError::ProtocolError {
+                reason: "MQTT-3.3.2-1",
+            });
+        }
+
+        // Topic names may not contain wildcards, they would be stored and matched like filters
+        if MqttTopic::from_str(publish.topic_name).is_err() {
+            return Err(MqttServerConnectionError::TopicNameInvalid {
+                topic: publish.topic_name.to_owned(),
+            });
+        }
+
+        let qos = QualityOfService::from(publish.quality_of_service);
+        let packet_identifier = publish.packet_identifier.map(PacketIdentifier::from);
+        let authorized = self
+            .authorizer
+            .authorize(
+                &self.client,
+                Action::Publish {
+                    topic: publish.topic_name,
+                },
+            )
+            .await;
+
+        let response = {
+            let mut inner = self.inner.lock().await;
+            let inner = &mut *inner;
+
+            if qos > inner.config.maximum_qos {
+                return Err(MqttServerConnectionError::CapabilityExceeded {
+                    reason_code: DisconnectReasonCode::QoSNotSupported,
+                });
+            }
+
+            if publish.retain && !inner.config.retain_available {
+                return Err(MqttServerConnectionError::CapabilityExceeded {
+                    reason_code: DisconnectReasonCode::RetainNotSupported,
+                });
+            }
+
+            let mut duplicate = false;
+            if let (true, QualityOfService::ExactlyOnce, Some(packet_identifier)) =
+                (authorized, qos, packet_identifier)
+            {
+                let receive_maximum = inner.config.receive_maximum.get() as usize;
+                if let Some(session) = inner.sessions.get_mut(&self.client.client_identifier) {
+                    if session.awaiting_release.contains(&packet_identifier) {
+                        tracing::debug!(
+                            %packet_identifier,
+                            "Received duplicate QoS 2 message, not routing it again"
+                        );
+                        duplicate = true;
+                    } else if session.awaiting_release.len() >= receive_maximum {
+                        return Err(MqttServerConnectionError::CapabilityExceeded {
+                            reason_code: DisconnectReasonCode::ReceiveMaximumExceeded,
+                        });
+                    } else {
+                        session.awaiting_release.insert(packet_identifier);
+                    }
+                }
+            }
+
+            let outcome = if !authorized {
+                tracing::debug!(
+                    topic = publish.topic_name,
+                    "Client may not publish to topic"
+                );
+                Outcome::NotAuthorized
+            } else if duplicate
+                || inner.publish(
+                    &Message::from_publish(publish, SystemTime::now()),
+                    &self.client.client_identifier,
+                )
+            {
+                Outcome::Routed
+            } else {
+                Outcome::NoMatchingSubscribers
+            };
+
+            match (qos, packet_identifier) {
+                (QualityOfService::AtMostOnce, _) => None,
+                (QualityOfService::AtLeastOnce, Some(packet_identifier)) => {
+                    Some(FormatMqttPacket::Puback(MPuback {
+                        packet_identifier: packet_identifier.into(),
+                        reason: match outcome {
+                            Outcome::Routed => PubackReasonCode::Success,
+                            Outcome::NoMatchingSubscribers => {
+                                PubackReasonCode::NoMatchingSubscribers
+                            }
+                            Outcome::NotAuthorized => PubackReasonCode::NotAuthorized,
+                        },
+                        properties: mqtt_format::v5::packets::puback::PubackProperties::new(),
+                    }))
+                }
+                (QualityOfService::ExactlyOnce, Some(packet_identifier)) => {
+                    Some(FormatMqttPacket::Pubrec(MPubrec {
+                        packet_identifier: packet_identifier.into(),
+                        reason: match outcome {
+                            Outcome::Routed => PubrecReasonCode::Success,
+                            Outcome::NoMatchingSubscribers => {
+                                PubrecReasonCode::NoMatchingSubscribers
+                            }
+                            Outcome::NotAuthorized => PubrecReasonCode::NotAuthorized,
+                        },
+                        properties: mqtt_format::v5::packets::pubrec::PubrecProperties::new(),
+                    }))
+                }
+                (_, None) => {
+                    return Err(MqttServerConnectionError::ProtocolError {
+                        reason: "QoS 1 and 2 PUBLISH packets require a packet identifier",
+                    });
+                }
+            }
+        };
+
+        if let Some(response) = response {
+            self.send(response).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Complete the QoS 2 flow of a message the client published
+    pub(super) async fn handle_pubrel(
+        &mut self,
+        pubrel: &MPubrel<'_>,
+    ) -> Result<(), MqttServerConnectionError> {
+        let packet_identifier = PacketIdentifier::from(pubrel.packet_identifier);
+
+        let released = self
+            .inner
+            .lock()
+            .await
+            .sessions
+            .get_mut(&self.client.client_identifier)
+            .is_some_and(|session| session.awaiting_release.remove(&packet_identifier));
+
+        self.send(FormatMqttPacket::Pubcomp(MPubcomp {
+            packet_identifier: pubrel.packet_identifier,
+            reason: if released {
+                PubcompReasonCode::Success
+            } else {
+                PubcompReasonCode::PacketIdentifierNotFound
+            },
+            properties: mqtt_format::v5::packets::pubcomp::PubcompProperties::new(),
+        }))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+    use mqtt_format::v5::packets::puback::PubackReasonCode;
+    use mqtt_format::v5::packets::pubcomp::PubcompReasonCode;
+    use mqtt_format::v5::packets::pubrec::PubrecReasonCode;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::qos::QualityOfService;
+
+    use crate::server::tests::connect;
+    use crate::server::tests::expect_nothing_pending;
+    use crate::server::tests::packet_identifier;
+    use crate::server::tests::publish_packet;
+    use crate::server::tests::subscribe;
+    use crate::server::tests::subscription_options;
+    use crate::server::MqttServer;
+    use crate::server::MqttServerConnectionError;
+
+    #[tokio::test]
+    async fn messages_are_routed_to_subscribers() {
+        let server = MqttServer::builder().build();
+        let (mut publisher, _publishing, _) = connect(&server, "publisher", true).await;
+        let (mut subscriber, _subscribing, _) = connect(&server, "subscriber", true).await;
+
+        publisher
+            .send(publish_packet(
+                "a/b",
+                b"lost",
+                QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        let puback = publisher.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Puback(puback) = puback.get() else {
+            panic!("Expected PUBACK, got {:?}", puback.get());
+        };
+        assert_eq!(puback.reason, PubackReasonCode::NoMatchingSubscribers);
+
+        subscribe(
+            &mut subscriber,
+            "a/#",
+            &subscription_options(QualityOfService::AtMostOnce),
+        )
+        .await;
+        // The publisher does not receive its own messages with no_local
+        let mut no_local = subscription_options(QualityOfService::AtMostOnce);
+        no_local.no_local = true;
+        subscribe(&mut publisher, "a/#", &no_local).await;
+
+        // A retransmitted QoS 2 message is only routed once
+        for _ in 0..2 {
+            publisher
+                .send(publish_packet(
+                    "a/b",
+                    b"once",
+                    QualityOfService::ExactlyOnce,
+                    false,
+                ))
+                .await
+                .unwrap();
+            let pubrec = publisher.next().await.unwrap().unwrap();
+            let FormatMqttPacket::Pubrec(pubrec) = pubrec.get() else {
+                panic!("Expected PUBREC, got {:?}", pubrec.get());
+            };
+            assert_eq!(pubrec.reason, PubrecReasonCode::Success);
+        }
+
+        let publish = subscriber.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish) = publish.get() else {
+            panic!("Expected PUBLISH, got {:?}", publish.get());
+        };
+        assert_eq!(publish.topic_name, "a/b");
+        assert_eq!(publish.payload, b"once");
+        expect_nothing_pending(&mut subscriber).await;
+
+        for expected in [
+            PubcompReasonCode::Success,
+            PubcompReasonCode::PacketIdentifierNotFound,
+        ] {
+            publisher
+                .send(FormatMqttPacket::Pubrel(
+                    mqtt_format::v5::packets::pubrel::MPubrel {
+                        packet_identifier: packet_identifier(1),
+                        reason: mqtt_format::v5::packets::pubrel::PubrelReasonCode::Success,
+                        properties: mqtt_format::v5::packets::pubrel::PubrelProperties::new(),
+                    },
+                ))
+                .await
+                .unwrap();
+            let pubcomp = publisher.next().await.unwrap().unwrap();
+            let FormatMqttPacket::Pubcomp(pubcomp) = pubcomp.get() else {
+                panic!("Expected PUBCOMP, got {:?}", pubcomp.get());
+            };
+            assert_eq!(pubcomp.reason, expected);
+        }
+        expect_nothing_pending(&mut publisher).await;
+    }
+
+    #[tokio::test]
+    async fn wildcard_topic_names_are_rejected() {
+        let server = MqttServer::builder().build();
+        let (mut publisher, publishing, _) = connect(&server, "publisher", true).await;
+
+        publisher
+            .send(publish_packet(
+                "a/+/b",
+                b"wildcard",
+                QualityOfService::AtMostOnce,
+                true,
+            ))
+            .await
+            .unwrap();
+        let packet = publisher.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Disconnect(disconnect) = packet.get() else {
+            panic!("Expected DISCONNECT, got {:?}", packet.get());
+        };
+        assert_eq!(
+            disconnect.reason_code,
+            DisconnectReasonCode::TopicNameInvalid
+        );
+        assert!(matches!(
+            publishing.await.unwrap(),
+            Err(MqttServerConnectionError::TopicNameInvalid { topic }) if topic == "a/+/b"
+        ));
+
+        // Nothing was retained under the wildcard
+        let (mut subscriber, _subscribing, _) = connect(&server, "subscriber", true).await;
+        subscribe(
+            &mut subscriber,
+            "#",
+            &subscription_options(QualityOfService::AtMostOnce),
+        )
+        .await;
+        expect_nothing_pending(&mut subscriber).await;
+    }
+}
@@ -0,0 +1,377 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Storage of retained messages
+//!
+//! The server keeps the last retained message of every topic in a [`RetainedStore`]. Stores are
+//! called while the server state is locked, so they should not block for long.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use super::message::Message;
+use crate::topic::MqttTopicFilter;
+
+pub trait RetainedStore: Send {
+    /// Store `message` as the retained message of its topic, replacing the previous one
+    fn insert(&mut self, message: Message) -> std::io::Result<()>;
+
+    /// Remove the retained message of `topic`, if there is one
+    fn remove(&mut self, topic: &str) -> std::io::Result<()>;
+
+    /// All retained messages with a topic matching `filter` that have not expired at `now`
+    fn matching(
+        &mut self,
+        filter: &MqttTopicFilter,
+        now: SystemTime,
+    ) -> std::io::Result<Vec<Message>>;
+
+    /// The number of stored messages, including expired ones that were not removed yet
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A [`RetainedStore`] that keeps messages in memory only
+#[derive(Debug, Default)]
+pub struct MemoryRetainedStore {
+    messages: HashMap<String, Message>,
+}
+
+impl MemoryRetainedStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn remove_expired(&mut self, now: SystemTime) -> Vec<String> {
+        let expired = self
+            .messages
+            .iter()
+            .filter(|(_, message)| message.is_expired(now))
+            .map(|(topic, _)| topic.clone())
+            .collect::<Vec<_>>();
+
+        for topic in &expired {
+            self.messages.remove(topic);
+        }
+
+        expired
+    }
+}
+
+impl RetainedStore for MemoryRetainedStore {
+    fn insert(&mut self, message: Message) -> std::io::Result<()> {
+        self.messages.insert(message.topic().to_string(), message);
+        Ok(())
+    }
+
+    fn remove(&mut self, topic: &str) -> std::io::Result<()> {
+        self.messages.remove(topic);
+        Ok(())
+    }
+
+    fn matching(
+        &mut self,
+        filter: &MqttTopicFilter,
+        now: SystemTime,
+    ) -> std::io::Result<Vec<Message>> {
+        self.remove_expired(now);
+
+        Ok(self
+            .messages
+            .values()
+            .filter(|message| filter.matches(message.topic()))
+            .cloned()
+            .collect())
+    }
+
+    fn len(&self) -> usize {
+        self.messages.len()
+    }
+}
+
+const RECORD_INSERT: u8 = 1;
+const RECORD_REMOVE: u8 = 2;
+
+/// A [`RetainedStore`] that persists messages in a file
+///
+/// Changes are appended to the file as they happen, and all messages are kept in memory as well.
+pub struct FileRetainedStore {
+    path: PathBuf,
+    file: File,
+    memory: MemoryRetainedStore,
+    records: usize,
+}
+
+impl FileRetainedStore {
+    /// Open the store at `path`, creating the file if it does not exist
+    ///
+    /// A record that was only partially written, for example because the process was killed, is
+    /// discarded.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let mut memory = MemoryRetainedStore::new();
+
+        let mut contents = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut contents)?;
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+
+        let mut input = &contents[..];
+        while let Some((kind, record, rest)) = split_record(input) {
+            match kind {
+                RECORD_INSERT => {
+                    let Some(message) = Message::decode(record) else {
+                        tracing::warn!(path = %path.display(), "Skipping invalid retained message");
+                        input = rest;
+                        continue;
+                    };
+                    memory.insert(message)?;
+                }
+                RECORD_REMOVE => {
+                    if let Ok(topic) = std::str::from_utf8(record) {
+                        memory.remove(topic)?;
+                    }
+                }
+                _ => break,
+            }
+            input = rest;
+        }
+
+        if !input.is_empty() {
+            tracing::warn!(
+                path = %path.display(),
+                bytes = input.len(),
+                "Discarding incomplete record at the end of the retained message store"
+            );
+        }
+
+        memory.remove_expired(SystemTime::now());
+
+        let file = rewrite(&path, &memory)?;
+        Ok(Self {
+            records: memory.len(),
+            path,
+            file,
+            memory,
+        })
+    }
+
+    fn append(&mut self, kind: u8, data: &[u8]) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(data.len() + 5);
+        write_record(&mut record, kind, data);
+        self.file.write_all(&record)?;
+        self.records += 1;
+
+        // Rewrite the file once it contains mostly outdated records
+        if self.records > 2 * self.memory.len() + 64 {
+            self.file = rewrite(&self.path, &self.memory)?;
+            self.records = self.memory.len();
+        }
+
+        Ok(())
+    }
+}
+
+/// Replace the file at `path` with one containing only the messages in `memory`
+///
+/// Returns the file opened for appending further records.
+fn rewrite(path: &Path, memory: &MemoryRetainedStore) -> std::io::Result<File> {
+    let temporary = path.with_extension("tmp");
+
+    let mut contents = Vec::new();
+    for message in memory.messages.values() {
+        write_record(&mut contents, RECORD_INSERT, &message.encode());
+    }
+
+    let mut file = File::create(&temporary)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+
+    std::fs::OpenOptions::new().append(true).open(path)
+}
+
+/// Records are a kind byte, the length of the data as four bytes and the data itself
+fn write_record(buffer: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    buffer.push(kind);
+    buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(data);
+}
+
+fn split_record(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&kind, rest) = input.split_first()?;
+    let length = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+    let record = rest.get(4..4 + length)?;
+
+    Some((kind, record, &rest[4 + length..]))
+}
+
+impl RetainedStore for FileRetainedStore {
+    fn insert(&mut self, message: Message) -> std::io::Result<()> {
+        // The memory is updated first, as appending might rewrite the file from it
+        let encoded = message.encode();
+        self.memory.insert(message)?;
+        self.append(RECORD_INSERT, &encoded)
+    }
+
+    fn remove(&mut self, topic: &str) -> std::io::Result<()> {
+        if !self.memory.messages.contains_key(topic) {
+            return Ok(());
+        }
+
+        self.memory.remove(topic)?;
+        self.append(RECORD_REMOVE, topic.as_bytes())
+    }
+
+    fn matching(
+        &mut self,
+        filter: &MqttTopicFilter,
+        now: SystemTime,
+    ) -> std::io::Result<Vec<Message>> {
+        for topic in self.memory.remove_expired(now) {
+            self.append(RECORD_REMOVE, topic.as_bytes())?;
+        }
+
+        self.memory.matching(filter, now)
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use super::FileRetainedStore;
+    use super::MemoryRetainedStore;
+    use super::RetainedStore;
+    use crate::packets::publish::PublishProperties;
+    use crate::qos::QualityOfService;
+    use crate::server::message::Message;
+
+    fn message(topic: &str, payload: &'static [u8]) -> Message {
+        Message::new(
+            &topic.parse().unwrap(),
+            payload.try_into().unwrap(),
+            QualityOfService::AtLeastOnce,
+        )
+        .with_retain(true)
+    }
+
+    fn topics(store: &mut dyn RetainedStore, filter: &str, now: SystemTime) -> Vec<String> {
+        let mut topics = store
+            .matching(&filter.parse().unwrap(), now)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.topic().to_string())
+            .collect::<Vec<_>>();
+        topics.sort();
+        topics
+    }
+
+    #[test]
+    fn memory_store_keeps_last_message() {
+        let mut store = MemoryRetainedStore::new();
+        let now = SystemTime::now();
+
+        store.insert(message("a/b", b"first")).unwrap();
+        store.insert(message("a/b", b"second")).unwrap();
+        store.insert(message("a/c", b"other")).unwrap();
+        store.insert(message("$SYS/x", b"system")).unwrap();
+
+        let messages = store.matching(&"a/b".parse().unwrap(), now).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload(), b"second");
+
+        assert_eq!(topics(&mut store, "#", now), ["a/b", "a/c"]);
+        assert_eq!(topics(&mut store, "$SYS/+", now), ["$SYS/x"]);
+
+        store.remove("a/b").unwrap();
+        assert_eq!(topics(&mut store, "a/+", now), ["a/c"]);
+    }
+
+    #[test]
+    fn expired_messages_are_not_delivered() {
+        let mut store = MemoryRetainedStore::new();
+        let mut properties = PublishProperties::new();
+        properties.with_message_expiry_interval(10);
+
+        store
+            .insert(message("a", b"expiring").with_properties(properties))
+            .unwrap();
+        store.insert(message("b", b"forever")).unwrap();
+
+        let now = SystemTime::now();
+        assert_eq!(topics(&mut store, "+", now), ["a", "b"]);
+        assert_eq!(
+            topics(&mut store, "+", now + Duration::from_secs(11)),
+            ["b"]
+        );
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn file_store_survives_reopening() {
+        let directory = std::env::temp_dir().join(format!(
+            "cloudmqtt-retained-{}-{:?}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("retained");
+
+        {
+            let mut store = FileRetainedStore::open(&path).unwrap();
+            store.insert(message("a/b", b"first")).unwrap();
+            store.insert(message("a/c", b"other")).unwrap();
+            store.insert(message("a/b", b"second")).unwrap();
+            store.remove("a/c").unwrap();
+        }
+
+        // A partially written record at the end is ignored
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| std::io::Write::write_all(&mut file, &[1, 0, 0, 1]))
+            .unwrap();
+
+        let mut store = FileRetainedStore::open(&path).unwrap();
+        let now = SystemTime::now();
+        let messages = store.matching(&"#".parse().unwrap(), now).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic(), "a/b");
+        assert_eq!(messages[0].payload(), b"second");
+
+        for i in 0..200 {
+            store.insert(message("a/b", b"again")).unwrap();
+            store.insert(message(&format!("t/{i}"), b"many")).unwrap();
+        }
+        drop(store);
+
+        let mut store = FileRetainedStore::open(&path).unwrap();
+        assert_eq!(store.len(), 201);
+        assert_eq!(topics(&mut store, "a/#", now), ["a/b"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::message::Delivery;
+use super::message::Message;
+use super::outbound::Outbound;
+use crate::packet_identifier::PacketIdentifier;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ConnectionId(u64);
+
+#[derive(Debug)]
+pub(crate) enum ConnectionCommand {
+    /// Another connection with the same client identifier took over the session
+    TakenOver,
+    /// A QoS 0 message the connection has to send to its client
+    Deliver(Delivery),
+    /// Messages were added to the outbound queue of the session
+    SendQueued,
+}
+
+/// A will message that is published once its will delay interval passed
+pub(crate) struct PendingWill {
+    /// The connection that ended and left the will behind
+    pub(crate) connection: ConnectionId,
+    pub(crate) message: Message,
+}
+
+/// The link from a session to the connection currently using it
+pub(crate) struct ConnectionLink {
+    pub(crate) id: ConnectionId,
+    pub(crate) commands: futures::channel::mpsc::UnboundedSender<ConnectionCommand>,
+}
+
+pub(crate) struct Session {
+    pub(crate) client_identifier: String,
+    /// The connection that started the session, which tells sessions of a client apart
+    pub(crate) started_by: ConnectionId,
+    /// Seconds the session is kept after its connection closed, `u32::MAX` means forever
+    pub(crate) expiry_interval: u32,
+    pub(crate) connection: Option<ConnectionLink>,
+    pub(crate) disconnected_at: Option<Instant>,
+    /// QoS 2 messages received from the client that were not released yet
+    pub(crate) awaiting_release: HashSet<PacketIdentifier>,
+    pub(crate) outbound: Outbound,
+    pub(crate) pending_will: Option<PendingWill>,
+}
+
+impl Session {
+    fn new(client_identifier: String, started_by: ConnectionId, expiry_interval: u32) -> Self {
+        Self {
+            client_identifier,
+            started_by,
+            expiry_interval,
+            connection: None,
+            disconnected_at: None,
+            awaiting_release: HashSet::new(),
+            outbound: Outbound::new(),
+            pending_will: None,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        if self.expiry_interval == u32::MAX {
+            return false;
+        }
+
+        self.disconnected_at.is_some_and(|disconnected_at| {
+            now.saturating_duration_since(disconnected_at)
+                >= Duration::from_secs(self.expiry_interval.into())
+        })
+    }
+}
+
+/// All sessions known to the server, keyed by client identifier
+pub(crate) struct SessionRegistry {
+    sessions: HashMap<String, Session>,
+    next_connection_id: u64,
+}
+
+impl SessionRegistry {
+    pub(crate) fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+            next_connection_id: 0,
+        }
+    }
+
+    pub(crate) fn next_connection_id(&mut self) -> ConnectionId {
+        self.next_connection_id += 1;
+        ConnectionId(self.next_connection_id)
+    }
+
+    pub(crate) fn contains(&self, client_identifier: &str) -> bool {
+        self.sessions.contains_key(client_identifier)
+    }
+
+    pub(crate) fn get(&self, client_identifier: &str) -> Option<&Session> {
+        self.sessions.get(client_identifier)
+    }
+
+    pub(crate) fn get_mut(&mut self, client_identifier: &str) -> Option<&mut Session> {
+        self.sessions.get_mut(client_identifier)
+    }
+
+    /// Attach a new connection to the session of `client_identifier`
+    ///
+    /// A connection that is still attached to the session is told that it was taken over. Returns
+    /// whether an existing session was resumed.
+    pub(crate) fn attach(
+        &mut self,
+        client_identifier: &str,
+        clean_start: bool,
+        expiry_interval: u32,
+        link: ConnectionLink,
+        now: Instant,
+    ) -> bool {
+        if let Some(existing) = self.sessions.get_mut(client_identifier) {
+            if let Some(previous) = existing.connection.take() {
+                tracing::debug!(
+                    client_identifier,
+                    "Session is taken over by a new connection"
+                );
+                let _ = previous
+                    .commands
+                    .unbounded_send(ConnectionCommand::TakenOver);
+            }
+        }
+
+        let resumable = self
+            .sessions
+            .get(client_identifier)
+            .is_some_and(|session| !session.is_expired(now));
+
+        let session_present = if clean_start || !resumable {
+            self.sessions.insert(
+                client_identifier.to_string(),
+                Session::new(client_identifier.to_string(), link.id, expiry_interval),
+            );
+            false
+        } else {
+            true
+        };
+
+        let session = self
+            .sessions
+            .get_mut(client_identifier)
+            .expect("Session was inserted above");
+        session.expiry_interval = expiry_interval;
+        session.connection = Some(link);
+        session.disconnected_at = None;
+
+        session_present
+    }
+
+    /// Detach the connection `id` from its session, if it is still attached to it
+    ///
+    /// Sessions with an expiry interval of zero are removed right away. Returns whether the
+    /// session was removed.
+    pub(crate) fn detach(
+        &mut self,
+        client_identifier: &str,
+        id: ConnectionId,
+        expiry_interval: Option<u32>,
+        now: Instant,
+    ) -> bool {
+        let Some(session) = self.sessions.get_mut(client_identifier) else {
+            return false;
+        };
+
+        if session.connection.as_ref().map(|link| link.id) != Some(id) {
+            tracing::trace!(client_identifier, "Session was taken over, not detaching");
+            return false;
+        }
+
+        session.connection = None;
+        session.disconnected_at = Some(now);
+        if let Some(expiry_interval) = expiry_interval {
+            session.expiry_interval = expiry_interval;
+        }
+
+        if session.expiry_interval == 0 {
+            tracing::debug!(client_identifier, "Session ended with its connection");
+            self.sessions.remove(client_identifier);
+            return true;
+        }
+
+        false
+    }
+
+    /// Remove and return all sessions whose expiry interval has passed
+    pub(crate) fn purge_expired(&mut self, now: Instant) -> Vec<Session> {
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_expired(now))
+            .map(|(client_identifier, _)| client_identifier.clone())
+            .collect::<Vec<_>>();
+
+        expired
+            .into_iter()
+            .filter_map(|client_identifier| {
+                tracing::debug!(client_identifier, "Session expired");
+                self.sessions.remove(&client_identifier)
+            })
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub(crate) fn connected(&self) -> impl Iterator<Item = &str> {
+        self.sessions
+            .values()
+            .filter(|session| session.connection.is_some())
+            .map(|session| session.client_identifier.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use futures::StreamExt;
+
+    use super::ConnectionCommand;
+    use super::ConnectionLink;
+    use super::SessionRegistry;
+
+    fn link(
+        registry: &mut SessionRegistry,
+    ) -> (
+        ConnectionLink,
+        futures::channel::mpsc::UnboundedReceiver<ConnectionCommand>,
+    ) {
+        let (commands, recv) = futures::channel::mpsc::unbounded();
+        (
+            ConnectionLink {
+                id: registry.next_connection_id(),
+                commands,
+            },
+            recv,
+        )
+    }
+
+    #[tokio::test]
+    async fn takeover_and_resume() {
+        let mut registry = SessionRegistry::new();
+        let now = Instant::now();
+
+        let (first, mut first_commands) = link(&mut registry);
+        let first_id = first.id;
+        assert!(!registry.attach("client", false, 60, first, now));
+
+        let (second, _second_commands) = link(&mut registry);
+        assert!(registry.attach("client", false, 60, second, now));
+        assert!(matches!(
+            first_commands.next().await,
+            Some(ConnectionCommand::TakenOver)
+        ));
+
+        // The old connection closing must not detach the new one
+        registry.detach("client", first_id, None, now);
+        assert_eq!(registry.connected().collect::<Vec<_>>(), ["client"]);
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let mut registry = SessionRegistry::new();
+        let now = Instant::now();
+
+        let (first, _commands) = link(&mut registry);
+        let id = first.id;
+        registry.attach("client", true, 10, first, now);
+        registry.detach("client", id, None, now);
+        assert!(registry.contains("client"));
+
+        registry.purge_expired(now + Duration::from_secs(10));
+        assert!(!registry.contains("client"));
+
+        let (second, _commands) = link(&mut registry);
+        let id = second.id;
+        registry.attach("client", true, 10, second, now);
+        registry.detach("client", id, Some(0), now);
+        assert!(!registry.contains("client"));
+    }
+}
@@ -0,0 +1,160 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Distributing the messages of shared subscriptions among their members
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+/// How the messages of a shared subscription are distributed among its members
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SharedSubscriptionStrategy {
+    /// The members take turns
+    #[default]
+    RoundRobin,
+    /// Every message goes to a randomly chosen member
+    Random,
+    /// All messages of a publisher go to the same member, as long as the members do not change
+    StickyByClient,
+    /// Every message goes to the member with the fewest unacknowledged messages
+    LeastInflight,
+}
+
+impl SharedSubscriptionStrategy {
+    /// Pick the candidate that receives the next message of a shared subscription
+    ///
+    /// `turn` counts the messages the subscription received before, `inflight` tells how many
+    /// messages a candidate did not acknowledge yet.
+    pub(crate) fn choose<'a, T>(
+        self,
+        candidates: &[&'a T],
+        turn: usize,
+        publisher: &str,
+        inflight: impl Fn(&T) -> usize,
+    ) -> Option<&'a T> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match self {
+            SharedSubscriptionStrategy::RoundRobin => turn % candidates.len(),
+            SharedSubscriptionStrategy::Random => {
+                let mut hasher = RandomState::new().build_hasher();
+                hasher.write_usize(turn);
+                hasher.finish() as usize % candidates.len()
+            }
+            SharedSubscriptionStrategy::StickyByClient => {
+                // The default hasher uses fixed keys, so a publisher always maps to the same index
+                let mut hasher = DefaultHasher::new();
+                publisher.hash(&mut hasher);
+                hasher.finish() as usize % candidates.len()
+            }
+            SharedSubscriptionStrategy::LeastInflight => {
+                // Starting at the current turn lets equally loaded members take turns
+                (0..candidates.len())
+                    .map(|offset| (turn + offset) % candidates.len())
+                    .min_by_key(|&index| inflight(candidates[index]))
+                    .unwrap_or_default()
+            }
+        };
+
+        Some(candidates[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::qos::QualityOfService;
+
+    use super::SharedSubscriptionStrategy;
+    use crate::server::tests::connect;
+    use crate::server::tests::expect_nothing_pending;
+    use crate::server::tests::expect_publish;
+    use crate::server::tests::puback;
+    use crate::server::tests::publish_packet;
+    use crate::server::tests::subscribe;
+    use crate::server::tests::subscription_options;
+    use crate::server::MqttServer;
+
+    #[test]
+    fn strategies_choose_members() {
+        let members = ["one", "two", "three"];
+        let candidates = members.iter().collect::<Vec<_>>();
+        let inflight = |member: &&str| if *member == "one" { 5 } else { 1 };
+
+        let chosen = |strategy: SharedSubscriptionStrategy, turn, publisher| {
+            *strategy
+                .choose(&candidates, turn, publisher, inflight)
+                .unwrap()
+        };
+
+        assert_eq!(
+            (0..4)
+                .map(|turn| chosen(SharedSubscriptionStrategy::RoundRobin, turn, "p"))
+                .collect::<Vec<_>>(),
+            ["one", "two", "three", "one"]
+        );
+        assert!((0..10).all(|turn| {
+            chosen(SharedSubscriptionStrategy::StickyByClient, turn, "p")
+                == chosen(SharedSubscriptionStrategy::StickyByClient, 0, "p")
+        }));
+        assert_eq!(
+            (0..3)
+                .map(|turn| chosen(SharedSubscriptionStrategy::LeastInflight, turn, "p"))
+                .collect::<Vec<_>>(),
+            ["two", "two", "three"]
+        );
+        assert!(SharedSubscriptionStrategy::Random
+            .choose::<&str>(&[], 0, "p", inflight)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_messages_move_to_other_members() {
+        let server = MqttServer::builder().build();
+        let (mut publisher, _publishing, _) = connect(&server, "publisher", true).await;
+        let (mut first, _first_serving, _) = connect(&server, "first", true).await;
+        let (mut second, _second_serving, _) = connect(&server, "second", true).await;
+
+        let options = subscription_options(QualityOfService::AtLeastOnce);
+        subscribe(&mut first, "$share/group/a", &options).await;
+        subscribe(&mut second, "$share/group/a", &options).await;
+
+        for payload in [b"one", b"two"] {
+            publisher
+                .send(publish_packet(
+                    "a",
+                    payload,
+                    QualityOfService::AtLeastOnce,
+                    false,
+                ))
+                .await
+                .unwrap();
+            publisher.next().await.unwrap().unwrap();
+        }
+
+        let received = expect_publish(&mut first).await;
+        assert_eq!(received.payload, b"one");
+        assert_eq!(received.packet_identifier, Some(1));
+        let received = expect_publish(&mut second).await;
+        assert_eq!(received.payload, b"two");
+        assert_eq!(received.packet_identifier, Some(1));
+        puback(&mut second, 1).await;
+        expect_nothing_pending(&mut first).await;
+        expect_nothing_pending(&mut second).await;
+
+        // The message the first member did not acknowledge goes to the remaining one
+        drop(first);
+        let received = expect_publish(&mut second).await;
+        assert_eq!(received.payload, b"one");
+        assert_eq!(received.packet_identifier, Some(2));
+    }
+}
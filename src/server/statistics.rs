@@ -0,0 +1,160 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Counters behind the `$SYS/broker/...` topics
+//!
+//! Connections update the counters without taking the server lock, the server reads them when it
+//! publishes its statistics.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+/// Traffic counters of a server since it was built
+pub(crate) struct Statistics {
+    started: Instant,
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl Statistics {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a packet of `bytes` received from a client, `publish` tells whether it was a PUBLISH
+    pub(crate) fn received(&self, bytes: usize, publish: bool) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        if publish {
+            self.messages_received.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a packet of `bytes` sent to a client, `publish` tells whether it was a PUBLISH
+    pub(crate) fn sent(&self, bytes: usize, publish: bool) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        if publish {
+            self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The `$SYS` topics of the counters and their current values
+    pub(crate) fn topics(&self, now: Instant) -> [(&'static str, u64); 5] {
+        [
+            (
+                "$SYS/broker/uptime",
+                now.saturating_duration_since(self.started).as_secs(),
+            ),
+            (
+                "$SYS/broker/messages/received",
+                self.messages_received.load(Ordering::Relaxed),
+            ),
+            (
+                "$SYS/broker/messages/sent",
+                self.messages_sent.load(Ordering::Relaxed),
+            ),
+            (
+                "$SYS/broker/bytes/received",
+                self.bytes_received.load(Ordering::Relaxed),
+            ),
+            (
+                "$SYS/broker/bytes/sent",
+                self.bytes_sent.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use mqtt_format::v5::qos::QualityOfService;
+
+    use crate::server::tests::connect;
+    use crate::server::tests::expect_nothing_pending;
+    use crate::server::tests::publish_packet;
+    use crate::server::tests::subscribe;
+    use crate::server::tests::subscription_options;
+    use crate::server::MqttServer;
+
+    #[tokio::test]
+    async fn statistics_are_published_as_retained_messages() {
+        let server = MqttServer::builder().build();
+        let (mut publisher, _publishing, _) = connect(&server, "publisher", true).await;
+        let (mut subscriber, _subscribing, _) = connect(&server, "subscriber", true).await;
+        subscribe(
+            &mut subscriber,
+            "$SYS/broker/#",
+            &subscription_options(QualityOfService::AtMostOnce),
+        )
+        .await;
+
+        publisher
+            .send(publish_packet(
+                "a",
+                b"counted",
+                QualityOfService::AtLeastOnce,
+                false,
+            ))
+            .await
+            .unwrap();
+        publisher.next().await.unwrap().unwrap();
+
+        server.publish_statistics().await;
+
+        let mut statistics = HashMap::new();
+        for _ in 0..9 {
+            let packet = subscriber.next().await.unwrap().unwrap();
+            let FormatMqttPacket::Publish(publish) = packet.get() else {
+                panic!("Expected PUBLISH, got {:?}", packet.get());
+            };
+            statistics.insert(
+                publish.topic_name.to_string(),
+                std::str::from_utf8(publish.payload)
+                    .unwrap()
+                    .parse::<u64>()
+                    .unwrap(),
+            );
+        }
+        expect_nothing_pending(&mut subscriber).await;
+
+        assert_eq!(statistics["$SYS/broker/clients/connected"], 2);
+        assert_eq!(statistics["$SYS/broker/clients/total"], 2);
+        assert_eq!(statistics["$SYS/broker/subscriptions/count"], 1);
+        assert_eq!(statistics["$SYS/broker/retained messages/count"], 0);
+        assert_eq!(statistics["$SYS/broker/messages/received"], 1);
+        assert_eq!(statistics["$SYS/broker/messages/sent"], 0);
+        assert!(statistics["$SYS/broker/bytes/received"] > 0);
+        assert!(statistics["$SYS/broker/bytes/sent"] > 0);
+
+        // New subscribers receive the last statistics right away
+        let (mut late, _late_serving, _) = connect(&server, "late", true).await;
+        subscribe(
+            &mut late,
+            "$SYS/broker/uptime",
+            &subscription_options(QualityOfService::AtMostOnce),
+        )
+        .await;
+        let packet = late.next().await.unwrap().unwrap();
+        let FormatMqttPacket::Publish(publish) = packet.get() else {
+            panic!("Expected PUBLISH, got {:?}", packet.get());
+        };
+        assert!(publish.retain);
+    }
+}
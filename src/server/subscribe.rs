@@ -0,0 +1,333 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use mqtt_format::v5::packets::disconnect::DisconnectReasonCode;
+use mqtt_format::v5::packets::suback::MSuback;
+use mqtt_format::v5::packets::suback::SubackReasonCode;
+use mqtt_format::v5::packets::subscribe::MSubscribe;
+use mqtt_format::v5::packets::subscribe::RetainHandling;
+use mqtt_format::v5::packets::subscribe::Subscription;
+use mqtt_format::v5::packets::unsuback::MUnsuback;
+use mqtt_format::v5::packets::unsuback::UnsubackReasonCode;
+use mqtt_format::v5::packets::unsubscribe::MUnsubscribe;
+use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+
+use super::auth::Action;
+use super::connection::Connection;
+use super::connection::MqttServerConnectionError;
+use super::message::Delivery;
+use super::subscriptions::split_shared_filter;
+use super::subscriptions::SubscriptionOptions;
+use super::InnerServer;
+use crate::qos::QualityOfService;
+use crate::topic::MqttTopicFilter;
+
+impl Connection {
+    /// Add the subscriptions of the client, then send the retained messages they match
+    pub(super) async fn handle_subscribe(
+        &mut self,
+        subscribe: &MSubscribe<'_>,
+    ) -> Result<(), MqttServerConnectionError> {
+        let subscription_identifier = subscribe
+            .properties
+            .subscription_identifier()
+            .map(|si| si.0);
+        let now = SystemTime::now();
+
+        if subscribe.subscriptions.iter().any(|subscription| {
+            subscription.options.no_local && subscription.topic_filter.starts_with("$share/")
+        }) {
+            return Err(MqttServerConnectionError::ProtocolError {
+                reason: "MQTT-3.8.3-4",
+            });
+        }
+
+        let mut authorized = Vec::new();
+        for subscription in subscribe.subscriptions.iter() {
+            let (share_name, topic_filter) = match split_shared_filter(subscription.topic_filter) {
+                Some((share_name, topic_filter)) => (Some(share_name), topic_filter),
+                None => (None, subscription.topic_filter),
+            };
+            authorized.push(
+                self.authorizer
+                    .authorize(
+                        &self.client,
+                        Action::Subscribe {
+                            topic_filter,
+                            share_name,
+                        },
+                    )
+                    .await,
+            );
+        }
+
+        let mut retained = Vec::new();
+        let reasons = {
+            let mut inner = self.inner.lock().await;
+
+            if subscription_identifier.is_some() && !inner.config.subscription_identifiers_available
+            {
+                return Err(MqttServerConnectionError::CapabilityExceeded {
+                    reason_code: DisconnectReasonCode::SubscriptionIdentifiersNotSupported,
+                });
+            }
+
+            subscribe
+                .subscriptions
+                .iter()
+                .zip(authorized)
+                .map(|(subscription, authorized)| {
+                    subscribe_one(
+                        &mut inner,
+                        &self.client.client_identifier,
+                        &subscription,
+                        authorized,
+                        subscription_identifier,
+                        now,
+                        &mut retained,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        self.send(FormatMqttPacket::Suback(MSuback {
+            packet_identifier: subscribe.packet_identifier,
+            properties: mqtt_format::v5::packets::suback::SubackProperties::new(),
+            reasons: &reasons,
+        }))
+        .await?;
+
+        for delivery in retained {
+            self.deliver(delivery).await?;
+        }
+
+        Ok(())
+    }
+
+    pub(super) async fn handle_unsubscribe(
+        &mut self,
+        unsubscribe: &MUnsubscribe<'_>,
+    ) -> Result<(), MqttServerConnectionError> {
+        let reasons = {
+            let mut inner = self.inner.lock().await;
+
+            unsubscribe
+                .unsubscriptions
+                .iter()
+                .map(|unsubscription| {
+                    match MqttTopicFilter::from_str(unsubscription.topic_filter) {
+                        Err(_) => UnsubackReasonCode::TopicFilterInvalid,
+                        Ok(filter) => {
+                            match inner
+                                .subscriptions
+                                .unsubscribe(&filter, self.client.client_identifier.as_str())
+                            {
+                                Some(_) => UnsubackReasonCode::Success,
+                                None => UnsubackReasonCode::NoSubscriptionExisted,
+                            }
+                        }
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        self.send(FormatMqttPacket::Unsuback(MUnsuback {
+            packet_identifier: unsubscribe.packet_identifier,
+            properties: mqtt_format::v5::packets::unsuback::UnsubackProperties::new(),
+            reasons: &reasons,
+        }))
+        .await
+    }
+}
+
+/// Add a single subscription of a SUBSCRIBE
+///
+/// Retained messages that have to be sent for the subscription are added to `retained`.
+fn subscribe_one(
+    inner: &mut InnerServer,
+    client_identifier: &str,
+    subscription: &Subscription<'_>,
+    authorized: bool,
+    subscription_identifier: Option<u32>,
+    now: SystemTime,
+    retained: &mut Vec<Delivery>,
+) -> SubackReasonCode {
+    let Ok(filter) = MqttTopicFilter::from_str(subscription.topic_filter) else {
+        return SubackReasonCode::TopicFilterInvalid;
+    };
+
+    let shared = split_shared_filter(filter.as_ref()).is_some();
+    if filter.as_ref().starts_with("$share/") {
+        if !inner.config.shared_subscription_available {
+            return SubackReasonCode::SharedSubscriptionsNotSupported;
+        }
+        if !shared {
+            return SubackReasonCode::TopicFilterInvalid;
+        }
+    }
+
+    if !inner.config.wildcard_subscription_available && filter.as_ref().contains(['+', '#']) {
+        return SubackReasonCode::WildcardSubscriptionsNotSupported;
+    }
+
+    if !authorized {
+        tracing::debug!(
+            filter = filter.as_ref(),
+            "Client may not subscribe to topic filter"
+        );
+        return SubackReasonCode::NotAuthorized;
+    }
+
+    let qos = QualityOfService::from(subscription.options.quality_of_service)
+        .min(inner.config.maximum_qos);
+
+    let options = SubscriptionOptions {
+        qos,
+        no_local: subscription.options.no_local,
+        retain_as_published: subscription.options.retain_as_published,
+        retain_handling: subscription.options.retain_handling,
+        subscription_identifier,
+    };
+    let existed = inner
+        .subscriptions
+        .subscribe(&filter, client_identifier.to_string(), options)
+        .is_some();
+
+    // Retained messages are not sent for shared subscriptions
+    let send_retained = !shared
+        && match subscription.options.retain_handling {
+            RetainHandling::SendRetainedMessagesAlways => true,
+            RetainHandling::SendRetainedMessagesOnNewSubscribe => !existed,
+            RetainHandling::DoNotSendRetainedMessages => false,
+        };
+
+    if send_retained {
+        match inner.retained.matching(&filter, now) {
+            Ok(messages) => {
+                retained.extend(messages.into_iter().map(|message| Delivery {
+                    qos: message.qos().min(qos),
+                    // Retained messages sent because of a subscription always keep the flag
+                    retain: true,
+                    subscription_identifiers: subscription_identifier.into_iter().collect(),
+                    shared_filter: None,
+                    message,
+                }))
+            }
+            Err(error) => {
+                tracing::warn!(%error, filter = filter.as_ref(), "Could not read retained messages");
+            }
+        }
+    }
+
+    match qos {
+        QualityOfService::AtMostOnce => SubackReasonCode::GrantedQoS0,
+        QualityOfService::AtLeastOnce => SubackReasonCode::GrantedQoS1,
+        QualityOfService::ExactlyOnce => SubackReasonCode::GrantedQoS2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use mqtt_format::v5::packets::suback::SubackReasonCode;
+    use mqtt_format::v5::packets::subscribe::RetainHandling;
+    use mqtt_format::v5::qos::QualityOfService;
+
+    use crate::server::tests::connect;
+    use crate::server::tests::expect_nothing_pending;
+    use crate::server::tests::expect_publish;
+    use crate::server::tests::publish_packet;
+    use crate::server::tests::subscribe;
+    use crate::server::tests::subscription_options;
+    use crate::server::MqttServer;
+
+    #[tokio::test]
+    async fn retained_messages_follow_retain_handling() {
+        let server = MqttServer::builder().build();
+        let (mut publisher, _publishing, _) = connect(&server, "publisher", true).await;
+        let (mut subscriber, _subscribing, _) = connect(&server, "subscriber", true).await;
+
+        publisher
+            .send(publish_packet(
+                "a/b",
+                b"retained",
+                QualityOfService::AtMostOnce,
+                true,
+            ))
+            .await
+            .unwrap();
+        expect_nothing_pending(&mut publisher).await;
+
+        let mut options = subscription_options(QualityOfService::AtLeastOnce);
+        assert_eq!(
+            subscribe(&mut subscriber, "a/+", &options).await,
+            SubackReasonCode::GrantedQoS1
+        );
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.topic, "a/b");
+        assert_eq!(received.payload, b"retained");
+        assert!(received.retain);
+
+        // Subscribing again only sends retained messages if asked to always do so
+        options.retain_handling = RetainHandling::SendRetainedMessagesOnNewSubscribe;
+        subscribe(&mut subscriber, "a/+", &options).await;
+        expect_nothing_pending(&mut subscriber).await;
+
+        options.retain_handling = RetainHandling::SendRetainedMessagesAlways;
+        subscribe(&mut subscriber, "a/+", &options).await;
+        expect_publish(&mut subscriber).await;
+
+        // Messages routed to existing subscriptions lose the retain flag, unless asked to keep it
+        publisher
+            .send(publish_packet(
+                "a/b",
+                b"",
+                QualityOfService::AtMostOnce,
+                true,
+            ))
+            .await
+            .unwrap();
+        let received = expect_publish(&mut subscriber).await;
+        assert_eq!(received.topic, "a/b");
+        assert!(received.payload.is_empty());
+        assert!(!received.retain);
+
+        // The empty retained message cleared the stored one
+        options.retain_handling = RetainHandling::SendRetainedMessagesAlways;
+        subscribe(&mut subscriber, "a/#", &options).await;
+        expect_nothing_pending(&mut subscriber).await;
+    }
+
+    #[tokio::test]
+    async fn unsupported_subscriptions_are_refused() {
+        let server = MqttServer::builder()
+            .with_wildcard_subscription_available(false)
+            .with_shared_subscription_available(false)
+            .build();
+        let (mut client, _serving, _) = connect(&server, "client", true).await;
+        let options = subscription_options(QualityOfService::AtMostOnce);
+
+        assert_eq!(
+            subscribe(&mut client, "a/#", &options).await,
+            SubackReasonCode::WildcardSubscriptionsNotSupported
+        );
+        assert_eq!(
+            subscribe(&mut client, "$share/group/a", &options).await,
+            SubackReasonCode::SharedSubscriptionsNotSupported
+        );
+        assert_eq!(
+            subscribe(&mut client, "a/b#", &options).await,
+            SubackReasonCode::TopicFilterInvalid
+        );
+        assert_eq!(
+            subscribe(&mut client, "a/b", &options).await,
+            SubackReasonCode::GrantedQoS0
+        );
+    }
+}
@@ -0,0 +1,630 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Matching topic names against the topic filters of subscribers
+//!
+//! [`SubscriptionTree`] does not depend on the rest of the server, it can be used and measured on
+//! its own. Filters are stored in a trie with one node per topic level, so matching a topic only
+//! visits the nodes of filters that can match it.
+//!
+//! Shared subscriptions (`$share/<share name>/<filter>`) are kept as groups in the node of their
+//! filter. A message matching a group is delivered to only one of its members, which one is left
+//! to the caller.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+pub use mqtt_format::v5::packets::subscribe::RetainHandling;
+
+use crate::qos::QualityOfService;
+use crate::topic::MqttTopicFilter;
+
+/// The options a subscriber requested for one topic filter
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionOptions {
+    /// The QoS granted by the server
+    pub qos: QualityOfService,
+    /// Do not deliver messages published by the subscriber itself
+    pub no_local: bool,
+    /// Keep the retain flag of delivered messages as it was published
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+    pub subscription_identifier: Option<u32>,
+}
+
+impl SubscriptionOptions {
+    pub fn new(qos: QualityOfService) -> Self {
+        Self {
+            qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendRetainedMessagesAlways,
+            subscription_identifier: None,
+        }
+    }
+}
+
+/// A subscriber a message has to be delivered to
+///
+/// If several subscriptions of the subscriber match, they are combined into one: the message is
+/// delivered once, with the highest QoS and the identifiers of all of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionMatch<S> {
+    pub subscriber: S,
+    pub qos: QualityOfService,
+    pub retain_as_published: bool,
+    pub subscription_identifiers: Vec<u32>,
+}
+
+/// A shared subscription a message has to be delivered to one member of
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedMatch<S> {
+    /// The filter of the shared subscription, including its `$share/<share name>/` prefix
+    pub shared_filter: String,
+    /// The members in the order they subscribed
+    pub members: Vec<(S, SubscriptionOptions)>,
+    /// How many messages were handed to the group before this one
+    pub turn: usize,
+}
+
+/// Split a shared subscription filter into its share name and topic filter
+///
+/// Returns `None` if `filter` is not a valid shared subscription filter, that is if it does not
+/// start with `$share/`, its share name is empty or contains wildcards, or its filter is empty.
+pub fn split_shared_filter(filter: &str) -> Option<(&str, &str)> {
+    let (share_name, filter) = filter.strip_prefix("$share/")?.split_once('/')?;
+
+    if share_name.is_empty() || share_name.contains(['+', '#']) || filter.is_empty() {
+        return None;
+    }
+
+    Some((share_name, filter))
+}
+
+struct SharedGroup<S> {
+    shared_filter: String,
+    members: Vec<(S, SubscriptionOptions)>,
+    turns: usize,
+}
+
+impl<S: Clone + Eq> SharedGroup<S> {
+    fn new(shared_filter: String) -> Self {
+        Self {
+            shared_filter,
+            members: Vec::new(),
+            turns: 0,
+        }
+    }
+
+    fn insert(
+        &mut self,
+        subscriber: S,
+        options: SubscriptionOptions,
+    ) -> Option<SubscriptionOptions> {
+        match self
+            .members
+            .iter_mut()
+            .find(|(member, _)| *member == subscriber)
+        {
+            Some((_, existing)) => Some(std::mem::replace(existing, options)),
+            None => {
+                self.members.push((subscriber, options));
+                None
+            }
+        }
+    }
+
+    fn remove<Q>(&mut self, subscriber: &Q) -> Option<SubscriptionOptions>
+    where
+        S: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let position = self
+            .members
+            .iter()
+            .position(|(member, _)| member.borrow() == subscriber)?;
+        Some(self.members.remove(position).1)
+    }
+
+    /// Take the next turn of the group
+    fn next_match(&mut self) -> SharedMatch<S> {
+        let turn = self.turns;
+        self.turns = turn.wrapping_add(1);
+
+        SharedMatch {
+            shared_filter: self.shared_filter.clone(),
+            members: self.members.clone(),
+            turn,
+        }
+    }
+}
+
+struct Node<S> {
+    children: HashMap<String, Node<S>>,
+    subscribers: HashMap<S, SubscriptionOptions>,
+    /// Shared subscriptions to the filter of the node, by share name
+    shared: HashMap<String, SharedGroup<S>>,
+}
+
+impl<S> Node<S> {
+    fn new() -> Self {
+        Self {
+            children: HashMap::new(),
+            subscribers: HashMap::new(),
+            shared: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty() && self.shared.is_empty()
+    }
+}
+
+pub struct SubscriptionTree<S> {
+    root: Node<S>,
+    len: usize,
+}
+
+impl<S> Default for SubscriptionTree<S> {
+    fn default() -> Self {
+        Self {
+            root: Node::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<S: Clone + Eq + Hash> SubscriptionTree<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a subscription, replacing an existing one of the subscriber to the same filter
+    ///
+    /// Filters of shared subscriptions add the subscriber to the group of their share name.
+    /// Returns the options of the replaced subscription.
+    pub fn subscribe(
+        &mut self,
+        filter: &MqttTopicFilter,
+        subscriber: S,
+        options: SubscriptionOptions,
+    ) -> Option<SubscriptionOptions> {
+        let shared = split_shared_filter(filter.as_ref());
+        let node = shared
+            .map_or(filter.as_ref(), |(_, filter)| filter)
+            .split('/')
+            .fold(&mut self.root, |node, level| {
+                node.children
+                    .entry(level.to_string())
+                    .or_insert_with(Node::new)
+            });
+
+        let replaced = match shared {
+            None => node.subscribers.insert(subscriber, options),
+            Some((share_name, _)) => node
+                .shared
+                .entry(share_name.to_string())
+                .or_insert_with(|| SharedGroup::new(filter.as_ref().to_string()))
+                .insert(subscriber, options),
+        };
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    /// Remove the subscription of `subscriber` to `filter`, returning its options
+    pub fn unsubscribe<Q>(
+        &mut self,
+        filter: &MqttTopicFilter,
+        subscriber: &Q,
+    ) -> Option<SubscriptionOptions>
+    where
+        S: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let shared = split_shared_filter(filter.as_ref());
+        let levels = shared
+            .map_or(filter.as_ref(), |(_, filter)| filter)
+            .split('/')
+            .collect::<Vec<_>>();
+        let share_name = shared.map(|(share_name, _)| share_name);
+
+        let removed = Self::remove(&mut self.root, &levels, share_name, subscriber);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    fn remove<Q>(
+        node: &mut Node<S>,
+        levels: &[&str],
+        share_name: Option<&str>,
+        subscriber: &Q,
+    ) -> Option<SubscriptionOptions>
+    where
+        S: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let Some((level, rest)) = levels.split_first() else {
+            let Some(share_name) = share_name else {
+                return node.subscribers.remove(subscriber);
+            };
+
+            let group = node.shared.get_mut(share_name)?;
+            let removed = group.remove(subscriber);
+            if group.members.is_empty() {
+                node.shared.remove(share_name);
+            }
+            return removed;
+        };
+
+        let child = node.children.get_mut(*level)?;
+        let removed = Self::remove(child, rest, share_name, subscriber);
+        if child.is_empty() {
+            node.children.remove(*level);
+        }
+        removed
+    }
+
+    /// Remove all subscriptions of `subscriber`
+    pub fn unsubscribe_all<Q>(&mut self, subscriber: &Q)
+    where
+        S: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.len -= Self::remove_all(&mut self.root, subscriber);
+    }
+
+    fn remove_all<Q>(node: &mut Node<S>, subscriber: &Q) -> usize
+    where
+        S: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut removed = usize::from(node.subscribers.remove(subscriber).is_some());
+
+        node.shared.retain(|_, group| {
+            removed += usize::from(group.remove(subscriber).is_some());
+            !group.members.is_empty()
+        });
+
+        node.children.retain(|_, child| {
+            removed += Self::remove_all(child, subscriber);
+            !child.is_empty()
+        });
+
+        removed
+    }
+
+    /// Find all subscribers that have to receive a message published to `topic`
+    ///
+    /// Subscriptions with `no_local` set do not match messages of their own `publisher`. Topics
+    /// starting with `$` are not matched by filters starting with a wildcard.
+    pub fn matches<Q>(&self, topic: &str, publisher: Option<&Q>) -> Vec<SubscriptionMatch<S>>
+    where
+        S: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let mut matches = HashMap::new();
+        self.visit_matching(topic, &mut |node| {
+            Self::add_subscribers(node, publisher, &mut matches)
+        });
+
+        matches.into_values().collect()
+    }
+
+    /// Find all shared subscriptions with a filter matching `topic`
+    ///
+    /// Every returned group takes its next turn.
+    pub fn shared_matches(&mut self, topic: &str) -> Vec<SharedMatch<S>> {
+        let mut shared_filters = Vec::new();
+        self.visit_matching(topic, &mut |node| {
+            shared_filters.extend(
+                node.shared
+                    .values()
+                    .map(|group| group.shared_filter.clone()),
+            )
+        });
+
+        shared_filters
+            .iter()
+            .filter_map(|shared_filter| self.shared_group(shared_filter))
+            .collect()
+    }
+
+    /// The shared subscription to `shared_filter`, if it still has members
+    ///
+    /// The group takes its next turn, just like it does when it matches a message.
+    pub fn shared_group(&mut self, shared_filter: &str) -> Option<SharedMatch<S>> {
+        let (share_name, filter) = split_shared_filter(shared_filter)?;
+
+        filter
+            .split('/')
+            .try_fold(&mut self.root, |node, level| node.children.get_mut(level))?
+            .shared
+            .get_mut(share_name)
+            .map(SharedGroup::next_match)
+    }
+
+    fn visit_matching(&self, topic: &str, visit: &mut impl FnMut(&Node<S>)) {
+        let levels = topic.split('/').collect::<Vec<_>>();
+
+        let is_system_topic = topic.starts_with('$');
+        Self::collect(&self.root, &levels, is_system_topic, visit);
+    }
+
+    fn collect(
+        node: &Node<S>,
+        levels: &[&str],
+        skip_wildcards: bool,
+        visit: &mut impl FnMut(&Node<S>),
+    ) {
+        if !skip_wildcards {
+            // '#' also matches the parent level, so it is checked before the end of the topic
+            if let Some(multi_level) = node.children.get("#") {
+                visit(multi_level);
+            }
+        }
+
+        let Some((level, rest)) = levels.split_first() else {
+            visit(node);
+            return;
+        };
+
+        if let Some(child) = node.children.get(*level) {
+            Self::collect(child, rest, false, visit);
+        }
+
+        if !skip_wildcards {
+            if let Some(single_level) = node.children.get("+") {
+                Self::collect(single_level, rest, false, visit);
+            }
+        }
+    }
+
+    fn add_subscribers<Q>(
+        node: &Node<S>,
+        publisher: Option<&Q>,
+        matches: &mut HashMap<S, SubscriptionMatch<S>>,
+    ) where
+        S: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        for (subscriber, options) in &node.subscribers {
+            if options.no_local && publisher == Some(subscriber.borrow()) {
+                continue;
+            }
+
+            let subscription_match =
+                matches
+                    .entry(subscriber.clone())
+                    .or_insert_with(|| SubscriptionMatch {
+                        subscriber: subscriber.clone(),
+                        qos: options.qos,
+                        retain_as_published: false,
+                        subscription_identifiers: Vec::new(),
+                    });
+
+            subscription_match.qos = subscription_match.qos.max(options.qos);
+            subscription_match.retain_as_published |= options.retain_as_published;
+            subscription_match
+                .subscription_identifiers
+                .extend(options.subscription_identifier);
+        }
+    }
+
+    /// The number of subscriptions in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_shared_filter;
+    use super::SubscriptionMatch;
+    use super::SubscriptionOptions;
+    use super::SubscriptionTree;
+    use crate::qos::QualityOfService;
+
+    static_assertions::assert_impl_all!(SubscriptionTree<String>: Send, Sync);
+
+    fn subscribers(mut matches: Vec<SubscriptionMatch<&'static str>>) -> Vec<&'static str> {
+        matches.sort_by_key(|m| m.subscriber);
+        matches.into_iter().map(|m| m.subscriber).collect()
+    }
+
+    #[test]
+    fn wildcards_match() {
+        let mut tree = SubscriptionTree::new();
+        for (subscriber, filter) in [
+            ("exact", "sport/tennis/player1"),
+            ("single", "sport/+/player1"),
+            ("multi", "sport/#"),
+            ("all", "#"),
+            ("root_single", "+/tennis/#"),
+            ("empty_level", "sport//player1"),
+        ] {
+            tree.subscribe(
+                &filter.parse().unwrap(),
+                subscriber,
+                SubscriptionOptions::new(QualityOfService::AtMostOnce),
+            );
+        }
+        assert_eq!(tree.len(), 6);
+
+        assert_eq!(
+            subscribers(tree.matches::<&str>("sport/tennis/player1", None)),
+            ["all", "exact", "multi", "root_single", "single"]
+        );
+        assert_eq!(
+            subscribers(tree.matches::<&str>("sport", None)),
+            ["all", "multi"]
+        );
+        assert_eq!(
+            subscribers(tree.matches::<&str>("sport//player1", None)),
+            ["all", "empty_level", "multi", "single"]
+        );
+        assert_eq!(subscribers(tree.matches::<&str>("other", None)), ["all"]);
+    }
+
+    #[test]
+    fn system_topics_are_not_matched_by_leading_wildcards() {
+        let mut tree = SubscriptionTree::new();
+        for (subscriber, filter) in [
+            ("all", "#"),
+            ("single", "+/broker/uptime"),
+            ("sys", "$SYS/#"),
+        ] {
+            tree.subscribe(
+                &filter.parse().unwrap(),
+                subscriber,
+                SubscriptionOptions::new(QualityOfService::AtMostOnce),
+            );
+        }
+
+        assert_eq!(
+            subscribers(tree.matches::<&str>("$SYS/broker/uptime", None)),
+            ["sys"]
+        );
+    }
+
+    #[test]
+    fn overlapping_subscriptions_are_combined() {
+        let mut tree = SubscriptionTree::new();
+        tree.subscribe(
+            &"a/+".parse().unwrap(),
+            "client",
+            SubscriptionOptions {
+                subscription_identifier: Some(1),
+                ..SubscriptionOptions::new(QualityOfService::AtMostOnce)
+            },
+        );
+        tree.subscribe(
+            &"a/#".parse().unwrap(),
+            "client",
+            SubscriptionOptions {
+                subscription_identifier: Some(2),
+                ..SubscriptionOptions::new(QualityOfService::ExactlyOnce)
+            },
+        );
+
+        let mut matches = tree.matches::<&str>("a/b", None);
+        assert_eq!(matches.len(), 1);
+        let m = matches.pop().unwrap();
+        assert_eq!(m.qos, QualityOfService::ExactlyOnce);
+        let mut identifiers = m.subscription_identifiers;
+        identifiers.sort();
+        assert_eq!(identifiers, [1, 2]);
+    }
+
+    #[test]
+    fn no_local_only_filters_own_messages() {
+        let mut tree = SubscriptionTree::new();
+        let no_local = SubscriptionOptions {
+            no_local: true,
+            ..SubscriptionOptions::new(QualityOfService::AtMostOnce)
+        };
+        tree.subscribe(&"a".parse().unwrap(), "publisher", no_local.clone());
+        tree.subscribe(&"a".parse().unwrap(), "other", no_local);
+
+        assert_eq!(
+            subscribers(tree.matches("a", Some(&"publisher"))),
+            ["other"]
+        );
+    }
+
+    #[test]
+    fn unsubscribe_prunes_the_tree() {
+        let mut tree = SubscriptionTree::new();
+        let options = SubscriptionOptions::new(QualityOfService::AtMostOnce);
+        tree.subscribe(&"a/b/c".parse().unwrap(), "one", options.clone());
+        tree.subscribe(&"a/b".parse().unwrap(), "one", options.clone());
+        tree.subscribe(&"a/#".parse().unwrap(), "two", options.clone());
+
+        assert!(tree
+            .unsubscribe(&"a/b/c".parse().unwrap(), &"two")
+            .is_none());
+        assert_eq!(
+            tree.unsubscribe(&"a/b/c".parse().unwrap(), &"one"),
+            Some(options)
+        );
+        assert!(!tree.root.children["a"].children["b"]
+            .children
+            .contains_key("c"));
+
+        tree.unsubscribe_all(&"one");
+        assert_eq!(tree.len(), 1);
+        assert_eq!(subscribers(tree.matches::<&str>("a/b", None)), ["two"]);
+
+        tree.unsubscribe_all(&"two");
+        assert!(tree.is_empty());
+        assert!(tree.root.is_empty());
+    }
+
+    #[test]
+    fn shared_subscriptions_form_groups() {
+        assert_eq!(
+            split_shared_filter("$share/group/a/+"),
+            Some(("group", "a/+"))
+        );
+        for invalid in [
+            "a/b",
+            "$share/group",
+            "$share//a",
+            "$share/gr+oup/a",
+            "$share/group/",
+        ] {
+            assert_eq!(split_shared_filter(invalid), None, "{invalid}");
+        }
+
+        let mut tree = SubscriptionTree::new();
+        let options = SubscriptionOptions::new(QualityOfService::AtMostOnce);
+        for (subscriber, filter) in [
+            ("one", "$share/group/a/+"),
+            ("two", "$share/group/a/+"),
+            ("three", "$share/other/a/#"),
+            ("plain", "a/b"),
+        ] {
+            tree.subscribe(&filter.parse().unwrap(), subscriber, options.clone());
+        }
+        assert_eq!(tree.len(), 4);
+
+        // Shared subscriptions are not matched like ordinary ones
+        assert_eq!(subscribers(tree.matches::<&str>("a/b", None)), ["plain"]);
+
+        let mut groups = tree.shared_matches("a/b");
+        groups.sort_by(|a, b| a.shared_filter.cmp(&b.shared_filter));
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].shared_filter, "$share/group/a/+");
+        assert_eq!(
+            groups[0].members.iter().map(|m| m.0).collect::<Vec<_>>(),
+            ["one", "two"]
+        );
+        assert_eq!(groups[0].turn, 0);
+        assert_eq!(tree.shared_group("$share/group/a/+").unwrap().turn, 1);
+
+        assert!(tree.unsubscribe(&"a/+".parse().unwrap(), &"one").is_none());
+        assert_eq!(
+            tree.unsubscribe(&"$share/group/a/+".parse().unwrap(), &"one"),
+            Some(options)
+        );
+        tree.unsubscribe_all(&"two");
+        assert!(tree.shared_group("$share/group/a/+").is_none());
+        assert_eq!(tree.shared_matches("a/b").len(), 1);
+
+        tree.unsubscribe_all(&"three");
+        tree.unsubscribe_all(&"plain");
+        assert!(tree.is_empty());
+        assert!(tree.root.is_empty());
+    }
+}
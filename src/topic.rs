@@ -69,3 +69,146 @@ fn try_from(value: &str) -> Result<Self, Self::Error> {
         Self::from_str(value)
     }
 }
+
+/// A topic filter as used in subscriptions, which may contain the wildcards `+` and `#`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MqttTopicFilter(MqttString);
+
+impl AsRef<str> for MqttTopicFilter {
+    fn as_ref(&self) -> &str {
+        self.0.as_ref()
+    }
+}
+
+impl MqttTopicFilter {
+    /// Whether a message published to `topic` matches this filter
+    ///
+    /// Topics starting with `$` are not matched by filters starting with a wildcard.
+    pub fn matches(&self, topic: &str) -> bool {
+        let filter = self.0.as_ref();
+        if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+            return false;
+        }
+
+        let mut topic_levels = topic.split('/');
+        for filter_level in filter.split('/') {
+            if filter_level == "#" {
+                return true;
+            }
+
+            match topic_levels.next() {
+                Some(topic_level) if filter_level == "+" || filter_level == topic_level => {}
+                _ => return false,
+            }
+        }
+
+        topic_levels.next().is_none()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MqttTopicFilterError {
+    #[error(transparent)]
+    String(#[from] MqttStringError),
+
+    #[error("MQTT Topic Filters are not allowed to be empty")]
+    Empty,
+
+    #[error("MQTT Topic Filters are not allowed to contain a NULL (U+0000) character")]
+    Null,
+
+    #[error("The single-level wildcard '+' has to occupy an entire level")]
+    InvalidSingleLevelWildcard,
+
+    #[error("The multi-level wildcard '#' has to be the last level and occupy it entirely")]
+    InvalidMultiLevelWildcard,
+}
+
+impl FromStr for MqttTopicFilter {
+    type Err = MqttTopicFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(MqttTopicFilterError::Empty);
+        }
+
+        if s.contains('\0') {
+            return Err(MqttTopicFilterError::Null);
+        }
+
+        let mut levels = s.split('/').peekable();
+        while let Some(level) = levels.next() {
+            if level.contains('+') && level != "+" {
+                return Err(MqttTopicFilterError::InvalidSingleLevelWildcard);
+            }
+
+            if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+                return Err(MqttTopicFilterError::InvalidMultiLevelWildcard);
+            }
+        }
+
+        Ok(MqttTopicFilter(MqttString::from_str(s)?))
+    }
+}
+
+impl TryFrom<String> for MqttTopicFilter {
+    type Error = MqttTopicFilterError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
+
+impl TryFrom<&str> for MqttTopicFilter {
+    type Error = MqttTopicFilterError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::from_str(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::MqttTopicFilter;
+    use super::MqttTopicFilterError;
+
+    #[test]
+    fn topic_filter_wildcards() {
+        for valid in ["a/b", "#", "+", "a/+/c", "a/#", "+/+", "/", "a//b"] {
+            assert!(MqttTopicFilter::from_str(valid).is_ok(), "{valid}");
+        }
+
+        assert!(matches!(
+            MqttTopicFilter::from_str("a/b+"),
+            Err(MqttTopicFilterError::InvalidSingleLevelWildcard)
+        ));
+        assert!(matches!(
+            MqttTopicFilter::from_str("a/#/c"),
+            Err(MqttTopicFilterError::InvalidMultiLevelWildcard)
+        ));
+        assert!(matches!(
+            MqttTopicFilter::from_str("a#"),
+            Err(MqttTopicFilterError::InvalidMultiLevelWildcard)
+        ));
+        assert!(matches!(
+            MqttTopicFilter::from_str(""),
+            Err(MqttTopicFilterError::Empty)
+        ));
+    }
+
+    #[test]
+    fn topic_filter_matches() {
+        let filter = |s: &str| MqttTopicFilter::from_str(s).unwrap();
+
+        assert!(filter("sport/#").matches("sport"));
+        assert!(filter("sport/#").matches("sport/tennis/player1"));
+        assert!(filter("sport/+/player1").matches("sport/tennis/player1"));
+        assert!(filter("+/+").matches("/finance"));
+        assert!(!filter("sport/+").matches("sport/tennis/player1"));
+        assert!(!filter("sport/tennis").matches("sport"));
+        assert!(!filter("#").matches("$SYS/broker"));
+        assert!(filter("$SYS/#").matches("$SYS/broker"));
+    }
+}
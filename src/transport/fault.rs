@@ -0,0 +1,544 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Injecting faults into a transport, for testing how a connection copes with an unreliable network
+//!
+//! Faults are applied to every chunk of bytes that is read or written, which usually corresponds to
+//! one or a few packets. Random decisions are taken from a seeded generator, so a failing test can
+//! be reproduced with the same seed.
+//!
+//! ```
+//! # async fn run() {
+//! use cloudmqtt::transport::fault::FaultConfig;
+//! use cloudmqtt::transport::MqttConnectTransport;
+//!
+//! let (client, _server) = tokio::io::duplex(1024);
+//! let (transport, faults) = FaultConfig::new(42)
+//!     .with_delay(std::time::Duration::from_millis(10))
+//!     .with_corrupt_probability(0.01)
+//!     .inject(MqttConnectTransport::TokioDuplex(client));
+//!
+//! // Later on, simulate the connection breaking down
+//! faults.disconnect();
+//! # }
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::ready;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::task::AtomicWaker;
+use futures_timer::Delay;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+
+use super::MqttConnectTransport;
+use super::MqttConnection;
+use super::MqttTransport;
+
+/// Which direction of the connection the per-chunk faults are applied to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultDirection {
+    Incoming,
+    Outgoing,
+    Both,
+}
+
+impl FaultDirection {
+    fn incoming(self) -> bool {
+        matches!(self, FaultDirection::Incoming | FaultDirection::Both)
+    }
+
+    fn outgoing(self) -> bool {
+        matches!(self, FaultDirection::Outgoing | FaultDirection::Both)
+    }
+}
+
+/// Which faults to inject, all of them are disabled by default
+#[derive(Debug, Clone)]
+pub struct FaultConfig {
+    seed: u64,
+    direction: FaultDirection,
+    delay: Option<Duration>,
+    drop_probability: f64,
+    truncate_probability: f64,
+    duplicate_probability: f64,
+    corrupt_probability: f64,
+    disconnect_probability: f64,
+    disconnect_after: Option<Duration>,
+    disconnect_after_bytes: Option<usize>,
+}
+
+impl FaultConfig {
+    /// A configuration without any faults, using `seed` for all random decisions
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            direction: FaultDirection::Both,
+            delay: None,
+            drop_probability: 0.0,
+            truncate_probability: 0.0,
+            duplicate_probability: 0.0,
+            corrupt_probability: 0.0,
+            disconnect_probability: 0.0,
+            disconnect_after: None,
+            disconnect_after_bytes: None,
+        }
+    }
+
+    /// Only inject per-chunk faults in the given direction, defaults to both
+    pub fn with_direction(mut self, direction: FaultDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Hold back every chunk for the given time
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Silently drop whole chunks
+    pub fn with_drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = probability;
+        self
+    }
+
+    /// Cut off chunks at a random position
+    pub fn with_truncate_probability(mut self, probability: f64) -> Self {
+        self.truncate_probability = probability;
+        self
+    }
+
+    /// Send or receive chunks twice
+    pub fn with_duplicate_probability(mut self, probability: f64) -> Self {
+        self.duplicate_probability = probability;
+        self
+    }
+
+    /// Flip a random bit in chunks
+    pub fn with_corrupt_probability(mut self, probability: f64) -> Self {
+        self.corrupt_probability = probability;
+        self
+    }
+
+    /// Break the connection when a chunk is transferred
+    pub fn with_disconnect_probability(mut self, probability: f64) -> Self {
+        self.disconnect_probability = probability;
+        self
+    }
+
+    /// Break the connection once the given time has passed since it was wrapped
+    pub fn with_disconnect_after(mut self, after: Duration) -> Self {
+        self.disconnect_after = Some(after);
+        self
+    }
+
+    /// Break the connection once the given number of bytes was transferred in either direction
+    pub fn with_disconnect_after_bytes(mut self, bytes: usize) -> Self {
+        self.disconnect_after_bytes = Some(bytes);
+        self
+    }
+
+    /// Wrap `transport`, returning the faulty transport and a handle to break it at will
+    pub fn inject(self, transport: MqttConnectTransport) -> (MqttConnectTransport, FaultHandle) {
+        let transport = FaultyTransport::new(MqttConnection::from(transport), self);
+        let handle = transport.handle();
+
+        (MqttConnectTransport::custom(transport), handle)
+    }
+}
+
+/// A small SplitMix64 generator, good enough for deciding on faults and stable across versions
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+struct Faults {
+    config: FaultConfig,
+    rng: Rng,
+}
+
+impl Faults {
+    fn apply(&mut self, chunk: &[u8]) -> Vec<u8> {
+        if self.rng.chance(self.config.drop_probability) {
+            tracing::debug!(len = chunk.len(), "Dropping chunk");
+            return Vec::new();
+        }
+
+        let mut chunk = chunk.to_vec();
+
+        if !chunk.is_empty() && self.rng.chance(self.config.truncate_probability) {
+            let len = self.rng.below(chunk.len());
+            tracing::debug!(from = chunk.len(), to = len, "Truncating chunk");
+            chunk.truncate(len);
+        }
+
+        if !chunk.is_empty() && self.rng.chance(self.config.corrupt_probability) {
+            let position = self.rng.below(chunk.len());
+            tracing::debug!(%position, "Corrupting chunk");
+            chunk[position] ^= 1 << self.rng.below(8);
+        }
+
+        if self.rng.chance(self.config.duplicate_probability) {
+            tracing::debug!(len = chunk.len(), "Duplicating chunk");
+            chunk.extend_from_within(..);
+        }
+
+        chunk
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    disconnected: AtomicBool,
+    read_waker: AtomicWaker,
+    write_waker: AtomicWaker,
+}
+
+impl Shared {
+    fn disconnect(&self) {
+        if !self.disconnected.swap(true, Ordering::SeqCst) {
+            tracing::debug!("Injecting disconnect");
+        }
+        self.read_waker.wake();
+        self.write_waker.wake();
+    }
+}
+
+/// Breaks the connection of a [`FaultyTransport`] from the outside
+#[derive(Clone)]
+pub struct FaultHandle {
+    shared: Arc<Shared>,
+}
+
+impl FaultHandle {
+    /// Fail all pending and future reads and writes of the transport
+    pub fn disconnect(&self) {
+        self.shared.disconnect();
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.shared.disconnected.load(Ordering::SeqCst)
+    }
+}
+
+/// A transport that injects faults into the wrapped transport
+///
+/// Written chunks are accepted completely and then written out in the background of the following
+/// writes and flushes. After a disconnect all operations fail with
+/// [`ConnectionReset`](std::io::ErrorKind::ConnectionReset), the wrapped transport is closed once
+/// this one is dropped.
+pub struct FaultyTransport<T> {
+    inner: T,
+    faults: Faults,
+    shared: Arc<Shared>,
+    disconnect_timer: Option<Delay>,
+    bytes_transferred: usize,
+    write_delay: Option<Delay>,
+    write_buffer: Vec<u8>,
+    write_position: usize,
+    read_delay: Option<Delay>,
+    read_buffer: Vec<u8>,
+    read_position: usize,
+}
+
+impl<T: MqttTransport> FaultyTransport<T> {
+    pub fn new(inner: T, config: FaultConfig) -> Self {
+        Self {
+            inner,
+            disconnect_timer: config.disconnect_after.map(Delay::new),
+            faults: Faults {
+                rng: Rng(config.seed),
+                config,
+            },
+            shared: Arc::default(),
+            bytes_transferred: 0,
+            write_delay: None,
+            write_buffer: Vec::new(),
+            write_position: 0,
+            read_delay: None,
+            read_buffer: Vec::new(),
+            read_position: 0,
+        }
+    }
+
+    pub fn handle(&self) -> FaultHandle {
+        FaultHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    fn poll_disconnected(&mut self, cx: &mut Context<'_>) -> std::io::Result<()> {
+        if let Some(timer) = &mut self.disconnect_timer {
+            if Pin::new(timer).poll(cx).is_ready() {
+                self.disconnect_timer = None;
+                self.shared.disconnect();
+            }
+        }
+
+        if self
+            .faults
+            .config
+            .disconnect_after_bytes
+            .is_some_and(|limit| self.bytes_transferred >= limit)
+        {
+            self.shared.disconnect();
+        }
+
+        if self.shared.disconnected.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "Connection reset by fault injection",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.write_position < self.write_buffer.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner)
+                    .poll_write(cx, &self.write_buffer[self.write_position..]))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_position += written;
+        }
+
+        self.write_buffer.clear();
+        self.write_position = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: MqttTransport> AsyncRead for FaultyTransport<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        this.shared.read_waker.register(cx.waker());
+
+        loop {
+            // Bytes that were already received are handed out even if the connection broke since
+            if this.read_position < this.read_buffer.len() {
+                if let Some(timer) = &mut this.read_delay {
+                    ready!(Pin::new(timer).poll(cx));
+                    this.read_delay = None;
+                }
+
+                let available = &this.read_buffer[this.read_position..];
+                let length = available.len().min(buf.remaining());
+                buf.put_slice(&available[..length]);
+                this.read_position += length;
+                return Poll::Ready(Ok(()));
+            }
+
+            this.poll_disconnected(cx)?;
+
+            let mut chunk = vec![0; buf.remaining()];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+
+            let received = chunk_buf.filled();
+            if received.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.bytes_transferred += received.len();
+
+            if this.faults.config.direction.incoming() {
+                if this
+                    .faults
+                    .rng
+                    .chance(this.faults.config.disconnect_probability)
+                {
+                    this.shared.disconnect();
+                    continue;
+                }
+
+                this.read_buffer = this.faults.apply(received);
+                this.read_delay = this.faults.config.delay.map(Delay::new);
+            } else {
+                this.read_buffer = received.to_vec();
+            }
+            this.read_position = 0;
+        }
+    }
+}
+
+impl<T: MqttTransport> AsyncWrite for FaultyTransport<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        this.shared.write_waker.register(cx.waker());
+        this.poll_disconnected(cx)?;
+        ready!(this.poll_write_buffer(cx))?;
+
+        if this.faults.config.direction.outgoing() {
+            if let Some(delay) = this.faults.config.delay {
+                let timer = this.write_delay.get_or_insert_with(|| Delay::new(delay));
+                ready!(Pin::new(timer).poll(cx));
+                this.write_delay = None;
+            }
+
+            if this
+                .faults
+                .rng
+                .chance(this.faults.config.disconnect_probability)
+            {
+                this.shared.disconnect();
+                this.poll_disconnected(cx)?;
+            }
+
+            this.write_buffer = this.faults.apply(buf);
+        } else {
+            this.write_buffer = buf.to_vec();
+        }
+        this.bytes_transferred += buf.len();
+
+        // The chunk is accepted either way, whatever could not be written yet is written later
+        if let Poll::Ready(Err(error)) = this.poll_write_buffer(cx) {
+            return Poll::Ready(Err(error));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        this.shared.write_waker.register(cx.waker());
+        this.poll_disconnected(cx)?;
+        ready!(this.poll_write_buffer(cx))?;
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        this.shared.write_waker.register(cx.waker());
+        this.poll_disconnected(cx)?;
+        ready!(this.poll_write_buffer(cx))?;
+
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    use super::FaultConfig;
+    use super::FaultDirection;
+    use super::FaultyTransport;
+
+    async fn send_through(config: FaultConfig, chunks: &[&[u8]]) -> Vec<u8> {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = FaultyTransport::new(client, config);
+
+        for chunk in chunks {
+            client.write_all(chunk).await.unwrap();
+        }
+        client.flush().await.unwrap();
+        drop(client);
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn faults_are_reproducible_with_the_same_seed() {
+        let chunks: &[&[u8]] = &[&[0xC0, 0x00], &[0xD0, 0x00], &[0xE0, 0x00]];
+
+        assert_eq!(
+            send_through(FaultConfig::new(1), chunks).await,
+            vec![0xC0, 0x00, 0xD0, 0x00, 0xE0, 0x00]
+        );
+
+        let config = FaultConfig::new(7)
+            .with_corrupt_probability(0.5)
+            .with_duplicate_probability(0.5)
+            .with_drop_probability(0.2);
+        let first = send_through(config.clone(), chunks).await;
+        assert_eq!(first, send_through(config, chunks).await);
+        assert_ne!(first, vec![0xC0, 0x00, 0xD0, 0x00, 0xE0, 0x00]);
+
+        let duplicated = send_through(
+            FaultConfig::new(3).with_duplicate_probability(1.0),
+            &[&[0xC0, 0x00]],
+        )
+        .await;
+        assert_eq!(duplicated, vec![0xC0, 0x00, 0xC0, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_fails_pending_read() {
+        let (client, _server) = tokio::io::duplex(1024);
+        let mut client = FaultyTransport::new(client, FaultConfig::new(0));
+        let handle = client.handle();
+
+        let read = tokio::spawn(async move {
+            let mut buf = [0; 16];
+            client.read(&mut buf).await
+        });
+
+        tokio::task::yield_now().await;
+        handle.disconnect();
+
+        let error = read.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(handle.is_disconnected());
+    }
+
+    #[tokio::test]
+    async fn disconnect_after_bytes() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = FaultyTransport::new(
+            client,
+            FaultConfig::new(0)
+                .with_direction(FaultDirection::Incoming)
+                .with_disconnect_after_bytes(4),
+        );
+
+        server.write_all(&[0xC0, 0x00, 0xD0, 0x00]).await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+
+        let error = client.write_all(&[0xC0, 0x00]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+}
@@ -0,0 +1,321 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use futures::AsyncRead as FuturesAsyncRead;
+use futures::AsyncWrite as FuturesAsyncWrite;
+use tokio::io::AsyncRead as TokioAsyncRead;
+use tokio::io::AsyncWrite as TokioAsyncWrite;
+use tokio::io::DuplexStream;
+#[cfg(feature = "tokio")]
+use tokio::net::TcpStream;
+use tokio_util::compat::Compat as TokioCompat;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+pub mod fault;
+pub mod proxy;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+/// A byte stream an MQTT connection can be established over
+///
+/// This is implemented for every tokio stream that is `Send + Unpin`, so TLS, WebSocket or
+/// in-memory streams can be used through [`MqttConnectTransport::Custom`].
+pub trait MqttTransport: TokioAsyncRead + TokioAsyncWrite + Send + Unpin + 'static {}
+
+impl<T> MqttTransport for T where T: TokioAsyncRead + TokioAsyncWrite + Send + Unpin + 'static {}
+
+/// Adapt a stream implementing the `futures` IO traits, e.g. from async-std or smol
+pub(crate) fn from_futures_io<T>(io: T) -> Box<dyn MqttTransport>
+where
+    T: FuturesAsyncRead + FuturesAsyncWrite + Send + Unpin + 'static,
+{
+    Box::new(io.compat())
+}
+
+pub(crate) enum MqttConnection {
+    #[cfg(feature = "tokio")]
+    Tokio(TokioCompat<tokio::net::TcpStream>),
+    Duplex(TokioCompat<tokio::io::DuplexStream>),
+    #[cfg(all(unix, feature = "tokio"))]
+    Unix(TokioCompat<tokio::net::UnixStream>),
+    Custom(TokioCompat<Box<dyn MqttTransport>>),
+}
+
+impl TokioAsyncRead for MqttConnection {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match &mut *self {
+            #[cfg(feature = "tokio")]
+            MqttConnection::Tokio(t) => std::pin::pin!(t.get_mut()).poll_read(cx, buf),
+            MqttConnection::Duplex(d) => std::pin::pin!(d.get_mut()).poll_read(cx, buf),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnection::Unix(u) => std::pin::pin!(u.get_mut()).poll_read(cx, buf),
+            MqttConnection::Custom(c) => std::pin::pin!(c.get_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl TokioAsyncWrite for MqttConnection {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        match &mut *self {
+            #[cfg(feature = "tokio")]
+            MqttConnection::Tokio(t) => std::pin::pin!(t.get_mut()).poll_write(cx, buf),
+            MqttConnection::Duplex(d) => std::pin::pin!(d.get_mut()).poll_write(cx, buf),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnection::Unix(u) => std::pin::pin!(u.get_mut()).poll_write(cx, buf),
+            MqttConnection::Custom(c) => std::pin::pin!(c.get_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        match &mut *self {
+            #[cfg(feature = "tokio")]
+            MqttConnection::Tokio(t) => std::pin::pin!(t.get_mut()).poll_flush(cx),
+            MqttConnection::Duplex(d) => std::pin::pin!(d.get_mut()).poll_flush(cx),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnection::Unix(u) => std::pin::pin!(u.get_mut()).poll_flush(cx),
+            MqttConnection::Custom(c) => std::pin::pin!(c.get_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        match &mut *self {
+            #[cfg(feature = "tokio")]
+            MqttConnection::Tokio(t) => std::pin::pin!(t.get_mut()).poll_shutdown(cx),
+            MqttConnection::Duplex(d) => std::pin::pin!(d.get_mut()).poll_shutdown(cx),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnection::Unix(u) => std::pin::pin!(u.get_mut()).poll_shutdown(cx),
+            MqttConnection::Custom(c) => std::pin::pin!(c.get_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+impl FuturesAsyncRead for MqttConnection {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match &mut *self {
+            #[cfg(feature = "tokio")]
+            MqttConnection::Tokio(t) => std::pin::pin!(t).poll_read(cx, buf),
+            MqttConnection::Duplex(d) => std::pin::pin!(d).poll_read(cx, buf),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnection::Unix(u) => std::pin::pin!(u).poll_read(cx, buf),
+            MqttConnection::Custom(c) => std::pin::pin!(c).poll_read(cx, buf),
+        }
+    }
+}
+
+impl FuturesAsyncWrite for MqttConnection {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match &mut *self {
+            #[cfg(feature = "tokio")]
+            MqttConnection::Tokio(t) => std::pin::pin!(t).poll_write(cx, buf),
+            MqttConnection::Duplex(d) => std::pin::pin!(d).poll_write(cx, buf),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnection::Unix(u) => std::pin::pin!(u).poll_write(cx, buf),
+            MqttConnection::Custom(c) => std::pin::pin!(c).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match &mut *self {
+            #[cfg(feature = "tokio")]
+            MqttConnection::Tokio(t) => std::pin::pin!(t).poll_flush(cx),
+            MqttConnection::Duplex(d) => std::pin::pin!(d).poll_flush(cx),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnection::Unix(u) => std::pin::pin!(u).poll_flush(cx),
+            MqttConnection::Custom(c) => std::pin::pin!(c).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match &mut *self {
+            #[cfg(feature = "tokio")]
+            MqttConnection::Tokio(t) => std::pin::pin!(t).poll_close(cx),
+            MqttConnection::Duplex(d) => std::pin::pin!(d).poll_close(cx),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnection::Unix(u) => std::pin::pin!(u).poll_close(cx),
+            MqttConnection::Custom(c) => std::pin::pin!(c).poll_close(cx),
+        }
+    }
+}
+
+pub enum MqttConnectTransport {
+    #[cfg(feature = "tokio")]
+    TokioTcp(TcpStream),
+    TokioDuplex(DuplexStream),
+    #[cfg(all(unix, feature = "tokio"))]
+    Unix(tokio::net::UnixStream),
+    Custom(Box<dyn MqttTransport>),
+}
+
+impl MqttConnectTransport {
+    pub fn custom<T: MqttTransport>(transport: T) -> Self {
+        MqttConnectTransport::Custom(Box::new(transport))
+    }
+
+    /// Use a stream implementing the `futures` IO traits, e.g. from async-std or smol
+    pub fn futures<T>(transport: T) -> Self
+    where
+        T: FuturesAsyncRead + FuturesAsyncWrite + Send + Unpin + 'static,
+    {
+        MqttConnectTransport::Custom(from_futures_io(transport))
+    }
+
+    #[cfg(all(unix, feature = "tokio"))]
+    pub async fn connect_unix(path: impl AsRef<std::path::Path> + Send) -> std::io::Result<Self> {
+        tokio::net::UnixStream::connect(path)
+            .await
+            .map(MqttConnectTransport::Unix)
+    }
+
+    /// Connect to a socket in the abstract namespace, `name` is given without the leading NUL byte
+    ///
+    /// Only the standard library can connect to abstract addresses, and it blocks while the
+    /// backlog of the listener is full, so the connect runs on the blocking thread pool of tokio.
+    #[cfg(all(target_os = "linux", feature = "tokio"))]
+    pub async fn connect_unix_abstract(name: &[u8]) -> std::io::Result<Self> {
+        use std::os::linux::net::SocketAddrExt;
+
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let stream = tokio::task::spawn_blocking(move || {
+            std::os::unix::net::UnixStream::connect_addr(&address)
+        })
+        .await??;
+        stream.set_nonblocking(true)?;
+
+        tokio::net::UnixStream::from_std(stream).map(MqttConnectTransport::Unix)
+    }
+}
+
+impl From<MqttConnectTransport> for MqttConnection {
+    fn from(value: MqttConnectTransport) -> Self {
+        match value {
+            #[cfg(feature = "tokio")]
+            MqttConnectTransport::TokioTcp(t) => MqttConnection::Tokio(t.compat()),
+            MqttConnectTransport::TokioDuplex(d) => MqttConnection::Duplex(d.compat()),
+            #[cfg(all(unix, feature = "tokio"))]
+            MqttConnectTransport::Unix(u) => MqttConnection::Unix(u.compat()),
+            MqttConnectTransport::Custom(c) => MqttConnection::Custom(c.compat()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::pingreq::MPingreq;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_util::codec::Framed;
+
+    use super::MqttConnectTransport;
+    use super::MqttConnection;
+    use crate::codecs::MqttPacketCodec;
+
+    #[tokio::test]
+    async fn custom_transport_roundtrip() {
+        let (client, server) = tokio::io::duplex(100);
+
+        let mut client = Framed::new(
+            MqttConnection::from(MqttConnectTransport::custom(client)),
+            MqttPacketCodec,
+        );
+        let mut server = Framed::new(
+            MqttConnection::from(MqttConnectTransport::custom(server)),
+            MqttPacketCodec,
+        );
+
+        client
+            .send(FormatMqttPacket::Pingreq(MPingreq))
+            .await
+            .unwrap();
+
+        let packet = server.next().await.unwrap().unwrap();
+        assert_eq!(*packet.get(), FormatMqttPacket::Pingreq(MPingreq));
+    }
+
+    #[cfg(all(unix, feature = "tokio"))]
+    async fn assert_pingreq_roundtrip(
+        client: MqttConnectTransport,
+        server: tokio::net::UnixStream,
+    ) {
+        let mut client = Framed::new(MqttConnection::from(client), MqttPacketCodec);
+        let mut server = Framed::new(
+            MqttConnection::from(MqttConnectTransport::Unix(server)),
+            MqttPacketCodec,
+        );
+
+        client
+            .send(FormatMqttPacket::Pingreq(MPingreq))
+            .await
+            .unwrap();
+
+        let packet = server.next().await.unwrap().unwrap();
+        assert_eq!(*packet.get(), FormatMqttPacket::Pingreq(MPingreq));
+    }
+
+    #[cfg(all(unix, feature = "tokio"))]
+    #[tokio::test]
+    async fn unix_socket_roundtrip() {
+        let path = std::env::temp_dir().join(format!("cloudmqtt-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let client = MqttConnectTransport::connect_unix(&path).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_pingreq_roundtrip(client, server).await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "tokio"))]
+    #[tokio::test]
+    async fn abstract_unix_socket_roundtrip() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("cloudmqtt-test-{}", std::process::id());
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let listener = std::os::unix::net::UnixListener::bind_addr(&address).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::UnixListener::from_std(listener).unwrap();
+
+        let client = MqttConnectTransport::connect_unix_abstract(name.as_bytes())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        assert_pingreq_roundtrip(client, server).await;
+    }
+}
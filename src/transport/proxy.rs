@@ -0,0 +1,448 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Tunnelling connections through HTTP CONNECT or SOCKS5 proxies
+//!
+//! The tunnel is established over a stream to the proxy, afterwards the same stream is connected
+//! to the target and can be used directly or wrapped further, e.g. with TLS:
+//!
+//! ```no_run
+//! # async fn connect() -> Result<(), Box<dyn std::error::Error>> {
+//! use cloudmqtt::transport::proxy::Proxy;
+//! use cloudmqtt::transport::MqttConnectTransport;
+//!
+//! let stream = tokio::net::TcpStream::connect("proxy.example.com:3128").await?;
+//! let stream = Proxy::http_connect()
+//!     .tunnel(stream, "broker.example.com", 1883)
+//!     .await?;
+//! let transport = MqttConnectTransport::custom(stream);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Endpoints given as [`MqttUrlTransport`](crate::client::connect_url::MqttUrlTransport) are
+//! tunnelled through a [`ProxyServer`] with
+//! [`MqttUrlTransport::connect_with_proxy`](crate::client::connect_url::MqttUrlTransport::connect_with_proxy)
+//! or [`MqttFailoverConnector::with_proxy`](crate::client::failover::MqttFailoverConnector::with_proxy).
+
+use base64::Engine;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+
+use super::MqttTransport;
+
+/// The maximum size of the response header of an HTTP proxy
+const MAX_HTTP_RESPONSE_SIZE: usize = 8 * 1024;
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_NO_AUTHENTICATION: u8 = 0x00;
+const SOCKS5_USERNAME_PASSWORD: u8 = 0x02;
+const SOCKS5_NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const SOCKS5_CONNECT: u8 = 0x01;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    #[error("Could not communicate with the proxy")]
+    Io(#[from] std::io::Error),
+
+    #[error("The proxy refused the tunnel: {}", .0)]
+    HttpRefused(String),
+
+    #[error("The proxy sent an invalid HTTP response")]
+    InvalidHttpResponse,
+
+    #[error("The proxy does not accept any of the offered authentication methods")]
+    Socks5NoAcceptableMethod,
+
+    #[error("The proxy rejected the credentials")]
+    Socks5AuthenticationFailed,
+
+    #[error("The proxy could not connect to the target, reply code {:#04x}", .0)]
+    Socks5ConnectFailed(u8),
+
+    #[error("The proxy sent an invalid SOCKS5 response")]
+    InvalidSocks5Response,
+
+    #[error("The {} is longer than 255 bytes, which SOCKS5 does not support", .0)]
+    Socks5ValueTooLong(&'static str),
+}
+
+#[derive(Clone)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for ProxyCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyCredentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Proxy {
+    /// An HTTP proxy supporting the `CONNECT` method, with optional basic authentication
+    HttpConnect {
+        credentials: Option<ProxyCredentials>,
+    },
+    /// A SOCKS5 proxy, with optional username/password authentication
+    Socks5 {
+        credentials: Option<ProxyCredentials>,
+    },
+}
+
+impl Proxy {
+    pub fn http_connect() -> Self {
+        Proxy::HttpConnect { credentials: None }
+    }
+
+    pub fn socks5() -> Self {
+        Proxy::Socks5 { credentials: None }
+    }
+
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        let new = Some(ProxyCredentials {
+            username: username.into(),
+            password: password.into(),
+        });
+        match &mut self {
+            Proxy::HttpConnect { credentials } | Proxy::Socks5 { credentials } => {
+                *credentials = new
+            }
+        }
+        self
+    }
+
+    /// Ask the proxy at the other end of `stream` to connect to `host` and `port`
+    ///
+    /// Once this returns, everything written to the stream is forwarded to the target.
+    pub async fn tunnel<S: MqttTransport>(
+        &self,
+        mut stream: S,
+        host: &str,
+        port: u16,
+    ) -> Result<S, ProxyError> {
+        match self {
+            Proxy::HttpConnect { credentials } => {
+                http_connect(&mut stream, host, port, credentials.as_ref()).await?
+            }
+            Proxy::Socks5 { credentials } => {
+                socks5_connect(&mut stream, host, port, credentials.as_ref()).await?
+            }
+        }
+
+        tracing::debug!(%host, %port, "Established proxy tunnel");
+        Ok(stream)
+    }
+}
+
+/// A proxy together with the address it is reached at
+#[derive(Debug, Clone)]
+pub struct ProxyServer {
+    pub proxy: Proxy,
+    pub host: String,
+    pub port: u16,
+}
+
+impl ProxyServer {
+    pub fn new(proxy: Proxy, host: impl Into<String>, port: u16) -> Self {
+        Self {
+            proxy,
+            host: host.into(),
+            port,
+        }
+    }
+}
+
+async fn http_connect<S: MqttTransport>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    credentials: Option<&ProxyCredentials>,
+) -> Result<(), ProxyError> {
+    let authority = if host.parse::<std::net::Ipv6Addr>().is_ok() {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(credentials) = credentials {
+        let token = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", credentials.username, credentials.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // The response is read byte by byte, so that nothing after the header is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE_SIZE {
+            return Err(ProxyError::InvalidHttpResponse);
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = std::str::from_utf8(&response).map_err(|_| ProxyError::InvalidHttpResponse)?;
+    let status_line = response.lines().next().unwrap_or_default();
+
+    let mut parts = status_line.splitn(3, ' ');
+    let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+        return Err(ProxyError::InvalidHttpResponse);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(ProxyError::InvalidHttpResponse);
+    }
+    if !status.starts_with('2') || status.len() != 3 {
+        return Err(ProxyError::HttpRefused(status_line.to_owned()));
+    }
+
+    Ok(())
+}
+
+async fn socks5_connect<S: MqttTransport>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    credentials: Option<&ProxyCredentials>,
+) -> Result<(), ProxyError> {
+    let method = match credentials {
+        Some(_) => SOCKS5_USERNAME_PASSWORD,
+        None => SOCKS5_NO_AUTHENTICATION,
+    };
+    stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+    stream.flush().await?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    match reply {
+        [SOCKS5_VERSION, SOCKS5_NO_ACCEPTABLE_METHOD] => {
+            return Err(ProxyError::Socks5NoAcceptableMethod)
+        }
+        [SOCKS5_VERSION, selected] if selected == method => {}
+        _ => return Err(ProxyError::InvalidSocks5Response),
+    }
+
+    if let Some(credentials) = credentials {
+        // RFC 1929
+        let username = socks5_value("username", credentials.username.as_bytes())?;
+        let password = socks5_value("password", credentials.password.as_bytes())?;
+
+        let mut request = vec![0x01];
+        request.extend_from_slice(&username);
+        request.extend_from_slice(&password);
+        stream.write_all(&request).await?;
+        stream.flush().await?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(ProxyError::Socks5AuthenticationFailed);
+        }
+    }
+
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CONNECT, 0x00];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(address)) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&address.octets());
+        }
+        Ok(std::net::IpAddr::V6(address)) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            request.extend_from_slice(&address.octets());
+        }
+        Err(_) => {
+            request.push(SOCKS5_ATYP_DOMAIN);
+            request.extend_from_slice(&socks5_value("host", host.as_bytes())?);
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(ProxyError::InvalidSocks5Response);
+    }
+    if reply[1] != 0x00 {
+        return Err(ProxyError::Socks5ConnectFailed(reply[1]));
+    }
+
+    // The bound address is not needed, but has to be consumed
+    let address_length = match reply[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(ProxyError::InvalidSocks5Response),
+    };
+    let mut bound = vec![0; address_length + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+/// Encode a value prefixed with its length as a single byte
+fn socks5_value(name: &'static str, value: &[u8]) -> Result<Vec<u8>, ProxyError> {
+    let length = u8::try_from(value.len()).map_err(|_| ProxyError::Socks5ValueTooLong(name))?;
+
+    let mut encoded = vec![length];
+    encoded.extend_from_slice(value);
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::pingreq::MPingreq;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::Framed;
+
+    use super::Proxy;
+    use super::ProxyError;
+    use crate::codecs::MqttPacketCodec;
+    use crate::transport::MqttConnectTransport;
+    use crate::transport::MqttConnection;
+
+    async fn assert_pingreq_tunnelled(client: DuplexStream, mut proxy: DuplexStream) {
+        let mut client = Framed::new(
+            MqttConnection::from(MqttConnectTransport::TokioDuplex(client)),
+            MqttPacketCodec,
+        );
+        client
+            .send(FormatMqttPacket::Pingreq(MPingreq))
+            .await
+            .unwrap();
+
+        let mut packet = [0; 2];
+        proxy.read_exact(&mut packet).await.unwrap();
+        assert_eq!(packet, [0xC0, 0x00]);
+
+        // The first bytes of the target are not swallowed by the handshake
+        proxy.write_all(&[0xD0, 0x00]).await.unwrap();
+        let packet = client.next().await.unwrap().unwrap();
+        assert!(matches!(packet.get(), FormatMqttPacket::Pingresp(_)));
+    }
+
+    #[tokio::test]
+    async fn http_connect_with_basic_auth() {
+        let (client, mut proxy) = tokio::io::duplex(1024);
+
+        let stub = tokio::spawn(async move {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(proxy.read_u8().await.unwrap());
+            }
+            assert_eq!(
+                std::str::from_utf8(&request).unwrap(),
+                "CONNECT broker:1883 HTTP/1.1\r\nHost: broker:1883\r\n\
+                 Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+            );
+
+            proxy
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            proxy
+        });
+
+        let proxy_config = Proxy::http_connect().with_credentials("user", "pass");
+        let (client, proxy) = futures::join!(proxy_config.tunnel(client, "broker", 1883), stub);
+
+        assert_pingreq_tunnelled(client.unwrap(), proxy.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn http_connect_refused() {
+        let (client, mut proxy) = tokio::io::duplex(1024);
+
+        let stub = tokio::spawn(async move {
+            let mut request = [0; 64];
+            let _ = proxy.read(&mut request).await.unwrap();
+            proxy
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+            proxy
+        });
+
+        let result = Proxy::http_connect().tunnel(client, "broker", 1883).await;
+        assert!(matches!(result, Err(ProxyError::HttpRefused(_))));
+        drop(stub.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn socks5_with_username_password() {
+        let (client, mut proxy) = tokio::io::duplex(1024);
+
+        let stub = tokio::spawn(async move {
+            let mut greeting = [0; 3];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x02]);
+            proxy.write_all(&[0x05, 0x02]).await.unwrap();
+
+            let mut auth = [0; 11];
+            proxy.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            proxy.write_all(&[0x01, 0x00]).await.unwrap();
+
+            let mut connect = [0; 13];
+            proxy.read_exact(&mut connect).await.unwrap();
+            assert_eq!(&connect, b"\x05\x01\x00\x03\x06broker\x07\x5b");
+            proxy
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x04, 0x00])
+                .await
+                .unwrap();
+            proxy
+        });
+
+        let proxy_config = Proxy::socks5().with_credentials("user", "pass");
+        let (client, proxy) = futures::join!(proxy_config.tunnel(client, "broker", 1883), stub);
+
+        assert_pingreq_tunnelled(client.unwrap(), proxy.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_failure() {
+        let (client, mut proxy) = tokio::io::duplex(1024);
+
+        let stub = tokio::spawn(async move {
+            let mut greeting = [0; 3];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            proxy.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut connect = [0; 10];
+            proxy.read_exact(&mut connect).await.unwrap();
+            assert_eq!(connect[3], 0x01);
+            // Connection refused
+            proxy
+                .write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            proxy
+        });
+
+        let result = Proxy::socks5().tunnel(client, "10.0.0.1", 1883).await;
+        assert!(matches!(result, Err(ProxyError::Socks5ConnectFailed(0x05))));
+        drop(stub.await.unwrap());
+    }
+}
@@ -0,0 +1,302 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! TLS transport based on rustls
+//!
+//! ```no_run
+//! # async fn connect() -> Result<(), Box<dyn std::error::Error>> {
+//! use cloudmqtt::transport::tls::TlsConfig;
+//!
+//! let config = TlsConfig::builder()
+//!     .add_root_certificates_pem(&std::fs::read("ca.pem")?)?
+//!     .build()?;
+//!
+//! let stream = tokio::net::TcpStream::connect("broker.example.com:8883").await?;
+//! let transport = config.connect(stream, "broker.example.com").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+pub use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::rustls::RootCertStore;
+
+use super::MqttConnectTransport;
+use super::MqttTransport;
+
+/// The ALPN protocol name registered for MQTT
+pub const MQTT_ALPN_PROTOCOL: &[u8] = b"mqtt";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("The server name is not a valid DNS name or IP address")]
+    InvalidServerName(#[from] rustls::pki_types::InvalidDnsNameError),
+
+    #[error("Could not read PEM data")]
+    Pem(#[source] std::io::Error),
+
+    #[error("Could not load the root certificates of the platform")]
+    NativeRoots(#[source] std::io::Error),
+
+    #[error("The PEM data does not contain a private key")]
+    MissingPrivateKey,
+
+    #[error("Invalid TLS configuration")]
+    Config(#[from] rustls::Error),
+
+    #[error("The TLS handshake failed")]
+    Handshake(#[source] std::io::Error),
+}
+
+/// A client TLS configuration that can be shared between connections
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConfig {
+    pub fn builder() -> TlsConfigBuilder {
+        TlsConfigBuilder::new()
+    }
+
+    /// Use a rustls configuration as is, ALPN and SNI are not changed
+    pub fn from_rustls(config: Arc<ClientConfig>) -> Self {
+        Self { config }
+    }
+
+    /// Run the TLS handshake over `stream` and verify the certificate against `server_name`
+    ///
+    /// `server_name` is also sent via SNI, unless disabled or an IP address is given.
+    pub async fn connect<S: MqttTransport>(
+        &self,
+        stream: S,
+        server_name: &str,
+    ) -> Result<MqttConnectTransport, TlsError> {
+        self.connect_stream(stream, server_name)
+            .await
+            .map(MqttConnectTransport::custom)
+    }
+
+    /// Like [`TlsConfig::connect`], but returns the TLS stream so it can be wrapped further, e.g.
+    /// for WebSockets over TLS
+    pub async fn connect_stream<S: MqttTransport>(
+        &self,
+        stream: S,
+        server_name: &str,
+    ) -> Result<tokio_rustls::client::TlsStream<S>, TlsError> {
+        let server_name = ServerName::try_from(server_name.to_owned())?;
+
+        tokio_rustls::TlsConnector::from(self.config.clone())
+            .connect(server_name, stream)
+            .await
+            .map_err(TlsError::Handshake)
+    }
+}
+
+pub struct TlsConfigBuilder {
+    root_store: RootCertStore,
+    client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    alpn_protocols: Vec<Vec<u8>>,
+    enable_sni: bool,
+}
+
+impl TlsConfigBuilder {
+    fn new() -> Self {
+        Self {
+            root_store: RootCertStore::empty(),
+            client_auth: None,
+            alpn_protocols: vec![MQTT_ALPN_PROTOCOL.to_vec()],
+            enable_sni: true,
+        }
+    }
+
+    /// Replace the trusted root certificates
+    pub fn with_root_store(mut self, root_store: RootCertStore) -> Self {
+        self.root_store = root_store;
+        self
+    }
+
+    /// Trust the root certificates of the platform
+    ///
+    /// Certificates that cannot be parsed are skipped.
+    pub fn with_native_roots(mut self) -> Result<Self, TlsError> {
+        let certificates =
+            rustls_native_certs::load_native_certs().map_err(TlsError::NativeRoots)?;
+        let (added, ignored) = self.root_store.add_parsable_certificates(certificates);
+        tracing::debug!(%added, %ignored, "Loaded native root certificates");
+        Ok(self)
+    }
+
+    pub fn add_root_certificate(
+        mut self,
+        certificate: CertificateDer<'_>,
+    ) -> Result<Self, TlsError> {
+        self.root_store.add(certificate)?;
+        Ok(self)
+    }
+
+    /// Trust all certificates contained in the given PEM data
+    pub fn add_root_certificates_pem(mut self, pem: &[u8]) -> Result<Self, TlsError> {
+        for certificate in rustls_pemfile::certs(&mut &*pem) {
+            self.root_store.add(certificate.map_err(TlsError::Pem)?)?;
+        }
+        Ok(self)
+    }
+
+    /// Authenticate with the given certificate chain and private key (mTLS)
+    pub fn with_client_auth(
+        mut self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Self {
+        self.client_auth = Some((cert_chain, key));
+        self
+    }
+
+    /// Authenticate with a certificate chain and private key read from PEM data (mTLS)
+    pub fn with_client_auth_pem(self, cert_chain: &[u8], key: &[u8]) -> Result<Self, TlsError> {
+        let cert_chain = rustls_pemfile::certs(&mut &*cert_chain)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(TlsError::Pem)?;
+        let key = rustls_pemfile::private_key(&mut &*key)
+            .map_err(TlsError::Pem)?
+            .ok_or(TlsError::MissingPrivateKey)?;
+
+        Ok(self.with_client_auth(cert_chain, key))
+    }
+
+    /// Set the offered ALPN protocols, defaults to [`MQTT_ALPN_PROTOCOL`]
+    ///
+    /// Pass an empty list to not use ALPN at all.
+    pub fn with_alpn_protocols(mut self, alpn_protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = alpn_protocols;
+        self
+    }
+
+    /// Whether to send the server name via SNI, enabled by default
+    pub fn with_sni(mut self, enable_sni: bool) -> Self {
+        self.enable_sni = enable_sni;
+        self
+    }
+
+    pub fn build(self) -> Result<TlsConfig, TlsError> {
+        let builder = ClientConfig::builder().with_root_certificates(self.root_store);
+
+        let mut config = match self.client_auth {
+            Some((cert_chain, key)) => builder.with_client_auth_cert(cert_chain, key)?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols;
+        config.enable_sni = self.enable_sni;
+
+        Ok(TlsConfig::from_rustls(Arc::new(config)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::pingreq::MPingreq;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+    use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::RootCertStore;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_util::codec::Framed;
+
+    use super::TlsConfig;
+    use super::MQTT_ALPN_PROTOCOL;
+    use crate::codecs::MqttPacketCodec;
+    use crate::transport::MqttConnection;
+
+    fn self_signed(name: &str) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        (
+            CertificateDer::from(cert.serialize_der().unwrap()),
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.serialize_private_key_der())),
+        )
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_roundtrip() {
+        let (server_cert, server_key) = self_signed("localhost");
+        let (client_cert, client_key) = self_signed("client");
+
+        let mut client_roots = RootCertStore::empty();
+        client_roots.add(client_cert.clone()).unwrap();
+        let mut server_config = ServerConfig::builder()
+            .with_client_cert_verifier(
+                WebPkiClientVerifier::builder(Arc::new(client_roots))
+                    .build()
+                    .unwrap(),
+            )
+            .with_single_cert(vec![server_cert.clone()], server_key)
+            .unwrap();
+        server_config.alpn_protocols = vec![MQTT_ALPN_PROTOCOL.to_vec()];
+
+        let config = TlsConfig::builder()
+            .add_root_certificate(server_cert)
+            .unwrap()
+            .with_client_auth(vec![client_cert], client_key)
+            .build()
+            .unwrap();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let server = tokio::spawn(async move {
+            let stream = acceptor.accept(server).await.unwrap();
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(MQTT_ALPN_PROTOCOL));
+            assert_eq!(stream.get_ref().1.server_name(), Some("localhost"));
+
+            let mut server = Framed::new(stream, MqttPacketCodec);
+            let packet = server.next().await.unwrap().unwrap();
+            assert_eq!(*packet.get(), FormatMqttPacket::Pingreq(MPingreq));
+        });
+
+        let transport = config.connect(client, "localhost").await.unwrap();
+        let mut client = Framed::new(MqttConnection::from(transport), MqttPacketCodec);
+        client
+            .send(FormatMqttPacket::Pingreq(MPingreq))
+            .await
+            .unwrap();
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn untrusted_server_certificate_is_rejected() {
+        let (server_cert, server_key) = self_signed("localhost");
+        let (other_cert, _) = self_signed("localhost");
+
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![server_cert], server_key)
+            .unwrap();
+
+        let config = TlsConfig::builder()
+            .add_root_certificate(other_cert)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let server = tokio::spawn(async move { acceptor.accept(server).await });
+
+        assert!(config.connect(client, "localhost").await.is_err());
+        assert!(server.await.unwrap().is_err());
+    }
+}
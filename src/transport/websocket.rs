@@ -0,0 +1,384 @@
+//
+//   This Source Code Form is subject to the terms of the Mozilla Public
+//   License, v. 2.0. If a copy of the MPL was not distributed with this
+//   file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! MQTT over WebSockets
+//!
+//! MQTT packets are sent as binary WebSocket messages. A message may contain several or only parts
+//! of packets, so the received messages are exposed as a plain byte stream again.
+//!
+//! ```no_run
+//! # async fn connect() -> Result<(), Box<dyn std::error::Error>> {
+//! use cloudmqtt::transport::websocket::WebSocketConfig;
+//!
+//! let stream = tokio::net::TcpStream::connect("broker.example.com:80").await?;
+//! let transport = WebSocketConfig::new()
+//!     .with_path("/mqtt")
+//!     .connect(stream, "broker.example.com", 80)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::pin::Pin;
+use std::task::ready;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::Sink;
+use futures::Stream;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderName;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use super::MqttConnectTransport;
+use super::MqttTransport;
+
+/// The WebSocket subprotocol registered for MQTT
+pub const MQTT_SUBPROTOCOL: &str = "mqtt";
+
+const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebSocketError {
+    #[error("Could not build the WebSocket request")]
+    InvalidRequest(#[source] tungstenite::Error),
+
+    #[error("The WebSocket handshake failed")]
+    Handshake(#[source] tungstenite::Error),
+
+    #[error("The server did not agree on any of the offered subprotocols")]
+    SubprotocolMismatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    path: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    subprotocols: Vec<String>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebSocketConfig {
+    /// A configuration connecting to `/mqtt` with the `mqtt` subprotocol
+    pub fn new() -> Self {
+        Self {
+            path: String::from("/mqtt"),
+            headers: Vec::new(),
+            subprotocols: vec![String::from(MQTT_SUBPROTOCOL)],
+        }
+    }
+
+    /// Set the path of the request, it must start with a `/`
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Add a header to the request, e.g. for authentication at a load balancer
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Set the offered subprotocols, in order of preference
+    ///
+    /// If any are offered the server has to select one of them, otherwise the handshake fails.
+    pub fn with_subprotocols(mut self, subprotocols: Vec<String>) -> Self {
+        self.subprotocols = subprotocols;
+        self
+    }
+
+    /// Run the WebSocket handshake over `stream`
+    ///
+    /// `host` and `port` are sent as the `Host` header, IPv6 addresses may be given with or
+    /// without brackets. For `wss://` pass a stream from
+    /// [`TlsConfig::connect_stream`](super::tls::TlsConfig::connect_stream).
+    pub async fn connect<S: MqttTransport>(
+        &self,
+        stream: S,
+        host: &str,
+        port: u16,
+    ) -> Result<MqttConnectTransport, WebSocketError> {
+        self.connect_stream(stream, host, port)
+            .await
+            .map(MqttConnectTransport::custom)
+    }
+
+    /// Like [`WebSocketConfig::connect`], but returns the byte stream over the WebSocket
+    pub async fn connect_stream<S: MqttTransport>(
+        &self,
+        stream: S,
+        host: &str,
+        port: u16,
+    ) -> Result<WebSocketTransport<S>, WebSocketError> {
+        let mut request = format!("ws://{}{}", authority(host, port), self.path)
+            .into_client_request()
+            .map_err(WebSocketError::InvalidRequest)?;
+
+        for (name, value) in &self.headers {
+            request.headers_mut().append(name.clone(), value.clone());
+        }
+
+        if !self.subprotocols.is_empty() {
+            let subprotocols = HeaderValue::from_str(&self.subprotocols.join(", "))
+                .map_err(|e| WebSocketError::InvalidRequest(tungstenite::Error::from(e)))?;
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, subprotocols);
+        }
+
+        let (inner, response) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .map_err(WebSocketError::Handshake)?;
+
+        if !self.subprotocols.is_empty() {
+            let selected = response
+                .headers()
+                .get(SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|value| value.to_str().ok());
+
+            if !selected.is_some_and(|selected| self.subprotocols.iter().any(|p| p == selected)) {
+                tracing::error!(?selected, "Server selected an unexpected subprotocol");
+                return Err(WebSocketError::SubprotocolMismatch);
+            }
+        }
+
+        Ok(WebSocketTransport::new(inner))
+    }
+}
+
+/// The `host:port` authority of the request URI, with IPv6 addresses in brackets
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// A byte stream over a WebSocket connection
+///
+/// Every write is sent as one binary message, received binary messages are read in order.
+pub struct WebSocketTransport<S> {
+    inner: WebSocketStream<S>,
+    read_buffer: Vec<u8>,
+    read_position: usize,
+}
+
+impl<S> WebSocketTransport<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buffer: Vec::new(),
+            read_position: 0,
+        }
+    }
+}
+
+fn into_io_error(error: tungstenite::Error) -> std::io::Error {
+    match error {
+        tungstenite::Error::Io(error) => error,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            std::io::ErrorKind::BrokenPipe.into()
+        }
+        error => std::io::Error::new(std::io::ErrorKind::Other, error),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketTransport<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if self.read_position < self.read_buffer.len() {
+                let this = &mut *self;
+                let available = &this.read_buffer[this.read_position..];
+                let length = available.len().min(buf.remaining());
+                buf.put_slice(&available[..length]);
+                this.read_position += length;
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_buffer = data;
+                    self.read_position = 0;
+                }
+                // Pings are answered by tungstenite on the next write or flush
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Received a text message, MQTT requires binary messages",
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketTransport<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(into_io_error)?;
+
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(into_io_error)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(into_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use mqtt_format::v5::packets::pingreq::MPingreq;
+    use mqtt_format::v5::packets::MqttPacket as FormatMqttPacket;
+    use tokio_tungstenite::tungstenite::handshake::server::Request;
+    use tokio_tungstenite::tungstenite::handshake::server::Response;
+    use tokio_tungstenite::tungstenite::http::HeaderName;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_util::codec::Framed;
+
+    use super::WebSocketConfig;
+    use super::WebSocketError;
+    use crate::codecs::MqttPacketCodec;
+    use crate::transport::MqttConnection;
+
+    async fn accept(
+        stream: tokio::io::DuplexStream,
+        subprotocol: Option<&'static str>,
+        host: &'static str,
+    ) -> tokio_tungstenite::WebSocketStream<tokio::io::DuplexStream> {
+        tokio_tungstenite::accept_hdr_async(
+            stream,
+            move |request: &Request, mut response: Response| {
+                assert_eq!(request.uri().path(), "/broker");
+                assert_eq!(request.headers()["host"], host);
+                assert_eq!(request.headers()["authorization"], "Bearer token");
+                if let Some(subprotocol) = subprotocol {
+                    response.headers_mut().insert(
+                        "Sec-WebSocket-Protocol",
+                        HeaderValue::from_static(subprotocol),
+                    );
+                }
+                Ok(response)
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    fn config() -> WebSocketConfig {
+        WebSocketConfig::new().with_path("/broker").with_header(
+            HeaderName::from_static("authorization"),
+            HeaderValue::from_static("Bearer token"),
+        )
+    }
+
+    #[tokio::test]
+    async fn packets_split_across_messages() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut server = accept(server, Some("mqtt"), "localhost:80").await;
+
+            // A PINGREQ split into two messages, followed by one message with two PINGREQs
+            server.send(Message::Binary(vec![0xC0])).await.unwrap();
+            server.send(Message::Binary(vec![0x00])).await.unwrap();
+            server
+                .send(Message::Binary(vec![0xC0, 0x00, 0xC0, 0x00]))
+                .await
+                .unwrap();
+
+            let Some(Ok(Message::Binary(data))) = server.next().await else {
+                panic!("Expected a binary message");
+            };
+            assert_eq!(data, vec![0xC0, 0x00]);
+        });
+
+        let transport = config().connect(client, "localhost", 80).await.unwrap();
+        let mut client = Framed::new(MqttConnection::from(transport), MqttPacketCodec);
+
+        for _ in 0..3 {
+            let packet = client.next().await.unwrap().unwrap();
+            assert_eq!(*packet.get(), FormatMqttPacket::Pingreq(MPingreq));
+        }
+
+        client
+            .send(FormatMqttPacket::Pingreq(MPingreq))
+            .await
+            .unwrap();
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_subprotocol_is_rejected() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(accept(server, None, "localhost:80"));
+
+        let result = config().connect(client, "localhost", 80).await;
+        assert!(matches!(result, Err(WebSocketError::SubprotocolMismatch)));
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn host_header_contains_non_default_port() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(accept(server, Some("mqtt"), "broker.example.com:9001"));
+
+        config()
+            .connect(client, "broker.example.com", 9001)
+            .await
+            .unwrap();
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ipv6_host_is_bracketed() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(accept(server, Some("mqtt"), "[::1]:9001"));
+
+        config().connect(client, "::1", 9001).await.unwrap();
+
+        server.await.unwrap();
+    }
+}